@@ -0,0 +1,122 @@
+//! Feed and comment tree resolvers against a seeded database.
+//!
+//! Needs DATABASE_URL pointing at a database with some content in it, BENCH_SUB and BENCH_POST
+//! pick the sub and post (public id) to load. A fresh Context is built for every iteration so
+//! the loaders start with empty caches, like they would for a real request.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use model::{
+    auth::UserState, config::Config, events::LocalBus, mailer::LogMailer, Context, Mutation, Query,
+    RequestInfo, Schema,
+};
+use std::{env, sync::Arc};
+use tokio::runtime::{Builder, Runtime};
+
+struct Bench {
+    runtime: Runtime,
+    schema: Schema,
+    pool: sqlx::PgPool,
+    config: Arc<Config>,
+}
+
+impl Bench {
+    fn new() -> Option<Self> {
+        dotenv::dotenv().ok();
+        let url = env::var("DATABASE_URL").ok()?;
+        let mut runtime = Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let pool = runtime.block_on(sqlx::Pool::connect(&url)).unwrap();
+        Some(Bench {
+            runtime,
+            schema: Schema::new(
+                Query,
+                Mutation,
+                juniper::EmptySubscription::<Context>::new(),
+            ),
+            pool,
+            config: Arc::new(Config::from_env()),
+        })
+    }
+
+    fn run(&mut self, query: &str, variables: serde_json::Value) {
+        let context = Context::new(
+            UserState::anonymous(),
+            RequestInfo::default(),
+            self.pool.clone(),
+            Arc::new(LogMailer),
+            Arc::new(LocalBus::new()),
+            self.config.clone(),
+        );
+        let request: juniper::http::GraphQLRequest =
+            serde_json::from_value(serde_json::json!({ "query": query, "variables": variables }))
+                .unwrap();
+        let schema = &self.schema;
+        let response = self.runtime.block_on(request.execute(schema, &context));
+        assert!(
+            response.is_ok(),
+            "{}",
+            serde_json::to_string(&response).unwrap()
+        );
+    }
+}
+
+const SUB_POSTS: &str = r#"
+query($sub: String!) {
+    getSub(name: $sub) {
+        posts(count: 50) {
+            edges { node { id title } }
+        }
+    }
+}
+"#;
+
+const COMMENT_TREE: &str = r#"
+query($post: ID!) {
+    getPost(id: $post) {
+        comments(limit: 50) {
+            edges { node { id content children(limit: 20) {
+                edges { node { id content children(limit: 10) {
+                    edges { node { id content } }
+                } } }
+            } } }
+        }
+    }
+}
+"#;
+
+/// Every post pulls in its author and sub, which should come out as one query per loader
+const HOME_WITH_RELATIONS: &str = r#"
+query {
+    getAllPosts(count: 100) {
+        edges { node { id author { name } sub { name } } }
+    }
+}
+"#;
+
+fn feeds(c: &mut Criterion) {
+    let mut bench = match Bench::new() {
+        Some(bench) => bench,
+        None => {
+            eprintln!("DATABASE_URL not set, skipping");
+            return;
+        }
+    };
+    let sub = env::var("BENCH_SUB").unwrap_or_else(|_| "test".into());
+    let post = env::var("BENCH_POST").unwrap_or_else(|_| "1".into());
+
+    c.bench_function("get_related_posts", |b| {
+        b.iter(|| bench.run(SUB_POSTS, serde_json::json!({ "sub": sub })))
+    });
+    c.bench_function("comment_tree", |b| {
+        b.iter(|| bench.run(COMMENT_TREE, serde_json::json!({ "post": post })))
+    });
+    c.bench_function("loader_batching", |b| {
+        b.iter(|| bench.run(HOME_WITH_RELATIONS, serde_json::json!({})))
+    });
+}
+
+criterion_group!(benches, feeds);
+criterion_main!(benches);
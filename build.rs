@@ -0,0 +1,42 @@
+use std::{
+    env, fs,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Stamps the build with the commit and time for the schemaVersion query. GIT_SHA and
+/// SOURCE_DATE_EPOCH take precedence, for builds outside a checkout or reproducible ones.
+fn main() {
+    let sha = env::var("GIT_SHA").ok().or_else(|| {
+        Command::new("git")
+            .args(&["rev-parse", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|sha| sha.trim().to_string())
+    });
+    let time = env::var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs().to_string())
+            .unwrap_or_default()
+    });
+
+    println!(
+        "cargo:rustc-env=THROATQL_GIT_SHA={}",
+        sha.unwrap_or_else(|| "unknown".to_string())
+    );
+    println!("cargo:rustc-env=THROATQL_BUILD_TIME={}", time);
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    // A commit moves the branch HEAD names, not HEAD itself. Packed branches live in packed-refs.
+    if let Some(head) = fs::read_to_string(".git/HEAD").ok().and_then(|head| {
+        head.strip_prefix("ref: ")
+            .map(|name| name.trim().to_string())
+    }) {
+        println!("cargo:rerun-if-changed=.git/{}", head);
+    }
+    println!("cargo:rerun-if-changed=.git/packed-refs");
+}
@@ -0,0 +1,9 @@
+-- Single use tokens mailed to users for password resets and email verification
+CREATE TABLE IF NOT EXISTS user_token (
+    token text PRIMARY KEY,
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    kind text NOT NULL,
+    expires timestamp NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS user_token_uid ON user_token (uid);
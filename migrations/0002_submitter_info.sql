@@ -0,0 +1,14 @@
+-- Where posts and comments were submitted from, only written when RECORD_SUBMITTER_IP is set
+CREATE TABLE IF NOT EXISTS submitter_info (
+    id serial PRIMARY KEY,
+    pid integer REFERENCES sub_post (pid) ON DELETE CASCADE,
+    cid text REFERENCES sub_post_comment (cid) ON DELETE CASCADE,
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    ip text NOT NULL,
+    user_agent text,
+    time timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS submitter_info_ip ON submitter_info (ip);
+CREATE INDEX IF NOT EXISTS submitter_info_pid ON submitter_info (pid);
+CREATE INDEX IF NOT EXISTS submitter_info_cid ON submitter_info (cid);
@@ -0,0 +1,10 @@
+-- Record of sensitive admin actions such as impersonating other users
+CREATE TABLE IF NOT EXISTS admin_audit (
+    id serial PRIMARY KEY,
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    action text NOT NULL,
+    target text,
+    time timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS admin_audit_uid ON admin_audit (uid);
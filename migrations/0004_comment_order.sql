@@ -0,0 +1,5 @@
+-- Lets comment pages be read straight off an index in (time, cid) order
+CREATE INDEX IF NOT EXISTS sub_post_comment_children
+    ON sub_post_comment (parentcid, time, cid);
+CREATE INDEX IF NOT EXISTS sub_post_comment_top_level
+    ON sub_post_comment (pid, time, cid) WHERE parentcid IS NULL;
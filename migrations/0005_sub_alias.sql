@@ -0,0 +1,9 @@
+-- Names a sub used to have, so links to the old name keep working after a rename
+CREATE TABLE IF NOT EXISTS sub_alias (
+    name text NOT NULL,
+    sid text NOT NULL REFERENCES sub (sid) ON DELETE CASCADE,
+    renamed timestamp NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS sub_alias_name ON sub_alias (lower(name));
+CREATE INDEX IF NOT EXISTS sub_alias_sid ON sub_alias (sid);
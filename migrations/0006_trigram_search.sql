@@ -0,0 +1,5 @@
+-- Fuzzy name matching for searchSubs and searchUsers
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX IF NOT EXISTS sub_name_trgm ON sub USING gin (lower(name) gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS user_name_trgm ON public.user USING gin (lower(name) gin_trgm_ops);
@@ -0,0 +1,9 @@
+-- Posts each user has already seen, trimmed to the most recent ones by mark_seen
+CREATE TABLE IF NOT EXISTS post_seen (
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    pid integer NOT NULL REFERENCES sub_post (pid) ON DELETE CASCADE,
+    seen timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (uid, pid)
+);
+
+CREATE INDEX IF NOT EXISTS post_seen_recent ON post_seen (uid, seen DESC);
@@ -0,0 +1,10 @@
+-- Unfinished posts and comments, one per user, kind and target
+CREATE TABLE IF NOT EXISTS user_draft (
+    id serial PRIMARY KEY,
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    kind text NOT NULL,
+    target text NOT NULL,
+    content text NOT NULL,
+    updated timestamp NOT NULL DEFAULT now(),
+    UNIQUE (uid, kind, target)
+);
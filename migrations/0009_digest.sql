@@ -0,0 +1,4 @@
+-- Digest preferences and the last send time live in user_metadata ('digest', 'digest_sent'),
+-- this only speeds up finding who is due
+CREATE INDEX IF NOT EXISTS user_metadata_digest ON user_metadata (key, uid)
+    WHERE key IN ('digest', 'digest_sent');
@@ -0,0 +1,49 @@
+-- Web Push subscriptions of the PWA, one per browser
+CREATE TABLE IF NOT EXISTS push_subscription (
+    endpoint text PRIMARY KEY,
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    p256dh text NOT NULL,
+    auth text NOT NULL,
+    created timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS push_subscription_uid ON push_subscription (uid);
+
+-- Replies and private messages are written by Throat itself, these put them on the event bus
+-- (PG_EVENTS) so the push worker hears about them
+CREATE OR REPLACE FUNCTION throatql_comment_replied() RETURNS trigger AS $$
+DECLARE
+    recipient text;
+BEGIN
+    IF NEW.parentcid IS NOT NULL THEN
+        SELECT uid INTO recipient FROM sub_post_comment WHERE cid = NEW.parentcid;
+    ELSE
+        SELECT uid INTO recipient FROM sub_post WHERE pid = NEW.pid;
+    END IF;
+    IF recipient IS NOT NULL AND recipient <> NEW.uid THEN
+        PERFORM pg_notify('throatql_events', json_build_object(
+            'type', 'CommentReplied', 'cid', NEW.cid, 'uid', recipient
+        )::text);
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS throatql_comment_replied ON sub_post_comment;
+CREATE TRIGGER throatql_comment_replied AFTER INSERT ON sub_post_comment
+    FOR EACH ROW EXECUTE PROCEDURE throatql_comment_replied();
+
+CREATE OR REPLACE FUNCTION throatql_message_received() RETURNS trigger AS $$
+BEGIN
+    IF NEW.receivedby IS NOT NULL AND NEW.receivedby IS DISTINCT FROM NEW.sentby THEN
+        PERFORM pg_notify('throatql_events', json_build_object(
+            'type', 'MessageReceived', 'mid', NEW.mid, 'uid', NEW.receivedby
+        )::text);
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS throatql_message_received ON message;
+CREATE TRIGGER throatql_message_received AFTER INSERT ON message
+    FOR EACH ROW EXECUTE PROCEDURE throatql_message_received();
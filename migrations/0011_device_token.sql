@@ -0,0 +1,9 @@
+-- APNs and FCM tokens of the mobile apps, one per installation
+CREATE TABLE IF NOT EXISTS device_token (
+    token text PRIMARY KEY,
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    platform text NOT NULL,
+    created timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS device_token_uid ON device_token (uid);
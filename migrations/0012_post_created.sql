@@ -0,0 +1,14 @@
+-- Posts are submitted through Throat, this puts them on the event bus (PG_EVENTS) so the
+-- ActivityPub outboxes pick them up
+CREATE OR REPLACE FUNCTION throatql_post_created() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('throatql_events', json_build_object(
+        'type', 'PostCreated', 'pid', NEW.pid, 'sid', NEW.sid
+    )::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS throatql_post_created ON sub_post;
+CREATE TRIGGER throatql_post_created AFTER INSERT ON sub_post
+    FOR EACH ROW EXECUTE PROCEDURE throatql_post_created();
@@ -0,0 +1,13 @@
+-- Moderation done through the API, one row per action however many targets it covered
+CREATE TABLE IF NOT EXISTS mod_log (
+    id serial PRIMARY KEY,
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    -- Null when the targets spanned several subs
+    sid text REFERENCES sub (sid) ON DELETE CASCADE,
+    action text NOT NULL,
+    targets text[] NOT NULL,
+    reason text,
+    time timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS mod_log_sid ON mod_log (sid, time);
@@ -0,0 +1,13 @@
+-- Exports requested by sub mods, built in the background and downloaded through a signed link
+CREATE TABLE IF NOT EXISTS sub_export (
+    id text PRIMARY KEY,
+    sid text NOT NULL REFERENCES sub (sid) ON DELETE CASCADE,
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    kind text NOT NULL,
+    format text NOT NULL,
+    created timestamp NOT NULL DEFAULT now(),
+    body text,
+    error text
+);
+
+CREATE INDEX IF NOT EXISTS sub_export_created ON sub_export (created);
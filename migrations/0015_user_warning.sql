@@ -0,0 +1,11 @@
+-- Formal warnings mods give users in their sub
+CREATE TABLE IF NOT EXISTS user_warning (
+    id serial PRIMARY KEY,
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    sid text NOT NULL REFERENCES sub (sid) ON DELETE CASCADE,
+    issued_by text REFERENCES public.user (uid) ON DELETE SET NULL,
+    reason text NOT NULL,
+    time timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS user_warning_uid ON user_warning (uid, sid, time);
@@ -0,0 +1,10 @@
+-- Temporary mutes, unlike bans they run out by themselves. Expired rows are ignored and cleaned up
+-- whenever the user is muted again.
+CREATE TABLE IF NOT EXISTS sub_mute (
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    sid text NOT NULL REFERENCES sub (sid) ON DELETE CASCADE,
+    muted_by text REFERENCES public.user (uid) ON DELETE SET NULL,
+    reason text,
+    expires timestamp NOT NULL,
+    PRIMARY KEY (uid, sid)
+);
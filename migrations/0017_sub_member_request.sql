@@ -0,0 +1,13 @@
+-- Requests to join restricted subs (sub_metadata 'restricted' = '1') and what the mods decided
+CREATE TABLE IF NOT EXISTS sub_member_request (
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    sid text NOT NULL REFERENCES sub (sid) ON DELETE CASCADE,
+    status text NOT NULL DEFAULT 'pending',
+    requested timestamp NOT NULL DEFAULT now(),
+    decided timestamp,
+    decided_by text REFERENCES public.user (uid) ON DELETE SET NULL,
+    PRIMARY KEY (uid, sid)
+);
+
+CREATE INDEX IF NOT EXISTS sub_member_request_pending ON sub_member_request (sid, requested)
+    WHERE status = 'pending';
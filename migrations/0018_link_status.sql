@@ -0,0 +1,10 @@
+-- Whether link posts still lead somewhere, kept up to date by the link checker (LINK_CHECKER)
+CREATE TABLE IF NOT EXISTS link_status (
+    pid int PRIMARY KEY REFERENCES sub_post (pid) ON DELETE CASCADE,
+    dead boolean NOT NULL,
+    checked timestamp NOT NULL DEFAULT now(),
+    -- Set by a mod, the checker leaves these alone
+    manual boolean NOT NULL DEFAULT false
+);
+
+CREATE INDEX IF NOT EXISTS link_status_checked ON link_status (checked);
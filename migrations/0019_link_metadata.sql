@@ -0,0 +1,9 @@
+-- OpenGraph details of the page a link post points at
+CREATE TABLE IF NOT EXISTS link_metadata (
+    pid int PRIMARY KEY REFERENCES sub_post (pid) ON DELETE CASCADE,
+    title text,
+    description text,
+    image_url text,
+    site_name text,
+    fetched_at timestamp NOT NULL DEFAULT now()
+);
@@ -0,0 +1,13 @@
+-- Uploaded images, unattached until their author adds them to a post (or comment)
+CREATE TABLE IF NOT EXISTS attachment (
+    id text PRIMARY KEY,
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    pid int REFERENCES sub_post (pid) ON DELETE CASCADE,
+    filename text NOT NULL,
+    content_type text NOT NULL,
+    size int NOT NULL,
+    created timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS attachment_uid ON attachment (uid);
+CREATE INDEX IF NOT EXISTS attachment_pid ON attachment (pid) WHERE pid IS NOT NULL;
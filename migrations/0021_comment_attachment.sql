@@ -0,0 +1,4 @@
+-- Images on comments, only where the sub turned on the comment_images setting
+ALTER TABLE attachment ADD COLUMN IF NOT EXISTS cid text REFERENCES sub_post_comment (cid) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS attachment_cid ON attachment (cid) WHERE cid IS NOT NULL;
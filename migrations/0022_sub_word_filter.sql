@@ -0,0 +1,13 @@
+-- Words or patterns mods don't want in their sub. 'remove' refuses the content outright, 'hold'
+-- lets it through but removes it until a mod approves it.
+CREATE TABLE IF NOT EXISTS sub_word_filter (
+    id serial PRIMARY KEY,
+    sid text NOT NULL REFERENCES sub (sid) ON DELETE CASCADE,
+    pattern text NOT NULL,
+    is_regex boolean NOT NULL DEFAULT false,
+    action text NOT NULL CHECK (action IN ('remove', 'hold')),
+    created_by text REFERENCES public.user (uid) ON DELETE SET NULL,
+    created timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS sub_word_filter_sid ON sub_word_filter (sid);
@@ -0,0 +1,9 @@
+-- Language of each post as found by the language detector (LANGUAGE_DETECTOR). A null language
+-- means detection wasn't confident, the row still keeps the post from being looked at again.
+CREATE TABLE IF NOT EXISTS post_language (
+    pid int PRIMARY KEY REFERENCES sub_post (pid) ON DELETE CASCADE,
+    language text,
+    detected timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS post_language_language ON post_language (language, pid);
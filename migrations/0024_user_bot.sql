@@ -0,0 +1,11 @@
+-- Accounts run by programs. Users ask for the flag themselves and it only shows once an admin
+-- approved it.
+CREATE TABLE IF NOT EXISTS user_bot (
+    uid text PRIMARY KEY REFERENCES public.user (uid) ON DELETE CASCADE,
+    approved boolean NOT NULL DEFAULT false,
+    requested timestamp NOT NULL DEFAULT now(),
+    decided_by text REFERENCES public.user (uid) ON DELETE SET NULL,
+    decided timestamp
+);
+
+CREATE INDEX IF NOT EXISTS user_bot_pending ON user_bot (requested) WHERE NOT approved;
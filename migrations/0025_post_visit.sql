@@ -0,0 +1,9 @@
+-- When each user last opened a post's comments, for the "X new comments" counts
+CREATE TABLE IF NOT EXISTS post_visit (
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    pid integer NOT NULL REFERENCES sub_post (pid) ON DELETE CASCADE,
+    visited timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (uid, pid)
+);
+
+CREATE INDEX IF NOT EXISTS post_visit_recent ON post_visit (uid, visited DESC);
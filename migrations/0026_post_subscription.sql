@@ -0,0 +1,36 @@
+-- Threads users follow without having written them, every new comment in one is a notification
+CREATE TABLE IF NOT EXISTS post_subscription (
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    pid integer NOT NULL REFERENCES sub_post (pid) ON DELETE CASCADE,
+    created timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (uid, pid)
+);
+
+CREATE INDEX IF NOT EXISTS post_subscription_pid ON post_subscription (pid);
+
+-- Subscribers already told through CommentReplied, and the comment's author, are skipped
+CREATE OR REPLACE FUNCTION throatql_thread_commented() RETURNS trigger AS $$
+DECLARE
+    replied_to text;
+    subscriber text;
+BEGIN
+    IF NEW.parentcid IS NOT NULL THEN
+        SELECT uid INTO replied_to FROM sub_post_comment WHERE cid = NEW.parentcid;
+    ELSE
+        SELECT uid INTO replied_to FROM sub_post WHERE pid = NEW.pid;
+    END IF;
+    FOR subscriber IN
+        SELECT uid FROM post_subscription
+        WHERE pid = NEW.pid AND uid IS DISTINCT FROM NEW.uid AND uid IS DISTINCT FROM replied_to
+    LOOP
+        PERFORM pg_notify('throatql_events', json_build_object(
+            'type', 'ThreadCommented', 'cid', NEW.cid, 'pid', NEW.pid, 'uid', subscriber
+        )::text);
+    END LOOP;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS throatql_thread_commented ON sub_post_comment;
+CREATE TRIGGER throatql_thread_commented AFTER INSERT ON sub_post_comment
+    FOR EACH ROW EXECUTE PROCEDURE throatql_thread_commented();
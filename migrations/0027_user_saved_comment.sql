@@ -0,0 +1,9 @@
+-- Saved comments, next to Throat's user_saved for posts
+CREATE TABLE IF NOT EXISTS user_saved_comment (
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    cid text NOT NULL REFERENCES sub_post_comment (cid) ON DELETE CASCADE,
+    saved timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (uid, cid)
+);
+
+CREATE INDEX IF NOT EXISTS user_saved_post ON user_saved (uid, pid);
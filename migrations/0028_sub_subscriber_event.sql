@@ -0,0 +1,36 @@
+-- Every subscribe (+1) and unsubscribe (-1), for Sub.subscriberHistory. Throat writes
+-- sub_subscriber itself, so a trigger records the changes instead of the API.
+CREATE TABLE IF NOT EXISTS sub_subscriber_event (
+    id serial PRIMARY KEY,
+    sid text NOT NULL REFERENCES sub (sid) ON DELETE CASCADE,
+    uid text NOT NULL,
+    change smallint NOT NULL,
+    time timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS sub_subscriber_event_sid_time ON sub_subscriber_event (sid, time);
+
+-- Unsubscribes from before this existed are lost, current subscribers at least add up
+INSERT INTO sub_subscriber_event (sid, uid, change, time)
+SELECT s.sid, s.uid, 1, coalesce(s.time, sub.creation)
+FROM sub_subscriber s
+JOIN sub USING (sid)
+WHERE s.status = 1
+    AND NOT EXISTS (SELECT 1 FROM sub_subscriber_event);
+
+CREATE OR REPLACE FUNCTION throatql_subscriber_changed() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') AND OLD.status = 1 THEN
+        INSERT INTO sub_subscriber_event (sid, uid, change) VALUES (OLD.sid, OLD.uid, -1);
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') AND NEW.status = 1 THEN
+        INSERT INTO sub_subscriber_event (sid, uid, change) VALUES (NEW.sid, NEW.uid, 1);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS throatql_subscriber_changed ON sub_subscriber;
+CREATE TRIGGER throatql_subscriber_changed AFTER INSERT OR DELETE OR UPDATE OF status, sid, uid
+    ON sub_subscriber
+    FOR EACH ROW EXECUTE PROCEDURE throatql_subscriber_changed();
@@ -0,0 +1,61 @@
+-- Posts and comments that were created, edited, deleted or restored, in the order it happened,
+-- for getChanges. Written by triggers so changes made through Throat are in it too.
+CREATE TABLE IF NOT EXISTS content_change (
+    id bigserial PRIMARY KEY,
+    change_type text NOT NULL,
+    action text NOT NULL,
+    pid integer,
+    cid text,
+    time timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS content_change_time ON content_change (time);
+
+CREATE OR REPLACE FUNCTION throatql_post_changed() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO content_change (change_type, action, pid) VALUES ('post', 'created', NEW.pid);
+    ELSIF TG_OP = 'DELETE' THEN
+        INSERT INTO content_change (change_type, action, pid) VALUES ('post', 'deleted', OLD.pid);
+    ELSIF coalesce(OLD.deleted, 0) = 0 AND coalesce(NEW.deleted, 0) <> 0 THEN
+        INSERT INTO content_change (change_type, action, pid) VALUES ('post', 'deleted', NEW.pid);
+    ELSIF coalesce(OLD.deleted, 0) <> 0 AND coalesce(NEW.deleted, 0) = 0 THEN
+        INSERT INTO content_change (change_type, action, pid) VALUES ('post', 'restored', NEW.pid);
+    ELSIF (OLD.title, OLD.content, OLD.link) IS DISTINCT FROM (NEW.title, NEW.content, NEW.link) THEN
+        INSERT INTO content_change (change_type, action, pid) VALUES ('post', 'edited', NEW.pid);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS throatql_post_changed ON sub_post;
+CREATE TRIGGER throatql_post_changed AFTER INSERT OR DELETE OR UPDATE OF title, content, link, deleted
+    ON sub_post
+    FOR EACH ROW EXECUTE PROCEDURE throatql_post_changed();
+
+CREATE OR REPLACE FUNCTION throatql_comment_changed() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO content_change (change_type, action, pid, cid)
+        VALUES ('comment', 'created', NEW.pid, NEW.cid);
+    ELSIF TG_OP = 'DELETE' THEN
+        INSERT INTO content_change (change_type, action, pid, cid)
+        VALUES ('comment', 'deleted', OLD.pid, OLD.cid);
+    ELSIF coalesce(OLD.status, 0) = 0 AND coalesce(NEW.status, 0) <> 0 THEN
+        INSERT INTO content_change (change_type, action, pid, cid)
+        VALUES ('comment', 'deleted', NEW.pid, NEW.cid);
+    ELSIF coalesce(OLD.status, 0) <> 0 AND coalesce(NEW.status, 0) = 0 THEN
+        INSERT INTO content_change (change_type, action, pid, cid)
+        VALUES ('comment', 'restored', NEW.pid, NEW.cid);
+    ELSIF OLD.content IS DISTINCT FROM NEW.content THEN
+        INSERT INTO content_change (change_type, action, pid, cid)
+        VALUES ('comment', 'edited', NEW.pid, NEW.cid);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS throatql_comment_changed ON sub_post_comment;
+CREATE TRIGGER throatql_comment_changed AFTER INSERT OR DELETE OR UPDATE OF content, status
+    ON sub_post_comment
+    FOR EACH ROW EXECUTE PROCEDURE throatql_comment_changed();
@@ -0,0 +1,21 @@
+-- Custom emoji a sub's mods added for reactions, next to the standard ones
+CREATE TABLE IF NOT EXISTS sub_emoji (
+    sid text NOT NULL REFERENCES sub (sid) ON DELETE CASCADE,
+    name text NOT NULL,
+    url text NOT NULL,
+    created_by text REFERENCES public.user (uid) ON DELETE SET NULL,
+    created timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (sid, name)
+);
+
+-- Reactions on comments, only where the sub turned on the comment_reactions setting. They don't
+-- count towards the score.
+CREATE TABLE IF NOT EXISTS sub_post_comment_reaction (
+    cid text NOT NULL REFERENCES sub_post_comment (cid) ON DELETE CASCADE,
+    uid text NOT NULL REFERENCES public.user (uid) ON DELETE CASCADE,
+    emoji text NOT NULL,
+    time timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (cid, uid, emoji)
+);
+
+CREATE INDEX IF NOT EXISTS sub_post_comment_reaction_cid ON sub_post_comment_reaction (cid, emoji);
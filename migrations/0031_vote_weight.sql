@@ -0,0 +1,3 @@
+-- How much a post vote counts towards rankings, set when it's cast. Votes from accounts newer
+-- than the new_account_vote_days site setting get new_account_vote_weight instead of 1.
+ALTER TABLE sub_post_vote ADD COLUMN IF NOT EXISTS weight double precision NOT NULL DEFAULT 1;
@@ -0,0 +1,14 @@
+-- Vote patterns of posts that got a lot of votes within an hour, recorded by the brigade detector
+-- (BRIGADE_DETECTOR) every few minutes and kept for a week. `outsider_fraction` is the part of
+-- those votes from accounts with no earlier visit to another post of the sub.
+CREATE TABLE IF NOT EXISTS post_vote_signal (
+    id serial PRIMARY KEY,
+    pid integer NOT NULL REFERENCES sub_post (pid) ON DELETE CASCADE,
+    votes integer NOT NULL,
+    velocity_ratio double precision NOT NULL,
+    outsider_fraction double precision NOT NULL,
+    computed timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS post_vote_signal_computed ON post_vote_signal (computed);
+CREATE INDEX IF NOT EXISTS sub_post_vote_datetime ON sub_post_vote (datetime);
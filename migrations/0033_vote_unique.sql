@@ -0,0 +1,47 @@
+-- One vote per user and post or comment, so votePost and voteComment can upsert. Older duplicates
+-- are dropped in favour of the latest vote, and the counters of what they were on are redone
+-- from the votes that stay.
+UPDATE sub_post p
+SET upvotes = v.up, downvotes = v.down, score = v.up - v.down
+FROM (
+    SELECT pid, count(*) FILTER (WHERE positive > 0) as up,
+        count(*) FILTER (WHERE positive < 0) as down
+    FROM (
+        SELECT DISTINCT ON (uid, pid) pid, positive
+        FROM sub_post_vote
+        ORDER BY uid, pid, xid DESC
+    ) latest
+    GROUP BY pid
+) v
+WHERE p.pid = v.pid AND p.pid IN (
+    SELECT pid FROM sub_post_vote GROUP BY uid, pid HAVING count(*) > 1
+);
+
+DELETE FROM sub_post_vote a
+USING sub_post_vote b
+WHERE a.uid = b.uid AND a.pid = b.pid AND a.xid < b.xid;
+
+CREATE UNIQUE INDEX IF NOT EXISTS sub_post_vote_uid_pid ON sub_post_vote (uid, pid);
+
+UPDATE sub_post_comment c
+SET upvotes = v.up, downvotes = v.down, score = v.up - v.down
+FROM (
+    SELECT cid, count(*) FILTER (WHERE positive > 0) as up,
+        count(*) FILTER (WHERE positive < 0) as down
+    FROM (
+        SELECT DISTINCT ON (uid, cid) cid, positive
+        FROM sub_post_comment_vote
+        ORDER BY uid, cid, xid DESC
+    ) latest
+    GROUP BY cid
+) v
+WHERE c.cid = v.cid AND c.cid IN (
+    SELECT cid FROM sub_post_comment_vote GROUP BY uid, cid HAVING count(*) > 1
+);
+
+DELETE FROM sub_post_comment_vote a
+USING sub_post_comment_vote b
+WHERE a.uid = b.uid AND a.cid = b.cid AND a.xid < b.xid;
+
+CREATE UNIQUE INDEX IF NOT EXISTS sub_post_comment_vote_uid_cid
+    ON sub_post_comment_vote (uid, cid);
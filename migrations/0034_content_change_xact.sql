@@ -0,0 +1,7 @@
+-- The transaction that wrote each change. Ids are handed out before commit, so a change can
+-- become visible after one with a higher id. getChanges only shows changes whose transaction
+-- is older than every running one, in transaction order, so none can turn up behind a cursor.
+-- txid_current() rather than pg_current_xact_id() so older servers work too.
+ALTER TABLE content_change ADD COLUMN IF NOT EXISTS xact bigint NOT NULL DEFAULT txid_current();
+
+CREATE INDEX IF NOT EXISTS content_change_xact ON content_change (xact, id);
@@ -0,0 +1,7 @@
+-- Throat also writes reply, mention and other notifications to message, those already get
+-- their own push (CommentReplied). Only private messages and mod messages (mtype 1 and 2)
+-- are MessageReceived.
+DROP TRIGGER IF EXISTS throatql_message_received ON message;
+CREATE TRIGGER throatql_message_received AFTER INSERT ON message
+    FOR EACH ROW WHEN (NEW.mtype IN (1, 2))
+    EXECUTE PROCEDURE throatql_message_received();
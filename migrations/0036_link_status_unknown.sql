@@ -0,0 +1,2 @@
+-- Checked, but the site wouldn't say. Reads as UNKNOWN until a later check can tell.
+ALTER TABLE link_status ALTER COLUMN dead DROP NOT NULL;
@@ -0,0 +1,24 @@
+-- The duplicate votes 0033_vote_unique dropped had counted towards their authors' score and their
+-- voters' given. Which users that was isn't known once they're gone, so both are redone for
+-- everyone from the votes that stay, as update_user_scores would have kept them.
+UPDATE public.user u
+SET score = (
+        SELECT coalesce(sum(sign(v.positive)), 0)
+        FROM sub_post_vote v
+        JOIN sub_post p ON p.pid = v.pid
+        WHERE p.uid = u.uid
+    ) + (
+        SELECT coalesce(sum(sign(v.positive)), 0)
+        FROM sub_post_comment_vote v
+        JOIN sub_post_comment c ON c.cid = v.cid
+        WHERE c.uid = u.uid
+    ),
+    given = (
+        SELECT coalesce(sum(sign(v.positive)), 0)
+        FROM sub_post_vote v
+        WHERE v.uid = u.uid
+    ) + (
+        SELECT coalesce(sum(sign(v.positive)), 0)
+        FROM sub_post_comment_vote v
+        WHERE v.uid = u.uid
+    );
@@ -0,0 +1,254 @@
+//! Subs as read-only ActivityPub actors. Fediverse servers find them through WebFinger and read
+//! their outbox of Create/Page activities, nothing is accepted in the inbox. Everything is served
+//! under SITE_URL, so the proxy in front has to send /ap and /.well-known/webfinger here.
+use crate::{
+    events::{Event, EventBus},
+    middleware::Response,
+    post, stats, Context,
+};
+use chrono::NaiveDateTime;
+use futures_util::stream::StreamExt;
+use http::{header, StatusCode};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::RecvError;
+
+const OUTBOX_SIZE: i64 = 20;
+const ACTIVITY_JSON: &str = "application/activity+json";
+/// How long a rendered outbox is served. Removals and posts Throat creates without PG_EVENTS
+/// don't reach the bus, they show up once the outbox is rendered again.
+const OUTBOX_MAX_AGE: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // Rendered outboxes by sid, remote servers poll these a lot more often than subs get posts
+    static ref OUTBOXES: Mutex<HashMap<String, (Instant, Arc<Vec<u8>>)>> =
+        Mutex::new(HashMap::new());
+}
+
+fn reply(status: StatusCode, content_type: &'static str, body: Vec<u8>) -> Response {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(content_type),
+    );
+    response
+}
+
+fn not_found() -> Response {
+    reply(StatusCode::NOT_FOUND, "text/plain", b"Not found".to_vec())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn actor_id(context: &Context, name: &str) -> String {
+    format!("{}/ap/s/{}", context.config.site_url, name)
+}
+
+fn host(context: &Context) -> &str {
+    context
+        .config
+        .site_url
+        .splitn(2, "://")
+        .nth(1)
+        .unwrap_or_default()
+}
+
+fn published(date: Option<NaiveDateTime>) -> Option<String> {
+    date.map(|date| date.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+}
+
+/// GET /.well-known/webfinger?resource=acct:{sub}@{host}
+pub async fn webfinger(context: Context, resource: &str) -> Response {
+    let name = match resource
+        .strip_prefix("acct:")
+        .and_then(|account| account.strip_suffix(&format!("@{}", host(&context))))
+    {
+        Some(name) => name,
+        None => return not_found(),
+    };
+    let sub = match context.sub_loader.load(name.to_string().into()).await {
+        Ok(sub) => sub,
+        Err(_) => return not_found(),
+    };
+    let name = sub.name.unwrap_or_default();
+
+    let body = json!({
+        "subject": format!("acct:{}@{}", name, host(&context)),
+        "links": [{
+            "rel": "self",
+            "type": ACTIVITY_JSON,
+            "href": actor_id(&context, &name),
+        }],
+    });
+    reply(
+        StatusCode::OK,
+        "application/jrd+json",
+        body.to_string().into_bytes(),
+    )
+}
+
+/// GET /ap/s/{name}, the sub as a Group actor
+pub async fn actor(context: Context, name: String) -> Response {
+    let sub = match context.sub_loader.load(name.into()).await {
+        Ok(sub) => sub,
+        Err(_) => return not_found(),
+    };
+    let name = sub.name.unwrap_or_default();
+    let id = actor_id(&context, &name);
+
+    let body = json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": id,
+        "type": "Group",
+        "preferredUsername": name,
+        "name": sub.title.unwrap_or_else(|| name.clone()),
+        "summary": format!("<p>{}</p>", escape(&sub.sidebar)),
+        "url": format!("{}/s/{}", context.config.site_url, name),
+        "published": published(Some(sub.creation)),
+        "inbox": format!("{}/inbox", id),
+        "outbox": format!("{}/outbox", id),
+        "sensitive": sub.nsfw,
+    });
+    reply(StatusCode::OK, ACTIVITY_JSON, body.to_string().into_bytes())
+}
+
+async fn render_outbox(context: &Context, sid: &str, name: &str) -> anyhow::Result<Vec<u8>> {
+    let actor = actor_id(context, name);
+    let posts = sqlx::query!(
+        r#"
+        SELECT pid, title, content, link, nsfw, posted
+        FROM sub_post
+        WHERE sid = $1 AND coalesce(deleted, 0) = 0
+        ORDER BY posted DESC
+        LIMIT $2
+        "#,
+        sid,
+        OUTBOX_SIZE
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let items: Vec<Value> = posts
+        .into_iter()
+        .map(|post| {
+            let title = post.title.unwrap_or_default();
+            let permalink = format!(
+                "{}/s/{}/{}/{}",
+                context.config.site_url,
+                name,
+                *context.config.post_ids.encode(post.pid),
+                post::slugify(&title)
+            );
+            let content = match (post.link, post.content) {
+                (Some(link), _) => format!(r#"<p><a href="{0}">{0}</a></p>"#, escape(&link)),
+                (None, Some(content)) => format!("<p>{}</p>", escape(&content)),
+                (None, None) => String::new(),
+            };
+            json!({
+                "id": format!("{}#create", permalink),
+                "type": "Create",
+                "actor": actor,
+                "published": published(post.posted),
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "object": {
+                    "id": permalink,
+                    "type": "Page",
+                    "attributedTo": actor,
+                    "name": title,
+                    "content": content,
+                    "url": permalink,
+                    "published": published(post.posted),
+                    "sensitive": post.nsfw.unwrap_or(false),
+                    "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                },
+            })
+        })
+        .collect();
+
+    Ok(serde_json::to_vec(&json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{}/outbox", actor),
+        "type": "OrderedCollection",
+        "totalItems": items.len(),
+        "orderedItems": items,
+    }))?)
+}
+
+/// GET /ap/s/{name}/outbox, the newest posts as Create activities
+pub async fn outbox(context: Context, name: String) -> Response {
+    let sub = match context.sub_loader.load(name.into()).await {
+        Ok(sub) => sub,
+        Err(_) => return not_found(),
+    };
+    let cached = OUTBOXES
+        .lock()
+        .unwrap()
+        .get(&sub.sid)
+        .filter(|(stored, _)| stored.elapsed() < OUTBOX_MAX_AGE)
+        .map(|(_, body)| body.clone());
+    stats::record_cache("activitypub_outboxes", cached.is_some());
+    if let Some(body) = cached {
+        return reply(StatusCode::OK, ACTIVITY_JSON, body.to_vec());
+    }
+
+    let name = sub.name.unwrap_or_default();
+    match render_outbox(&context, &sub.sid, &name).await {
+        Ok(body) => {
+            let mut outboxes = OUTBOXES.lock().unwrap();
+            outboxes.retain(|_, (stored, _)| stored.elapsed() < OUTBOX_MAX_AGE);
+            outboxes.insert(sub.sid, (Instant::now(), Arc::new(body.clone())));
+            reply(StatusCode::OK, ACTIVITY_JSON, body)
+        }
+        Err(err) => {
+            log::error!("Could not render the outbox of {} - {}", name, err);
+            reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain",
+                b"Internal server error".to_vec(),
+            )
+        }
+    }
+}
+
+/// POST /ap/s/{name}/inbox, federation is read-only so nothing is accepted
+pub fn inbox() -> Response {
+    reply(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "text/plain",
+        b"This actor doesn't accept activities".to_vec(),
+    )
+}
+
+/// Drops cached outboxes whenever the bus says their posts changed
+pub async fn forget_outboxes(events: Arc<dyn EventBus>) {
+    let mut receiver = events.subscribe();
+    loop {
+        match receiver.recv().await {
+            Ok(Event::PostCreated { sid, .. }) => {
+                OUTBOXES.lock().unwrap().remove(&sid);
+            }
+            Ok(Event::SubRenamed { sid, .. }) => {
+                OUTBOXES.lock().unwrap().remove(&sid);
+            }
+            // Not worth a lookup to find the sub, edits are rare enough
+            Ok(Event::PostEdited { .. }) => OUTBOXES.lock().unwrap().clear(),
+            Ok(_) => {}
+            Err(RecvError::Lagged(_)) => OUTBOXES.lock().unwrap().clear(),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
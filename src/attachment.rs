@@ -0,0 +1,451 @@
+use crate::{config::Config, legacy, moderation, post::PostType, sub::Sub, Context};
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLObject, ID};
+use std::path::Path;
+
+/// Most images on one post
+const MAX_PER_POST: usize = 20;
+/// Most images on one comment, and the largest each may be
+const MAX_PER_COMMENT: usize = 4;
+const MAX_COMMENT_SIZE: i32 = 5 * 1024 * 1024;
+
+#[derive(GraphQLObject, Debug)]
+pub struct Attachment {
+    pub id: ID,
+    pub url: String,
+    pub content_type: String,
+    /// In bytes
+    pub size: i32,
+}
+
+/// Where uploads are kept and served from, see UPLOAD_DIR
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    pub dir: String,
+    /// Public url of `dir`
+    pub url: String,
+    /// Largest single upload, in bytes
+    pub max_size: usize,
+    /// Most bytes one user may have stored
+    pub quota: i64,
+}
+
+/// Content type and extension, going by the first bytes rather than what the client claims
+fn sniff(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(("image/png", "png"))
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        Some(("image/jpeg", "jpg"))
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some(("image/gif", "gif"))
+    } else if data.len() > 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some(("image/webp", "webp"))
+    } else {
+        None
+    }
+}
+
+fn url(config: &UploadConfig, filename: &str) -> String {
+    format!("{}/{}", config.url, filename)
+}
+
+fn attachment(
+    uploads: &UploadConfig,
+    id: String,
+    filename: &str,
+    content_type: String,
+    size: i32,
+) -> Attachment {
+    Attachment {
+        id: id.into(),
+        url: url(uploads, filename),
+        content_type,
+        size,
+    }
+}
+
+fn upload_config(config: &Config) -> Result<&UploadConfig, FieldError> {
+    config
+        .uploads
+        .as_ref()
+        .ok_or_else(|| "Uploads are turned off".into())
+}
+
+/// Stores an uploaded image for the viewer, it can be attached to a post afterwards
+pub async fn upload(context: &Context, data: &[u8]) -> Result<Attachment, FieldError> {
+    let uid = context.user.user_id()?;
+    let uploads = upload_config(&context.config)?;
+    if data.len() > uploads.max_size {
+        return Err(format!("Uploads can be at most {} bytes", uploads.max_size).into());
+    }
+    let (content_type, extension) =
+        sniff(data).ok_or("Only PNG, JPEG, GIF and WebP images can be uploaded")?;
+
+    let mut tx = context.pool.begin().await?;
+    // Held until the insert commits, so two uploads at once can't both fit in what's left
+    sqlx::query!(
+        r#"
+        SELECT uid
+        FROM public.user
+        WHERE uid = $1
+        FOR UPDATE
+        "#,
+        uid
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    let used = sqlx::query!(
+        r#"
+        SELECT coalesce(sum(size), 0) as "used!"
+        FROM attachment
+        WHERE uid = $1
+        "#,
+        uid
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .used;
+    if used + data.len() as i64 > uploads.quota {
+        return Err("Your upload quota is used up, remove some attachments first".into());
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let filename = format!("{}.{}", id, extension);
+    let path = Path::new(&uploads.dir).join(&filename);
+    tokio::fs::write(&path, data).await?;
+    let saved = sqlx::query!(
+        r#"
+        INSERT INTO attachment (id, uid, filename, content_type, size)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        id,
+        uid,
+        filename,
+        content_type,
+        data.len() as i32
+    )
+    .execute(&mut tx)
+    .await;
+    let saved = match saved {
+        Ok(_) => tx.commit().await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = saved {
+        // Nothing points at the file, it would only take up space
+        if let Err(err) = tokio::fs::remove_file(&path).await {
+            log::warn!("Could not remove upload {} - {}", filename, err);
+        }
+        return Err(err.into());
+    }
+
+    Ok(attachment(
+        uploads,
+        id,
+        &filename,
+        content_type.to_string(),
+        data.len() as i32,
+    ))
+}
+
+pub async fn post_attachments(context: &Context, pid: i32) -> Result<Vec<Attachment>, FieldError> {
+    let uploads = match context.config.uploads {
+        Some(ref uploads) => uploads,
+        None => return Ok(vec![]),
+    };
+    Ok(sqlx::query!(
+        r#"
+        SELECT id, filename, content_type, size
+        FROM attachment
+        WHERE pid = $1
+        ORDER BY created
+        "#,
+        pid
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| attachment(uploads, row.id, &row.filename, row.content_type, row.size))
+    .collect())
+}
+
+pub async fn comment_attachments(
+    context: &Context,
+    cid: &str,
+) -> Result<Vec<Attachment>, FieldError> {
+    let uploads = match context.config.uploads {
+        Some(ref uploads) => uploads,
+        None => return Ok(vec![]),
+    };
+    Ok(sqlx::query!(
+        r#"
+        SELECT id, filename, content_type, size
+        FROM attachment
+        WHERE cid = $1
+        ORDER BY created
+        "#,
+        cid
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| attachment(uploads, row.id, &row.filename, row.content_type, row.size))
+    .collect())
+}
+
+/// Adds the viewer's uploads to one of their text posts
+pub async fn attach_to_post(
+    context: &Context,
+    id: ID,
+    attachments: Vec<ID>,
+) -> Result<Vec<Attachment>, FieldError> {
+    let uid = context.user.user_id()?;
+    let pid = context.config.post_ids.decode(&id)?;
+    let post = sqlx::query!(
+        r#"
+        SELECT uid, ptype
+        FROM sub_post
+        WHERE pid = $1 AND coalesce(deleted, 0) = 0
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Post not found {}", *id))?;
+    if post.uid.as_deref() != Some(uid) {
+        return Err("Only the author can add attachments".into());
+    }
+    if legacy::post_type(post.ptype) != Some(PostType::Text) {
+        return Err("Only text posts can have attachments".into());
+    }
+    let ids: Vec<String> = attachments.iter().map(|id| id.to_string()).collect();
+
+    let mut tx = context.pool.begin().await?;
+    let attached = sqlx::query!(
+        r#"
+        UPDATE attachment
+        SET pid = $1
+        WHERE id = ANY($2) AND uid = $3 AND pid IS NULL AND cid IS NULL
+        RETURNING id
+        "#,
+        pid,
+        &ids,
+        uid
+    )
+    .fetch(&mut tx)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    if attached.len() != ids.len() {
+        return Err("Some of the attachments aren't yours or are already used".into());
+    }
+    let total = sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM attachment
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .cnt;
+    if total as usize > MAX_PER_POST {
+        return Err(format!("Posts can have at most {} attachments", MAX_PER_POST).into());
+    }
+    tx.commit().await?;
+
+    post_attachments(context, pid).await
+}
+
+/// Subs opt in to images in comments, they're off by default
+pub async fn comment_images_allowed(pool: &sqlx::PgPool, sid: &str) -> Result<bool, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT value
+        FROM sub_metadata
+        WHERE sid = $1 AND key = 'comment_images'
+        "#,
+        sid
+    )
+    .fetch_optional(pool)
+    .await?
+    .and_then(|row| row.value)
+    .as_deref()
+        == Some("1"))
+}
+
+/// For the sub's mods, the change ends up in the mod log
+pub async fn set_comment_images(
+    context: &Context,
+    sub: String,
+    enabled: bool,
+) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub: Sub = context
+        .sub_loader
+        .load(sub.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM sub_metadata
+        WHERE sid = $1 AND key = 'comment_images'
+        "#,
+        sub.sid
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO sub_metadata (sid, key, value)
+        VALUES ($1, 'comment_images', $2)
+        "#,
+        sub.sid,
+        if enabled { "1" } else { "0" }
+    )
+    .execute(&mut tx)
+    .await?;
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sub.sid.clone()),
+        if enabled {
+            "enable_comment_images"
+        } else {
+            "disable_comment_images"
+        },
+        vec![],
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(enabled)
+}
+
+/// Adds the viewer's uploads to one of their comments, where the sub allows images in comments.
+/// Comments get fewer and smaller images than text posts.
+pub async fn attach_to_comment(
+    context: &Context,
+    id: ID,
+    attachments: Vec<ID>,
+) -> Result<Vec<Attachment>, FieldError> {
+    let uid = context.user.user_id()?;
+    upload_config(&context.config)?;
+    let comment = sqlx::query!(
+        r#"
+        SELECT c.uid, c.status, p.sid
+        FROM sub_post_comment c
+        JOIN sub_post p ON p.pid = c.pid
+        WHERE c.cid = $1
+        "#,
+        id.as_str()
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Comment not found {}", *id))?;
+    if comment.uid.as_deref() != Some(uid) {
+        return Err("Only the author can add attachments".into());
+    }
+    if comment.status.unwrap_or(0) != 0 {
+        return Err("Deleted comments can't have attachments".into());
+    }
+    let sid = comment.sid.unwrap_or_default();
+    if !comment_images_allowed(&context.pool, &sid).await? {
+        return Err("This sub doesn't allow images in comments".into());
+    }
+    moderation::check_not_muted(context, &sid).await?;
+    let ids: Vec<String> = attachments.iter().map(|id| id.to_string()).collect();
+
+    let mut tx = context.pool.begin().await?;
+    let attached = sqlx::query!(
+        r#"
+        UPDATE attachment
+        SET cid = $1
+        WHERE id = ANY($2) AND uid = $3 AND pid IS NULL AND cid IS NULL
+        RETURNING id, content_type, size
+        "#,
+        id.as_str(),
+        &ids,
+        uid
+    )
+    .fetch(&mut tx)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    if attached.len() != ids.len() {
+        return Err("Some of the attachments aren't yours or are already used".into());
+    }
+    // Uploads were sniffed on the way in, this only guards against rows from elsewhere
+    if attached
+        .iter()
+        .any(|row| !row.content_type.starts_with("image/"))
+    {
+        return Err("Only images can be added to comments".into());
+    }
+    if attached.iter().any(|row| row.size > MAX_COMMENT_SIZE) {
+        return Err(format!(
+            "Images on comments can be at most {} bytes",
+            MAX_COMMENT_SIZE
+        )
+        .into());
+    }
+    let total = sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM attachment
+        WHERE cid = $1
+        "#,
+        id.as_str()
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .cnt;
+    if total as usize > MAX_PER_COMMENT {
+        return Err(format!("Comments can have at most {} attachments", MAX_PER_COMMENT).into());
+    }
+    tx.commit().await?;
+
+    comment_attachments(context, id.as_str()).await
+}
+
+/// Frees up quota. Files are removed from disk as well, whether or not they were attached.
+pub async fn delete_attachment(context: &Context, id: ID) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let uploads = upload_config(&context.config)?;
+    let deleted = sqlx::query!(
+        r#"
+        DELETE FROM attachment
+        WHERE id = $1 AND uid = $2
+        RETURNING filename
+        "#,
+        id.as_str(),
+        uid
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+
+    match deleted {
+        Some(row) => {
+            if let Err(err) =
+                tokio::fs::remove_file(Path::new(&uploads.dir).join(&row.filename)).await
+            {
+                log::warn!("Could not remove upload {} - {}", row.filename, err);
+            }
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
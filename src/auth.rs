@@ -34,78 +34,143 @@ pub enum UserState {
 }
 
 lazy_static! {
-    static ref PUB_KEY: String = std::env::var("PUB_KEY").unwrap();
+    // config::validate_env makes sure this is set and parses before the server starts
+    static ref PUB_KEY: String = std::env::var("PUB_KEY").unwrap_or_default();
     static ref DECODING_KEY: Result<DecodingKey<'static>, jsonwebtoken::errors::Error> =
         DecodingKey::from_rsa_pem(&PUB_KEY.as_bytes());
 }
 
+/// Checks PUB_KEY the same way login will use it
+pub fn check_pub_key(key: &str) -> Result<(), String> {
+    DecodingKey::from_rsa_pem(key.as_bytes())
+        .map(|_| ())
+        .map_err(|err| format!("{:?}", err))
+}
+
 impl UserState {
     pub fn anonymous() -> UserState {
         UserState::Anonymous
     }
     pub fn login(jwt: String, pool: sqlx::PgPool) -> UserState {
-        let token = decode::<Claims>(
-            &jwt,
-            (*DECODING_KEY).as_ref().unwrap(),
-            &Validation::new(Algorithm::RS256),
-        );
+        let key = match *DECODING_KEY {
+            Ok(ref key) => key,
+            Err(ref err) => {
+                log::error!(
+                    "PUB_KEY is unusable, treating everyone as anonymous - {:?}",
+                    err
+                );
+                return UserState::Anonymous;
+            }
+        };
+        let token = decode::<Claims>(&jwt, key, &Validation::new(Algorithm::RS256));
         // This is probably a bad idea, but I don't have a better way
         block_on(async {
             if let Ok(token) = token {
-                sqlx::query!(
-                    r#"
-                SELECT name, uid, a.admin, m.subs, m.level 
-                FROM public.user 
-                LEFT JOIN (
-                    SELECT uid, 1 as admin 
-                    FROM user_metadata 
-                    WHERE key = 'admin' AND value = '1'
-                ) a USING (uid) 
-                LEFT JOIN (
-                    SELECT uid, array_agg(m.sid) as subs, array_agg(m.power_level) as level 
-                    FROM sub_mod as m 
-                    GROUP BY m.uid 
-                ) m USING (uid)  
-                WHERE lower(name) = $1
-            "#,
-                    token.claims.preferred_username
-                )
-                .fetch_one(&pool)
-                .await
-                .map(|user| UserState::LoggedIn {
-                    name: user.name.unwrap_or_else(|| "".into()),
-                    id: user.uid,
-                    roles: {
-                        let mut roles: Vec<_> = user
-                            .subs
-                            .unwrap_or_default()
-                            .into_iter()
-                            .zip(user.level.unwrap_or_default().into_iter())
-                            .map(|(sub, level)| {
-                                Role::Mod(
-                                    sub,
-                                    match level {
-                                        0 => Level::Owner,
-                                        1 => Level::Mod,
-                                        _ => Level::Janitor,
-                                    },
-                                )
-                            })
-                            .collect();
-                        if user.admin.is_some() {
-                            roles.push(Role::Admin);
-                        }
-
-                        roles
-                    },
-                })
-                .unwrap_or(UserState::Anonymous)
+                UserState::fetch(&token.claims.preferred_username, &pool).await
             } else {
                 UserState::Anonymous
             }
         })
     }
 
+    /// Lets an admin see the API exactly as another user would by sending X-Impersonate-User.
+    /// Every use is written to admin_audit, requests from anyone else are left untouched.
+    pub fn impersonate(self, target: Option<String>, pool: sqlx::PgPool) -> UserState {
+        let target = match target {
+            Some(target) => target,
+            None => return self,
+        };
+        let admin_id = match self {
+            UserState::LoggedIn { ref id, .. } if self.is_admin() => id.clone(),
+            _ => {
+                log::warn!(
+                    "Ignoring impersonation of {} by non admin {:?}",
+                    target,
+                    self
+                );
+                return self;
+            }
+        };
+
+        block_on(async {
+            let impersonated = UserState::fetch(&target.to_lowercase(), &pool).await;
+            if impersonated.is_anon() {
+                log::warn!("{} tried to impersonate unknown user {}", admin_id, target);
+                return self;
+            }
+
+            log::info!("{} is impersonating {}", admin_id, target);
+            if let Err(err) = sqlx::query!(
+                r#"
+                INSERT INTO admin_audit (uid, action, target)
+                VALUES ($1, 'impersonate', $2)
+                "#,
+                admin_id,
+                target
+            )
+            .execute(&pool)
+            .await
+            {
+                // Refuse rather than allow an impersonation that left no trace
+                log::error!("Could not audit impersonation - {:?}", err);
+                return self;
+            }
+
+            impersonated
+        })
+    }
+
+    async fn fetch(name: &str, pool: &sqlx::PgPool) -> UserState {
+        sqlx::query!(
+            r#"
+            SELECT name, uid, a.admin, m.subs, m.level 
+            FROM public.user 
+            LEFT JOIN (
+                SELECT uid, 1 as admin 
+                FROM user_metadata 
+                WHERE key = 'admin' AND value = '1'
+            ) a USING (uid) 
+            LEFT JOIN (
+                SELECT uid, array_agg(m.sid) as subs, array_agg(m.power_level) as level 
+                FROM sub_mod as m 
+                GROUP BY m.uid 
+            ) m USING (uid)  
+            WHERE lower(name) = $1
+        "#,
+            name
+        )
+        .fetch_one(pool)
+        .await
+        .map(|user| UserState::LoggedIn {
+            name: user.name.unwrap_or_else(|| "".into()),
+            id: user.uid,
+            roles: {
+                let mut roles: Vec<_> = user
+                    .subs
+                    .unwrap_or_default()
+                    .into_iter()
+                    .zip(user.level.unwrap_or_default().into_iter())
+                    .map(|(sub, level)| {
+                        Role::Mod(
+                            sub,
+                            match level {
+                                0 => Level::Owner,
+                                1 => Level::Mod,
+                                _ => Level::Janitor,
+                            },
+                        )
+                    })
+                    .collect();
+                if user.admin.is_some() {
+                    roles.push(Role::Admin);
+                }
+
+                roles
+            },
+        })
+        .unwrap_or(UserState::Anonymous)
+    }
+
     pub fn private_user_data(&self, check_id: &str) -> Result<(), String> {
         log::debug!("Auth - {:?}", self);
         match self {
@@ -138,6 +203,65 @@ impl UserState {
         }
     }
 
+    /// Admins count as mods of every sub
+    pub fn is_mod(&self, sub_id: &str) -> bool {
+        match self {
+            UserState::Anonymous => false,
+            UserState::LoggedIn { roles, .. } => roles.iter().any(|role| match role {
+                Role::Admin => true,
+                Role::Mod(sub, _) => sub == sub_id,
+            }),
+        }
+    }
+
+    /// Owner of the sub, or an admin
+    pub fn is_owner(&self, sub_id: &str) -> bool {
+        match self {
+            UserState::Anonymous => false,
+            UserState::LoggedIn { roles, .. } => roles.iter().any(|role| match role {
+                Role::Admin => true,
+                Role::Mod(sub, Level::Owner) => sub == sub_id,
+                Role::Mod(..) => false,
+            }),
+        }
+    }
+
+    /// Sids the user is an actual mod of, whether or not they're an admin too
+    pub fn modded_subs(&self) -> Vec<String> {
+        match self {
+            UserState::Anonymous => vec![],
+            UserState::LoggedIn { roles, .. } => roles
+                .iter()
+                .filter_map(|role| match role {
+                    Role::Mod(sub, _) => Some(sub.clone()),
+                    Role::Admin => None,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn user_id(&self) -> Result<&str, String> {
+        match self {
+            UserState::Anonymous => Err("Not Authorized".to_string()),
+            UserState::LoggedIn { id, .. } => Ok(id),
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        match self {
+            UserState::Anonymous => false,
+            UserState::LoggedIn { roles, .. } => roles.contains(&Role::Admin),
+        }
+    }
+
+    pub fn require_admin(&self) -> Result<(), String> {
+        if self.is_admin() {
+            Ok(())
+        } else {
+            Err("Not Authorized".to_string())
+        }
+    }
+
     pub fn is_anon(&self) -> bool {
         if let UserState::Anonymous = self {
             true
@@ -0,0 +1,160 @@
+use crate::{user::UserRef, Context};
+use chrono::NaiveDateTime;
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLEnum, GraphQLObject};
+
+/// Most requests shown to admins at once, oldest first
+const PENDING_SHOWN: i64 = 200;
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum BotStatus {
+    /// A regular account
+    None,
+    /// Asked to be marked as a bot, waiting for an admin
+    Pending,
+    Approved,
+}
+
+#[derive(GraphQLObject, Debug)]
+pub struct BotRequest {
+    pub name: String,
+    pub requested: NaiveDateTime,
+}
+
+pub async fn get_status(pool: &sqlx::PgPool, uid: &str) -> Result<BotStatus, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT approved
+        FROM user_bot
+        WHERE uid = $1
+        "#,
+        uid
+    )
+    .fetch_optional(pool)
+    .await?
+    .map_or(BotStatus::None, |row| {
+        if row.approved {
+            BotStatus::Approved
+        } else {
+            BotStatus::Pending
+        }
+    }))
+}
+
+pub async fn is_bot(pool: &sqlx::PgPool, uid: &str) -> Result<bool, FieldError> {
+    Ok(get_status(pool, uid).await? == BotStatus::Approved)
+}
+
+/// Asking to be a bot waits for an admin, going back to a regular account happens right away
+pub async fn set_bot(context: &Context, bot: bool) -> Result<BotStatus, FieldError> {
+    let uid = context.user.user_id()?;
+    if bot {
+        sqlx::query!(
+            r#"
+            INSERT INTO user_bot (uid)
+            VALUES ($1)
+            ON CONFLICT (uid) DO NOTHING
+            "#,
+            uid
+        )
+        .execute(&context.pool)
+        .await?;
+    } else {
+        sqlx::query!(
+            r#"
+            DELETE FROM user_bot
+            WHERE uid = $1
+            "#,
+            uid
+        )
+        .execute(&context.pool)
+        .await?;
+    }
+    get_status(&context.pool, uid).await
+}
+
+pub async fn pending(context: &Context) -> Result<Vec<BotRequest>, FieldError> {
+    context.user.require_admin()?;
+    Ok(sqlx::query!(
+        r#"
+        SELECT u.name as "name!", b.requested
+        FROM user_bot b
+        JOIN public.user u ON u.uid = b.uid
+        WHERE NOT b.approved
+        ORDER BY b.requested
+        LIMIT $1
+        "#,
+        PENDING_SHOWN
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| BotRequest {
+        name: row.name,
+        requested: row.requested,
+    })
+    .collect())
+}
+
+/// Approving marks the account as a bot, denying drops the request. Either way it's audited.
+pub async fn decide(context: &Context, user: String, approve: bool) -> Result<bool, FieldError> {
+    context.user.require_admin()?;
+    let admin = context.user.user_id()?;
+    let target = context
+        .user_loader
+        .load(UserRef::name(user))
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+
+    let mut tx = context.pool.begin().await?;
+    let decided = if approve {
+        sqlx::query!(
+            r#"
+            UPDATE user_bot
+            SET approved = true, decided_by = $2, decided = now()
+            WHERE uid = $1 AND NOT approved
+            RETURNING uid
+            "#,
+            target.uid,
+            admin
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .is_some()
+    } else {
+        sqlx::query!(
+            r#"
+            DELETE FROM user_bot
+            WHERE uid = $1 AND NOT approved
+            RETURNING uid
+            "#,
+            target.uid
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .is_some()
+    };
+    if !decided {
+        return Err(format!(
+            "{} hasn't asked to be a bot",
+            target.name.unwrap_or_default()
+        )
+        .into());
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO admin_audit (uid, action, target)
+        VALUES ($1, $2, $3)
+        "#,
+        admin,
+        if approve { "approve_bot" } else { "deny_bot" },
+        target.uid
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
@@ -0,0 +1,198 @@
+use crate::{post::Post, Context};
+use chrono::NaiveDateTime;
+use futures_util::stream::StreamExt;
+use juniper::{graphql_object, FieldError, GraphQLEnum};
+use std::time::Duration;
+
+/// Pause between aggregation rounds
+const AGGREGATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Posts with fewer votes in the last hour aren't looked at, too few to say anything
+const MIN_VOTES: i64 = 20;
+/// An hour with this many times the sub's usual votes per post and hour counts as a spike
+const VELOCITY_FACTOR: f64 = 5.0;
+/// Part of an hour's votes from accounts new to the sub that counts as suspicious
+const OUTSIDER_FRACTION: f64 = 0.8;
+const MAX_SHOWN: i32 = 100;
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum AnomalyReason {
+    /// Far more votes in an hour than posts in the sub usually get
+    Velocity,
+    /// Most votes came from accounts that hadn't visited the sub before
+    Outsiders,
+}
+
+/// A post whose votes look organized, as of the last aggregation that flagged it
+#[derive(Debug, Clone)]
+pub struct VoteAnomaly {
+    pid: i32,
+    votes: i32,
+    velocity_ratio: f64,
+    outsider_fraction: f64,
+    computed: NaiveDateTime,
+}
+
+impl VoteAnomaly {
+    fn flagged_for(&self) -> Vec<AnomalyReason> {
+        let mut reasons = vec![];
+        if self.velocity_ratio >= VELOCITY_FACTOR {
+            reasons.push(AnomalyReason::Velocity);
+        }
+        if self.outsider_fraction >= OUTSIDER_FRACTION {
+            reasons.push(AnomalyReason::Outsiders);
+        }
+        reasons
+    }
+}
+
+#[graphql_object(context = Context)]
+impl VoteAnomaly {
+    /// Null once the post is gone
+    async fn post(&self, context: &Context) -> Option<Post> {
+        context.post_loader.load(self.pid).await.ok()
+    }
+
+    fn reasons(&self) -> Vec<AnomalyReason> {
+        self.flagged_for()
+    }
+
+    /// Votes in the hour before computedAt
+    fn votes(&self) -> i32 {
+        self.votes
+    }
+
+    /// votes against the sub's average votes per post and hour over the last week
+    fn velocity_ratio(&self) -> f64 {
+        self.velocity_ratio
+    }
+
+    /// Part of the votes from accounts that hadn't visited another post of the sub before. Only
+    /// visits through this API are known, so new deployments see more outsiders at first.
+    fn outsider_fraction(&self) -> f64 {
+        self.outsider_fraction
+    }
+
+    fn computed_at(&self) -> NaiveDateTime {
+        self.computed
+    }
+}
+
+/// Records the last hour's vote pattern of every post with enough votes in it
+async fn aggregate(pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        WITH recent AS (
+            SELECT v.pid, v.uid, v.datetime, p.sid
+            FROM sub_post_vote v
+            JOIN sub_post p ON p.pid = v.pid
+            WHERE v.datetime > now() - interval '1 hour'
+        ), per_post AS (
+            SELECT r.pid, r.sid, count(*) as votes,
+                count(*) FILTER (WHERE NOT EXISTS (
+                    SELECT 1
+                    FROM post_visit pv
+                    JOIN sub_post vp ON vp.pid = pv.pid
+                    WHERE pv.uid = r.uid AND vp.sid = r.sid AND pv.pid <> r.pid
+                        AND pv.visited < r.datetime
+                )) as outsiders
+            FROM recent r
+            GROUP BY r.pid, r.sid
+            HAVING count(*) >= $1
+        ), baseline AS (
+            SELECT p.sid,
+                count(*)::float8 / count(DISTINCT (v.pid, date_trunc('hour', v.datetime)))
+                    as hourly
+            FROM sub_post_vote v
+            JOIN sub_post p ON p.pid = v.pid
+            WHERE v.datetime > now() - interval '7 days'
+                AND p.sid IN (SELECT sid FROM per_post)
+            GROUP BY p.sid
+        )
+        INSERT INTO post_vote_signal (pid, votes, velocity_ratio, outsider_fraction)
+        SELECT pp.pid, pp.votes, pp.votes / greatest(b.hourly, 1),
+            pp.outsiders::float8 / pp.votes
+        FROM per_post pp
+        LEFT JOIN baseline b ON b.sid = pp.sid
+        "#,
+        MIN_VOTES
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM post_vote_signal
+        WHERE computed < now() - interval '7 days'
+        "#
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Only one process should run this, the rounds would be recorded twice otherwise
+pub async fn run(pool: sqlx::PgPool) {
+    loop {
+        if let Err(err) = aggregate(&pool).await {
+            log::error!("Vote aggregation failed - {}", err);
+        }
+        tokio::time::delay_for(AGGREGATE_INTERVAL).await;
+    }
+}
+
+/// Flagged posts of the last week, most recently flagged first. Admins only.
+pub async fn vote_anomalies(
+    context: &Context,
+    count: Option<i32>,
+) -> Result<Vec<VoteAnomaly>, FieldError> {
+    context.user.require_admin()?;
+    let count = count.unwrap_or(25).max(0).min(MAX_SHOWN) as i64;
+    Ok(sqlx::query!(
+        r#"
+        SELECT pid as "pid!", votes as "votes!", velocity_ratio as "velocity_ratio!",
+            outsider_fraction as "outsider_fraction!", computed as "computed!"
+        FROM (
+            SELECT DISTINCT ON (pid) pid, votes, velocity_ratio, outsider_fraction, computed
+            FROM post_vote_signal
+            WHERE velocity_ratio >= $1 OR outsider_fraction >= $2
+            ORDER BY pid, computed DESC
+        ) flagged
+        ORDER BY computed DESC
+        LIMIT $3
+        "#,
+        VELOCITY_FACTOR,
+        OUTSIDER_FRACTION,
+        count
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| VoteAnomaly {
+        pid: row.pid,
+        votes: row.votes,
+        velocity_ratio: row.velocity_ratio,
+        outsider_fraction: row.outsider_fraction,
+        computed: row.computed,
+    })
+    .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reasons_follow_thresholds() {
+        let anomaly = VoteAnomaly {
+            pid: 1,
+            votes: 50,
+            velocity_ratio: 6.0,
+            outsider_fraction: 0.5,
+            computed: chrono::Utc::now().naive_utc(),
+        };
+        assert_eq!(anomaly.flagged_for(), vec![AnomalyReason::Velocity]);
+    }
+}
@@ -0,0 +1,35 @@
+use crate::stats;
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    // Serialized responses for anonymous viewers, keyed by everything that went into the request
+    static ref RESPONSES: Mutex<HashMap<Vec<u8>, (Instant, Arc<Vec<u8>>)>> =
+        Mutex::new(HashMap::new());
+}
+
+pub fn get(key: &[u8], ttl: Duration) -> Option<Arc<Vec<u8>>> {
+    let responses = RESPONSES.lock().unwrap();
+    let body = match responses.get(key) {
+        Some((stored, body)) if stored.elapsed() < ttl => Some(body.clone()),
+        _ => None,
+    };
+    stats::record_cache("anonymous_responses", body.is_some());
+    body
+}
+
+/// Expired entries are only dropped once the cache is full, if that doesn't free up room the
+/// response just isn't cached
+pub fn put(key: Vec<u8>, body: Arc<Vec<u8>>, ttl: Duration, max_entries: usize) {
+    let mut responses = RESPONSES.lock().unwrap();
+    if responses.len() >= max_entries {
+        responses.retain(|_, (stored, _)| stored.elapsed() < ttl);
+    }
+    if responses.len() < max_entries {
+        responses.insert(key, (Instant::now(), body));
+    }
+}
@@ -0,0 +1,315 @@
+use crate::{config::Config, content::Content, ndjson, Context, Cursor, Edge, Page, PageInfo};
+use chrono::NaiveDateTime;
+use futures_util::{
+    stream::{BoxStream, StreamExt},
+    TryStreamExt,
+};
+use juniper::{graphql_object, FieldError, GraphQLEnum, ID};
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ChangeType {
+    Post,
+    Comment,
+}
+
+impl ChangeType {
+    fn kind(self) -> &'static str {
+        match self {
+            ChangeType::Post => "post",
+            ChangeType::Comment => "comment",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ChangeAction {
+    Created,
+    Edited,
+    Deleted,
+    Restored,
+}
+
+/// Something that happened to a post or comment, see getChanges
+#[derive(Debug, Clone)]
+pub struct Change {
+    change_type: ChangeType,
+    action: ChangeAction,
+    pid: Option<i32>,
+    cid: Option<String>,
+    time: NaiveDateTime,
+}
+
+impl Change {
+    fn encoded_id(&self, config: &Config) -> ID {
+        match self.change_type {
+            ChangeType::Post => config.post_ids.encode(self.pid.unwrap_or_default()),
+            ChangeType::Comment => ID::new(self.cid.clone().unwrap_or_default()),
+        }
+    }
+}
+
+#[graphql_object(context = Context)]
+impl Change {
+    fn change_type(&self) -> ChangeType {
+        self.change_type
+    }
+
+    fn action(&self) -> ChangeAction {
+        self.action
+    }
+
+    fn time(&self) -> &NaiveDateTime {
+        &self.time
+    }
+
+    /// Id of the post or comment, there even when it's gone for good
+    fn content_id(&self, context: &Context) -> ID {
+        self.encoded_id(&context.config)
+    }
+
+    /// The post or comment as it is now, null once it's removed from the database
+    async fn node(&self, context: &Context) -> Option<Content> {
+        match self.change_type {
+            ChangeType::Post => context
+                .post_loader
+                .load(self.pid?)
+                .await
+                .ok()
+                .map(Content::Post),
+            ChangeType::Comment => context
+                .comment_loader
+                .load(self.cid.clone()?)
+                .await
+                .ok()
+                .map(Content::Comment),
+        }
+    }
+}
+
+#[graphql_object(name = "ChangeNode", context = Context)]
+impl Edge<Change> {
+    fn node(&self) -> &Change {
+        &self.node
+    }
+
+    fn cursor(&self) -> &Cursor {
+        &self.cursor
+    }
+}
+
+#[graphql_object(name = "ChangePage", context = Context)]
+impl Page<Change> {
+    fn edges(&self) -> &Vec<Edge<Change>> {
+        &self.edges
+    }
+
+    fn page_info(&self) -> &PageInfo {
+        &self.page_info
+    }
+
+    fn total_count(&self) -> i32 {
+        self.total_count
+    }
+}
+
+fn kinds(types: Option<Vec<ChangeType>>) -> Vec<String> {
+    types
+        .unwrap_or_else(|| vec![ChangeType::Post, ChangeType::Comment])
+        .into_iter()
+        .map(|kind| kind.kind().to_string())
+        .collect()
+}
+
+/// Where a cursor points, the change's transaction and id. Cursors from before
+/// 0034_content_change_xact.sql are a bare id and have no transaction, the change's own is
+/// looked up.
+#[derive(Debug, PartialEq)]
+struct Place {
+    xact: Option<i64>,
+    id: i64,
+}
+
+fn parse_cursor(after: Option<Cursor>) -> Result<Place, FieldError> {
+    let invalid = |cursor: &str| -> FieldError { format!("Invalid cursor {}", cursor).into() };
+    match after.as_deref() {
+        None | Some("") => Ok(Place {
+            xact: Some(0),
+            id: 0,
+        }),
+        Some(cursor) => {
+            let mut parts = cursor.splitn(2, ':');
+            let first = parts.next().unwrap_or_default();
+            let parse = |part: &str| part.parse::<i64>().map_err(|_| invalid(cursor));
+            match parts.next() {
+                Some(id) => Ok(Place {
+                    xact: Some(parse(first)?),
+                    id: parse(id)?,
+                }),
+                None => Ok(Place {
+                    xact: None,
+                    id: parse(first)?,
+                }),
+            }
+        }
+    }
+}
+
+fn cursor(xact: i64, id: i64) -> Cursor {
+    format!("{}:{}", xact, id)
+}
+
+/// Changes after `after` with their cursors, oldest first. Changes from transactions that might
+/// still be running alongside one that isn't done yet are left for later. `limit` of None means
+/// all of them.
+fn change_stream<'a>(
+    pool: &'a sqlx::PgPool,
+    after: &Place,
+    kinds: &'a [String],
+    since: Option<NaiveDateTime>,
+    limit: Option<i64>,
+) -> BoxStream<'a, Result<(Cursor, Change), FieldError>> {
+    sqlx::query!(
+        r#"
+        SELECT id, xact, change_type, action, pid, cid, time
+        FROM content_change
+        WHERE (xact, id) > (
+                coalesce($1::bigint, (SELECT xact FROM content_change WHERE id = $2), 0), $2
+            )
+            AND xact < txid_snapshot_xmin(txid_current_snapshot())
+            AND change_type = ANY($3) AND ($4::timestamp IS NULL OR time >= $4)
+        ORDER BY xact, id
+        LIMIT $5
+        "#,
+        after.xact,
+        after.id,
+        kinds,
+        since,
+        limit
+    )
+    .fetch(pool)
+    .map(|row| -> Result<(i64, Change), FieldError> {
+        let row = row?;
+        let change = Change {
+            change_type: match row.change_type.as_str() {
+                "post" => ChangeType::Post,
+                "comment" => ChangeType::Comment,
+                kind => return Err(format!("Unknown change type {}", kind).into()),
+            },
+            action: match row.action.as_str() {
+                "created" => ChangeAction::Created,
+                "edited" => ChangeAction::Edited,
+                "deleted" => ChangeAction::Deleted,
+                "restored" => ChangeAction::Restored,
+                action => return Err(format!("Unknown change {}", action).into()),
+            },
+            pid: row.pid,
+            cid: row.cid,
+            time: row.time,
+        };
+        Ok((cursor(row.xact, row.id), change))
+    })
+    .boxed()
+}
+
+/// Changes to posts and comments since `since`, in the order their transactions started. Cursors
+/// stay valid, so an indexer can keep the end cursor and pick up from there next time. A change
+/// shows up once every transaction that started before its own is done.
+pub async fn get_changes(
+    context: &Context,
+    since: Option<NaiveDateTime>,
+    types: Option<Vec<ChangeType>>,
+    count: Option<i32>,
+    after: Option<String>,
+) -> Result<Page<Change>, FieldError> {
+    let count = count.unwrap_or(100).max(0).min(1000) as i64;
+    let place = parse_cursor(after.clone())?;
+    let kinds = kinds(types);
+
+    let edges = change_stream(&context.pool, &place, &kinds, since, Some(count))
+        .map_ok(|(cursor, change)| Edge {
+            node: change,
+            cursor,
+        })
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let total_count = sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM content_change
+        WHERE (xact, id) > (
+                coalesce($1::bigint, (SELECT xact FROM content_change WHERE id = $2), 0), $2
+            )
+            AND xact < txid_snapshot_xmin(txid_current_snapshot())
+            AND change_type = ANY($3) AND ($4::timestamp IS NULL OR time >= $4)
+        "#,
+        place.xact,
+        place.id,
+        &kinds,
+        since
+    )
+    .fetch_one(&context.pool)
+    .await?
+    .cnt as i32;
+
+    // Empty pages keep the cursor they were asked with, so polling doesn't start over
+    let end_cursor = edges.last().map_or_else(
+        || {
+            after
+                .filter(|after| !after.is_empty())
+                .unwrap_or_else(|| "0".into())
+        },
+        |edge| edge.cursor.clone(),
+    );
+
+    Ok(Page {
+        page_info: PageInfo {
+            has_next_page: (edges.len() as i32) < total_count,
+            end_cursor,
+        },
+        edges,
+        total_count,
+    })
+}
+
+/// getChanges as NDJSON without a page size, one change per line with the same fields except
+/// the node. Each line's cursor can be used to pick up from there.
+pub fn stream_changes(
+    context: &Context,
+    since: Option<NaiveDateTime>,
+    types: Option<Vec<ChangeType>>,
+    after: Option<String>,
+) -> Result<ndjson::Lines, FieldError> {
+    let after = parse_cursor(after)?;
+    let kinds = kinds(types);
+    let pool = context.pool.clone();
+    let config = context.config.clone();
+
+    Ok(ndjson::spawn(move |mut lines| async move {
+        let mut changes = change_stream(&pool, &after, &kinds, since, None);
+        while let Some(change) = changes.next().await {
+            let (cursor, change) = match change {
+                Ok(change) => change,
+                Err(err) => {
+                    log::error!("Streaming changes failed - {:?}", err);
+                    lines.fail("The changes could not be finished").await;
+                    return;
+                }
+            };
+            let line = json!({
+                "cursor": cursor,
+                "changeType": change.change_type,
+                "action": change.action,
+                "contentId": change.encoded_id(&config).to_string(),
+                "time": change.time.format("%Y-%m-%dT%H:%M:%S%.fZ").to_string(),
+            });
+            if !lines.send(&line).await {
+                return;
+            }
+        }
+    }))
+}
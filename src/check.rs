@@ -0,0 +1,248 @@
+//! Consistency checks for `throatql check`. Loaders fail on rows like these instead of guessing,
+//! so they're better found and fixed ahead of time.
+
+use crate::{legacy, post::DeleteStatus};
+
+/// Rows shown per problem, the count says how many there are in all
+const MAX_EXAMPLES: i32 = 10;
+
+/// One kind of bad data found
+#[derive(Debug)]
+pub struct Finding {
+    pub problem: &'static str,
+    pub count: i64,
+    /// Ids of the first few rows with the problem
+    pub examples: Vec<String>,
+    /// Rows fixed with `--repair`, None where there's no safe fix
+    pub repaired: Option<usize>,
+}
+
+/// Runs every check, repairing what can be repaired when `repair` is set. Only problems that were
+/// found are returned.
+pub async fn run(pool: &sqlx::PgPool, repair: bool) -> Result<Vec<Finding>, sqlx::Error> {
+    let mut findings = vec![];
+    // Codes LEGACY_ENUM_FILE gives a meaning count as known
+    let known = legacy::known_codes();
+
+    let orphans = sqlx::query!(
+        r#"
+        SELECT count(*) as "count!",
+            coalesce((array_agg(c.cid ORDER BY c.cid))[1:$1], '{}') as "examples!"
+        FROM sub_post_comment c
+        WHERE c.parentcid IS NOT NULL
+            AND NOT EXISTS (SELECT 1 FROM sub_post_comment p WHERE p.cid = c.parentcid)
+        "#,
+        MAX_EXAMPLES
+    )
+    .fetch_one(pool)
+    .await?;
+    let repaired = if repair && orphans.count > 0 {
+        // Shown as top level comments from then on, the parent is gone for good
+        Some(
+            sqlx::query!(
+                r#"
+                UPDATE sub_post_comment c
+                SET parentcid = NULL
+                WHERE c.parentcid IS NOT NULL
+                    AND NOT EXISTS (SELECT 1 FROM sub_post_comment p WHERE p.cid = c.parentcid)
+                RETURNING cid
+                "#
+            )
+            .fetch_all(pool)
+            .await?
+            .len(),
+        )
+    } else {
+        None
+    };
+    findings.push(Finding {
+        problem: "comments replying to a missing comment",
+        count: orphans.count,
+        examples: orphans.examples,
+        repaired,
+    });
+
+    // Walks up from every reply, stopping where a comment repeats. Comments that get back to
+    // where they started are in a cycle, and anything below one would never reach the top.
+    let cycles = sqlx::query!(
+        r#"
+        WITH RECURSIVE walk (start, cid, path) AS (
+            SELECT c.cid::text, c.parentcid::text, ARRAY[c.cid::text]
+            FROM sub_post_comment c
+            WHERE c.parentcid IS NOT NULL
+            UNION ALL
+            SELECT walk.start, p.parentcid::text, walk.path || walk.cid
+            FROM walk
+            JOIN sub_post_comment p ON p.cid = walk.cid
+            WHERE p.parentcid IS NOT NULL AND walk.cid <> ALL(walk.path)
+        )
+        SELECT count(*) as "count!",
+            coalesce((array_agg(start ORDER BY start))[1:$1], '{}') as "examples!"
+        FROM (SELECT DISTINCT start FROM walk WHERE cid = start) cyclic
+        "#,
+        MAX_EXAMPLES
+    )
+    .fetch_one(pool)
+    .await?;
+    findings.push(Finding {
+        problem: "comments whose parents lead back to themselves",
+        count: cycles.count,
+        examples: cycles.examples,
+        // Which link to cut is a judgement call
+        repaired: None,
+    });
+
+    let missing_sub = sqlx::query!(
+        r#"
+        SELECT count(*) as "count!",
+            coalesce((array_agg(p.pid::text ORDER BY p.pid))[1:$1], '{}') as "examples!"
+        FROM sub_post p
+        WHERE p.sid IS NULL OR NOT EXISTS (SELECT 1 FROM sub s WHERE s.sid = p.sid)
+        "#,
+        MAX_EXAMPLES
+    )
+    .fetch_one(pool)
+    .await?;
+    findings.push(Finding {
+        problem: "posts in a missing sub",
+        count: missing_sub.count,
+        examples: missing_sub.examples,
+        repaired: None,
+    });
+
+    let missing_user = sqlx::query!(
+        r#"
+        SELECT count(*) as "count!",
+            coalesce((array_agg(p.pid::text ORDER BY p.pid))[1:$1], '{}') as "examples!"
+        FROM sub_post p
+        WHERE p.uid IS NOT NULL AND NOT EXISTS (SELECT 1 FROM public.user u WHERE u.uid = p.uid)
+        "#,
+        MAX_EXAMPLES
+    )
+    .fetch_one(pool)
+    .await?;
+    findings.push(Finding {
+        problem: "posts by a missing user",
+        count: missing_user.count,
+        examples: missing_user.examples,
+        repaired: None,
+    });
+
+    let post_types = sqlx::query!(
+        r#"
+        SELECT count(*) as "count!",
+            coalesce((array_agg(p.pid::text ORDER BY p.pid))[1:$1], '{}') as "examples!"
+        FROM sub_post p
+        WHERE p.ptype IS NULL OR p.ptype <> ALL($2)
+        "#,
+        MAX_EXAMPLES,
+        &known.ptype
+    )
+    .fetch_one(pool)
+    .await?;
+    findings.push(Finding {
+        problem: "posts with an unknown ptype",
+        count: post_types.count,
+        examples: post_types.examples,
+        repaired: None,
+    });
+
+    let post_statuses = sqlx::query!(
+        r#"
+        SELECT count(*) as "count!",
+            coalesce((array_agg(p.pid::text ORDER BY p.pid))[1:$1], '{}') as "examples!"
+        FROM sub_post p
+        WHERE p.deleted <> ALL($2)
+        "#,
+        MAX_EXAMPLES,
+        &known.post_deleted
+    )
+    .fetch_one(pool)
+    .await?;
+    let repaired = if repair && post_statuses.count > 0 {
+        Some(
+            sqlx::query!(
+                r#"
+                UPDATE sub_post
+                SET deleted = $1
+                WHERE deleted <> ALL($2)
+                RETURNING pid
+                "#,
+                DeleteStatus::Admin.to_db(),
+                &known.post_deleted
+            )
+            .fetch_all(pool)
+            .await?
+            .len(),
+        )
+    } else {
+        None
+    };
+    findings.push(Finding {
+        problem: "posts with an unknown deleted status",
+        count: post_statuses.count,
+        examples: post_statuses.examples,
+        repaired,
+    });
+
+    let comment_statuses = sqlx::query!(
+        r#"
+        SELECT count(*) as "count!",
+            coalesce((array_agg(c.cid ORDER BY c.cid))[1:$1], '{}') as "examples!"
+        FROM sub_post_comment c
+        WHERE c.status <> ALL($2)
+        "#,
+        MAX_EXAMPLES,
+        &known.comment_status
+    )
+    .fetch_one(pool)
+    .await?;
+    let repaired = if repair && comment_statuses.count > 0 {
+        Some(
+            sqlx::query!(
+                r#"
+                UPDATE sub_post_comment
+                SET status = $1
+                WHERE status <> ALL($2)
+                RETURNING cid
+                "#,
+                DeleteStatus::Admin.to_db(),
+                &known.comment_status
+            )
+            .fetch_all(pool)
+            .await?
+            .len(),
+        )
+    } else {
+        None
+    };
+    findings.push(Finding {
+        problem: "comments with an unknown status",
+        count: comment_statuses.count,
+        examples: comment_statuses.examples,
+        repaired,
+    });
+
+    let users = sqlx::query!(
+        r#"
+        SELECT count(*) as "count!",
+            coalesce((array_agg(u.name ORDER BY u.name))[1:$1], '{}') as "examples!"
+        FROM public.user u
+        WHERE u.status <> ALL($2) OR u.crypto <> ALL($3)
+        "#,
+        MAX_EXAMPLES,
+        &known.user_status,
+        &known.crypto
+    )
+    .fetch_one(pool)
+    .await?;
+    findings.push(Finding {
+        problem: "users with an unknown status or crypto",
+        count: users.count,
+        examples: users.examples,
+        repaired: None,
+    });
+
+    findings.retain(|finding| finding.count > 0);
+    Ok(findings)
+}
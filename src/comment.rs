@@ -1,10 +1,24 @@
-use crate::post::{DeleteStatus, Post};
-use crate::{user::User, Context, Cursor, Edge, Page, PageInfo};
+use crate::post::{self, DeleteStatus, Post};
+use crate::{
+    attachment::{self, Attachment},
+    auth::UserState,
+    bot,
+    events::Event,
+    legacy, markdown, moderation,
+    reaction::{self, Reaction},
+    repo::{CommentRepo, Memory},
+    saved, stats,
+    sub::Sub,
+    user::{User, UserRef},
+    visit,
+    vote::{self, Votable, VotableValue, VoteDirection},
+    word_filter, Context, Cursor, Edge, Page, PageInfo,
+};
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
 use dataloader::BatchFn;
 use futures_util::stream::StreamExt;
-use juniper::{graphql_object, FieldError, ID};
+use juniper::{graphql_interface, graphql_object, FieldError, ID};
 use std::{collections::HashMap, sync::Arc};
 
 #[derive(Debug, Clone)]
@@ -14,7 +28,7 @@ pub struct Comment {
     content: Option<String>,
     last_edit: Option<NaiveDateTime>,
     parent_cid: Option<String>,
-    children: Vec<String>,
+    child_count: i32,
     pid: Option<i32>,
     score: Option<i32>,
     up_votes: i32,
@@ -22,6 +36,27 @@ pub struct Comment {
     status: DeleteStatus,
     time: Option<NaiveDateTime>,
     uid: Option<String>,
+    collapsed: bool,
+}
+
+impl Comment {
+    fn effective_score(&self) -> i32 {
+        self.score.unwrap_or(self.up_votes - self.down_votes)
+    }
+
+    /// The content unless it's deleted and the viewer may not see deleted content
+    fn visible_content(&self, user: &UserState) -> &Option<String> {
+        if self.status == DeleteStatus::Not
+            || user.can_view_deleted(
+                &self.sid.to_owned().unwrap_or_else(|| "".to_string()),
+                &self.uid.to_owned().unwrap_or_else(|| "".to_string()),
+            )
+        {
+            &self.content
+        } else {
+            &None
+        }
+    }
 }
 
 #[graphql_object(name = "CommentNode", context = Context)]
@@ -53,23 +88,21 @@ impl Page<Result<Comment, FieldError>> {
     }
 }
 
-#[graphql_object(context = Context)]
+#[graphql_object(context = Context, impl = VotableValue)]
 impl Comment {
     fn id(&self, _ctx: &Context) -> ID {
         self.cid.clone().into()
     }
 
     fn content(&self, context: &Context) -> &Option<String> {
-        if self.status == DeleteStatus::Not
-            || context.user.can_view_deleted(
-                &self.sid.to_owned().unwrap_or_else(|| "".to_string()),
-                &self.uid.to_owned().unwrap_or_else(|| "".to_string()),
-            )
-        {
-            &self.content
-        } else {
-            &None
-        }
+        self.visible_content(&context.user)
+    }
+
+    /// content as sanitized HTML, the same previewMarkdown shows
+    fn content_html(&self, context: &Context) -> Option<String> {
+        self.visible_content(&context.user)
+            .as_ref()
+            .map(|content| markdown::render(&context.config.site_url, content))
     }
 
     fn deleted(&self, _context: &Context) -> &DeleteStatus {
@@ -91,41 +124,71 @@ impl Comment {
             .map_err(|err| format!("{:?}", err).into())
     }
 
+    /// How many comments up the top-level comment is, 0 for top-level comments
+    async fn depth(&self, ctx: &Context) -> Result<i32, FieldError> {
+        depth(&ctx.pool, &self.cid).await
+    }
+
+    /// False once replying would go deeper than the sub allows, see Sub.maxReplyDepth
+    async fn can_reply(&self, ctx: &Context) -> Result<bool, FieldError> {
+        let sid = match self.sid {
+            Some(ref sid) => sid,
+            None => return Ok(true),
+        };
+        Ok(match max_reply_depth(ctx, sid).await? {
+            Some(max) => depth(&ctx.pool, &self.cid).await? < max,
+            None => true,
+        })
+    }
+
+    /// Set when the score fell below the threshold of the page this comment was loaded in
+    fn collapsed(&self, _ctx: &Context) -> bool {
+        self.collapsed
+    }
+
+    /// Always empty for collapsed comments, load the comment by id to expand it
     async fn children(
         &self,
         ctx: &Context,
         limit: Option<i32>,
         after: Option<Cursor>,
-    ) -> Page<Result<Comment, FieldError>> {
-        let limit = limit.unwrap_or(25);
-        let after: String = after.unwrap_or_else(|| "".into());
-
-        let page: Vec<_> = self
-            .children
-            .iter()
-            .skip_while(|cid| (after != "") && (cid != &&after))
-            .take(limit as usize)
-            .cloned()
-            .collect();
-
-        let comments = ctx.comment_loader.load_many(page.clone()).await;
-
-        let page_len = page.len();
-
-        Page {
-            total_count: self.children.len() as i32,
-            page_info: PageInfo {
-                has_next_page: page_len as i32 == limit,
-                end_cursor: page.last().cloned().unwrap_or_else(|| "".into()),
-            },
-            edges: comments
-                .into_iter()
-                .map(|comment| Edge {
-                    cursor: comment.0,
-                    node: comment.1.map_err(|err| format!("{:?}", err).into()),
-                })
-                .collect(),
+        collapsed_below_score: Option<i32>,
+    ) -> Result<Page<Result<Comment, FieldError>>, FieldError> {
+        if self.collapsed {
+            return Ok(Page {
+                total_count: self.child_count,
+                page_info: PageInfo {
+                    has_next_page: self.child_count > 0,
+                    end_cursor: "".into(),
+                },
+                edges: vec![],
+            });
         }
+
+        children_page(
+            ctx,
+            CommentParent::Comment(self.cid.clone()),
+            self.child_count,
+            limit,
+            after,
+            collapsed_below_score,
+        )
+        .await
+    }
+
+    /// The post's permalink followed by this comment's id
+    async fn permalink(&self, ctx: &Context) -> Result<String, FieldError> {
+        let post = ctx
+            .post_loader
+            .load(self.pid.ok_or("Comment not related to post?")?)
+            .await
+            .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+
+        Ok(format!(
+            "{}/{}",
+            post::permalink(ctx, &post).await?,
+            self.cid
+        ))
     }
 
     async fn post(&self, ctx: &Context) -> Result<Post, FieldError> {
@@ -135,8 +198,12 @@ impl Comment {
             .map_err(|err| format!("{:?}", err).into())
     }
 
-    fn score(&self, _ctx: &Context) -> Option<i32> {
-        self.score
+    fn score(&self, _ctx: &Context) -> i32 {
+        self.effective_score()
+    }
+
+    async fn viewer_vote(&self, ctx: &Context) -> Result<Option<VoteDirection>, FieldError> {
+        vote::comment_vote(ctx, &self.cid).await
     }
 
     fn up_votes(&self, _ctx: &Context) -> i32 {
@@ -151,21 +218,433 @@ impl Comment {
         self.time
     }
 
+    /// Images added with attachToComment, only in subs that allow them
+    async fn attachments(&self, ctx: &Context) -> Result<Vec<Attachment>, FieldError> {
+        attachment::comment_attachments(ctx, &self.cid).await
+    }
+
+    /// Only in subs that turned reactions on, they don't count towards the score
+    async fn reactions(&self, ctx: &Context) -> Result<Vec<Reaction>, FieldError> {
+        match self.sid {
+            Some(ref sid) => reaction::comment_reactions(ctx, &self.cid, sid).await,
+            None => Ok(vec![]),
+        }
+    }
+
+    async fn is_saved_by_viewer(&self, ctx: &Context) -> Result<bool, FieldError> {
+        saved::comment_saved(ctx, &self.cid).await
+    }
+
+    /// Came in after the viewer last visited the post, for highlighting
+    async fn is_new_for_viewer(&self, ctx: &Context) -> Result<bool, FieldError> {
+        visit::is_new(ctx, self.pid, self.uid.as_deref(), self.time).await
+    }
+
+    /// Written by an approved bot account
+    async fn by_bot(&self, ctx: &Context) -> Result<bool, FieldError> {
+        match self.uid {
+            Some(ref uid) => bot::is_bot(&ctx.pool, uid).await,
+            None => Ok(false),
+        }
+    }
+
     async fn author(&self, ctx: &Context) -> Result<User, FieldError> {
         ctx.user_loader
-            .load(
-                self.uid
-                    .clone()
-                    .ok_or("Comment not related to post?")?
-                    .into(),
-            )
+            .load(UserRef::Uid(
+                self.uid.clone().ok_or("Comment not related to post?")?,
+            ))
             .await
             .map_err(|err| format!("{:?}", err).into())
     }
 }
 
+#[graphql_interface]
+impl Votable for Comment {
+    fn id(&self, _context: &Context) -> ID {
+        self.cid.clone().into()
+    }
+
+    fn score(&self) -> i32 {
+        self.effective_score()
+    }
+
+    fn up_votes(&self) -> i32 {
+        self.up_votes
+    }
+
+    fn down_votes(&self) -> i32 {
+        self.down_votes
+    }
+
+    async fn viewer_vote(&self, context: &Context) -> Result<Option<VoteDirection>, FieldError> {
+        vote::comment_vote(context, &self.cid).await
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CommentParent {
+    /// Top level comments of a post
+    Post(i32),
+    Comment(String),
+}
+
+/// One page of replies, comments are ordered by time with the cid breaking ties
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChildrenKey {
+    pub parent: CommentParent,
+    pub after: Option<String>,
+    pub limit: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChildrenPage {
+    pub cids: Vec<String>,
+    pub has_next_page: bool,
+}
+
+impl ChildrenPage {
+    /// From the page's comments with one extra after them if there are more
+    fn from_rows(mut cids: Vec<String>, limit: i32) -> Self {
+        let has_next_page = cids.len() > limit as usize;
+        cids.truncate(limit as usize);
+        ChildrenPage {
+            cids,
+            has_next_page,
+        }
+    }
+}
+
+/// Same rules as post::delete_post
+pub async fn delete_comment(
+    context: &Context,
+    id: ID,
+    reason: Option<String>,
+) -> Result<Comment, FieldError> {
+    let comment = sqlx::query!(
+        r#"
+        SELECT c.uid, c.status, p.sid
+        FROM sub_post_comment c
+        JOIN sub_post p ON p.pid = c.pid
+        WHERE c.cid = $1
+        "#,
+        id.as_str()
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Comment not found {}", *id))?;
+    let sid = comment.sid.unwrap_or_default();
+    let status = post::deletion_by(&context.user, &sid, comment.uid.as_deref())?;
+    if comment.status.unwrap_or(0) != 0 {
+        return Err("This is already deleted".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    // Checked again here, a delete racing this one must not log a second removal
+    let deleted = sqlx::query!(
+        r#"
+        UPDATE sub_post_comment
+        SET status = $2
+        WHERE cid = $1 AND coalesce(status, 0) = 0
+        RETURNING cid
+        "#,
+        id.as_str(),
+        status.to_db()
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    if deleted.is_none() {
+        return Err("This is already deleted".into());
+    }
+    if status != DeleteStatus::User {
+        moderation::log_action(
+            &mut tx,
+            context.user.user_id()?,
+            Some(sid),
+            "remove_comments",
+            vec![id.to_string()],
+            reason,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    context.comment_loader.clear(id.to_string()).await;
+    context
+        .comment_loader
+        .load(id.to_string())
+        .await
+        .map_err(|err| format!("{:?}", err).into())
+}
+
+/// Same rules as post::edit_post, `last_edited_at` is the lastEdit (or time) of the copy edited
+pub async fn edit_comment(
+    context: &Context,
+    id: ID,
+    content: String,
+    last_edited_at: Option<NaiveDateTime>,
+) -> Result<Comment, FieldError> {
+    let uid = context.user.user_id()?;
+
+    // Editing while muted would just be commenting through the back door
+    let sid = sqlx::query!(
+        r#"
+        SELECT p.sid
+        FROM sub_post_comment c
+        JOIN sub_post p ON p.pid = c.pid
+        WHERE c.cid = $1
+        "#,
+        id.as_str()
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .and_then(|row| row.sid);
+    let mut held = None;
+    if let Some(ref sid) = sid {
+        moderation::check_not_muted(context, sid).await?;
+        held = word_filter::screen(context, sid, &content).await?;
+    }
+
+    let updated = sqlx::query!(
+        r#"
+        UPDATE sub_post_comment
+        SET content = $3, lastedit = now()
+        WHERE cid = $1 AND uid = $2 AND coalesce(status, 0) = 0
+            AND ($4::timestamp IS NULL
+                 OR date_trunc('second', coalesce(lastedit, time)) = date_trunc('second', $4))
+        RETURNING cid
+        "#,
+        id.as_str(),
+        uid,
+        content,
+        last_edited_at
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+
+    let current = sqlx::query!(
+        r#"
+        SELECT uid, content, status, coalesce(lastedit, time) as last_edit
+        FROM sub_post_comment
+        WHERE cid = $1
+        "#,
+        id.as_str()
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Comment not found {}", *id))?;
+
+    if updated.is_none() {
+        if current.uid.as_deref() != Some(uid) {
+            return Err("Only the author can edit a comment".into());
+        }
+        if current.status.unwrap_or(0) != 0 {
+            return Err("Deleted comments can't be edited".into());
+        }
+        return Err(post::edit_conflict(current.content, current.last_edit));
+    }
+    if let (Some(sid), Some(pattern)) = (sid, held) {
+        word_filter::hold_comment(context, id.as_str(), &sid, pattern).await?;
+    }
+
+    context.comment_loader.clear(id.to_string()).await;
+    context
+        .publish(Event::CommentEdited {
+            cid: id.to_string(),
+        })
+        .await;
+    context
+        .comment_loader
+        .load(id.to_string())
+        .await
+        .map_err(|err| format!("{:?}", err).into())
+}
+
+/// Furthest depth() follows parentcid, a cycle in it would otherwise never end. `throatql check`
+/// reports cycles.
+const MAX_CHAIN: i32 = 10_000;
+
+async fn depth(pool: &sqlx::PgPool, cid: &str) -> Result<i32, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        WITH RECURSIVE chain (cid, parentcid, depth) AS (
+            SELECT cid, parentcid, 0
+            FROM sub_post_comment
+            WHERE cid = $1
+            UNION ALL
+            SELECT c.cid, c.parentcid, chain.depth + 1
+            FROM sub_post_comment c
+            JOIN chain ON c.cid = chain.parentcid
+            WHERE chain.depth < $2
+        )
+        SELECT coalesce(max(depth), 0) as "depth!"
+        FROM chain
+        "#,
+        cid,
+        MAX_CHAIN
+    )
+    .fetch_one(pool)
+    .await?
+    .depth)
+}
+
+/// Deepest a reply may go in the sub: the sub's own limit or MAX_REPLY_DEPTH, whichever is lower
+pub async fn max_reply_depth(context: &Context, sid: &str) -> Result<Option<i32>, FieldError> {
+    let sub_limit = sqlx::query!(
+        r#"
+        SELECT value
+        FROM sub_metadata
+        WHERE sid = $1 AND key = 'max_reply_depth'
+        "#,
+        sid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .and_then(|row| row.value)
+    .and_then(|value| value.parse::<i32>().ok());
+    let site_limit = context.config.max_reply_depth.map(|max| max as i32);
+    Ok(match (sub_limit, site_limit) {
+        (Some(sub), Some(site)) => Some(sub.min(site)),
+        (sub, site) => sub.or(site),
+    })
+}
+
+/// For the sub's mods, `None` falls back to the site-wide limit
+pub async fn set_max_reply_depth(
+    context: &Context,
+    sub: String,
+    depth: Option<i32>,
+) -> Result<Option<i32>, FieldError> {
+    let uid = context.user.user_id()?;
+    if depth.map_or(false, |depth| depth < 1) {
+        return Err("The reply depth limit must be positive".into());
+    }
+    let sub: Sub = context
+        .sub_loader
+        .load(sub.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM sub_metadata
+        WHERE sid = $1 AND key = 'max_reply_depth'
+        "#,
+        sub.sid
+    )
+    .execute(&mut tx)
+    .await?;
+    if let Some(depth) = depth {
+        sqlx::query!(
+            r#"
+            INSERT INTO sub_metadata (sid, key, value)
+            VALUES ($1, 'max_reply_depth', $2)
+            "#,
+            sub.sid,
+            depth.to_string()
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sub.sid.clone()),
+        "set_max_reply_depth",
+        vec![],
+        depth.map(|depth| depth.to_string()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    max_reply_depth(context, &sub.sid).await
+}
+
+/// cids are uuids, anything else can't name a comment and would only make for an empty page
+fn valid_cid(cid: &str) -> bool {
+    !cid.is_empty() && cid.len() <= 40 && cid.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+pub async fn children_page(
+    ctx: &Context,
+    parent: CommentParent,
+    total_count: i32,
+    limit: Option<i32>,
+    after: Option<Cursor>,
+    collapsed_below_score: Option<i32>,
+) -> Result<Page<Result<Comment, FieldError>>, FieldError> {
+    let limit = limit.unwrap_or(25).max(0);
+    let after = after.filter(|after| after != "");
+    if let Some(ref after) = after {
+        if !valid_cid(after) {
+            return Err(format!("Invalid cursor {}", after).into());
+        }
+    }
+    let collapse_threshold = match collapsed_below_score {
+        Some(score) => Some(score),
+        None => ctx
+            .preference("collapse_below_score")
+            .await?
+            .and_then(|score| score.parse::<i32>().ok()),
+    };
+
+    let page = ctx
+        .comment_children_loader
+        .load(ChildrenKey {
+            parent,
+            after,
+            limit,
+        })
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+
+    let mut comments = ctx.comment_loader.load_many(page.cids.clone()).await;
+
+    Ok(Page {
+        total_count,
+        page_info: PageInfo {
+            has_next_page: page.has_next_page,
+            end_cursor: page.cids.last().cloned().unwrap_or_else(|| "".into()),
+        },
+        edges: page
+            .cids
+            .into_iter()
+            .map(|cid| Edge {
+                node: comments
+                    .remove(&cid)
+                    .unwrap_or_else(|| Err(Arc::new(format!("Could not find {}", cid).into())))
+                    .map(|mut comment| {
+                        comment.collapsed = collapse_threshold
+                            .map_or(false, |threshold| comment.effective_score() < threshold);
+                        comment
+                    })
+                    .map_err(|err| format!("{:?}", err).into()),
+                cursor: cid,
+            })
+            .collect(),
+    })
+}
+
+pub struct CommentChildrenLoader {
+    pub repo: Arc<dyn CommentRepo>,
+}
+
+#[async_trait]
+impl BatchFn<ChildrenKey, Result<ChildrenPage, Arc<FieldError>>> for CommentChildrenLoader {
+    async fn load(
+        &self,
+        keys: &[ChildrenKey],
+    ) -> HashMap<ChildrenKey, Result<ChildrenPage, Arc<FieldError>>> {
+        stats::record_batch("comment_children", keys.len());
+        self.repo.load_children(keys).await
+    }
+}
+
 pub struct CommentLoader {
-    pub pool: sqlx::PgPool,
+    pub repo: Arc<dyn CommentRepo>,
 }
 
 #[async_trait]
@@ -175,13 +654,111 @@ impl BatchFn<String, Result<Comment, Arc<FieldError>>> for CommentLoader {
         String: 'async_trait,
         Result<Comment, Arc<FieldError>>: 'async_trait,
     {
-        let comments: Vec<_> = sqlx::query!(
+        stats::record_batch("comment", keys.len());
+        self.repo.load_comments(keys).await
+    }
+}
+
+#[async_trait]
+impl CommentRepo for sqlx::PgPool {
+    async fn load_children(
+        &self,
+        keys: &[ChildrenKey],
+    ) -> HashMap<ChildrenKey, Result<ChildrenPage, Arc<FieldError>>> {
+        // Empty strings stand in for "no parent comment" and "from the start"
+        let mut pids = vec![];
+        let mut parents = vec![];
+        let mut afters = vec![];
+        let mut limits = vec![];
+        for key in keys {
+            match key.parent {
+                CommentParent::Post(pid) => {
+                    pids.push(pid);
+                    parents.push("".to_string());
+                }
+                CommentParent::Comment(ref cid) => {
+                    pids.push(0);
+                    parents.push(cid.clone());
+                }
+            }
+            afters.push(key.after.clone().unwrap_or_default());
+            // One extra row tells us whether there is a next page
+            limits.push(key.limit as i64 + 1);
+        }
+
+        let rows = sqlx::query!(
             r#"
+            SELECT k.idx as "idx!", c.cid as "cid!"
+            FROM unnest($1::int[], $2::text[], $3::text[], $4::bigint[])
+                WITH ORDINALITY AS k(pid, parent, after, lim, idx)
+            CROSS JOIN LATERAL (
+                SELECT c.cid
+                FROM sub_post_comment c
+                WHERE (
+                    (k.parent = '' AND c.pid = k.pid AND c.parentcid IS NULL)
+                    OR (k.parent <> '' AND c.parentcid = k.parent)
+                )
+                AND (k.after = '' OR (c.time, c.cid) > (
+                    SELECT a.time, a.cid
+                    FROM sub_post_comment a
+                    WHERE a.cid = k.after
+                ))
+                ORDER BY c.time, c.cid
+                LIMIT k.lim
+            ) c
+            "#,
+            &pids,
+            &parents,
+            &afters,
+            &limits
+        )
+        .fetch(self)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>();
+
+        let rows = match rows {
+            Ok(rows) => rows,
+            Err(err) => {
+                let err = Arc::new(FieldError::from(err));
+                return keys
+                    .iter()
+                    .map(|key| (key.clone(), Err(err.clone())))
+                    .collect();
+            }
+        };
+
+        let mut pages: Vec<Vec<String>> = vec![vec![]; keys.len()];
+        rows.into_iter().for_each(|row| {
+            // ORDINALITY counts from 1
+            if let Some(page) = pages.get_mut(row.idx as usize - 1) {
+                page.push(row.cid);
+            }
+        });
+
+        keys.iter()
+            .cloned()
+            .zip(pages.into_iter())
+            .map(|(key, cids)| {
+                let limit = key.limit;
+                (key, Ok(ChildrenPage::from_rows(cids, limit)))
+            })
+            .collect()
+    }
+
+    async fn load_comments(
+        &self,
+        keys: &[String],
+    ) -> HashMap<String, Result<Comment, Arc<FieldError>>> {
+        let comments: Vec<Result<(String, Result<Comment, FieldError>), FieldError>> =
+            sqlx::query!(
+                r#"
                 SELECT p.cid, p.content, p.lastedit, p.parentcid, p.pid, p.score, p.upvotes, 
-                       p.downvotes, p.status, p.time, p.uid, c.child_arr as children, sp.sid
+                       p.downvotes, p.status, p.time, p.uid, c.child_count, sp.sid
                 FROM sub_post_comment   p
                 LEFT JOIN ( 
-                    SELECT c.parentcid AS cid, array_agg(c.cid) as child_arr
+                    SELECT c.parentcid AS cid, count(*) as child_count
                     FROM sub_post_comment AS c
                     GROUP BY c.parentcid
                 ) c USING (cid)
@@ -192,50 +769,204 @@ impl BatchFn<String, Result<Comment, Arc<FieldError>>> for CommentLoader {
                 WHERE p.cid = ANY($1::text[])
                 AND sp.pid = p.pid
             "#,
-            keys
-        )
-        .fetch(&self.pool)
-        .map(|comment| -> Result<Comment, FieldError> {
-            let comment = comment?;
-            Ok(Comment {
-                sid: comment.sid,
-                children: comment.children.unwrap_or_default(),
-                cid: comment.cid.clone(),
-                uid: comment.uid,
-                time: comment.time,
-                status: match comment.status {
-                    Some(1) => Ok(DeleteStatus::User),
-                    Some(2) => Ok(DeleteStatus::Mod),
-                    Some(3) => Ok(DeleteStatus::Admin),
-                    Some(0) => Ok(DeleteStatus::Not),
-                    None => Ok(DeleteStatus::Not),
-                    _ => Err(format!("Unknown Delete Status - {}", comment.cid)),
-                }?,
-                score: comment.score,
-                parent_cid: comment.parentcid,
-                pid: comment.pid,
-                content: comment.content,
-                down_votes: comment.downvotes,
-                up_votes: comment.upvotes,
-                last_edit: comment.lastedit,
-            })
-        })
-        .collect()
-        .await;
+                keys
+            )
+            .fetch(self)
+            .map(
+                |comment| -> Result<(String, Result<Comment, FieldError>), FieldError> {
+                    let comment = comment?;
+                    let cid = comment.cid.clone();
+                    // A row that fails to decode only fails its own key
+                    let decode = move || -> Result<Comment, FieldError> {
+                        Ok(Comment {
+                            sid: comment.sid,
+                            child_count: comment.child_count.unwrap_or(0) as i32,
+                            cid: comment.cid.clone(),
+                            uid: comment.uid,
+                            time: comment.time,
+                            status: legacy::comment_status(comment.status).ok_or_else(|| {
+                                format!("Unknown Delete Status - {}", comment.cid)
+                            })?,
+                            score: comment.score,
+                            parent_cid: comment.parentcid,
+                            pid: comment.pid,
+                            content: comment.content,
+                            down_votes: comment.downvotes,
+                            up_votes: comment.upvotes,
+                            last_edit: comment.lastedit,
+                            collapsed: false,
+                        })
+                    };
+                    Ok((cid, decode()))
+                },
+            )
+            .collect()
+            .await;
 
-        let mut map: HashMap<String, Result<Comment, Arc<FieldError>>> = comments
-            .into_iter()
-            .filter_map(|comment| {
-                let comment = comment.ok()?;
-                Some((comment.cid.clone(), Ok(comment)))
-            })
-            .collect();
+        let mut map: HashMap<String, Result<Comment, Arc<FieldError>>> = HashMap::new();
+        let mut batch_error: Option<Arc<FieldError>> = None;
+
+        comments.into_iter().for_each(|comment| match comment {
+            Ok((cid, comment)) => {
+                let comment = comment.map_err(|err| {
+                    Arc::new(format!("Could not load comment {} - {}", cid, err.message()).into())
+                });
+                map.insert(cid, comment);
+            }
+            Err(err) => {
+                log::error!("Batch Load Comment - {:?}", err);
+                batch_error = Some(Arc::new(err));
+            }
+        });
 
         keys.iter().for_each(|key| {
-            map.entry(key.clone())
-                .or_insert_with(|| Err(Arc::new(format!("Could not find {}", key).into())));
+            map.entry(key.clone()).or_insert_with(|| match batch_error {
+                Some(ref err) => Err(err.clone()),
+                None => Err(Arc::new(format!("Could not find {}", key).into())),
+            });
         });
 
         map
     }
 }
+
+#[async_trait]
+impl CommentRepo for Memory {
+    async fn load_children(
+        &self,
+        keys: &[ChildrenKey],
+    ) -> HashMap<ChildrenKey, Result<ChildrenPage, Arc<FieldError>>> {
+        keys.iter()
+            .map(|key| {
+                let mut children: Vec<&Comment> = self
+                    .comments
+                    .iter()
+                    .filter(|comment| match key.parent {
+                        CommentParent::Post(pid) => {
+                            comment.pid == Some(pid) && comment.parent_cid.is_none()
+                        }
+                        CommentParent::Comment(ref cid) => comment.parent_cid.as_ref() == Some(cid),
+                    })
+                    .collect();
+                // Postgres sorts missing times last
+                children.sort_by(|a, b| {
+                    (a.time.is_none(), a.time, &a.cid).cmp(&(b.time.is_none(), b.time, &b.cid))
+                });
+                // Like in SQL, a cursor that isn't one of the replies finds nothing after it
+                let start = match key.after {
+                    Some(ref after) => children
+                        .iter()
+                        .position(|comment| comment.cid == *after)
+                        .map_or(children.len(), |at| at + 1),
+                    None => 0,
+                };
+                let cids = children
+                    .into_iter()
+                    .skip(start)
+                    .take(key.limit as usize + 1)
+                    .map(|comment| comment.cid.clone())
+                    .collect();
+                (key.clone(), Ok(ChildrenPage::from_rows(cids, key.limit)))
+            })
+            .collect()
+    }
+
+    async fn load_comments(
+        &self,
+        keys: &[String],
+    ) -> HashMap<String, Result<Comment, Arc<FieldError>>> {
+        keys.iter()
+            .map(|key| {
+                let comment = self.comments.iter().find(|comment| comment.cid == *key);
+                (
+                    key.clone(),
+                    comment
+                        .cloned()
+                        .ok_or_else(|| Arc::new(format!("Could not find {}", key).into())),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use proptest::prelude::*;
+
+    fn comment(cid: &str, minute: u32, status: DeleteStatus) -> Comment {
+        Comment {
+            sid: Some("test-sid".into()),
+            cid: cid.into(),
+            content: Some(format!("Text of {}", cid)),
+            last_edit: None,
+            parent_cid: None,
+            child_count: 0,
+            pid: Some(1),
+            score: None,
+            up_votes: 0,
+            down_votes: 0,
+            status,
+            time: Some(NaiveDate::from_ymd(2020, 1, 1).and_hms(0, minute, 0)),
+            uid: Some("alice-uid".into()),
+            collapsed: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_come_in_pages_and_deleted_ones_are_hidden() {
+        let repo: Arc<dyn CommentRepo> = Arc::new(Memory {
+            comments: vec![
+                comment("c", 3, DeleteStatus::Not),
+                comment("a", 1, DeleteStatus::Not),
+                comment("b", 2, DeleteStatus::User),
+            ],
+            ..Memory::default()
+        });
+        let children = CommentChildrenLoader { repo: repo.clone() };
+
+        let first = ChildrenKey {
+            parent: CommentParent::Post(1),
+            after: None,
+            limit: 2,
+        };
+        let pages = children.load(&[first.clone()]).await;
+        let page = pages[&first].as_ref().unwrap();
+        assert_eq!(page.cids, vec!["a", "b"]);
+        assert!(page.has_next_page);
+
+        let next = ChildrenKey {
+            after: Some("b".into()),
+            ..first
+        };
+        let pages = children.load(&[next.clone()]).await;
+        let page = pages[&next].as_ref().unwrap();
+        assert_eq!(page.cids, vec!["c"]);
+        assert!(!page.has_next_page);
+
+        let comments = CommentLoader { repo }.load(&["b".to_string()]).await;
+        let deleted = comments["b"].as_ref().unwrap();
+        assert_eq!(deleted.visible_content(&UserState::Anonymous), &None);
+        let author = UserState::LoggedIn {
+            name: "alice".into(),
+            id: "alice-uid".into(),
+            roles: vec![],
+        };
+        assert!(deleted.visible_content(&author).is_some());
+    }
+
+    proptest! {
+        #[test]
+        fn uuids_are_valid(bytes in any::<[u8; 16]>()) {
+            let cid = uuid::Uuid::from_bytes(bytes).to_hyphenated().to_string();
+            prop_assert!(valid_cid(&cid));
+        }
+
+        #[test]
+        fn other_characters_are_not(prefix in "[a-f0-9-]{0,10}", bad in "[^a-zA-Z0-9-]") {
+            let cid = format!("{}{}", prefix, bad);
+            prop_assert!(!valid_cid(&cid));
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{comment::Comment, parse_offset, post::Post, Context, Cursor, Edge, Page, PageInfo};
+use chrono::{Datelike, NaiveDate, Utc};
+use futures_util::stream::StreamExt;
+use juniper::{graphql_object, FieldError, GraphQLObject, GraphQLUnion};
+
+/// Posts and comments mixed together, for feeds like a user's profile
+#[derive(Debug, Clone, GraphQLUnion)]
+#[graphql(context = Context)]
+pub enum Content {
+    Post(Post),
+    Comment(Comment),
+}
+
+#[graphql_object(name = "ContentNode", context = Context)]
+impl Edge<Content> {
+    fn node(&self) -> &Content {
+        &self.node
+    }
+
+    fn cursor(&self) -> &Cursor {
+        &self.cursor
+    }
+}
+
+#[graphql_object(name = "ContentPage", context = Context)]
+impl Page<Content> {
+    fn edges(&self) -> &Vec<Edge<Content>> {
+        &self.edges
+    }
+
+    fn page_info(&self) -> &PageInfo {
+        &self.page_info
+    }
+
+    fn total_count(&self) -> i32 {
+        self.total_count
+    }
+}
+
+/// Everything a user posted or commented, newest first
+pub async fn get_user_overview(
+    context: &Context,
+    uid: &str,
+    count: Option<i32>,
+    after: Option<String>,
+) -> Result<Page<Content>, FieldError> {
+    let count = count.unwrap_or(25);
+    let offset = parse_offset(after)?;
+
+    let rows = sqlx::query!(
+        r#"
+        SELECT kind as "kind!", id as "id!"
+        FROM (
+            SELECT 'post' as kind, pid::text as id, posted as time
+            FROM sub_post
+            WHERE uid = $1
+            UNION ALL
+            SELECT 'comment' as kind, cid as id, time
+            FROM sub_post_comment
+            WHERE uid = $1
+        ) content
+        ORDER BY time DESC NULLS LAST, id DESC
+        LIMIT $2
+        OFFSET $3
+        "#,
+        uid,
+        count as i64,
+        offset
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let pids = rows
+        .iter()
+        .filter(|row| row.kind == "post")
+        .filter_map(|row| row.id.parse::<i32>().ok())
+        .collect::<Vec<_>>();
+    let cids = rows
+        .iter()
+        .filter(|row| row.kind == "comment")
+        .map(|row| row.id.clone())
+        .collect::<Vec<_>>();
+
+    let mut posts = context.post_loader.load_many(pids).await;
+    let mut comments = context.comment_loader.load_many(cids).await;
+
+    let edges = rows
+        .into_iter()
+        .enumerate()
+        .map(|(i, row)| -> Result<Edge<Content>, FieldError> {
+            let node = if row.kind == "post" {
+                let pid = row.id.parse::<i32>()?;
+                Content::Post(
+                    posts
+                        .remove(&pid)
+                        .ok_or_else(|| format!("Post not found {}", pid))?
+                        .map_err(|err| format!("{:?}", err))?,
+                )
+            } else {
+                Content::Comment(
+                    comments
+                        .remove(&row.id)
+                        .ok_or_else(|| format!("Could not find {}", row.id))?
+                        .map_err(|err| format!("{:?}", err))?,
+                )
+            };
+
+            Ok(Edge {
+                node,
+                cursor: (offset + i as i64 + 1).to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let total_count = sqlx::query!(
+        r#"
+        SELECT (SELECT count(*) FROM sub_post WHERE uid = $1)
+            + (SELECT count(*) FROM sub_post_comment WHERE uid = $1) as "cnt!"
+        "#,
+        uid
+    )
+    .fetch_one(&context.pool)
+    .await?
+    .cnt as i32;
+
+    let end_cursor = edges
+        .iter()
+        .last()
+        .map_or("".into(), |val| val.cursor.clone());
+
+    Ok(Page {
+        edges,
+        total_count,
+        page_info: PageInfo {
+            has_next_page: offset + (count as i64) < total_count as i64,
+            end_cursor,
+        },
+    })
+}
+
+/// Posts and comments of one day, in UTC
+#[derive(Debug, Clone, GraphQLObject)]
+pub struct ActivityDay {
+    pub date: NaiveDate,
+    pub posts: i32,
+    pub comments: i32,
+}
+
+/// Days of `year` (this one by default) the user posted or commented on, oldest first. Days
+/// without anything are left out and deleted content isn't counted.
+pub async fn get_activity_heatmap(
+    context: &Context,
+    uid: &str,
+    year: Option<i32>,
+) -> Result<Vec<ActivityDay>, FieldError> {
+    let year = year.unwrap_or_else(|| Utc::now().year());
+    let from = NaiveDate::from_ymd_opt(year, 1, 1)
+        .ok_or_else(|| format!("Invalid year {}", year))?
+        .and_hms(0, 0, 0);
+    let until = NaiveDate::from_ymd_opt(year + 1, 1, 1)
+        .ok_or_else(|| format!("Invalid year {}", year))?
+        .and_hms(0, 0, 0);
+
+    let days = sqlx::query!(
+        r#"
+        SELECT time::date as "date!",
+            count(*) FILTER (WHERE kind = 'post') as "posts!",
+            count(*) FILTER (WHERE kind = 'comment') as "comments!"
+        FROM (
+            SELECT 'post' as kind, posted as time
+            FROM sub_post
+            WHERE uid = $1 AND coalesce(deleted, 0) = 0
+                AND posted >= $2 AND posted < $3
+            UNION ALL
+            SELECT 'comment' as kind, time
+            FROM sub_post_comment
+            WHERE uid = $1 AND coalesce(status, 0) = 0
+                AND time >= $2 AND time < $3
+        ) content
+        GROUP BY 1
+        ORDER BY 1
+        "#,
+        uid,
+        from,
+        until
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| ActivityDay {
+        date: row.date,
+        posts: row.posts as i32,
+        comments: row.comments as i32,
+    })
+    .collect();
+
+    Ok(days)
+}
@@ -0,0 +1,244 @@
+use crate::{config::Config, mailer::Mailer, post, user, Context};
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLEnum};
+use std::sync::Arc;
+
+const UNSUBSCRIBE_TOKEN: &str = "digest_unsubscribe";
+const POSTS_PER_DIGEST: i64 = 10;
+/// How often the worker looks for users whose digest is due
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum DigestFrequency {
+    Off,
+    Daily,
+    Weekly,
+}
+
+impl DigestFrequency {
+    fn from_db(value: &str) -> Self {
+        match value {
+            "daily" => DigestFrequency::Daily,
+            "weekly" => DigestFrequency::Weekly,
+            _ => DigestFrequency::Off,
+        }
+    }
+
+    fn to_db(self) -> Option<&'static str> {
+        match self {
+            DigestFrequency::Off => None,
+            DigestFrequency::Daily => Some("daily"),
+            DigestFrequency::Weekly => Some("weekly"),
+        }
+    }
+
+    fn period(self) -> Duration {
+        match self {
+            DigestFrequency::Weekly => Duration::days(7),
+            _ => Duration::days(1),
+        }
+    }
+}
+
+pub async fn get_frequency(pool: &sqlx::PgPool, uid: &str) -> Result<DigestFrequency, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT value
+        FROM user_metadata
+        WHERE uid = $1 AND key = 'digest'
+        "#,
+        uid
+    )
+    .fetch_optional(pool)
+    .await?
+    .and_then(|row| row.value)
+    .map(|value| DigestFrequency::from_db(&value))
+    .unwrap_or(DigestFrequency::Off))
+}
+
+async fn store_frequency(
+    pool: &sqlx::PgPool,
+    uid: &str,
+    frequency: DigestFrequency,
+) -> Result<(), FieldError> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM user_metadata
+        WHERE uid = $1 AND key = 'digest'
+        "#,
+        uid
+    )
+    .execute(&mut tx)
+    .await?;
+    if let Some(value) = frequency.to_db() {
+        sqlx::query!(
+            r#"
+            INSERT INTO user_metadata (uid, key, value)
+            VALUES ($1, 'digest', $2)
+            "#,
+            uid,
+            value
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn set_frequency(
+    context: &Context,
+    frequency: DigestFrequency,
+) -> Result<DigestFrequency, FieldError> {
+    let uid = context.user.user_id()?;
+    store_frequency(&context.pool, uid, frequency).await?;
+    Ok(frequency)
+}
+
+/// Redeems the token from the bottom of a digest, works without being logged in
+pub async fn unsubscribe(pool: &sqlx::PgPool, token: &str) -> Result<bool, FieldError> {
+    let uid = user::redeem_token(pool, token, UNSUBSCRIBE_TOKEN).await?;
+    store_frequency(pool, &uid, DigestFrequency::Off).await?;
+    Ok(true)
+}
+
+struct DueUser {
+    uid: String,
+    email: String,
+    frequency: DigestFrequency,
+}
+
+async fn due_users(pool: &sqlx::PgPool) -> Result<Vec<DueUser>, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT u.uid, u.email as "email!", d.value as "frequency!"
+        FROM public.user u
+        JOIN user_metadata d ON d.uid = u.uid AND d.key = 'digest'
+        LEFT JOIN user_metadata s ON s.uid = u.uid AND s.key = 'digest_sent'
+        WHERE u.status = 0 AND coalesce(u.email, '') <> ''
+            AND (s.value IS NULL OR s.value::timestamp < now() - CASE d.value
+                WHEN 'weekly' THEN interval '7 days'
+                ELSE interval '1 day'
+            END)
+        "#
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| DueUser {
+        uid: row.uid,
+        email: row.email,
+        frequency: DigestFrequency::from_db(&row.frequency),
+    })
+    .collect())
+}
+
+async fn send_digest(
+    pool: &sqlx::PgPool,
+    mailer: &dyn Mailer,
+    config: &Config,
+    user: &DueUser,
+) -> Result<(), FieldError> {
+    let since: NaiveDateTime = Utc::now().naive_utc() - user.frequency.period();
+    let posts = sqlx::query!(
+        r#"
+        SELECT p.pid, p.title, s.name as sub_name,
+            SUM(CASE WHEN v.positive > 0 THEN 1 WHEN v.positive < 0 THEN -1 ELSE 0 END) as score
+        FROM sub_post p
+        JOIN sub s ON s.sid = p.sid
+        LEFT JOIN sub_post_vote v ON v.pid = p.pid
+        WHERE p.sid IN (SELECT sid FROM sub_subscriber WHERE uid = $1 AND status = 1)
+            AND p.posted > $2 AND coalesce(p.deleted, 0) = 0
+        GROUP BY p.pid, p.title, s.name
+        ORDER BY score DESC NULLS LAST
+        LIMIT $3
+        "#,
+        user.uid,
+        since,
+        POSTS_PER_DIGEST
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    // Nothing worth mailing, try again next period
+    if !posts.is_empty() {
+        let token =
+            user::issue_token(pool, &user.uid, UNSUBSCRIBE_TOKEN, Duration::days(30)).await?;
+        let lines: Vec<String> = posts
+            .iter()
+            .map(|post| {
+                let title = post.title.as_deref().unwrap_or("");
+                format!(
+                    "{} (/s/{})\n{}/s/{}/{}/{}",
+                    title,
+                    post.sub_name.as_deref().unwrap_or(""),
+                    config.site_url,
+                    post.sub_name.as_deref().unwrap_or(""),
+                    *config.post_ids.encode(post.pid),
+                    post::slugify(title)
+                )
+            })
+            .collect();
+
+        mailer
+            .send(
+                &user.email,
+                "Top posts from your subs",
+                &format!(
+                    "{}\n\nTo stop getting these emails open {}/digest/unsubscribe?token={}",
+                    lines.join("\n\n"),
+                    config.site_url,
+                    token
+                ),
+            )
+            .await?;
+    }
+
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM user_metadata
+        WHERE uid = $1 AND key = 'digest_sent'
+        "#,
+        user.uid
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO user_metadata (uid, key, value)
+        VALUES ($1, 'digest_sent', now()::text)
+        "#,
+        user.uid
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Background worker, sends every due digest once an hour. Only run it in one process.
+pub async fn run(pool: sqlx::PgPool, mailer: Arc<dyn Mailer>, config: Arc<Config>) {
+    loop {
+        match due_users(&pool).await {
+            Ok(users) => {
+                for user in users {
+                    if let Err(err) = send_digest(&pool, mailer.as_ref(), &config, &user).await {
+                        log::error!("Could not send digest to {} - {:?}", user.uid, err);
+                    }
+                }
+            }
+            Err(err) => log::error!("Could not look up due digests - {:?}", err),
+        }
+        tokio::time::delay_for(CHECK_INTERVAL).await;
+    }
+}
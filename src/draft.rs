@@ -0,0 +1,142 @@
+use crate::Context;
+use chrono::NaiveDateTime;
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLEnum, GraphQLObject, ID};
+
+/// Drafts are only for syncing between devices, anything longer than a post could be is refused
+const MAX_DRAFT_LENGTH: usize = 65535;
+const MAX_DRAFTS_PER_USER: i64 = 50;
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum DraftKind {
+    Post,
+    Comment,
+}
+
+impl DraftKind {
+    fn from_db(value: &str) -> Option<Self> {
+        match value {
+            "post" => Some(DraftKind::Post),
+            "comment" => Some(DraftKind::Comment),
+            _ => None,
+        }
+    }
+
+    fn to_db(self) -> &'static str {
+        match self {
+            DraftKind::Post => "post",
+            DraftKind::Comment => "comment",
+        }
+    }
+}
+
+#[derive(GraphQLObject, Debug)]
+pub struct Draft {
+    pub id: ID,
+    pub kind: DraftKind,
+    /// What the draft is for, the sub name of a post or the id of the post or comment being
+    /// replied to
+    pub target: String,
+    pub content: String,
+    pub updated: NaiveDateTime,
+}
+
+pub async fn get_drafts(context: &Context) -> Result<Vec<Draft>, FieldError> {
+    let uid = context.user.user_id()?;
+    Ok(sqlx::query!(
+        r#"
+        SELECT id, kind, target, content, updated
+        FROM user_draft
+        WHERE uid = $1
+        ORDER BY updated DESC
+        "#,
+        uid
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .filter_map(|row| {
+        Some(Draft {
+            id: ID::new(row.id.to_string()),
+            kind: DraftKind::from_db(&row.kind)?,
+            target: row.target,
+            content: row.content,
+            updated: row.updated,
+        })
+    })
+    .collect())
+}
+
+/// Replaces the user's previous draft for the same kind and target
+pub async fn save_draft(
+    context: &Context,
+    kind: DraftKind,
+    target: String,
+    content: String,
+) -> Result<Draft, FieldError> {
+    let uid = context.user.user_id()?;
+    if content.len() > MAX_DRAFT_LENGTH {
+        return Err(format!("Drafts can be at most {} bytes", MAX_DRAFT_LENGTH).into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO user_draft (uid, kind, target, content)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (uid, kind, target)
+        DO UPDATE SET content = EXCLUDED.content, updated = now()
+        RETURNING id, updated
+        "#,
+        uid,
+        kind.to_db(),
+        target,
+        content
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    let count = sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM user_draft
+        WHERE uid = $1
+        "#,
+        uid
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .cnt;
+    if count > MAX_DRAFTS_PER_USER {
+        return Err(format!("You can keep at most {} drafts", MAX_DRAFTS_PER_USER).into());
+    }
+    tx.commit().await?;
+
+    Ok(Draft {
+        id: ID::new(row.id.to_string()),
+        kind,
+        target,
+        content,
+        updated: row.updated,
+    })
+}
+
+pub async fn delete_draft(context: &Context, id: ID) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let id: i32 = id.parse().map_err(|_| "Invalid draft id")?;
+    Ok(sqlx::query!(
+        r#"
+        DELETE FROM user_draft
+        WHERE id = $1 AND uid = $2
+        RETURNING id
+        "#,
+        id,
+        uid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .is_some())
+}
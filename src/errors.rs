@@ -0,0 +1,453 @@
+use serde_json::json;
+use std::{collections::HashMap, fs, path::Path};
+
+/// Messages in other languages, by language and then by error code
+pub type Translations = HashMap<String, HashMap<String, String>>;
+
+/// Error codes with the English message resolvers fail with. `{}` stands for whatever detail the
+/// message carries, like an id, and is put in the same place in translations. Clients get the
+/// code in the error's extensions so they don't have to go by the message.
+const CATALOG: &[(&str, &str)] = &[
+    ("NOT_AUTHORIZED", "Not Authorized"),
+    ("NOT_A_MOD", "Not a mod of {}"),
+    ("INVALID_TOKEN", "Invalid or expired token"),
+    ("INCORRECT_PASSWORD", "Incorrect password"),
+    (
+        "PASSWORD_EXTERNAL",
+        "Password is managed by the identity provider",
+    ),
+    ("TOTP_REQUIRED", "TOTP code required"),
+    ("TOTP_INVALID", "Invalid TOTP code"),
+    ("TOTP_ALREADY_ENABLED", "TOTP is already enabled"),
+    ("TOTP_NOT_ENABLED", "TOTP is not enabled"),
+    ("TOTP_NO_ENROLLMENT", "No pending TOTP enrollment"),
+    ("NO_EMAIL", "No email address set"),
+    ("POST_NOT_FOUND", "Post not found {}"),
+    ("COMMENT_NOT_FOUND", "Comment not found {}"),
+    ("USER_NOT_FOUND", "Could not find user {}"),
+    ("NOT_FOUND", "Could not find {}"),
+    ("EXPORT_NOT_FOUND", "Export not found {}"),
+    ("WORD_FILTER_NOT_FOUND", "Word filter not found {}"),
+    ("INVALID_CURSOR", "Invalid cursor {}"),
+    ("INVALID_SUB_NAME", "Invalid sub name {}"),
+    ("NAME_TAKEN", "{} is already taken"),
+    ("CONFLICT", "Edited somewhere else in the meantime"),
+    ("NOT_AUTHOR_POST", "Only the author can edit a post"),
+    ("NOT_AUTHOR_COMMENT", "Only the author can edit a comment"),
+    (
+        "NOT_AUTHOR_ATTACHMENT",
+        "Only the author can add attachments",
+    ),
+    ("POST_DELETED", "Deleted posts can't be edited"),
+    ("COMMENT_DELETED", "Deleted comments can't be edited"),
+    ("SELF_VOTE_POST", "You can't vote on your own post"),
+    ("SELF_VOTE_COMMENT", "You can't vote on your own comment"),
+    ("ALREADY_DELETED", "This is already deleted"),
+    ("TEXT_POSTS_ONLY", "Only text posts can be edited"),
+    ("MUTED", "You are muted in this sub until {}"),
+    ("BANNED", "You are banned from this sub"),
+    ("MEMBERS_ONLY", "Only approved members can post here"),
+    (
+        "ACCOUNT_TOO_NEW",
+        "Your account has to be {} days old to post or comment here",
+    ),
+    (
+        "SCORE_TOO_LOW",
+        "You need a score of {} to post or comment here",
+    ),
+    (
+        "NEGATIVE_ACCOUNT_AGE",
+        "The minimum account age can't be negative",
+    ),
+    (
+        "ACCOUNT_AGE_TOO_HIGH",
+        "The minimum account age can be at most {} days",
+    ),
+    (
+        "MEMBERS_ONLY_JOIN",
+        "Only approved members can join, see requestToJoin",
+    ),
+    ("TITLE_MISSING", "Posts need a title"),
+    ("TITLE_TOO_LONG", "Titles can be at most {} characters"),
+    ("INVALID_LINK", "Links must start with http:// or https://"),
+    ("LINK_FETCH_FAILED", "Could not fetch the link"),
+    ("WORD_FILTERED", "This contains words the sub doesn't allow"),
+    ("TEXT_POSTS_DISABLED", "This sub doesn't accept text posts"),
+    ("LINK_POSTS_DISABLED", "This sub doesn't accept link posts"),
+    (
+        "NO_POST_TYPES",
+        "A sub has to accept at least one post type",
+    ),
+    ("UPLOADS_DISABLED", "Uploads are turned off"),
+    ("UPLOAD_TOO_LARGE", "Uploads can be at most {} bytes"),
+    (
+        "UPLOAD_TYPE",
+        "Only PNG, JPEG, GIF and WebP images can be uploaded",
+    ),
+    (
+        "UPLOAD_QUOTA",
+        "Your upload quota is used up, remove some attachments first",
+    ),
+    (
+        "COMMENT_IMAGES_DISABLED",
+        "This sub doesn't allow images in comments",
+    ),
+    ("REACTIONS_DISABLED", "This sub doesn't allow reactions"),
+    ("UNKNOWN_EMOJI", "Unknown emoji {}"),
+    ("DRAFT_LIMIT", "You can keep at most {} drafts"),
+    ("DRAFT_TOO_LARGE", "Drafts can be at most {} bytes"),
+    ("ALREADY_REQUESTED", "You already asked to join"),
+    ("NOT_RESTRICTED", "Anyone can join this sub"),
+    ("SELF_BAN", "You can't ban yourself"),
+    ("SELF_MUTE", "You can't mute yourself"),
+    ("SELF_WARN", "You can't warn yourself"),
+    ("REPORT_REASON_MISSING", "Reports need a reason"),
+    (
+        "REPORT_REASON_TOO_LONG",
+        "Reasons can be at most {} characters",
+    ),
+    ("REPORT_DELETED", "Deleted content can't be reported"),
+    ("ALREADY_REPORTED", "You already reported this"),
+    ("TOO_MANY", "At most {} at once"),
+    (
+        "ATTACHMENTS_TEXT_ONLY",
+        "Only text posts can have attachments",
+    ),
+    (
+        "ATTACHMENTS_UNAVAILABLE",
+        "Some of the attachments aren't yours or are already used",
+    ),
+    (
+        "POST_ATTACHMENT_LIMIT",
+        "Posts can have at most {} attachments",
+    ),
+    (
+        "COMMENT_DELETED_ATTACHMENTS",
+        "Deleted comments can't have attachments",
+    ),
+    (
+        "COMMENT_IMAGES_ONLY",
+        "Only images can be added to comments",
+    ),
+    (
+        "COMMENT_IMAGE_TOO_LARGE",
+        "Images on comments can be at most {} bytes",
+    ),
+    (
+        "COMMENT_ATTACHMENT_LIMIT",
+        "Comments can have at most {} attachments",
+    ),
+    ("NO_BOT_REQUEST", "{} hasn't asked to be a bot"),
+    ("NO_JOIN_REQUEST", "{} hasn't asked to join"),
+    ("UNKNOWN_CHANGE_TYPE", "Unknown change type {}"),
+    ("UNKNOWN_EXPORT", "Unknown export {}"),
+    (
+        "INVALID_REPLY_DEPTH",
+        "The reply depth limit must be positive",
+    ),
+    ("INVALID_YEAR", "Invalid year {}"),
+    ("INVALID_DRAFT_ID", "Invalid draft id"),
+    ("INVALID_POST_ID", "Invalid post id {}"),
+    ("INVALID_ACCOUNT_AGE", "Invalid minimum account age {}"),
+    ("INVALID_DEVICE_TOKEN", "Invalid device token"),
+    (
+        "INVALID_PUSH_ENDPOINT",
+        "Push endpoints have to be https urls",
+    ),
+    ("INVALID_EMOJI_NAME", "Invalid emoji name {}"),
+    ("INVALID_EMOJI_URL", "Emoji images need an https:// URL"),
+    (
+        "INVALID_THRESHOLD",
+        "Similarity threshold must be between 0 and 1",
+    ),
+    ("INVALID_TIME", "{} must be a time like 2020-01-31T12:00:00"),
+    ("INVALID_PATTERN", "Invalid pattern: {}"),
+    (
+        "LINK_STATUS_LINK_ONLY",
+        "Only link posts have a link status",
+    ),
+    (
+        "LINK_METADATA_LINK_ONLY",
+        "Only link posts have link metadata",
+    ),
+    ("NOTHING_TO_ACT_ON", "Nothing to act on"),
+    ("POSTS_NOT_FOUND", "Some of the posts don't exist"),
+    ("MUTE_DURATION", "Mutes last 1 to {} hours"),
+    ("EMOJI_LIMIT", "Subs can have at most {} custom emoji"),
+    ("REACTION_DELETED", "Deleted comments can't get reactions"),
+    ("REACTION_LIMIT", "At most {} reactions per comment"),
+    (
+        "NEGATIVE_VOTE_DAYS",
+        "new_account_vote_days can't be negative",
+    ),
+    (
+        "VOTE_DAYS_TOO_HIGH",
+        "new_account_vote_days can be at most {}",
+    ),
+    (
+        "INVALID_VOTE_WEIGHT",
+        "new_account_vote_weight must be between 0 and 1",
+    ),
+    ("NOT_POSITIVE", "{} must be positive"),
+    ("NOT_OWNER", "Only the owner can see who is subscribed"),
+    ("THREAD_LIMIT", "You can follow at most {} threads"),
+    (
+        "PASSWORD_TOO_SHORT",
+        "Password must be at least {} characters",
+    ),
+    ("VOTE_DELETED_POST", "Deleted posts can't be voted on"),
+    ("VOTE_DELETED_COMMENT", "Deleted comments can't be voted on"),
+    (
+        "WARNING_REASON_LENGTH",
+        "The reason has to be 1 to {} bytes",
+    ),
+    (
+        "FILTER_PATTERN_LENGTH",
+        "Filters need a pattern of at most {} characters",
+    ),
+    ("FILTER_LIMIT", "Subs can have at most {} word filters"),
+];
+
+/// Reads `<language>.json` files from `dir`, each an object of error code to message
+pub fn load_translations(dir: &str) -> Result<Translations, String> {
+    let mut translations = Translations::new();
+    let entries = fs::read_dir(dir).map_err(|err| format!("{}: {}", dir, err))?;
+    for entry in entries {
+        let path = entry.map_err(|err| format!("{}: {}", dir, err))?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let language = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(stem) => stem.to_ascii_lowercase(),
+            None => continue,
+        };
+        translations.insert(language, read_messages(&path)?);
+    }
+    Ok(translations)
+}
+
+fn read_messages(path: &Path) -> Result<HashMap<String, String>, String> {
+    let messages: HashMap<String, String> = fs::read(path)
+        .map_err(|err| err.to_string())
+        .and_then(|data| serde_json::from_slice(&data).map_err(|err| err.to_string()))
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    if let Some(code) = messages
+        .keys()
+        .find(|code| !CATALOG.iter().any(|(known, _)| known == code))
+    {
+        return Err(format!("{}: unknown error code {}", path.display(), code));
+    }
+    Ok(messages)
+}
+
+/// Primary language tags from an Accept-Language header, most preferred first
+pub fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut languages: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';');
+            let tag = params.next()?.trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            let language = tag.split('-').next()?.to_ascii_lowercase();
+            if language.is_empty() || language == "*" || quality <= 0.0 {
+                None
+            } else {
+                Some((language, quality))
+            }
+        })
+        .collect();
+    // Stable, so equal qualities keep the client's order
+    languages.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut seen = vec![];
+    for (language, _) in languages {
+        if !seen.contains(&language) {
+            seen.push(language);
+        }
+    }
+    seen
+}
+
+/// The first of the viewer's languages there are messages for. English is what the messages are
+/// written in, so preferring it over the rest means no translation.
+pub fn pick_language<'a>(translations: &'a Translations, languages: &[String]) -> Option<&'a str> {
+    languages
+        .iter()
+        .take_while(|language| language.as_str() != "en")
+        .find_map(|language| translations.get_key_value(language.as_str()))
+        .map(|(language, _)| language.as_str())
+}
+
+/// Catalog entry for an English message, with the detail that filled in its `{}`
+fn find(message: &str) -> Option<(&'static str, &'static str, &str)> {
+    CATALOG.iter().find_map(|&(code, template)| {
+        let (prefix, suffix) = match template.find("{}") {
+            Some(at) => (&template[..at], &template[at + 2..]),
+            None if template == message => return Some((code, template, "")),
+            None => return None,
+        };
+        if message.len() >= prefix.len() + suffix.len()
+            && message.starts_with(prefix)
+            && message.ends_with(suffix)
+        {
+            Some((
+                code,
+                template,
+                &message[prefix.len()..message.len() - suffix.len()],
+            ))
+        } else {
+            None
+        }
+    })
+}
+
+/// Gives every error from the catalog its code, and its message in `language` where there's a
+/// translation. Codes resolvers set themselves are left alone.
+pub fn localize(
+    response: &mut serde_json::Value,
+    translations: &Translations,
+    language: Option<&str>,
+) {
+    let messages = language.and_then(|language| translations.get(language));
+    let results: Vec<&mut serde_json::Value> = match response {
+        serde_json::Value::Array(results) => results.iter_mut().collect(),
+        result => vec![result],
+    };
+    for result in results {
+        let errors = match result.get_mut("errors").and_then(|e| e.as_array_mut()) {
+            Some(errors) => errors,
+            None => continue,
+        };
+        for error in errors {
+            let error = match error {
+                serde_json::Value::Object(error) => error,
+                _ => continue,
+            };
+            let message = match error.get("message").and_then(|m| m.as_str()) {
+                Some(message) => message.to_string(),
+                None => continue,
+            };
+            let (code, template, detail) = match find(&message) {
+                Some(found) => found,
+                None => continue,
+            };
+
+            if let Some(translated) = messages.and_then(|messages| messages.get(code)) {
+                let translated = if template.contains("{}") {
+                    translated.replace("{}", detail)
+                } else {
+                    translated.clone()
+                };
+                error.insert("message".into(), json!(translated));
+            }
+            let extensions = error.entry("extensions").or_insert_with(|| json!({}));
+            if let serde_json::Value::Object(extensions) = extensions {
+                extensions.entry("code").or_insert_with(|| json!(code));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_language_goes_by_quality() {
+        assert_eq!(
+            parse_accept_language("en;q=0.5, de-AT, fr;q=0.8, de;q=0.9, *;q=0.1"),
+            vec!["de", "fr", "en"]
+        );
+        assert!(parse_accept_language("").is_empty());
+    }
+
+    #[test]
+    fn details_are_carried_into_translations() {
+        let mut translations = Translations::new();
+        let mut de = HashMap::new();
+        de.insert(
+            "POST_NOT_FOUND".to_string(),
+            "Beitrag nicht gefunden {}".to_string(),
+        );
+        translations.insert("de".to_string(), de);
+
+        let mut response = json!({
+            "data": null,
+            "errors": [
+                { "message": "Post not found abc" },
+                { "message": "Not Authorized" },
+                { "message": "Something else entirely" },
+            ],
+        });
+        localize(&mut response, &translations, Some("de"));
+
+        assert_eq!(
+            response["errors"][0]["message"],
+            "Beitrag nicht gefunden abc"
+        );
+        assert_eq!(
+            response["errors"][0]["extensions"]["code"],
+            "POST_NOT_FOUND"
+        );
+        assert_eq!(response["errors"][1]["message"], "Not Authorized");
+        assert_eq!(
+            response["errors"][1]["extensions"]["code"],
+            "NOT_AUTHORIZED"
+        );
+        assert!(response["errors"][2].get("extensions").is_none());
+    }
+
+    /// Messages about broken data or configuration, nothing a client could act on
+    const UNCATALOGUED: &[&str] = &[
+        "{}",
+        "{}: {}",
+        "{}: unknown error code {}",
+        "unknown role {}, use {}",
+        "No Parent",
+        "Comment not related to post?",
+        "Post not in a sub?",
+        "Post has no author",
+        "Corrupt TOTP secret",
+        "Unknown change {}",
+    ];
+
+    /// Every message a resolver fails with, so each keeps its code when it's reworded
+    #[test]
+    fn error_messages_are_catalogued() {
+        let error = regex::Regex::new(
+            r#"(?:Err\(|ok_or\(|ok_or_else\(\|\| |FieldError::new\(|map_err\(\|[^|]*\|\s*)\s*(?:format!\(\s*)?"((?:[^"\\]|\\.)*)""#,
+        )
+        .unwrap();
+        let placeholder = regex::Regex::new(r"\{[^{}]*\}").unwrap();
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        for entry in fs::read_dir(src).unwrap() {
+            let path = entry.unwrap().path();
+            let code = fs::read_to_string(&path).unwrap();
+            for literal in error.captures_iter(&code) {
+                let message = placeholder.replace_all(&literal[1], "{}");
+                if UNCATALOGUED.contains(&message.as_ref()) {
+                    continue;
+                }
+                assert_eq!(
+                    find(&message).map(|(_, template, _)| template),
+                    Some(message.as_ref()),
+                    "{} fails with {:?}",
+                    path.display(),
+                    message
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn english_first_means_no_translation() {
+        let mut translations = Translations::new();
+        translations.insert("de".to_string(), HashMap::new());
+        let languages = vec!["en".to_string(), "de".to_string()];
+        assert_eq!(pick_language(&translations, &languages), None);
+        assert_eq!(pick_language(&translations, &languages[1..]), Some("de"));
+    }
+}
@@ -0,0 +1,185 @@
+use crate::config::Config;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::broadcast;
+
+/// NOTIFY channel shared by every server process
+const CHANNEL: &str = "throatql_events";
+/// Consumers that fall further behind than this miss events, see broadcast::RecvError::Lagged
+const CAPACITY: usize = 1024;
+
+/// Something that happened which other parts of the system may want to react to. Mutations
+/// publish these after committing, consumers subscribe instead of being called directly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    PostEdited {
+        pid: i32,
+    },
+    CommentEdited {
+        cid: String,
+    },
+    SubRenamed {
+        sid: String,
+        name: String,
+    },
+    AccountDeleted {
+        uid: String,
+    },
+    /// Submitted through Throat, see migrations/0012_post_created.sql
+    PostCreated {
+        pid: i32,
+        sid: String,
+    },
+    /// Someone other than `uid` answered their post or comment
+    CommentReplied {
+        cid: String,
+        uid: String,
+    },
+    /// A mod gave `uid` warning `id` in sub `sid`
+    UserWarned {
+        id: i32,
+        uid: String,
+        sid: String,
+    },
+    /// `uid` asked to join restricted sub `sid`
+    JoinRequested {
+        uid: String,
+        sid: String,
+    },
+    /// The mods of `sid` let `uid` in, or didn't
+    MembershipDecided {
+        uid: String,
+        sid: String,
+        approved: bool,
+    },
+    /// New comment in post `pid`, which `uid` follows, see migrations/0026_post_subscription.sql
+    ThreadCommented {
+        cid: String,
+        pid: i32,
+        uid: String,
+    },
+    /// A private message arrived for `uid`
+    MessageReceived {
+        mid: i32,
+        uid: String,
+    },
+}
+
+#[async_trait]
+pub trait EventBus: Send + Sync {
+    async fn publish(&self, event: Event) -> anyhow::Result<()>;
+
+    /// Every event published after this call, from any process sharing the bus
+    fn subscribe(&self) -> broadcast::Receiver<Event>;
+}
+
+/// LISTEN/NOTIFY when PG_EVENTS is set, in process otherwise
+pub fn from_config(config: &Config, pool: &sqlx::PgPool) -> Arc<dyn EventBus> {
+    if config.pg_events {
+        Arc::new(PgBus::new(pool.clone()))
+    } else {
+        Arc::new(LocalBus::new())
+    }
+}
+
+/// Only reaches consumers in this process, for single instance deployments and tests
+pub struct LocalBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl LocalBus {
+    pub fn new() -> Self {
+        LocalBus {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl Default for LocalBus {
+    fn default() -> Self {
+        LocalBus::new()
+    }
+}
+
+#[async_trait]
+impl EventBus for LocalBus {
+    async fn publish(&self, event: Event) -> anyhow::Result<()> {
+        // Nobody listening isn't an error
+        let _ = self.sender.send(event);
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+/// Goes through Postgres LISTEN/NOTIFY so every server process sees every event
+pub struct PgBus {
+    pool: sqlx::PgPool,
+    sender: broadcast::Sender<Event>,
+}
+
+impl PgBus {
+    /// Starts listening in the background, reconnecting whenever the connection drops
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        let sender = broadcast::channel(CAPACITY).0;
+        tokio::spawn(listen(pool.clone(), sender.clone()));
+        PgBus { pool, sender }
+    }
+}
+
+async fn listen(pool: sqlx::PgPool, sender: broadcast::Sender<Event>) {
+    loop {
+        let mut listener = match sqlx::postgres::PgListener::connect_with(&pool).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                log::error!("Could not connect the event listener - {}", err);
+                tokio::time::delay_for(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+        if let Err(err) = listener.listen(CHANNEL).await {
+            log::error!("Could not LISTEN for events - {}", err);
+            tokio::time::delay_for(Duration::from_secs(5)).await;
+            continue;
+        }
+
+        loop {
+            match listener.recv().await {
+                Ok(notification) => match serde_json::from_str(notification.payload()) {
+                    Ok(event) => {
+                        let _ = sender.send(event);
+                    }
+                    Err(err) => log::warn!(
+                        "Ignoring unknown event {} - {}",
+                        notification.payload(),
+                        err
+                    ),
+                },
+                Err(err) => {
+                    log::error!("Event listener lost its connection - {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EventBus for PgBus {
+    async fn publish(&self, event: Event) -> anyhow::Result<()> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CHANNEL)
+            .bind(serde_json::to_string(&event)?)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,541 @@
+use crate::{config::Config, ndjson, sub::Sub, Context};
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures_util::{
+    stream::{BoxStream, StreamExt},
+    TryStreamExt,
+};
+use hmac::{Hmac, Mac, NewMac};
+use juniper::{graphql_object, FieldError, GraphQLEnum, ID};
+use serde_json::json;
+use sha2::Sha256;
+use std::sync::Arc;
+
+/// Download links, and the exports behind them, stop working after this long
+const EXPORT_LIFETIME_HOURS: i64 = 24;
+/// Most rows in one export, streamed ones have no limit
+const MAX_ROWS: i64 = 100_000;
+
+type Rows<'a> = BoxStream<'a, Result<Vec<String>, sqlx::Error>>;
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum ExportKind {
+    Posts,
+    Modlog,
+    Bans,
+    /// Only for the sub's owner
+    Subscribers,
+}
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum ExportStatus {
+    Pending,
+    Ready,
+    Failed,
+}
+
+impl ExportKind {
+    /// The names to_db gives, like "modlog"
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "posts" => Some(ExportKind::Posts),
+            "modlog" => Some(ExportKind::Modlog),
+            "bans" => Some(ExportKind::Bans),
+            "subscribers" => Some(ExportKind::Subscribers),
+            _ => None,
+        }
+    }
+
+    fn from_db(value: &str) -> Self {
+        ExportKind::parse(value).unwrap_or(ExportKind::Posts)
+    }
+
+    fn to_db(self) -> &'static str {
+        match self {
+            ExportKind::Posts => "posts",
+            ExportKind::Modlog => "modlog",
+            ExportKind::Bans => "bans",
+            ExportKind::Subscribers => "subscribers",
+        }
+    }
+}
+
+impl ExportFormat {
+    fn from_db(value: &str) -> Self {
+        match value {
+            "json" => ExportFormat::Json,
+            _ => ExportFormat::Csv,
+        }
+    }
+
+    fn to_db(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+pub struct SubExport {
+    id: String,
+    kind: ExportKind,
+    format: ExportFormat,
+    created: NaiveDateTime,
+    ready: bool,
+    error: Option<String>,
+}
+
+#[graphql_object(context = Context)]
+impl SubExport {
+    fn id(&self) -> ID {
+        self.id.clone().into()
+    }
+
+    fn kind(&self) -> ExportKind {
+        self.kind
+    }
+
+    fn format(&self) -> ExportFormat {
+        self.format
+    }
+
+    fn created(&self) -> &NaiveDateTime {
+        &self.created
+    }
+
+    fn status(&self) -> ExportStatus {
+        match (self.ready, &self.error) {
+            (_, Some(_)) => ExportStatus::Failed,
+            (true, None) => ExportStatus::Ready,
+            (false, None) => ExportStatus::Pending,
+        }
+    }
+
+    fn error(&self) -> &Option<String> {
+        &self.error
+    }
+
+    /// Signed link, only set once the export is ready. Anyone holding it can download the file
+    /// until it expires.
+    fn download_url(&self, context: &Context) -> Option<String> {
+        if !self.ready || self.error.is_some() {
+            return None;
+        }
+        let expires = (self.created + Duration::hours(EXPORT_LIFETIME_HOURS)).timestamp();
+        Some(format!(
+            "{}/exports/{}?expires={}&signature={}",
+            context.config.site_url,
+            self.id,
+            expires,
+            sign(&context.config, &self.id, expires)
+        ))
+    }
+}
+
+fn sign(config: &Config, id: &str, expires: i64) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(&config.signing_key).expect("any key length works");
+    mac.update(format!("{}:{}", id, expires).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn verify(config: &Config, id: &str, expires: i64, signature: &str) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let mut mac = Hmac::<Sha256>::new_varkey(&config.signing_key).expect("any key length works");
+    mac.update(format!("{}:{}", id, expires).as_bytes());
+    mac.verify(&signature).is_ok()
+}
+
+/// Only the mod who asked for an export (or an admin) can look it up
+pub async fn get_export(context: &Context, id: ID) -> Result<SubExport, FieldError> {
+    let uid = context.user.user_id()?;
+    let row = sqlx::query!(
+        r#"
+        SELECT id, uid, kind, format, created, body IS NOT NULL as "ready!", error
+        FROM sub_export
+        WHERE id = $1
+        "#,
+        id.as_str()
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .filter(|row| row.uid == uid || context.user.is_admin())
+    .ok_or_else(|| format!("Export not found {}", *id))?;
+
+    Ok(SubExport {
+        id: row.id,
+        kind: ExportKind::from_db(&row.kind),
+        format: ExportFormat::from_db(&row.format),
+        created: row.created,
+        ready: row.ready,
+        error: row.error,
+    })
+}
+
+/// The sub, if the viewer may export `kind` from it
+async fn exportable_sub(
+    context: &Context,
+    sub: String,
+    kind: ExportKind,
+) -> Result<Sub, FieldError> {
+    let sub = context
+        .sub_loader
+        .load(sub.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    let allowed = match kind {
+        ExportKind::Subscribers => context.user.is_owner(&sub.sid),
+        _ => context.user.is_mod(&sub.sid),
+    };
+    if allowed {
+        Ok(sub)
+    } else {
+        Err("Not Authorized".into())
+    }
+}
+
+/// Everything when no range is given
+fn time_range(
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+) -> (NaiveDateTime, NaiveDateTime) {
+    (
+        from.unwrap_or_else(|| chrono::NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0)),
+        to.unwrap_or_else(|| Utc::now().naive_utc()),
+    )
+}
+
+/// Starts building the export in the background, poll getSubExport for the download link
+pub async fn export_sub(
+    context: &Context,
+    sub: String,
+    kind: ExportKind,
+    format: ExportFormat,
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+) -> Result<SubExport, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub = exportable_sub(context, sub, kind).await?;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let created = sqlx::query!(
+        r#"
+        INSERT INTO sub_export (id, sid, uid, kind, format)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING created
+        "#,
+        id,
+        sub.sid,
+        uid,
+        kind.to_db(),
+        format.to_db()
+    )
+    .fetch_one(&context.pool)
+    .await?
+    .created;
+
+    tokio::spawn(build(
+        context.pool.clone(),
+        id.clone(),
+        sub.sid,
+        kind,
+        format,
+        from,
+        to,
+    ));
+
+    Ok(SubExport {
+        id,
+        kind,
+        format,
+        created,
+        ready: false,
+        error: None,
+    })
+}
+
+/// Header and rows of the export as they come from Postgres, everything as text. `limit` of
+/// None means every row.
+fn row_stream<'a>(
+    pool: &'a sqlx::PgPool,
+    sid: &'a str,
+    kind: ExportKind,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    limit: Option<i64>,
+) -> (Vec<&'static str>, Rows<'a>) {
+    let text = |value: Option<NaiveDateTime>| value.map(|v| v.to_string()).unwrap_or_default();
+    match kind {
+        ExportKind::Posts => (
+            vec!["pid", "uid", "title", "link", "posted", "deleted"],
+            sqlx::query!(
+                r#"
+                SELECT pid, uid, title, link, posted, deleted
+                FROM sub_post
+                WHERE sid = $1 AND posted BETWEEN $2 AND $3
+                ORDER BY posted
+                LIMIT $4
+                "#,
+                sid,
+                from,
+                to,
+                limit
+            )
+            .fetch(pool)
+            .map(move |row| {
+                row.map(|row| {
+                    vec![
+                        row.pid.to_string(),
+                        row.uid.unwrap_or_default(),
+                        row.title.unwrap_or_default(),
+                        row.link.unwrap_or_default(),
+                        text(row.posted),
+                        row.deleted.unwrap_or(0).to_string(),
+                    ]
+                })
+            })
+            .boxed(),
+        ),
+        ExportKind::Modlog => (
+            vec!["time", "uid", "action", "targets", "reason"],
+            sqlx::query!(
+                r#"
+                SELECT time, uid, action, targets, reason
+                FROM mod_log
+                WHERE sid = $1 AND time BETWEEN $2 AND $3
+                ORDER BY time
+                LIMIT $4
+                "#,
+                sid,
+                from,
+                to,
+                limit
+            )
+            .fetch(pool)
+            .map(|row| {
+                row.map(|row| {
+                    vec![
+                        row.time.to_string(),
+                        row.uid,
+                        row.action,
+                        row.targets.join(" "),
+                        row.reason.unwrap_or_default(),
+                    ]
+                })
+            })
+            .boxed(),
+        ),
+        ExportKind::Bans => (
+            vec!["uid", "created", "expires", "effective", "reason"],
+            sqlx::query!(
+                r#"
+                SELECT uid, created, expires, effective, reason
+                FROM sub_ban
+                WHERE sid = $1 AND created BETWEEN $2 AND $3
+                ORDER BY created
+                LIMIT $4
+                "#,
+                sid,
+                from,
+                to,
+                limit
+            )
+            .fetch(pool)
+            .map(move |row| {
+                row.map(|row| {
+                    vec![
+                        row.uid,
+                        text(row.created),
+                        text(row.expires),
+                        row.effective.unwrap_or(false).to_string(),
+                        row.reason.unwrap_or_default(),
+                    ]
+                })
+            })
+            .boxed(),
+        ),
+        // Everyone subscribed now, whenever they joined
+        ExportKind::Subscribers => (
+            vec!["name", "subscribed"],
+            sqlx::query!(
+                r#"
+                SELECT u.name, s.time
+                FROM sub_subscriber s
+                JOIN public.user u ON u.uid = s.uid
+                WHERE s.sid = $1 AND s.status = 1
+                ORDER BY s.time NULLS FIRST
+                LIMIT $2
+                "#,
+                sid,
+                limit
+            )
+            .fetch(pool)
+            .map(move |row| row.map(|row| vec![row.name.unwrap_or_default(), text(row.time)]))
+            .boxed(),
+        ),
+    }
+}
+
+/// Header and rows of the export, at most MAX_ROWS
+async fn rows(
+    pool: &sqlx::PgPool,
+    sid: &str,
+    kind: ExportKind,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> Result<(Vec<&'static str>, Vec<Vec<String>>), sqlx::Error> {
+    let (header, rows) = row_stream(pool, sid, kind, from, to, Some(MAX_ROWS));
+    Ok((header, rows.try_collect().await?))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// A row as an object keyed by the header
+fn row_object(header: &[&str], row: Vec<String>) -> serde_json::Value {
+    serde_json::Value::Object(
+        header
+            .iter()
+            .map(|name| name.to_string())
+            .zip(row.into_iter().map(serde_json::Value::String))
+            .collect(),
+    )
+}
+
+fn render(format: ExportFormat, header: &[&str], rows: Vec<Vec<String>>) -> String {
+    match format {
+        ExportFormat::Csv => std::iter::once(header.join(","))
+            .chain(rows.iter().map(|row| {
+                row.iter()
+                    .map(|value| csv_field(value))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            }))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        ExportFormat::Json => json!(rows
+            .into_iter()
+            .map(|row| row_object(header, row))
+            .collect::<Vec<_>>())
+        .to_string(),
+    }
+}
+
+async fn build(
+    pool: sqlx::PgPool,
+    id: String,
+    sid: String,
+    kind: ExportKind,
+    format: ExportFormat,
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+) {
+    let (from, to) = time_range(from, to);
+    let (body, error) = match rows(&pool, &sid, kind, from, to).await {
+        Ok((header, rows)) => (Some(render(format, &header, rows)), None),
+        Err(err) => {
+            log::error!("Export {} failed - {}", id, err);
+            (None, Some("The export could not be built".to_string()))
+        }
+    };
+
+    if let Err(err) = sqlx::query!(
+        r#"
+        UPDATE sub_export
+        SET body = $2, error = $3
+        WHERE id = $1
+        "#,
+        id,
+        body,
+        error
+    )
+    .execute(&pool)
+    .await
+    {
+        log::error!("Could not store export {} - {}", id, err);
+    }
+}
+
+/// The export as NDJSON, one object per row, sent while Postgres produces the rows instead of
+/// being built first. Has no row limit, for exports too large to keep around in sub_export.
+pub async fn stream_sub(
+    context: &Context,
+    sub: String,
+    kind: ExportKind,
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+) -> Result<ndjson::Lines, FieldError> {
+    let sub = exportable_sub(context, sub, kind).await?;
+    let pool = context.pool.clone();
+    let (from, to) = time_range(from, to);
+
+    Ok(ndjson::spawn(move |mut lines| async move {
+        let (header, mut rows) = row_stream(&pool, &sub.sid, kind, from, to, None);
+        while let Some(row) = rows.next().await {
+            let row = match row {
+                Ok(row) => row,
+                Err(err) => {
+                    log::error!("Streaming export of {} failed - {}", sub.sid, err);
+                    lines.fail("The export could not be finished").await;
+                    return;
+                }
+            };
+            if !lines.send(&row_object(&header, row)).await {
+                return;
+            }
+        }
+    }))
+}
+
+/// Body and content type of an export, for the signed download route. Expired exports are
+/// deleted on the way.
+pub async fn download(
+    pool: &sqlx::PgPool,
+    config: &Arc<Config>,
+    id: &str,
+    expires: i64,
+    signature: &str,
+) -> Result<Option<(String, &'static str)>, sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM sub_export
+        WHERE created < now() - $1 * interval '1 hour'
+        "#,
+        EXPORT_LIFETIME_HOURS as f64
+    )
+    .execute(pool)
+    .await?;
+
+    if expires < Utc::now().timestamp() || !verify(config, id, expires, signature) {
+        return Ok(None);
+    }
+    Ok(sqlx::query!(
+        r#"
+        SELECT body as "body!", format
+        FROM sub_export
+        WHERE id = $1 AND body IS NOT NULL
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|row| {
+        let content_type = match ExportFormat::from_db(&row.format) {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Json => "application/json",
+        };
+        (row.body, content_type)
+    }))
+}
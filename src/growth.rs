@@ -0,0 +1,112 @@
+use crate::{top::TopRange, Context};
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLEnum, GraphQLObject};
+
+/// Most buckets one history has, longer ranges start later
+const MAX_BUCKETS: i32 = 1000;
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum HistoryInterval {
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl HistoryInterval {
+    /// Field name for date_trunc
+    fn unit(self) -> &'static str {
+        match self {
+            HistoryInterval::Hour => "hour",
+            HistoryInterval::Day => "day",
+            HistoryInterval::Week => "week",
+            HistoryInterval::Month => "month",
+        }
+    }
+
+    /// Longest a bucket can be, months vary
+    fn length(self) -> Duration {
+        match self {
+            HistoryInterval::Hour => Duration::hours(1),
+            HistoryInterval::Day => Duration::days(1),
+            HistoryInterval::Week => Duration::weeks(1),
+            HistoryInterval::Month => Duration::days(31),
+        }
+    }
+}
+
+/// Subscribers at the end of a bucket and how they got there
+#[derive(Debug, Clone, GraphQLObject)]
+pub struct SubscriberCount {
+    /// Start of the bucket, UTC
+    pub bucket: NaiveDateTime,
+    pub subscribers: i32,
+    pub joined: i32,
+    pub left: i32,
+}
+
+/// Subscriber counts over the range, oldest bucket first. Only for the sub's mods.
+pub async fn subscriber_history(
+    context: &Context,
+    sid: &str,
+    creation: NaiveDateTime,
+    range: TopRange,
+    interval: HistoryInterval,
+) -> Result<Vec<SubscriberCount>, FieldError> {
+    if !context.user.is_mod(sid) {
+        return Err(format!("Not a mod of {}", sid).into());
+    }
+    let now = Utc::now().naive_utc();
+    let since = range
+        .since()
+        .map_or(creation, |since| now - since)
+        .max(creation)
+        .max(now - interval.length() * MAX_BUCKETS);
+
+    let history = sqlx::query!(
+        r#"
+        WITH buckets AS (
+            SELECT generate_series(
+                date_trunc($2, $3::timestamp),
+                date_trunc($2, now()::timestamp),
+                ('1 ' || $2)::interval
+            ) AS bucket
+        ), changes AS (
+            SELECT date_trunc($2, time) AS bucket,
+                count(*) FILTER (WHERE change > 0) AS joined,
+                count(*) FILTER (WHERE change < 0) AS unsubscribed,
+                sum(change) AS net
+            FROM sub_subscriber_event
+            WHERE sid = $1
+            GROUP BY 1
+        )
+        SELECT b.bucket as "bucket!",
+            coalesce(c.joined, 0) as "joined!",
+            coalesce(c.unsubscribed, 0) as "left!",
+            (SELECT coalesce(sum(net), 0) FROM changes WHERE changes.bucket <= b.bucket)::bigint
+                as "subscribers!"
+        FROM buckets b
+        LEFT JOIN changes c USING (bucket)
+        ORDER BY b.bucket
+        "#,
+        sid,
+        interval.unit(),
+        since
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| SubscriberCount {
+        bucket: row.bucket,
+        subscribers: row.subscribers as i32,
+        joined: row.joined as i32,
+        left: row.left as i32,
+    })
+    .collect();
+
+    Ok(history)
+}
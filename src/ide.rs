@@ -0,0 +1,100 @@
+use crate::config::{Config, GraphqlIde};
+use warp::{filters::BoxedFilter, path::FullPath, Filter, Reply};
+
+/// Keeps header values from closing the script tag they're embedded in
+fn script_json(value: &serde_json::Value) -> String {
+    value.to_string().replace("</", "<\\/")
+}
+
+fn playground_page(endpoint: &str, headers: &serde_json::Value) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8" />
+  <title>GraphQL Playground</title>
+  <link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/graphql-playground-react/build/static/css/index.css" />
+  <script src="https://cdn.jsdelivr.net/npm/graphql-playground-react/build/static/js/middleware.js"></script>
+</head>
+<body>
+  <div id="root"></div>
+  <script>
+    window.addEventListener('load', function () {{
+      GraphQLPlayground.init(document.getElementById('root'), {{
+        endpoint: {endpoint},
+        tabs: [{{ endpoint: {endpoint}, headers: {headers} }}]
+      }});
+    }});
+  </script>
+</body>
+</html>"#,
+        endpoint = script_json(&endpoint.into()),
+        headers = script_json(headers),
+    )
+}
+
+fn sandbox_page(endpoint: &str, headers: &serde_json::Value) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8" />
+  <title>Apollo Sandbox</title>
+</head>
+<body style="margin: 0">
+  <div id="sandbox" style="height: 100vh"></div>
+  <script src="https://embeddable-sandbox.cdn.apollographql.com/_latest/embeddable-sandbox.umd.production.min.js"></script>
+  <script>
+    new window.EmbeddedSandbox({{
+      target: '#sandbox',
+      initialEndpoint: window.location.origin + {endpoint},
+      initialState: {{ sharedHeaders: {headers} }}
+    }});
+  </script>
+</body>
+</html>"#,
+        endpoint = script_json(&endpoint.into()),
+        headers = script_json(headers),
+    )
+}
+
+/// The IDE picked with GRAPHQL_IDE at GRAPHQL_IDE_PATH, rejects everything when turned off
+pub fn routes(config: &Config, endpoint: &'static str) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let path = config.graphql_ide_path.trim_matches('/').to_string();
+    let at_path = warp::get()
+        .and(warp::path::full())
+        .and_then(move |full: FullPath| {
+            let found = full.as_str().trim_matches('/') == path;
+            async move {
+                if found {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one();
+    let headers = serde_json::Value::Object(config.graphql_ide_headers.clone());
+
+    match config.graphql_ide {
+        GraphqlIde::Graphiql => at_path
+            .and(juniper_warp::graphiql_filter(endpoint, None))
+            .map(|reply| Box::new(reply) as Box<dyn Reply>)
+            .boxed(),
+        GraphqlIde::Playground => {
+            let page = playground_page(endpoint, &headers);
+            at_path
+                .map(move || Box::new(warp::reply::html(page.clone())) as Box<dyn Reply>)
+                .boxed()
+        }
+        GraphqlIde::Sandbox => {
+            let page = sandbox_page(endpoint, &headers);
+            at_path
+                .map(move || Box::new(warp::reply::html(page.clone())) as Box<dyn Reply>)
+                .boxed()
+        }
+        GraphqlIde::Off => warp::any()
+            .and_then(|| async { Err::<Box<dyn Reply>, _>(warp::reject::not_found()) })
+            .boxed(),
+    }
+}
@@ -0,0 +1,56 @@
+use harsh::Harsh;
+use juniper::{FieldError, ID};
+use std::fmt;
+
+/// Turns sequential post ids into opaque strings when POST_ID_SALT is set, so the public ids
+/// don't give away posting volume. Plain numeric ids are always accepted as well.
+#[derive(Clone)]
+pub struct PostIds {
+    harsh: Option<Harsh>,
+}
+
+impl fmt::Debug for PostIds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PostIds")
+            .field("hashed", &self.harsh.is_some())
+            .finish()
+    }
+}
+
+impl PostIds {
+    pub fn new(salt: Option<String>) -> Self {
+        PostIds {
+            harsh: salt.and_then(|salt| {
+                Harsh::builder()
+                    .salt(salt)
+                    .length(8)
+                    .build()
+                    .map_err(|err| log::error!("Invalid post id salt - {:?}", err))
+                    .ok()
+            }),
+        }
+    }
+
+    pub fn encode(&self, pid: i32) -> ID {
+        match self.harsh {
+            Some(ref harsh) => harsh.encode(&[pid as u64]).into(),
+            None => pid.to_string().into(),
+        }
+    }
+
+    pub fn decode(&self, id: &str) -> Result<i32, FieldError> {
+        if let Some(ref harsh) = self.harsh {
+            // Hashes can be all digits too, only trust a decode that maps back to the same string
+            if let Ok(decoded) = harsh.decode(id) {
+                if let [pid] = decoded.as_slice() {
+                    if harsh.encode(&[*pid]) == id && *pid <= i32::MAX as u64 {
+                        return Ok(*pid as i32);
+                    }
+                }
+            }
+        }
+
+        id.parse::<i32>()
+            .map_err(|_| format!("Invalid post id {}", id).into())
+    }
+}
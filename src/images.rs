@@ -0,0 +1,54 @@
+use crate::config::Config;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+/// Largest variant the proxy is asked for, in either direction
+const MAX_DIMENSION: i32 = 2048;
+
+/// An imgproxy compatible resizing proxy, see IMGPROXY_URL
+#[derive(Clone)]
+pub struct ImageProxyConfig {
+    pub url: String,
+    pub key: Vec<u8>,
+    pub salt: Vec<u8>,
+}
+
+// Keeps the signing key out of logs
+impl std::fmt::Debug for ImageProxyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImageProxyConfig")
+            .field("url", &self.url)
+            .finish()
+    }
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Where a stored thumbnail can be fetched from. Zero or missing dimensions keep the original
+/// size along that axis. Without a proxy the original is returned whatever size was asked for.
+pub fn thumbnail_url(
+    config: &Config,
+    thumbnail: &str,
+    width: Option<i32>,
+    height: Option<i32>,
+) -> String {
+    let source = format!("{}/{}", config.thumbnail_url, thumbnail);
+    let proxy = match config.image_proxy {
+        Some(ref proxy) => proxy,
+        None => return source,
+    };
+
+    let width = width.unwrap_or(0).max(0).min(MAX_DIMENSION);
+    let height = height.unwrap_or(0).max(0).min(MAX_DIMENSION);
+    let path = format!("/rs:fit:{}:{}/{}", width, height, encode(source.as_bytes()));
+
+    // HMAC-SHA256 takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_varkey(&proxy.key).expect("any key length works");
+    mac.update(&proxy.salt);
+    mac.update(path.as_bytes());
+    let signature = encode(&mac.finalize().into_bytes());
+
+    format!("{}/{}{}", proxy.url, signature, path)
+}
@@ -0,0 +1,112 @@
+use futures_util::stream::StreamExt;
+use juniper::FieldError;
+use sqlx::{Postgres, Transaction};
+use std::time::Duration;
+
+/// Posts looked at per round, newest first
+const BATCH_SIZE: i64 = 500;
+/// Pause between rounds once there is nothing left to detect
+const DETECT_INTERVAL: Duration = Duration::from_secs(60);
+/// Only the start of long posts is read, more doesn't change the answer
+const MAX_TEXT_LENGTH: usize = 2000;
+
+/// ISO 639-3 code of the language `text` is written in, or None when it's too short or mixed to
+/// tell
+pub fn detect(text: &str) -> Option<&'static str> {
+    let end = text
+        .char_indices()
+        .nth(MAX_TEXT_LENGTH)
+        .map_or(text.len(), |(i, _)| i);
+    whatlang::detect(&text[..end])
+        .filter(|info| info.is_reliable())
+        .map(|info| info.lang().code())
+}
+
+fn post_text(title: Option<&str>, content: Option<&str>) -> String {
+    format!("{}\n{}", title.unwrap_or(""), content.unwrap_or(""))
+}
+
+/// Detects a new post's language as part of saving it
+pub(crate) async fn record(
+    tx: &mut Transaction<'_, Postgres>,
+    pid: i32,
+    title: &str,
+    content: Option<&str>,
+) -> Result<(), FieldError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO post_language (pid, language)
+        VALUES ($1, $2)
+        ON CONFLICT (pid) DO NOTHING
+        "#,
+        pid,
+        detect(&post_text(Some(title), content))
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+pub async fn post_language(pool: &sqlx::PgPool, pid: i32) -> Result<Option<String>, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT language
+        FROM post_language
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(pool)
+    .await?
+    .and_then(|row| row.language))
+}
+
+/// Returns how many posts were looked at
+async fn detect_batch(pool: &sqlx::PgPool) -> Result<usize, sqlx::Error> {
+    let posts = sqlx::query!(
+        r#"
+        SELECT p.pid, p.title, p.content
+        FROM sub_post p
+        LEFT JOIN post_language l USING (pid)
+        WHERE l.pid IS NULL
+        ORDER BY p.pid DESC
+        LIMIT $1
+        "#,
+        BATCH_SIZE
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    for post in &posts {
+        let text = post_text(post.title.as_deref(), post.content.as_deref());
+        sqlx::query!(
+            r#"
+            INSERT INTO post_language (pid, language)
+            VALUES ($1, $2)
+            ON CONFLICT (pid) DO NOTHING
+            "#,
+            post.pid,
+            detect(&text)
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(posts.len())
+}
+
+/// createPost detects the language right away. Posts made in Throat are picked up here shortly
+/// after, so those have no language until the next run. Existing posts get done the same way,
+/// newest first, on the first runs.
+pub async fn run(pool: sqlx::PgPool) {
+    loop {
+        match detect_batch(&pool).await {
+            Ok(n) if n as i64 == BATCH_SIZE => continue,
+            Ok(_) => {}
+            Err(err) => log::error!("Language detection failed - {}", err),
+        }
+        tokio::time::delay_for(DETECT_INTERVAL).await;
+    }
+}
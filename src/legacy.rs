@@ -0,0 +1,237 @@
+use crate::{
+    post::{DeleteStatus, PostType},
+    user::{Crypto, UserStatus},
+};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::{collections::HashMap, env, fs};
+
+lazy_static! {
+    // config::validate_env makes sure LEGACY_ENUM_FILE parses before the server starts
+    static ref CODES: Codes = env::var("LEGACY_ENUM_FILE")
+        .ok()
+        .and_then(|path| load(&path).ok())
+        .unwrap_or_default();
+}
+
+const POST_TYPES: &[(&str, PostType)] = &[
+    ("text", PostType::Text),
+    ("link", PostType::Link),
+    ("poll", PostType::Poll),
+];
+const DELETE_STATUSES: &[(&str, DeleteStatus)] = &[
+    ("not", DeleteStatus::Not),
+    ("user", DeleteStatus::User),
+    ("mod", DeleteStatus::Mod),
+    ("admin", DeleteStatus::Admin),
+];
+const USER_STATUSES: &[(&str, UserStatus)] = &[
+    ("ok", UserStatus::Ok),
+    ("deleted", UserStatus::Deleted),
+    ("site_ban", UserStatus::SiteBan),
+];
+const CRYPTOS: &[(&str, Crypto)] = &[("bcrypt", Crypto::BCrypt), ("keycloak", Crypto::KeyCloak)];
+
+/// What the integer columns mean, by column. Throat's own codes unless LEGACY_ENUM_FILE adds
+/// or changes some.
+struct Codes {
+    ptype: HashMap<i32, PostType>,
+    post_deleted: HashMap<i32, DeleteStatus>,
+    comment_status: HashMap<i32, DeleteStatus>,
+    user_status: HashMap<i32, UserStatus>,
+    crypto: HashMap<i32, Crypto>,
+}
+
+impl Default for Codes {
+    fn default() -> Self {
+        let deleted: HashMap<_, _> = vec![
+            (0, DeleteStatus::Not),
+            (1, DeleteStatus::User),
+            (2, DeleteStatus::Mod),
+            (3, DeleteStatus::Admin),
+        ]
+        .into_iter()
+        .collect();
+        Codes {
+            ptype: vec![
+                (0, PostType::Text),
+                (1, PostType::Link),
+                (3, PostType::Poll),
+            ]
+            .into_iter()
+            .collect(),
+            post_deleted: deleted.clone(),
+            comment_status: deleted,
+            user_status: vec![
+                (0, UserStatus::Ok),
+                (5, UserStatus::SiteBan),
+                (10, UserStatus::Deleted),
+            ]
+            .into_iter()
+            .collect(),
+            crypto: vec![(1, Crypto::BCrypt), (2, Crypto::KeyCloak)]
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+/// LEGACY_ENUM_FILE, e.g. `{"ptype": {"2": "poll"}, "user_status": {"3": "site_ban"}}`. Codes
+/// are read this way, content written through the API still gets Throat's codes.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+struct LegacyCodes {
+    ptype: HashMap<i32, String>,
+    post_deleted: HashMap<i32, String>,
+    comment_status: HashMap<i32, String>,
+    user_status: HashMap<i32, String>,
+    crypto: HashMap<i32, String>,
+}
+
+fn merge<T: Clone>(
+    codes: &mut HashMap<i32, T>,
+    column: &str,
+    names: &[(&str, T)],
+    legacy: HashMap<i32, String>,
+) -> Result<(), String> {
+    for (code, name) in legacy {
+        let value = names
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| {
+                let known: Vec<_> = names.iter().map(|(known, _)| *known).collect();
+                format!("{} can't be {:?}, use {}", column, name, known.join(", "))
+            })?;
+        codes.insert(code, value);
+    }
+    Ok(())
+}
+
+fn parse(json: &str) -> Result<Codes, String> {
+    let legacy: LegacyCodes = serde_json::from_str(json).map_err(|err| err.to_string())?;
+    let mut codes = Codes::default();
+    merge(&mut codes.ptype, "ptype", POST_TYPES, legacy.ptype)?;
+    merge(
+        &mut codes.post_deleted,
+        "post_deleted",
+        DELETE_STATUSES,
+        legacy.post_deleted,
+    )?;
+    merge(
+        &mut codes.comment_status,
+        "comment_status",
+        DELETE_STATUSES,
+        legacy.comment_status,
+    )?;
+    merge(
+        &mut codes.user_status,
+        "user_status",
+        USER_STATUSES,
+        legacy.user_status,
+    )?;
+    merge(&mut codes.crypto, "crypto", CRYPTOS, legacy.crypto)?;
+    Ok(codes)
+}
+
+fn load(path: &str) -> Result<Codes, String> {
+    let json = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    parse(&json).map_err(|err| format!("{}: {}", path, err))
+}
+
+/// For validate_env
+pub fn check_file(path: &str) -> Result<(), String> {
+    load(path).map(|_| ())
+}
+
+pub fn post_type(code: Option<i32>) -> Option<PostType> {
+    code.and_then(|code| CODES.ptype.get(&code)).copied()
+}
+
+fn codes_of(codes: &Codes, ptype: PostType) -> Vec<i32> {
+    codes_meaning(&codes.ptype, &ptype)
+}
+
+fn codes_meaning<T: PartialEq>(column: &HashMap<i32, T>, meaning: &T) -> Vec<i32> {
+    let mut codes: Vec<i32> = column
+        .iter()
+        .filter(|(_, known)| *known == meaning)
+        .map(|(code, _)| *code)
+        .collect();
+    codes.sort_unstable();
+    codes
+}
+
+/// Every code that reads as `ptype`, for filtering on it in SQL. Sorted, so the same types always
+/// give the same list.
+pub fn post_type_codes(ptype: PostType) -> Vec<i32> {
+    codes_of(&CODES, ptype)
+}
+
+/// A missing status means the post is there
+pub fn post_deleted(code: Option<i32>) -> Option<DeleteStatus> {
+    match code {
+        Some(code) => CODES.post_deleted.get(&code).cloned(),
+        None => Some(DeleteStatus::Not),
+    }
+}
+
+/// Every sub_post.deleted code that reads as `status`, for filtering on it in SQL. Writes use
+/// Throat's own code, DeleteStatus::to_db.
+pub fn post_deleted_codes(status: DeleteStatus) -> Vec<i32> {
+    codes_meaning(&CODES.post_deleted, &status)
+}
+
+/// Same as post_deleted_codes, for sub_post_comment.status
+pub fn comment_status_codes(status: DeleteStatus) -> Vec<i32> {
+    codes_meaning(&CODES.comment_status, &status)
+}
+
+pub fn comment_status(code: Option<i32>) -> Option<DeleteStatus> {
+    match code {
+        Some(code) => CODES.comment_status.get(&code).cloned(),
+        None => Some(DeleteStatus::Not),
+    }
+}
+
+pub fn user_status(code: i32) -> Option<UserStatus> {
+    CODES.user_status.get(&code).copied()
+}
+
+pub fn crypto(code: i32) -> Option<Crypto> {
+    CODES.crypto.get(&code).copied()
+}
+
+/// Every code that means something, for `throatql check`
+pub struct KnownCodes {
+    pub ptype: Vec<i32>,
+    pub post_deleted: Vec<i32>,
+    pub comment_status: Vec<i32>,
+    pub user_status: Vec<i32>,
+    pub crypto: Vec<i32>,
+}
+
+pub fn known_codes() -> KnownCodes {
+    KnownCodes {
+        ptype: CODES.ptype.keys().copied().collect(),
+        post_deleted: CODES.post_deleted.keys().copied().collect(),
+        comment_status: CODES.comment_status.keys().copied().collect(),
+        user_status: CODES.user_status.keys().copied().collect(),
+        crypto: CODES.crypto.keys().copied().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_codes_add_to_throats() {
+        let codes = parse(r#"{"ptype": {"2": "poll"}}"#).unwrap();
+        assert_eq!(codes.ptype.get(&2), Some(&PostType::Poll));
+        assert_eq!(codes.ptype.get(&3), Some(&PostType::Poll));
+        assert_eq!(codes_of(&codes, PostType::Poll), vec![2, 3]);
+        assert!(parse(r#"{"ptype": {"2": "video"}}"#).is_err());
+        assert!(parse(r#"{"post_type": {}}"#).is_err());
+    }
+}
@@ -1,10 +1,55 @@
-use dataloader::cached::Loader;
+use dataloader::{cached::Loader, BatchFn};
+use futures::lock::Mutex;
 use juniper::{graphql_object, FieldError, GraphQLObject, ID};
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, hash::Hash, sync::Arc};
 use unicase::UniCase;
+pub mod activitypub;
+mod attachment;
 pub mod auth;
+mod bot;
+pub mod brigade;
+mod cache;
+mod changes;
+pub mod check;
 mod comment;
+pub mod config;
+mod content;
+pub mod digest;
+mod draft;
+pub mod errors;
+pub mod events;
+mod export;
+mod growth;
+#[cfg(feature = "server")]
+mod ide;
+mod ids;
+mod images;
+pub mod language;
+mod legacy;
+pub mod links;
+pub mod mailer;
+mod markdown;
+mod membership;
+pub mod middleware;
+pub mod mobile;
+mod moderation;
+mod ndjson;
+pub mod oembed;
+pub mod policy;
 mod post;
+pub mod push;
+mod ratelimit;
+mod reaction;
+mod repo;
+mod report;
+pub mod rest;
+mod saved;
+mod search;
+#[cfg(feature = "server")]
+pub mod server;
+mod site;
+pub mod statements;
+pub mod stats;
 /// Top level concepts for Queries should be
 /// Sub
 /// User
@@ -14,10 +59,44 @@ mod post;
 /// These concepts need to be top level so that they can be linked to individually without having
 /// to decend a chain.
 mod sub;
+mod submitter;
+mod thread;
+mod top;
+mod totp;
+mod unread;
 mod user;
+mod version;
+mod visibility;
+mod visit;
+mod vote;
+mod warning;
+mod word_filter;
 
 type Cursor = String;
 
+/// Offset based cursors are the position of the last item seen. Anything else is rejected, quietly
+/// starting over from the top would hand out pages the client didn't ask for.
+fn parse_offset(after: Option<Cursor>) -> Result<i64, FieldError> {
+    match after.as_deref() {
+        None | Some("") => Ok(0),
+        Some(cursor) if cursor.bytes().all(|b| b.is_ascii_digit()) => cursor
+            .parse()
+            .map_err(|_| format!("Invalid cursor {}", cursor).into()),
+        Some(cursor) => Err(format!("Invalid cursor {}", cursor).into()),
+    }
+}
+
+/// Most objects getPosts, getComments and getUsers fetch at once
+const MAX_BULK_FETCH: usize = 100;
+
+fn check_bulk_fetch(count: usize) -> Result<(), FieldError> {
+    if count > MAX_BULK_FETCH {
+        Err(format!("At most {} at once", MAX_BULK_FETCH).into())
+    } else {
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct Edge<T> {
     pub node: T,
@@ -40,27 +119,167 @@ pub struct Page<T> {
 type GLoader<Key, Value, L> =
     Loader<Key, Result<Value, Arc<FieldError>>, L, HashMap<Key, Result<Value, Arc<FieldError>>>>;
 
+/// Details about the HTTP request a query came in on
+#[derive(Debug, Clone, Default)]
+pub struct RequestInfo {
+    /// X-Request-Id, taken from the client or generated, ties logs and errors to one request
+    pub id: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// From Accept-Language, most preferred first, picks the language errors are in
+    pub languages: Vec<String>,
+}
+
+fn loader<Key, Value, L>(batch_fn: L, config: &config::Config) -> GLoader<Key, Value, L>
+where
+    Key: Eq + Hash + Clone,
+    Value: Clone,
+    L: BatchFn<Key, Result<Value, Arc<FieldError>>>,
+{
+    Loader::new(batch_fn)
+        .with_max_batch_size(config.loader_max_batch_size)
+        .with_yield_count(config.loader_yield_count)
+}
+
 pub struct Context {
     pub user: auth::UserState,
+    pub request: RequestInfo,
+    pub config: Arc<config::Config>,
     pub pool: sqlx::Pool<sqlx::Postgres>, // This should probably be any, but I didn't compile with any so ???
     pub sub_loader: GLoader<UniCase<String>, sub::Sub, sub::SubLoader>,
-    pub user_loader: GLoader<UniCase<String>, user::User, user::UserLoader>,
+    pub user_loader: GLoader<user::UserRef, user::User, user::UserLoader>,
     pub post_loader: GLoader<i32, post::Post, post::PostLoader>,
     pub comment_loader: GLoader<String, comment::Comment, comment::CommentLoader>,
+    pub comment_children_loader:
+        GLoader<comment::ChildrenKey, comment::ChildrenPage, comment::CommentChildrenLoader>,
+    pub mailer: Arc<dyn mailer::Mailer>,
+    pub events: Arc<dyn events::EventBus>,
+    preferences: Mutex<HashMap<&'static str, Option<String>>>,
+    visits: Mutex<HashMap<i32, Option<chrono::NaiveDateTime>>>,
+    /// Deprecated fields the request used, see version::used
+    deprecations: std::sync::Mutex<Vec<&'static str>>,
 }
 impl Context {
-    pub fn new(user: auth::UserState, pool: sqlx::Pool<sqlx::Postgres>) -> Self {
+    pub fn new(
+        user: auth::UserState,
+        request: RequestInfo,
+        pool: sqlx::Pool<sqlx::Postgres>,
+        mailer: Arc<dyn mailer::Mailer>,
+        events: Arc<dyn events::EventBus>,
+        config: Arc<config::Config>,
+    ) -> Self {
+        let repos = repo::Repos::postgres(&pool);
+        Context::with_repos(user, request, pool, mailer, events, config, repos)
+    }
+
+    /// Loaders read through `repos` instead of straight from `pool`
+    pub fn with_repos(
+        user: auth::UserState,
+        request: RequestInfo,
+        pool: sqlx::Pool<sqlx::Postgres>,
+        mailer: Arc<dyn mailer::Mailer>,
+        events: Arc<dyn events::EventBus>,
+        config: Arc<config::Config>,
+        repos: repo::Repos,
+    ) -> Self {
         Context {
             user,
-            pool: pool.clone(),
-            sub_loader: Loader::new(sub::SubLoader { pool: pool.clone() }),
-            user_loader: Loader::new(user::UserLoader { pool: pool.clone() }),
-            comment_loader: Loader::new(comment::CommentLoader { pool: pool.clone() }),
-            post_loader: Loader::new(post::PostLoader { pool }),
+            request,
+            mailer,
+            events,
+            preferences: Mutex::new(HashMap::new()),
+            visits: Mutex::new(HashMap::new()),
+            deprecations: std::sync::Mutex::new(vec![]),
+            pool,
+            sub_loader: loader(sub::SubLoader { repo: repos.subs }, &config),
+            user_loader: loader(user::UserLoader { repo: repos.users }, &config),
+            comment_loader: loader(
+                comment::CommentLoader {
+                    repo: repos.comments.clone(),
+                },
+                &config,
+            ),
+            comment_children_loader: loader(
+                comment::CommentChildrenLoader {
+                    repo: repos.comments,
+                },
+                &config,
+            ),
+            post_loader: loader(post::PostLoader { repo: repos.posts }, &config),
+            config,
         }
     }
 }
 
+impl Context {
+    /// Events are published after the change is committed, a consumer missing out shouldn't
+    /// fail the mutation
+    pub async fn publish(&self, event: events::Event) {
+        if let Err(err) = self.events.publish(event.clone()).await {
+            log::error!("Could not publish {:?} - {:?}", event, err);
+        }
+    }
+
+    /// A preference of the viewer from user_metadata, cached for the rest of the request
+    pub async fn preference(&self, key: &'static str) -> Result<Option<String>, FieldError> {
+        let uid = match self.user.user_id() {
+            Ok(uid) => uid,
+            Err(_) => return Ok(None),
+        };
+
+        let mut preferences = self.preferences.lock().await;
+        if let Some(value) = preferences.get(key) {
+            return Ok(value.clone());
+        }
+
+        let value = sqlx::query!(
+            r#"
+            SELECT value
+            FROM user_metadata
+            WHERE uid = $1 AND key = $2
+            "#,
+            uid,
+            key
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .and_then(|row| row.value);
+
+        preferences.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// When the viewer last visited the post, see markPostVisited. Cached for the rest of the
+    /// request, every comment of a thread asks.
+    pub async fn last_visit(&self, pid: i32) -> Result<Option<chrono::NaiveDateTime>, FieldError> {
+        let uid = match self.user.user_id() {
+            Ok(uid) => uid,
+            Err(_) => return Ok(None),
+        };
+
+        let mut visits = self.visits.lock().await;
+        if let Some(visited) = visits.get(&pid) {
+            return Ok(*visited);
+        }
+
+        let visited = sqlx::query!(
+            r#"
+            SELECT visited
+            FROM post_visit
+            WHERE uid = $1 AND pid = $2
+            "#,
+            uid,
+            pid
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| row.visited);
+
+        visits.insert(pid, visited);
+        Ok(visited)
+    }
+}
+
 impl juniper::Context for Context {}
 
 pub struct Query;
@@ -68,10 +287,23 @@ pub struct Query;
     context = Context,
 )]
 impl Query {
-    fn apiVersion() -> &'static str {
+    #[graphql(deprecated = "Use capabilities and schemaVersion")]
+    fn apiVersion(context: &Context) -> &'static str {
+        version::used(context, "Query.apiVersion");
         "1.0"
     }
 
+    /// Features this server has enabled
+    fn capabilities(context: &Context) -> version::Capabilities {
+        version::capabilities(context)
+    }
+
+    /// Build of the API that answers and the deprecated fields it still has, with the day each
+    /// may go away
+    fn schema_version() -> version::SchemaVersion {
+        version::schema_version()
+    }
+
     async fn get_subs(
         context: &Context,
         count: Option<i32>,
@@ -91,27 +323,223 @@ impl Query {
     async fn get_post(context: &Context, id: ID) -> Result<post::Post, FieldError> {
         context
             .post_loader
-            .load(id.parse::<i32>()?)
+            .load(context.config.post_ids.decode(&id)?)
             .await
             .map_err(|err| format!("{:?}", err).into())
     }
 
+    /// Posts in the order asked for, null where there's no such post. At most 100 at once.
+    async fn get_posts(
+        context: &Context,
+        ids: Vec<ID>,
+    ) -> Result<Vec<Option<post::Post>>, FieldError> {
+        check_bulk_fetch(ids.len())?;
+        let pids = ids
+            .iter()
+            .map(|id| context.config.post_ids.decode(id))
+            .collect::<Result<Vec<_>, _>>()?;
+        let posts = context.post_loader.load_many(pids.clone()).await;
+        Ok(pids
+            .iter()
+            .map(|pid| posts.get(pid).and_then(|post| post.as_ref().ok()).cloned())
+            .collect())
+    }
+
+    async fn get_post_preview(context: &Context, id: ID) -> Result<post::PostPreview, FieldError> {
+        post::get_post_preview(context, id).await
+    }
+
+    async fn get_post_by_path(
+        context: &Context,
+        sub: String,
+        pid: ID,
+        slug: Option<String>,
+    ) -> Result<post::PostPath, FieldError> {
+        post::get_post_by_path(context, sub, pid, slug).await
+    }
+
+    /// Tries `text` against the sub's word filters, or only against `pattern` when given. Mods
+    /// only.
+    async fn test_word_filters(
+        context: &Context,
+        sub: String,
+        text: String,
+        pattern: Option<String>,
+        is_regex: Option<bool>,
+    ) -> Result<Vec<word_filter::WordFilterMatch>, FieldError> {
+        word_filter::test_filters(context, sub, text, pattern, is_regex).await
+    }
+
+    /// `language` is an ISO 639-3 code like "eng", see Post.language
     async fn get_home_posts(
         context: &Context,
+        types: Option<Vec<post::PostType>>,
+        language: Option<String>,
+        hide_seen: Option<bool>,
+        count: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Page<post::Post>, FieldError> {
+        post::get_home_posts(context, types, language, hide_seen, count, after).await
+    }
+
+    /// `language` is an ISO 639-3 code like "eng", see Post.language
+    async fn get_all_posts(
+        context: &Context,
+        types: Option<Vec<post::PostType>>,
+        language: Option<String>,
+        hide_seen: Option<bool>,
         count: Option<i32>,
         after: Option<String>,
     ) -> Result<Page<post::Post>, FieldError> {
-        post::get_home_posts(context, count, after).await
+        post::get_all_posts(context, types, language, hide_seen, count, after).await
+    }
+
+    /// Users in the order asked for, null where there's no such user. At most 100 at once.
+    async fn get_users(
+        context: &Context,
+        names: Vec<String>,
+    ) -> Result<Vec<Option<user::User>>, FieldError> {
+        check_bulk_fetch(names.len())?;
+        let keys = names
+            .into_iter()
+            .map(user::UserRef::name)
+            .collect::<Vec<_>>();
+        let users = context.user_loader.load_many(keys.clone()).await;
+        Ok(keys
+            .iter()
+            .map(|key| users.get(key).and_then(|user| user.as_ref().ok()).cloned())
+            .collect())
     }
 
     async fn get_user(context: &Context, name: String) -> Result<user::User, FieldError> {
         context
             .user_loader
-            .load(name.into())
+            .load(user::UserRef::name(name))
             .await
             .map_err(|err| format!("{:?}", err).into())
     }
 
+    /// Loader batching, cache hit ratios and connection pool use of the instance that answers.
+    /// Admins only.
+    fn get_runtime_stats(context: &Context) -> Result<stats::RuntimeStats, FieldError> {
+        stats::runtime_stats(context)
+    }
+
+    /// Accounts waiting for approveBotAccount or denyBotAccount, oldest first. Admins only.
+    async fn pending_bot_accounts(context: &Context) -> Result<Vec<bot::BotRequest>, FieldError> {
+        bot::pending(context).await
+    }
+
+    /// Posts whose recent votes look organized, from the brigade detector (BRIGADE_DETECTOR).
+    /// Admins only.
+    async fn vote_anomalies(
+        context: &Context,
+        count: Option<i32>,
+    ) -> Result<Vec<brigade::VoteAnomaly>, FieldError> {
+        brigade::vote_anomalies(context, count).await
+    }
+
+    async fn admin_find_alt_accounts(
+        context: &Context,
+        ip: String,
+    ) -> Result<Vec<user::User>, FieldError> {
+        submitter::find_alt_accounts(context, ip).await
+    }
+
+    async fn search(
+        context: &Context,
+        query: String,
+        types: Option<Vec<search::SearchType>>,
+        count: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Page<search::SearchResult>, FieldError> {
+        search::search(context, query, types, count, after).await
+    }
+
+    async fn search_subs(
+        context: &Context,
+        query: String,
+        threshold: Option<f64>,
+        count: Option<i32>,
+    ) -> Result<Vec<sub::Sub>, FieldError> {
+        search::search_subs(context, query, threshold, count).await
+    }
+
+    async fn search_users(
+        context: &Context,
+        query: String,
+        threshold: Option<f64>,
+        count: Option<i32>,
+    ) -> Result<Vec<user::User>, FieldError> {
+        search::search_users(context, query, threshold, count).await
+    }
+
+    async fn get_site_config(context: &Context) -> Result<site::SiteConfig, FieldError> {
+        site::get_site_config(context).await
+    }
+
+    async fn get_default_subs(context: &Context) -> Result<Vec<sub::Sub>, FieldError> {
+        site::get_default_subs(context).await
+    }
+
+    async fn me(context: &Context) -> Result<user::User, FieldError> {
+        user::me(context).await
+    }
+
+    /// applicationServerKey for PushManager.subscribe, null when push isn't set up
+    fn push_public_key(context: &Context) -> Option<String> {
+        context.config.vapid_public_key.clone()
+    }
+
+    async fn get_sub_export(context: &Context, id: ID) -> Result<export::SubExport, FieldError> {
+        export::get_export(context, id).await
+    }
+
+    /// The viewer's saved posts and comments, everything unless `kind` says otherwise
+    async fn get_saved(
+        context: &Context,
+        kind: Option<saved::SavedKind>,
+        count: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Page<content::Content>, FieldError> {
+        saved::get_saved(context, kind, count, after).await
+    }
+
+    /// Posts and comments created, edited, deleted or restored since `since`, oldest change
+    /// first. Lets indexers and mirrors sync incrementally, keep the end cursor for next time.
+    async fn get_changes(
+        context: &Context,
+        since: Option<chrono::NaiveDateTime>,
+        types: Option<Vec<changes::ChangeType>>,
+        count: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Page<changes::Change>, FieldError> {
+        changes::get_changes(context, since, types, count, after).await
+    }
+
+    /// The viewer's unfinished posts and comments, most recently saved first
+    async fn get_drafts(context: &Context) -> Result<Vec<draft::Draft>, FieldError> {
+        draft::get_drafts(context).await
+    }
+
+    /// Comments in the order asked for, null where there's no such comment. At most 100 at once.
+    async fn get_comments(
+        context: &Context,
+        cids: Vec<String>,
+    ) -> Result<Vec<Option<comment::Comment>>, FieldError> {
+        check_bulk_fetch(cids.len())?;
+        let comments = context.comment_loader.load_many(cids.clone()).await;
+        Ok(cids
+            .iter()
+            .map(|cid| {
+                comments
+                    .get(cid)
+                    .and_then(|comment| comment.as_ref().ok())
+                    .cloned()
+            })
+            .collect())
+    }
+
     async fn get_comment(context: &Context, id: ID) -> Result<comment::Comment, FieldError> {
         context
             .comment_loader
@@ -119,12 +547,567 @@ impl Query {
             .await
             .map_err(|err| format!("{:?}", err).into())
     }
+
+    /// Reports on the sub's posts and comments, newest first. A null status lists open and
+    /// closed ones. For the sub's mods and admins.
+    async fn get_reports(
+        context: &Context,
+        sub_name: String,
+        status: Option<report::ReportStatus>,
+        count: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Page<report::Report>, FieldError> {
+        report::get_reports(context, sub_name, status, count, after).await
+    }
 }
 pub struct Mutation;
+#[graphql_object(
+    context = Context,
+)]
+impl Mutation {
+    async fn request_password_reset(context: &Context, email: String) -> Result<bool, FieldError> {
+        user::request_password_reset(context, email).await
+    }
+
+    async fn reset_password(
+        context: &Context,
+        token: String,
+        new_password: String,
+    ) -> Result<bool, FieldError> {
+        user::reset_password(context, token, new_password).await
+    }
+
+    async fn request_email_verification(context: &Context) -> Result<bool, FieldError> {
+        user::request_email_verification(context).await
+    }
+
+    async fn verify_email(context: &Context, token: String) -> Result<bool, FieldError> {
+        user::verify_email(context, token).await
+    }
+
+    async fn change_password(
+        context: &Context,
+        old_password: String,
+        new_password: String,
+        totp_code: Option<String>,
+    ) -> Result<bool, FieldError> {
+        user::change_password(context, old_password, new_password, totp_code).await
+    }
+
+    async fn delete_account(
+        context: &Context,
+        password: String,
+        totp_code: Option<String>,
+    ) -> Result<bool, FieldError> {
+        user::delete_account(context, password, totp_code).await
+    }
+
+    async fn save_post(context: &Context, id: ID) -> Result<bool, FieldError> {
+        saved::save_post(context, id).await
+    }
+
+    async fn unsave_post(context: &Context, id: ID) -> Result<bool, FieldError> {
+        saved::unsave_post(context, id).await
+    }
+
+    async fn save_comment(context: &Context, id: ID) -> Result<bool, FieldError> {
+        saved::save_comment(context, id).await
+    }
+
+    async fn unsave_comment(context: &Context, id: ID) -> Result<bool, FieldError> {
+        saved::unsave_comment(context, id).await
+    }
+
+    /// Adds the sub to the viewer's home feed, Sub.subscribers counts them right away
+    async fn subscribe_sub(context: &Context, sub: String) -> Result<sub::Sub, FieldError> {
+        membership::subscribe(context, sub).await
+    }
+
+    async fn unsubscribe_sub(context: &Context, sub: String) -> Result<sub::Sub, FieldError> {
+        membership::unsubscribe(context, sub).await
+    }
+
+    /// Notifies the viewer of every new comment in the post, like replies to their own posts
+    async fn subscribe_to_post(context: &Context, id: ID) -> Result<bool, FieldError> {
+        thread::subscribe(context, id).await
+    }
+
+    async fn unsubscribe_from_post(context: &Context, id: ID) -> Result<bool, FieldError> {
+        thread::unsubscribe(context, id).await
+    }
+
+    /// Remember that the viewer read the post's comments, for Post.newCommentCount and
+    /// Comment.isNewForViewer. Call it after showing them.
+    async fn mark_post_visited(context: &Context, id: ID) -> Result<bool, FieldError> {
+        visit::mark_visited(context, id).await
+    }
+
+    /// Remember posts as seen, feeds skip them when asked to hideSeen
+    async fn mark_seen(context: &Context, ids: Vec<ID>) -> Result<bool, FieldError> {
+        post::mark_seen(context, ids).await
+    }
+
+    /// A link post when `link` is given, a text post otherwise. Posts in NSFW subs are always
+    /// NSFW.
+    async fn create_post(
+        context: &Context,
+        sub_name: String,
+        title: String,
+        content: Option<String>,
+        link: Option<String>,
+        nsfw: Option<bool>,
+    ) -> Result<post::Post, FieldError> {
+        post::create_post(context, sub_name, title, content, link, nsfw).await
+    }
+
+    /// content as contentHtml would show it once posted, nothing is saved
+    fn preview_markdown(context: &Context, content: String) -> String {
+        markdown::render(&context.config.site_url, &content)
+    }
+
+    /// A null direction takes the viewer's vote back
+    async fn vote_post(
+        context: &Context,
+        id: ID,
+        direction: Option<vote::VoteDirection>,
+    ) -> Result<vote::VoteResult, FieldError> {
+        vote::vote_post(context, id, direction).await
+    }
+
+    async fn vote_comment(
+        context: &Context,
+        cid: ID,
+        direction: Option<vote::VoteDirection>,
+    ) -> Result<vote::VoteResult, FieldError> {
+        vote::vote_comment(context, cid, direction).await
+    }
+
+    /// Fails with a CONFLICT error holding the current content when lastEditedAt doesn't match
+    /// the post's edited (or posted) time anymore
+    async fn edit_post(
+        context: &Context,
+        id: ID,
+        content: String,
+        last_edited_at: Option<chrono::NaiveDateTime>,
+    ) -> Result<post::Post, FieldError> {
+        post::edit_post(context, id, content, last_edited_at).await
+    }
+
+    /// Like editPost, lastEditedAt is compared with the comment's lastEdit (or time)
+    async fn edit_comment(
+        context: &Context,
+        id: ID,
+        content: String,
+        last_edited_at: Option<chrono::NaiveDateTime>,
+    ) -> Result<comment::Comment, FieldError> {
+        comment::edit_comment(context, id, content, last_edited_at).await
+    }
+
+    /// Authors delete their own posts, mods and admins remove others' with an optional reason
+    /// for the mod log
+    async fn delete_post(
+        context: &Context,
+        id: ID,
+        reason: Option<String>,
+    ) -> Result<post::Post, FieldError> {
+        post::delete_post(context, id, reason).await
+    }
+
+    async fn delete_comment(
+        context: &Context,
+        id: ID,
+        reason: Option<String>,
+    ) -> Result<comment::Comment, FieldError> {
+        comment::delete_comment(context, id, reason).await
+    }
+
+    /// Flags the post for the sub's mods, one open report per post and user
+    async fn report_post(context: &Context, id: ID, reason: String) -> Result<bool, FieldError> {
+        report::report_post(context, id, reason).await
+    }
+
+    async fn report_comment(context: &Context, id: ID, reason: String) -> Result<bool, FieldError> {
+        report::report_comment(context, id, reason).await
+    }
+
+    async fn set_digest_frequency(
+        context: &Context,
+        frequency: digest::DigestFrequency,
+    ) -> Result<digest::DigestFrequency, FieldError> {
+        digest::set_frequency(context, frequency).await
+    }
+
+    /// Takes the token from the bottom of a digest email, no login needed
+    async fn unsubscribe_digest(context: &Context, token: String) -> Result<bool, FieldError> {
+        digest::unsubscribe(&context.pool, &token).await
+    }
+
+    /// Stores the PushSubscription of this browser so replies and messages reach it
+    async fn register_push_subscription(
+        context: &Context,
+        endpoint: String,
+        keys: push::PushKeys,
+    ) -> Result<bool, FieldError> {
+        push::register(context, endpoint, keys).await
+    }
+
+    async fn unregister_push_subscription(
+        context: &Context,
+        endpoint: String,
+    ) -> Result<bool, FieldError> {
+        push::unregister(context, endpoint).await
+    }
+
+    /// Stores the APNs or FCM token of this app installation
+    async fn register_device_token(
+        context: &Context,
+        token: String,
+        platform: mobile::DevicePlatform,
+    ) -> Result<bool, FieldError> {
+        mobile::register(context, token, platform).await
+    }
+
+    async fn unregister_device_token(context: &Context, token: String) -> Result<bool, FieldError> {
+        mobile::unregister(context, token).await
+    }
+
+    async fn save_draft(
+        context: &Context,
+        kind: draft::DraftKind,
+        target: String,
+        content: String,
+    ) -> Result<draft::Draft, FieldError> {
+        draft::save_draft(context, kind, target, content).await
+    }
+
+    async fn delete_draft(context: &Context, id: ID) -> Result<bool, FieldError> {
+        draft::delete_draft(context, id).await
+    }
+
+    async fn update_site_config(
+        context: &Context,
+        input: site::SiteConfigInput,
+    ) -> Result<site::SiteConfig, FieldError> {
+        site::update_site_config(context, input).await
+    }
+
+    async fn add_default_sub(context: &Context, name: String) -> Result<Vec<sub::Sub>, FieldError> {
+        site::add_default_sub(context, name).await
+    }
+
+    async fn remove_default_sub(
+        context: &Context,
+        name: String,
+    ) -> Result<Vec<sub::Sub>, FieldError> {
+        site::remove_default_sub(context, name).await
+    }
+
+    async fn set_maintenance_mode(context: &Context, enabled: bool) -> Result<bool, FieldError> {
+        site::set_maintenance_mode(context, enabled).await
+    }
+
+    async fn set_collapse_below_score(
+        context: &Context,
+        score: Option<i32>,
+    ) -> Result<Option<i32>, FieldError> {
+        user::set_collapse_below_score(context, score).await
+    }
+
+    /// Removes the posts in one transaction and a single mod_log entry
+    async fn bulk_remove_posts(
+        context: &Context,
+        ids: Vec<ID>,
+        reason: String,
+    ) -> Result<i32, FieldError> {
+        moderation::bulk_remove_posts(context, ids, reason).await
+    }
+
+    async fn bulk_approve(context: &Context, ids: Vec<ID>) -> Result<i32, FieldError> {
+        moderation::bulk_approve(context, ids).await
+    }
+
+    async fn bulk_ban_users(
+        context: &Context,
+        sub: String,
+        users: Vec<String>,
+        reason: String,
+    ) -> Result<i32, FieldError> {
+        moderation::bulk_ban_users(context, sub, users, reason).await
+    }
+
+    /// Builds a CSV or JSON file of the sub's activity in the background, poll getSubExport for
+    /// the download link. Both ends of the range are optional.
+    async fn export_sub(
+        context: &Context,
+        sub: String,
+        kind: export::ExportKind,
+        format: export::ExportFormat,
+        from: Option<chrono::NaiveDateTime>,
+        to: Option<chrono::NaiveDateTime>,
+    ) -> Result<export::SubExport, FieldError> {
+        export::export_sub(context, sub, kind, format, from, to).await
+    }
+
+    /// Formal warning from a mod, the user is notified and it counts towards escalation
+    async fn warn_user(
+        context: &Context,
+        sub: String,
+        user: String,
+        reason: String,
+    ) -> Result<warning::Warning, FieldError> {
+        warning::warn_user(context, sub, user, reason).await
+    }
+
+    /// Keeps the user from commenting and sending modmail in the sub for `hours`
+    async fn mute_user(
+        context: &Context,
+        sub: String,
+        user: String,
+        hours: i32,
+        reason: Option<String>,
+    ) -> Result<chrono::NaiveDateTime, FieldError> {
+        moderation::mute_user(context, sub, user, hours, reason).await
+    }
+
+    async fn unmute_user(context: &Context, sub: String, user: String) -> Result<bool, FieldError> {
+        moderation::unmute_user(context, sub, user).await
+    }
+
+    /// For restricted subs, the sub's mods decide with approveMember or denyMember
+    async fn request_to_join(context: &Context, sub: String) -> Result<bool, FieldError> {
+        membership::request_to_join(context, sub).await
+    }
+
+    async fn approve_member(
+        context: &Context,
+        sub: String,
+        user: String,
+    ) -> Result<bool, FieldError> {
+        membership::decide(context, sub, user, true).await
+    }
+
+    async fn deny_member(context: &Context, sub: String, user: String) -> Result<bool, FieldError> {
+        membership::decide(context, sub, user, false).await
+    }
+
+    /// Marks the viewer's account as run by a program once an admin approves, `false` makes it a
+    /// regular account again right away
+    async fn set_bot_account(context: &Context, bot: bool) -> Result<bot::BotStatus, FieldError> {
+        bot::set_bot(context, bot).await
+    }
+
+    async fn approve_bot_account(context: &Context, user: String) -> Result<bool, FieldError> {
+        bot::decide(context, user, true).await
+    }
+
+    async fn deny_bot_account(context: &Context, user: String) -> Result<bool, FieldError> {
+        bot::decide(context, user, false).await
+    }
+
+    async fn add_word_filter(
+        context: &Context,
+        sub: String,
+        pattern: String,
+        is_regex: bool,
+        action: word_filter::FilterAction,
+    ) -> Result<word_filter::WordFilter, FieldError> {
+        word_filter::add_filter(context, sub, pattern, is_regex, action).await
+    }
+
+    async fn update_word_filter(
+        context: &Context,
+        id: ID,
+        pattern: String,
+        is_regex: bool,
+        action: word_filter::FilterAction,
+    ) -> Result<word_filter::WordFilter, FieldError> {
+        word_filter::update_filter(context, id, pattern, is_regex, action).await
+    }
+
+    async fn delete_word_filter(context: &Context, id: ID) -> Result<bool, FieldError> {
+        word_filter::delete_filter(context, id).await
+    }
+
+    /// Overrides the link checker for a link post, mods only
+    async fn set_link_status(
+        context: &Context,
+        id: ID,
+        status: links::LinkStatus,
+    ) -> Result<links::LinkStatus, FieldError> {
+        links::set_status(context, id, status).await
+    }
+
+    /// Fetches the linked page's title, description and image again, for its author and mods
+    async fn refresh_link_metadata(
+        context: &Context,
+        id: ID,
+    ) -> Result<links::LinkMetadata, FieldError> {
+        links::refresh_metadata(context, id).await
+    }
+
+    /// Adds images uploaded through /upload to one of the viewer's text posts
+    async fn attach_to_post(
+        context: &Context,
+        id: ID,
+        attachments: Vec<ID>,
+    ) -> Result<Vec<attachment::Attachment>, FieldError> {
+        attachment::attach_to_post(context, id, attachments).await
+    }
+
+    /// Like attachToPost, for subs that turned on commentImages
+    async fn attach_to_comment(
+        context: &Context,
+        id: ID,
+        attachments: Vec<ID>,
+    ) -> Result<Vec<attachment::Attachment>, FieldError> {
+        attachment::attach_to_comment(context, id, attachments).await
+    }
+
+    /// Limits reply nesting in the sub, null goes back to the site-wide limit
+    async fn set_max_reply_depth(
+        context: &Context,
+        sub: String,
+        depth: Option<i32>,
+    ) -> Result<Option<i32>, FieldError> {
+        comment::set_max_reply_depth(context, sub, depth).await
+    }
+
+    /// At least one type, posts of the others already in the sub stay
+    async fn set_allowed_post_types(
+        context: &Context,
+        sub: String,
+        types: Vec<post::PostType>,
+    ) -> Result<Vec<post::PostType>, FieldError> {
+        post::set_allowed_post_types(context, sub, types).await
+    }
+
+    /// Null drops a requirement, mods of the sub don't have to meet them
+    async fn set_posting_requirements(
+        context: &Context,
+        sub: String,
+        min_account_age_days: Option<i32>,
+        min_score: Option<i32>,
+    ) -> Result<membership::PostingRequirements, FieldError> {
+        membership::set_posting_requirements(context, sub, min_account_age_days, min_score).await
+    }
+
+    async fn set_comment_images(
+        context: &Context,
+        sub: String,
+        enabled: bool,
+    ) -> Result<bool, FieldError> {
+        attachment::set_comment_images(context, sub, enabled).await
+    }
+
+    async fn set_comment_reactions(
+        context: &Context,
+        sub: String,
+        enabled: bool,
+    ) -> Result<bool, FieldError> {
+        reaction::set_comment_reactions(context, sub, enabled).await
+    }
+
+    /// name is what reactions use, adding an existing name replaces its image
+    async fn add_sub_emoji(
+        context: &Context,
+        sub: String,
+        name: String,
+        url: String,
+    ) -> Result<reaction::SubEmoji, FieldError> {
+        reaction::add_emoji(context, sub, name, url).await
+    }
+
+    /// Removes the sub's reactions with it as well
+    async fn remove_sub_emoji(
+        context: &Context,
+        sub: String,
+        name: String,
+    ) -> Result<bool, FieldError> {
+        reaction::remove_emoji(context, sub, name).await
+    }
+
+    /// emoji is a standard emoji or the name of one of the sub's custom emoji. Returns the
+    /// comment's reactions.
+    async fn react_to_comment(
+        context: &Context,
+        cid: ID,
+        emoji: String,
+    ) -> Result<Vec<reaction::Reaction>, FieldError> {
+        reaction::react(context, cid, emoji).await
+    }
+
+    async fn remove_comment_reaction(
+        context: &Context,
+        cid: ID,
+        emoji: String,
+    ) -> Result<Vec<reaction::Reaction>, FieldError> {
+        reaction::unreact(context, cid, emoji).await
+    }
+
+    async fn delete_attachment(context: &Context, id: ID) -> Result<bool, FieldError> {
+        attachment::delete_attachment(context, id).await
+    }
+
+    async fn rename_sub(
+        context: &Context,
+        old: String,
+        new: String,
+    ) -> Result<sub::Sub, FieldError> {
+        sub::rename_sub(context, old, new).await
+    }
+
+    async fn enable_totp(context: &Context) -> Result<totp::TotpEnrollment, FieldError> {
+        totp::enable(context).await
+    }
 
-pub type Schema = juniper::RootNode<
-    'static,
-    Query,
-    juniper::EmptyMutation<Context>,
-    juniper::EmptySubscription<Context>,
->;
+    async fn confirm_totp(context: &Context, code: String) -> Result<bool, FieldError> {
+        totp::confirm(context, code).await
+    }
+
+    async fn disable_totp(context: &Context, code: String) -> Result<bool, FieldError> {
+        totp::disable(context, code).await
+    }
+}
+
+pub type Schema = juniper::RootNode<'static, Query, Mutation, juniper::EmptySubscription<Context>>;
+
+#[cfg(test)]
+mod tests {
+    use super::parse_offset;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn offsets_round_trip(offset in 0..i64::MAX) {
+            prop_assert_eq!(parse_offset(Some(offset.to_string())).unwrap(), offset);
+        }
+
+        #[test]
+        fn garbage_is_rejected(cursor in "\\PC*") {
+            prop_assume!(!cursor.is_empty() && !cursor.bytes().all(|b| b.is_ascii_digit()));
+            prop_assert!(parse_offset(Some(cursor)).is_err());
+        }
+
+        #[test]
+        fn overflow_is_rejected(digits in "[1-9][0-9]{19,40}") {
+            prop_assert!(parse_offset(Some(digits)).is_err());
+        }
+    }
+
+    #[test]
+    fn missing_cursor_starts_at_the_top() {
+        assert_eq!(parse_offset(None).unwrap(), 0);
+        assert_eq!(parse_offset(Some("".into())).unwrap(), 0);
+    }
+
+    #[test]
+    fn signs_and_whitespace_are_rejected() {
+        for cursor in &["-1", "+1", " 1", "1 ", "1.0", "0x10"] {
+            assert!(
+                parse_offset(Some(cursor.to_string())).is_err(),
+                "{}",
+                cursor
+            );
+        }
+    }
+}
@@ -0,0 +1,501 @@
+use crate::Context;
+use chrono::NaiveDateTime;
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLEnum, GraphQLObject, ID};
+use reqwest::{header::LOCATION, Method, Url};
+use std::{
+    fmt,
+    net::{IpAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+/// Links checked per round
+const BATCH_SIZE: i64 = 100;
+/// Pause between rounds
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How long a link is trusted before it is checked again
+const RECHECK_DAYS: i32 = 7;
+/// Only the start of a page is read for its metadata, that's where the head is
+const MAX_PAGE_BYTES: usize = 256 * 1024;
+/// Longest stored value, some pages stuff whole articles into their description
+const MAX_FIELD_LENGTH: usize = 1000;
+/// Redirects followed per request, every hop is checked like the link itself
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum LinkStatus {
+    /// Not checked yet, or the site wouldn't say
+    Unknown,
+    Alive,
+    Dead,
+}
+
+pub async fn get_status(context: &Context, pid: i32) -> Result<LinkStatus, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT dead
+        FROM link_status
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .and_then(|row| row.dead)
+    .map_or(LinkStatus::Unknown, |dead| {
+        if dead {
+            LinkStatus::Dead
+        } else {
+            LinkStatus::Alive
+        }
+    }))
+}
+
+/// Mods overriding the checker, for links it gets wrong. UNKNOWN hands the link back to it.
+pub async fn set_status(
+    context: &Context,
+    id: ID,
+    status: LinkStatus,
+) -> Result<LinkStatus, FieldError> {
+    let pid = context.config.post_ids.decode(&id)?;
+    let post = sqlx::query!(
+        r#"
+        SELECT sid, link
+        FROM sub_post
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Post not found {}", *id))?;
+    if !context.user.is_mod(&post.sid.unwrap_or_default()) {
+        return Err("Not Authorized".into());
+    }
+    if post.link.is_none() {
+        return Err("Only link posts have a link status".into());
+    }
+
+    match status {
+        LinkStatus::Unknown => {
+            sqlx::query!(
+                r#"
+                DELETE FROM link_status
+                WHERE pid = $1
+                "#,
+                pid
+            )
+            .execute(&context.pool)
+            .await?;
+        }
+        LinkStatus::Alive | LinkStatus::Dead => {
+            sqlx::query!(
+                r#"
+                INSERT INTO link_status (pid, dead, manual)
+                VALUES ($1, $2, true)
+                ON CONFLICT (pid) DO UPDATE
+                SET dead = $2, manual = true, checked = now()
+                "#,
+                pid,
+                status == LinkStatus::Dead
+            )
+            .execute(&context.pool)
+            .await?;
+        }
+    }
+
+    Ok(status)
+}
+
+#[derive(GraphQLObject, Debug, Default)]
+pub struct LinkMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub site_name: Option<String>,
+    pub fetched_at: Option<NaiveDateTime>,
+}
+
+pub async fn get_metadata(context: &Context, pid: i32) -> Result<Option<LinkMetadata>, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT title, description, image_url, site_name, fetched_at
+        FROM link_metadata
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .map(|row| LinkMetadata {
+        title: row.title,
+        description: row.description,
+        image_url: row.image_url,
+        site_name: row.site_name,
+        fetched_at: Some(row.fetched_at),
+    }))
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Value of `name="..."` (or single quoted) inside a tag
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lower = tag.to_ascii_lowercase();
+    let start = lower.find(&format!("{}=", name))? + name.len() + 1;
+    let rest = &tag[start..];
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let rest = &rest[1..];
+    rest.find(quote).map(|end| &rest[..end])
+}
+
+fn clean(value: &str) -> Option<String> {
+    let value = unescape(value.trim());
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.chars().take(MAX_FIELD_LENGTH).collect())
+    }
+}
+
+/// OpenGraph tags, with <title> and the plain description as fallbacks
+fn parse_metadata(html: &str) -> LinkMetadata {
+    let mut metadata = LinkMetadata::default();
+    let mut description = None;
+    for tag in html.split('<').filter(|tag| {
+        tag.get(..5)
+            .map_or(false, |start| start.eq_ignore_ascii_case("meta "))
+    }) {
+        let tag = tag.split('>').next().unwrap_or_default();
+        let key = match attribute(tag, "property").or_else(|| attribute(tag, "name")) {
+            Some(key) => key.to_ascii_lowercase(),
+            None => continue,
+        };
+        let value = match attribute(tag, "content").and_then(clean) {
+            Some(value) => value,
+            None => continue,
+        };
+        match key.as_str() {
+            "og:title" => metadata.title = Some(value),
+            "og:description" => metadata.description = Some(value),
+            "og:image" => metadata.image_url = Some(value),
+            "og:site_name" => metadata.site_name = Some(value),
+            "description" => description = Some(value),
+            _ => {}
+        }
+    }
+
+    if metadata.title.is_none() {
+        let lower = html.to_ascii_lowercase();
+        metadata.title = lower.find("<title").and_then(|start| {
+            let open = start + lower[start..].find('>')? + 1;
+            let close = open + lower[open..].find("</title")?;
+            clean(&html[open..close])
+        });
+    }
+    metadata.description = metadata.description.or(description);
+    metadata
+}
+
+/// A link pointing into the server's own network, see is_internal
+#[derive(Debug)]
+struct InternalAddress;
+
+impl fmt::Display for InternalAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Links to internal addresses aren't fetched")
+    }
+}
+
+impl std::error::Error for InternalAddress {}
+
+/// Loopback, private, link-local and other addresses that aren't on the public internet. Links
+/// are posted by anyone, so the server mustn't fetch these for them.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, _, _] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local fc00::/7 and link-local fe80::/10
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80
+                || ip.to_ipv4().map_or(false, |ip| is_internal(IpAddr::V4(ip)))
+        }
+    }
+}
+
+/// Fails for anything but http(s) URLs whose host only resolves to public addresses. reqwest
+/// resolves the host again when connecting, so this stops links and redirects to internal hosts
+/// but not DNS that changes its answer in between.
+async fn check_public(url: &Url) -> anyhow::Result<()> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        anyhow::bail!("Not a web page");
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("No host"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs = tokio::task::spawn_blocking(move || {
+        (host.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<_>>())
+    })
+    .await??;
+    if addrs.is_empty() || addrs.iter().any(|addr| is_internal(addr.ip())) {
+        return Err(InternalAddress.into());
+    }
+    Ok(())
+}
+
+/// Sends the request and follows redirects by hand, so each hop goes through check_public
+async fn send(
+    client: &reqwest::Client,
+    method: Method,
+    link: &str,
+) -> anyhow::Result<reqwest::Response> {
+    let mut url = Url::parse(link)?;
+    for _ in 0..=MAX_REDIRECTS {
+        check_public(&url).await?;
+        let response = client.request(method.clone(), url.clone()).send().await?;
+        if !response.status().is_redirection() {
+            return Ok(response);
+        }
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| anyhow::anyhow!("Redirect without a location"))?;
+        url = url.join(location)?;
+    }
+    anyhow::bail!("Too many redirects")
+}
+
+async fn fetch_metadata(client: &reqwest::Client, link: &str) -> anyhow::Result<LinkMetadata> {
+    let mut response = send(client, Method::GET, link).await?.error_for_status()?;
+    let mut page = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        page.extend_from_slice(&chunk);
+        if page.len() >= MAX_PAGE_BYTES {
+            break;
+        }
+    }
+    Ok(parse_metadata(&String::from_utf8_lossy(&page)))
+}
+
+async fn store_metadata(
+    pool: &sqlx::PgPool,
+    pid: i32,
+    metadata: &LinkMetadata,
+) -> Result<NaiveDateTime, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"
+        INSERT INTO link_metadata (pid, title, description, image_url, site_name)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (pid) DO UPDATE
+        SET title = $2, description = $3, image_url = $4, site_name = $5, fetched_at = now()
+        RETURNING fetched_at
+        "#,
+        pid,
+        metadata.title,
+        metadata.description,
+        metadata.image_url,
+        metadata.site_name
+    )
+    .fetch_one(pool)
+    .await?
+    .fetched_at)
+}
+
+fn client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .user_agent("throatql link checker")
+        // Followed in send instead, after checking where they lead
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+}
+
+/// Fetches the page again, for when it changed. The post's author and the sub's mods can do
+/// this.
+pub async fn refresh_metadata(context: &Context, id: ID) -> Result<LinkMetadata, FieldError> {
+    let uid = context.user.user_id()?;
+    let pid = context.config.post_ids.decode(&id)?;
+    let post = sqlx::query!(
+        r#"
+        SELECT uid, sid, link
+        FROM sub_post
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Post not found {}", *id))?;
+    if post.uid.as_deref() != Some(uid) && !context.user.is_mod(&post.sid.unwrap_or_default()) {
+        return Err("Not Authorized".into());
+    }
+    let link = post.link.ok_or("Only link posts have link metadata")?;
+
+    let client = client()?;
+    // The reason stays in the log, it would tell what the server can reach
+    let mut metadata = fetch_metadata(&client, &link).await.map_err(|err| {
+        log::info!("Could not fetch {} - {}", link, err);
+        "Could not fetch the link"
+    })?;
+    metadata.fetched_at = Some(store_metadata(&context.pool, pid, &metadata).await?);
+    Ok(metadata)
+}
+
+/// None when the answer doesn't say either way, like rate limits or server errors. Links to
+/// internal addresses always get None, so their status can't be used to probe the network.
+async fn check(client: &reqwest::Client, link: &str) -> Option<bool> {
+    if !link.starts_with("http://") && !link.starts_with("https://") {
+        return None;
+    }
+    let response = match send(client, Method::HEAD, link).await {
+        // Plenty of sites don't do HEAD
+        Ok(response) if response.status() == reqwest::StatusCode::METHOD_NOT_ALLOWED => {
+            send(client, Method::GET, link).await
+        }
+        response => response,
+    };
+    match response {
+        Ok(response) if response.status().is_success() => Some(false),
+        Ok(response) => match response.status().as_u16() {
+            404 | 410 => Some(true),
+            _ => None,
+        },
+        Err(err) if err.is::<InternalAddress>() => None,
+        Err(err)
+            if err
+                .downcast_ref::<reqwest::Error>()
+                .map_or(false, reqwest::Error::is_timeout) =>
+        {
+            None
+        }
+        // Gone domains and refused connections
+        Err(_) => Some(true),
+    }
+}
+
+async fn check_batch(pool: &sqlx::PgPool, client: &reqwest::Client) -> Result<(), sqlx::Error> {
+    let posts = sqlx::query!(
+        r#"
+        SELECT p.pid, p.link as "link!", m.pid IS NULL as "needs_metadata!"
+        FROM sub_post p
+        LEFT JOIN link_status s ON s.pid = p.pid
+        LEFT JOIN link_metadata m ON m.pid = p.pid
+        WHERE p.link IS NOT NULL AND coalesce(p.deleted, 0) = 0
+            AND coalesce(s.manual, false) = false
+            AND (s.checked IS NULL OR s.checked < now() - $1 * interval '1 day')
+        ORDER BY s.checked NULLS FIRST, p.posted DESC
+        LIMIT $2
+        "#,
+        RECHECK_DAYS as f64,
+        BATCH_SIZE
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    for post in posts {
+        let dead = check(client, &post.link).await;
+        // Unclear answers still count as checked, so one flaky site can't hold up the queue. A
+        // first one is stored as NULL, which reads as UNKNOWN.
+        sqlx::query!(
+            r#"
+            INSERT INTO link_status (pid, dead)
+            VALUES ($1, $2)
+            ON CONFLICT (pid) DO UPDATE
+            SET dead = coalesce($2, link_status.dead), checked = now()
+            WHERE link_status.manual = false
+            "#,
+            post.pid,
+            dead
+        )
+        .execute(pool)
+        .await?;
+
+        // New links get their metadata on the first visit, later changes need a refresh
+        if dead == Some(false) && post.needs_metadata {
+            match fetch_metadata(client, &post.link).await {
+                Ok(metadata) => {
+                    store_metadata(pool, post.pid, &metadata).await?;
+                }
+                Err(err) => log::debug!("No metadata for {} - {}", post.link, err),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Background worker, checks the least recently checked links every ten minutes and fetches the
+/// metadata of new ones. Only run it in one process.
+pub async fn run(pool: sqlx::PgPool) {
+    let client = match client() {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("Could not set up the link checker - {}", err);
+            return;
+        }
+    };
+
+    loop {
+        if let Err(err) = check_batch(&pool, &client).await {
+            log::error!("Link check failed - {}", err);
+        }
+        tokio::time::delay_for(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_addresses_are_refused() {
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_internal(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(!is_internal("93.184.216.34".parse().unwrap()));
+        assert!(!is_internal("2606:2800:220:1::".parse().unwrap()));
+    }
+}
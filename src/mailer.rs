@@ -0,0 +1,96 @@
+use crate::config::Config;
+use async_trait::async_trait;
+use lettre::{smtp::authentication::Credentials, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use std::sync::Arc;
+
+/// Anything that can deliver a plain text email. Mutations only ever talk to this trait so the
+/// transport can be swapped out (SMTP in production, logging in development).
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()>;
+}
+
+/// Where to send mail through, see SMTP_HOST
+#[derive(Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub from: String,
+    pub credentials: Option<(String, String)>,
+}
+
+// Keeps the password out of logs
+impl std::fmt::Debug for SmtpConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmtpConfig")
+            .field("host", &self.host)
+            .field("from", &self.from)
+            .field("user", &self.credentials.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+/// SMTP when a server is configured, the log otherwise
+pub fn from_config(config: &Config) -> Arc<dyn Mailer> {
+    match config.smtp {
+        Some(ref smtp) => Arc::new(SmtpMailer::new(
+            smtp.host.clone(),
+            smtp.from.clone(),
+            smtp.credentials.clone(),
+        )),
+        None => Arc::new(LogMailer),
+    }
+}
+
+pub struct SmtpMailer {
+    host: String,
+    from: String,
+    credentials: Option<Credentials>,
+}
+
+impl SmtpMailer {
+    pub fn new(host: String, from: String, credentials: Option<(String, String)>) -> Self {
+        SmtpMailer {
+            host,
+            from,
+            credentials: credentials.map(|(user, pass)| Credentials::new(user, pass)),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()> {
+        let email = EmailBuilder::new()
+            .to(to)
+            .from(self.from.as_str())
+            .subject(subject)
+            .text(body)
+            .build()?;
+
+        let host = self.host.clone();
+        let credentials = self.credentials.clone();
+
+        // lettre's transport is blocking, keep it off the executor
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let mut client = SmtpClient::new_simple(&host)?;
+            if let Some(credentials) = credentials {
+                client = client.credentials(credentials);
+            }
+            client.transport().send(email.into())?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+/// Used when no SMTP server is configured, just writes the mail to the log.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()> {
+        log::info!("Mail to {} - {}\n{}", to, subject, body);
+        Ok(())
+    }
+}
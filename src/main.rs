@@ -1,20 +1,31 @@
-use model::{auth, Context, Query, Schema};
-use std::env;
+use model::{
+    brigade, check,
+    config::{self, Config},
+    digest, events, language, links, mailer, mobile, push, server,
+    statements::StatementLogger,
+};
+use std::{env, sync::Arc};
 use warp::{http::Response, Filter};
 
-fn schema() -> Schema {
-    Schema::new(
-        Query,
-        juniper::EmptyMutation::<Context>::new(),
-        juniper::EmptySubscription::<Context>::new(),
-    )
-}
-
 #[tokio::main]
 async fn main() {
     // std::env::set_var("RUST_LOG", "warp_async");
     dotenv::dotenv().ok();
-    env_logger::init();
+    StatementLogger::init(env_logger::Builder::from_default_env().build()).unwrap();
+
+    // `throatql check [--repair]` looks for data the loaders can't deal with and exits
+    if env::args().nth(1).as_deref() == Some("check") {
+        let repair = env::args().skip(2).any(|arg| arg == "--repair");
+        std::process::exit(run_check(repair).await);
+    }
+
+    if let Err(problems) = config::validate_env() {
+        eprintln!("Can't start, the configuration has problems:");
+        for problem in problems {
+            eprintln!("  - {}", problem);
+        }
+        std::process::exit(1);
+    }
 
     let log = warp::log("warp_server");
 
@@ -28,38 +39,96 @@ async fn main() {
 
     log::info!("Listening on 127.0.0.1:8080");
 
-    let pool = sqlx::Pool::connect(&env::var("DATABASE_URL").unwrap())
-        .await
-        .unwrap();
+    let config = Arc::new(Config::from_env());
 
-    let auth_pool = pool.clone();
-    let user = warp::any().and(
-        warp::header::<String>("authorization")
-            .and(warp::any().map(move || auth_pool.clone()))
-            .map(auth::UserState::login)
-            .or(warp::any().map(auth::UserState::anonymous))
-            .unify(),
-    );
-    let state = warp::any()
-        .and(user)
-        .map(move |user: auth::UserState| -> Context { Context::new(user, pool.clone()) });
-    let graphql_filter = juniper_warp::make_graphql_filter(schema(), state.boxed());
+    // validate_env checked it's there
+    let pool = match server::connect(&env::var("DATABASE_URL").unwrap_or_default(), &config).await {
+        Ok(pool) => pool,
+        Err(err) => {
+            log::error!("Giving up on connecting to the database - {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let events = events::from_config(&config, &pool);
+    if config.push_worker {
+        tokio::spawn(push::run(pool.clone(), events.clone(), config.clone()));
+        tokio::spawn(mobile::run(pool.clone(), events.clone(), config.clone()));
+    }
+    if config.link_checker {
+        tokio::spawn(links::run(pool.clone()));
+    }
+    if config.language_detector {
+        tokio::spawn(language::run(pool.clone()));
+    }
+    if config.brigade_detector {
+        tokio::spawn(brigade::run(pool.clone()));
+    }
+    if config.digest_worker {
+        tokio::spawn(digest::run(
+            pool.clone(),
+            mailer::from_config(&config),
+            config.clone(),
+        ));
+    }
 
     warp::serve(
-        warp::get()
-            .and(warp::path("graphiql"))
-            .and(juniper_warp::graphiql_filter("/graphql", None))
-            .or(homepage)
-            .or(warp::path("graphql").and(graphql_filter))
-            .with(
-                warp::cors()
-                    .allow_method("POST")
-                    .allow_header("authorization")
-                    .allow_headers(vec!["content-type"])
-                    .allow_any_origin(),
-            )
+        homepage
+            .or(server::make_routes(config, pool, events))
             .with(log),
     )
     .run(([127, 0, 0, 1], 8080))
     .await
 }
+
+/// Exit code for `throatql check`, non-zero while problems are left
+async fn run_check(repair: bool) -> i32 {
+    let url = match env::var("DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("DATABASE_URL is not set");
+            return 2;
+        }
+    };
+    let pool = match server::connect(&url, &Config::from_env()).await {
+        Ok(pool) => pool,
+        Err(err) => {
+            eprintln!("Could not connect to the database - {}", err);
+            return 2;
+        }
+    };
+    let findings = match check::run(&pool, repair).await {
+        Ok(findings) => findings,
+        Err(err) => {
+            eprintln!("Checking failed - {}", err);
+            return 2;
+        }
+    };
+
+    if findings.is_empty() {
+        println!("No problems found");
+        return 0;
+    }
+    let mut left = false;
+    for finding in &findings {
+        println!(
+            "{} {}: {}",
+            finding.count,
+            finding.problem,
+            finding.examples.join(", ")
+        );
+        match finding.repaired {
+            Some(repaired) => println!("  repaired {}", repaired),
+            None if repair => {
+                println!("  can't be repaired automatically");
+                left = true;
+            }
+            None => left = true,
+        }
+    }
+    if left {
+        1
+    } else {
+        0
+    }
+}
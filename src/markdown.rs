@@ -0,0 +1,99 @@
+use lazy_static::lazy_static;
+use pulldown_cmark::{escape::escape_html, html, CowStr, Event, Options, Parser, Tag};
+use regex::{Captures, Regex};
+
+lazy_static! {
+    // `@name`, `/u/name` and `/s/name`, the leading slash optional for the last two
+    static ref MENTION: Regex =
+        Regex::new(r"(^|[^\w/])(?:@|/?(u|s)/)([\w-]{1,32})").unwrap();
+    static ref SANITIZER: ammonia::Builder<'static> = {
+        let mut builder = ammonia::Builder::default();
+        builder.link_rel(Some("nofollow noopener"));
+        builder
+    };
+}
+
+/// HTML for post and comment content, mentions and sub links become links into the site.
+/// Whatever the markdown has in it, the result is safe to put into a page.
+pub fn render(site_url: &str, markdown: &str) -> String {
+    let mut in_link = false;
+    let mut in_code = false;
+    let events = Parser::new_ext(
+        markdown,
+        Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES,
+    )
+    .map(|event| match event {
+        Event::Start(Tag::Link(..)) | Event::Start(Tag::Image(..)) => {
+            in_link = true;
+            event
+        }
+        Event::End(Tag::Link(..)) | Event::End(Tag::Image(..)) => {
+            in_link = false;
+            event
+        }
+        Event::Start(Tag::CodeBlock(_)) => {
+            in_code = true;
+            event
+        }
+        Event::End(Tag::CodeBlock(_)) => {
+            in_code = false;
+            event
+        }
+        Event::Text(text) if !in_link && !in_code => link_mentions(site_url, &text)
+            .map_or(Event::Text(text), |html| Event::Html(CowStr::from(html))),
+        event => event,
+    });
+
+    let mut unsafe_html = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut unsafe_html, events);
+    SANITIZER.clean(&unsafe_html).to_string()
+}
+
+/// The text as HTML with its mentions linked, None when it has none
+fn link_mentions(site_url: &str, text: &str) -> Option<String> {
+    if !MENTION.is_match(text) {
+        return None;
+    }
+    let mut escaped = String::new();
+    escape_html(&mut escaped, text).ok()?;
+    let linked = MENTION.replace_all(&escaped, |caps: &Captures| {
+        let section = caps.get(2).map_or("u", |section| section.as_str());
+        format!(
+            r#"{}<a href="{}/{}/{}">{}</a>"#,
+            &caps[1],
+            site_url,
+            section,
+            &caps[3],
+            &caps[0][caps[1].len()..]
+        )
+    });
+    Some(linked.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mentions_and_sub_links() {
+        let html = render("https://example.com", "hi @alice, see /s/news and u/bob");
+        assert!(html.contains(r#"href="https://example.com/u/alice""#));
+        assert!(html.contains(">@alice</a>"));
+        assert!(html.contains(r#"href="https://example.com/s/news""#));
+        assert!(html.contains(r#"href="https://example.com/u/bob""#));
+        assert!(html.contains("nofollow"));
+    }
+
+    #[test]
+    fn code_and_emails_are_left_alone() {
+        let html = render("https://example.com", "`@alice` mail me@example.com");
+        assert_eq!(html, "<p><code>@alice</code> mail me@example.com</p>\n");
+    }
+
+    #[test]
+    fn scripts_are_removed() {
+        let html = render("", "<script>alert(1)</script>[x](javascript:alert(1))");
+        assert!(!html.contains("script"));
+        assert!(!html.contains("javascript"));
+    }
+}
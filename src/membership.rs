@@ -0,0 +1,413 @@
+use crate::{events::Event, moderation, sub::Sub, user::UserRef, Context};
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLObject};
+
+/// Most requests shown to mods at once, oldest first
+const PENDING_SHOWN: i64 = 200;
+/// Highest minimum account age a sub can ask for, ten years
+const MAX_ACCOUNT_AGE_DAYS: i32 = 3650;
+
+#[derive(GraphQLObject, Debug)]
+pub struct JoinRequest {
+    pub name: String,
+    pub requested: NaiveDateTime,
+}
+
+/// Restricted subs only let approved members in, see requestToJoin
+pub async fn is_restricted(pool: &sqlx::PgPool, sid: &str) -> Result<bool, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT value
+        FROM sub_metadata
+        WHERE sid = $1 AND key = 'restricted'
+        "#,
+        sid
+    )
+    .fetch_optional(pool)
+    .await?
+    .and_then(|row| row.value)
+    .as_deref()
+        == Some("1"))
+}
+
+/// Whether the viewer may take part in the sub: anyone can outside restricted subs, in them only
+/// those let in and the mods
+async fn is_member(context: &Context, sid: &str) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    if context.user.is_mod(sid) || !is_restricted(&context.pool, sid).await? {
+        return Ok(true);
+    }
+    let approved = sqlx::query!(
+        r#"
+        SELECT uid
+        FROM sub_member_request
+        WHERE uid = $1 AND sid = $2 AND status = 'approved'
+        "#,
+        uid,
+        sid
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+    Ok(approved.is_some())
+}
+
+/// Fails in restricted subs unless the viewer was let in or mods the sub
+pub async fn check_member(context: &Context, sid: &str) -> Result<(), FieldError> {
+    if is_member(context, sid).await? {
+        Ok(())
+    } else {
+        Err("Only approved members can post here".into())
+    }
+}
+
+/// What a sub asks of accounts before they post or comment there. Mods are exempt.
+#[derive(GraphQLObject, Debug, Default)]
+pub struct PostingRequirements {
+    /// Days since signing up, null for none
+    pub min_account_age_days: Option<i32>,
+    /// Score of the account, null for none
+    pub min_score: Option<i32>,
+}
+
+pub async fn posting_requirements(
+    pool: &sqlx::PgPool,
+    sid: &str,
+) -> Result<PostingRequirements, FieldError> {
+    let mut requirements = PostingRequirements::default();
+    let rows = sqlx::query!(
+        r#"
+        SELECT key as "key!", value
+        FROM sub_metadata
+        WHERE sid = $1 AND key IN ('min_account_age_days', 'min_score')
+        "#,
+        sid
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await;
+    for row in rows {
+        let row = row?;
+        let value = row.value.and_then(|value| value.parse::<i32>().ok());
+        match row.key.as_str() {
+            "min_account_age_days" => requirements.min_account_age_days = value,
+            _ => requirements.min_score = value,
+        }
+    }
+    Ok(requirements)
+}
+
+/// For the sub's mods, the change ends up in the mod log. `None` drops a requirement.
+pub async fn set_posting_requirements(
+    context: &Context,
+    sub: String,
+    min_account_age_days: Option<i32>,
+    min_score: Option<i32>,
+) -> Result<PostingRequirements, FieldError> {
+    let uid = context.user.user_id()?;
+    if min_account_age_days.map_or(false, |days| days < 0) {
+        return Err("The minimum account age can't be negative".into());
+    }
+    if min_account_age_days.map_or(false, |days| days > MAX_ACCOUNT_AGE_DAYS) {
+        return Err(format!(
+            "The minimum account age can be at most {} days",
+            MAX_ACCOUNT_AGE_DAYS
+        )
+        .into());
+    }
+    let sub = load_sub(context, sub).await?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM sub_metadata
+        WHERE sid = $1 AND key IN ('min_account_age_days', 'min_score')
+        "#,
+        sub.sid
+    )
+    .execute(&mut tx)
+    .await?;
+    let mut changes = vec![];
+    for (key, value) in vec![
+        ("min_account_age_days", min_account_age_days),
+        ("min_score", min_score),
+    ] {
+        if let Some(value) = value {
+            sqlx::query!(
+                r#"
+                INSERT INTO sub_metadata (sid, key, value)
+                VALUES ($1, $2, $3)
+                "#,
+                sub.sid,
+                key,
+                value.to_string()
+            )
+            .execute(&mut tx)
+            .await?;
+            changes.push(format!("{} {}", key, value));
+        }
+    }
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sub.sid.clone()),
+        "set_posting_requirements",
+        vec![],
+        Some(changes.join(", ")).filter(|changes| !changes.is_empty()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    posting_requirements(&context.pool, &sub.sid).await
+}
+
+/// Fails when the viewer's account is newer or has a lower score than the sub asks for
+pub async fn check_requirements(context: &Context, sid: &str) -> Result<(), FieldError> {
+    let uid = context.user.user_id()?;
+    if context.user.is_mod(sid) {
+        return Ok(());
+    }
+    let requirements = posting_requirements(&context.pool, sid).await?;
+    if requirements.min_account_age_days.is_none() && requirements.min_score.is_none() {
+        return Ok(());
+    }
+    let account = sqlx::query!(
+        r#"
+        SELECT joindate, score
+        FROM public.user
+        WHERE uid = $1
+        "#,
+        uid
+    )
+    .fetch_one(&context.pool)
+    .await?;
+    if let Some(days) = requirements.min_account_age_days {
+        // sub_metadata can also be written by hand, so the cap alone isn't enough
+        let joined_by = Utc::now()
+            .naive_utc()
+            .checked_sub_signed(Duration::days(days as i64))
+            .ok_or_else(|| format!("Invalid minimum account age {}", days))?;
+        let old_enough = account.joindate.map_or(false, |joined| joined <= joined_by);
+        if !old_enough {
+            return Err(format!(
+                "Your account has to be {} days old to post or comment here",
+                days
+            )
+            .into());
+        }
+    }
+    if let Some(score) = requirements.min_score {
+        if account.score < score {
+            return Err(format!("You need a score of {} to post or comment here", score).into());
+        }
+    }
+    Ok(())
+}
+
+async fn load_sub(context: &Context, name: String) -> Result<Sub, FieldError> {
+    context
+        .sub_loader
+        .load(name.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })
+}
+
+/// Asks the mods of a restricted sub to let the viewer in. Asking again after being denied is
+/// allowed, it puts the request back in the queue.
+pub async fn request_to_join(context: &Context, sub: String) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub = load_sub(context, sub).await?;
+    if !is_restricted(&context.pool, &sub.sid).await? {
+        return Err("Anyone can join this sub".into());
+    }
+
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO sub_member_request (uid, sid)
+        VALUES ($1, $2)
+        ON CONFLICT (uid, sid) DO UPDATE
+        SET status = 'pending', requested = now(), decided = NULL, decided_by = NULL
+        WHERE sub_member_request.status = 'denied'
+        RETURNING status
+        "#,
+        uid,
+        sub.sid
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+    if row.is_none() {
+        return Err("You already asked to join".into());
+    }
+
+    context
+        .publish(Event::JoinRequested {
+            uid: uid.to_string(),
+            sid: sub.sid,
+        })
+        .await;
+    Ok(true)
+}
+
+pub async fn pending_members(context: &Context, sid: &str) -> Result<Vec<JoinRequest>, FieldError> {
+    if !context.user.is_mod(sid) {
+        return Err("Not Authorized".into());
+    }
+
+    Ok(sqlx::query!(
+        r#"
+        SELECT u.name as "name!", r.requested
+        FROM sub_member_request r
+        JOIN public.user u ON u.uid = r.uid
+        WHERE r.sid = $1 AND r.status = 'pending'
+        ORDER BY r.requested
+        LIMIT $2
+        "#,
+        sid,
+        PENDING_SHOWN
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| JoinRequest {
+        name: row.name,
+        requested: row.requested,
+    })
+    .collect())
+}
+
+/// Settles a pending request, approved members are subscribed to the sub as well
+pub async fn decide(
+    context: &Context,
+    sub: String,
+    user: String,
+    approve: bool,
+) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub = load_sub(context, sub).await?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+    let member = context
+        .user_loader
+        .load(UserRef::name(user))
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+
+    let mut tx = context.pool.begin().await?;
+    let decided = sqlx::query!(
+        r#"
+        UPDATE sub_member_request
+        SET status = $3, decided = now(), decided_by = $4
+        WHERE uid = $1 AND sid = $2 AND status = 'pending'
+        RETURNING uid
+        "#,
+        member.uid,
+        sub.sid,
+        if approve { "approved" } else { "denied" },
+        uid
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    if decided.is_none() {
+        return Err(format!("{} hasn't asked to join", member.name.unwrap_or_default()).into());
+    }
+    if approve {
+        sqlx::query!(
+            r#"
+            INSERT INTO sub_subscriber (uid, sid, status, time)
+            SELECT $1, $2, 1, now()
+            WHERE NOT EXISTS (
+                SELECT 1 FROM sub_subscriber WHERE uid = $1 AND sid = $2 AND status = 1
+            )
+            "#,
+            member.uid,
+            sub.sid
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sub.sid.clone()),
+        if approve {
+            "approve_member"
+        } else {
+            "deny_member"
+        },
+        vec![member.uid.clone()],
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    context
+        .publish(Event::MembershipDecided {
+            uid: member.uid,
+            sid: sub.sid,
+            approved: approve,
+        })
+        .await;
+    Ok(true)
+}
+
+/// Adds the sub to the viewer's home feed, taking back a block of it. Restricted subs need an
+/// approved requestToJoin first.
+pub async fn subscribe(context: &Context, sub: String) -> Result<Sub, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub = load_sub(context, sub).await?;
+    if !is_member(context, &sub.sid).await? {
+        return Err("Only approved members can join, see requestToJoin".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    // Throat keeps blocks in the same table with status 2
+    sqlx::query!(
+        r#"
+        DELETE FROM sub_subscriber
+        WHERE uid = $1 AND sid = $2 AND status <> 1
+        "#,
+        uid,
+        sub.sid
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO sub_subscriber (uid, sid, status, time)
+        SELECT $1, $2, 1, now()
+        WHERE NOT EXISTS (
+            SELECT 1 FROM sub_subscriber WHERE uid = $1 AND sid = $2 AND status = 1
+        )
+        "#,
+        uid,
+        sub.sid
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(sub)
+}
+
+/// Fine if the viewer wasn't subscribed
+pub async fn unsubscribe(context: &Context, sub: String) -> Result<Sub, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub = load_sub(context, sub).await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM sub_subscriber
+        WHERE uid = $1 AND sid = $2 AND status = 1
+        "#,
+        uid,
+        sub.sid
+    )
+    .execute(&context.pool)
+    .await?;
+    Ok(sub)
+}
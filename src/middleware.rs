@@ -0,0 +1,619 @@
+use crate::{
+    auth::UserState,
+    cache, errors, policy,
+    ratelimit::{self, RateLimit},
+    site,
+    statements::{self, Statement},
+    version, visibility, Context, Schema,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use graphql_parser::query::{Definition, OperationDefinition, Selection};
+use http::{header, StatusCode};
+use juniper::{
+    http::{GraphQLBatchRequest, GraphQLRequest},
+    InputValue,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Plain `http` types so any server framework can hand requests to this module, see
+/// server.rs for the warp side of things
+pub type Response = http::Response<Vec<u8>>;
+
+/// Mutations that keep working in maintenance mode, otherwise there'd be no way back out of it.
+/// previewMarkdown doesn't write anything.
+const MAINTENANCE_EXEMPT: &[&str] = &["setMaintenanceMode", "previewMarkdown", "__typename"];
+
+/// Just enough of a GraphQL request to look at the document before juniper executes it
+#[derive(Deserialize)]
+struct RawOperation {
+    query: String,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+    #[serde(default)]
+    variables: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawRequest {
+    Single(RawOperation),
+    Batch(Vec<RawOperation>),
+}
+
+impl RawRequest {
+    fn operations(&self) -> Vec<&RawOperation> {
+        match self {
+            RawRequest::Single(operation) => vec![operation],
+            RawRequest::Batch(operations) => operations.iter().collect(),
+        }
+    }
+}
+
+/// Root fields the operation would run with the kind of operation they're in. Documents that
+/// don't parse are left for juniper to report on.
+fn root_fields(operation: &RawOperation) -> Vec<(&'static str, String)> {
+    let document = match graphql_parser::parse_query::<&str>(&operation.query) {
+        Ok(document) => document,
+        Err(_) => return vec![],
+    };
+
+    document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Operation(OperationDefinition::Query(query)) => {
+                Some(("query", query.name, &query.selection_set))
+            }
+            Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+                Some(("query", None, selection_set))
+            }
+            Definition::Operation(OperationDefinition::Mutation(mutation)) => {
+                Some(("mutation", mutation.name, &mutation.selection_set))
+            }
+            Definition::Operation(OperationDefinition::Subscription(subscription)) => Some((
+                "subscription",
+                subscription.name,
+                &subscription.selection_set,
+            )),
+            Definition::Fragment(_) => None,
+        })
+        .filter(|(_, name, _)| match operation.operation_name {
+            Some(ref wanted) => *name == Some(wanted.as_str()),
+            None => true,
+        })
+        .flat_map(|(kind, _, selection_set)| {
+            selection_set
+                .items
+                .iter()
+                .map(move |selection| match selection {
+                    Selection::Field(field) => (kind, field.name.to_string()),
+                    // Can't tell what a fragment expands to without resolving it, assume the worst
+                    _ => (kind, "...".to_string()),
+                })
+        })
+        .collect()
+}
+
+/// visibility::trim reads a type's name from its `name` key, so non-admins don't get to rename
+/// fields in introspection
+fn refuse_aliased_introspection(
+    context: &Context,
+    introspection: &visibility::Introspection,
+) -> Option<Response> {
+    if introspection.aliased && !context.user.is_admin() {
+        Some(error_response(
+            StatusCode::BAD_REQUEST,
+            "BAD_REQUEST",
+            "Introspection fields can't be aliased",
+        ))
+    } else {
+        None
+    }
+}
+
+/// Root mutation fields the operation would run, empty for queries
+fn mutation_fields(fields: &[(&'static str, String)]) -> Vec<String> {
+    fields
+        .iter()
+        .filter(|(kind, _)| *kind == "mutation")
+        .map(|(_, field)| field.clone())
+        .collect()
+}
+
+/// Refuses operations OPERATION_POLICY doesn't let the viewer's role run
+fn check_policy(context: &Context, fields: &[(&'static str, String)]) -> Option<Response> {
+    let field = policy::refused(&context.config.operation_policy, &context.user, fields)?;
+    Some(error_response(
+        StatusCode::FORBIDDEN,
+        "OPERATION_NOT_ALLOWED",
+        &format!(
+            "{} is not allowed for {} requests",
+            field,
+            policy::role(&context.user)
+        ),
+    ))
+}
+
+pub fn error_response(status: StatusCode, code: &str, message: &str) -> Response {
+    let body = json!({
+        "data": null,
+        "errors": [{
+            "message": message,
+            "extensions": { "code": code },
+        }],
+    });
+    json_response(body.to_string().into_bytes(), status)
+}
+
+/// Apollo tracing extension. juniper has no hooks around individual resolvers, so only the
+/// operation as a whole is timed and the resolver list stays empty.
+struct Tracing {
+    start_time: DateTime<Utc>,
+    started: Instant,
+}
+
+impl Tracing {
+    fn start(context: &Context) -> Option<Self> {
+        if context.config.apollo_tracing {
+            Some(Tracing {
+                start_time: Utc::now(),
+                started: Instant::now(),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn extension(&self) -> serde_json::Value {
+        let duration = self.started.elapsed();
+        let end_time = self.start_time
+            + chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::zero());
+        json!({
+            "version": 1,
+            "startTime": self.start_time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "endTime": end_time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "duration": duration.as_nanos() as u64,
+            "execution": { "resolvers": [] },
+        })
+    }
+
+    /// Adds the extension to every operation's result, batches get one each
+    fn add_to(&self, response: &mut serde_json::Value) {
+        add_extension(response, "tracing", self.extension());
+    }
+}
+
+/// How many of the slowest statements a slow operation is logged with
+const SLOW_STATEMENTS_LOGGED: usize = 5;
+
+/// Logs how many statements every operation ran, a count growing with the page size means a
+/// loader is being bypassed. Operations that took longer than SLOW_OPERATION_MS are logged as
+/// warnings together with the statements that took longest.
+fn log_statements(
+    context: &Context,
+    operations: &[&RawOperation],
+    elapsed: Duration,
+    statements: &[Statement],
+) {
+    let names: Vec<&str> = operations
+        .iter()
+        .map(|operation| operation.operation_name.as_deref().unwrap_or("<unnamed>"))
+        .collect();
+    log::debug!(
+        "Operation [{}] ran {} statements in {:?}",
+        names.join(", "),
+        statements.len(),
+        elapsed
+    );
+
+    match context.config.slow_operation_threshold {
+        Some(threshold) if elapsed >= threshold => {}
+        _ => return,
+    }
+
+    // Only the keys, values may well be passwords
+    let variables: Vec<&str> = operations
+        .iter()
+        .filter_map(|operation| operation.variables.as_ref()?.as_object())
+        .flat_map(|variables| variables.keys())
+        .map(String::as_str)
+        .collect();
+    let viewer = match context.user {
+        UserState::LoggedIn { ref name, .. } => name.as_str(),
+        UserState::Anonymous => "anonymous",
+    };
+
+    let mut slowest = statements.iter().collect::<Vec<_>>();
+    slowest.sort_by(|a, b| b.elapsed.cmp(&a.elapsed));
+    let slowest: Vec<String> = slowest
+        .into_iter()
+        .take(SLOW_STATEMENTS_LOGGED)
+        .map(|statement| format!("{:?} {}", statement.elapsed, statement.summary))
+        .collect();
+
+    log::warn!(
+        "Slow operation [{}] took {:?} for {} with variables [{}], {} statements, slowest:\n  {}",
+        names.join(", "),
+        elapsed,
+        viewer,
+        variables.join(", "),
+        statements.len(),
+        slowest.join("\n  ")
+    );
+}
+
+/// Anonymous viewers all get the same answer to the same query, so theirs can be shared for a
+/// few seconds. Errors in the answer depend on the language it's asked for in.
+fn cache_key(context: &Context, request: &[u8]) -> Option<Vec<u8>> {
+    if context.user == UserState::Anonymous
+        && context.config.anonymous_cache_ttl.as_secs() > 0
+        && !context.config.apollo_tracing
+    {
+        let mut key = request.to_vec();
+        if let Some(language) = error_language(context) {
+            key.push(0);
+            key.extend_from_slice(language.as_bytes());
+        }
+        Some(key)
+    } else {
+        None
+    }
+}
+
+fn error_language(context: &Context) -> Option<&str> {
+    errors::pick_language(
+        &context.config.error_translations,
+        &context.request.languages,
+    )
+}
+
+fn json_response(body: Vec<u8>, status: StatusCode) -> Response {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+/// Puts the request id in every error's extensions so bug reports can be matched to the logs
+fn add_request_id(response: &mut serde_json::Value, id: &str) {
+    let results: Vec<&mut serde_json::Value> = match response {
+        serde_json::Value::Array(results) => results.iter_mut().collect(),
+        result => vec![result],
+    };
+    for result in results {
+        if let Some(errors) = result.get_mut("errors").and_then(|e| e.as_array_mut()) {
+            for error in errors {
+                if let serde_json::Value::Object(error) = error {
+                    let extensions = error.entry("extensions").or_insert_with(|| json!({}));
+                    if let serde_json::Value::Object(extensions) = extensions {
+                        extensions.insert("requestId".into(), json!(id));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn with_request_id_header(mut response: Response, id: &str) -> Response {
+    if let Ok(value) = header::HeaderValue::from_str(id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    response
+}
+
+fn with_rate_limit_headers(mut response: Response, rate: Option<RateLimit>) -> Response {
+    for (name, value) in rate.iter().flat_map(|rate| rate.headers().to_vec()) {
+        if let Ok(value) = header::HeaderValue::from_str(&value) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// Puts the viewer's rate limit in every operation's extensions, batches get one each
+fn add_rate_limit(response: &mut serde_json::Value, rate: &RateLimit) {
+    add_extension(response, "rateLimit", rate.extension());
+}
+
+/// Sets `name` in the extensions of every result, a batch has one per operation
+fn add_extension(response: &mut serde_json::Value, name: &str, extension: serde_json::Value) {
+    let results: Vec<&mut serde_json::Value> = match response {
+        serde_json::Value::Array(results) => results.iter_mut().collect(),
+        result => vec![result],
+    };
+    for result in results {
+        if let serde_json::Value::Object(result) = result {
+            let extensions = result.entry("extensions").or_insert_with(|| json!({}));
+            if let serde_json::Value::Object(extensions) = extensions {
+                extensions.insert(name.into(), extension.clone());
+            }
+        }
+    }
+}
+
+/// Trims every result with the operation it answers, a batch has one per operation
+fn trim_introspection(response: &mut serde_json::Value, operations: &[&RawOperation]) {
+    let results: Vec<&mut serde_json::Value> = match response {
+        serde_json::Value::Array(results) => results.iter_mut().collect(),
+        result => vec![result],
+    };
+    for (i, result) in results.into_iter().enumerate() {
+        match operations.get(i) {
+            Some(operation) => visibility::trim(
+                &operation.query,
+                operation.operation_name.as_deref(),
+                operation.variables.as_ref(),
+                result,
+            ),
+            // Nothing to tell what the data is, so none of it is shown
+            None => {
+                if let Some(data) = result.get_mut("data") {
+                    *data = serde_json::Value::Null;
+                }
+            }
+        }
+    }
+}
+
+/// The rate limit extension is viewer specific, so it's added after the body went to the cache
+/// and left out of answers from the cache. The headers are still there on those.
+fn respond(
+    context: &Context,
+    response: &impl Serialize,
+    ok: bool,
+    cache_key: Option<Vec<u8>>,
+    tracing: Option<Tracing>,
+    rate: Option<RateLimit>,
+    introspection: Option<&[&RawOperation]>,
+) -> Response {
+    let mut cached = None;
+    let body = match serde_json::to_value(response).and_then(|mut response| {
+        if let (Some(operations), false) = (introspection, context.user.is_admin()) {
+            trim_introspection(&mut response, operations);
+        }
+        if let Some(ref tracing) = tracing {
+            tracing.add_to(&mut response);
+        }
+        if let Some(deprecations) = version::extension(context) {
+            add_extension(&mut response, "deprecations", deprecations);
+        }
+        errors::localize(
+            &mut response,
+            &context.config.error_translations,
+            error_language(context),
+        );
+        if !ok {
+            add_request_id(&mut response, &context.request.id);
+        }
+        if let Some(ref rate) = rate {
+            if ok && cache_key.is_some() {
+                cached = Some(serde_json::to_vec(&response)?);
+            }
+            add_rate_limit(&mut response, rate);
+        }
+        serde_json::to_vec(&response)
+    }) {
+        Ok(body) => body,
+        Err(err) => {
+            log::error!("Could not serialize response - {:?}", err);
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "INTERNAL",
+                "Internal server error",
+            );
+        }
+    };
+    if !ok {
+        return json_response(body, StatusCode::BAD_REQUEST);
+    }
+
+    if let Some(key) = cache_key {
+        cache::put(
+            key,
+            Arc::new(cached.unwrap_or_else(|| body.clone())),
+            context.config.anonymous_cache_ttl,
+            context.config.anonymous_cache_size,
+        );
+    }
+    json_response(body, StatusCode::OK)
+}
+
+/// Runs a POST request against the schema. Checks that need the whole request, like maintenance
+/// mode, happen here before juniper sees it.
+pub async fn execute(schema: &Schema, context: Context, body: &[u8]) -> Response {
+    let id = context.request.id.clone();
+    let rate = ratelimit::hit(&context);
+    let response =
+        statements::with_request_id(id.clone(), execute_post(schema, context, body, rate));
+    with_rate_limit_headers(with_request_id_header(response.await, &id), rate)
+}
+
+/// Runs a GET request, these may only contain queries
+pub async fn execute_get(
+    schema: &Schema,
+    context: Context,
+    params: HashMap<String, String>,
+) -> Response {
+    let id = context.request.id.clone();
+    let rate = ratelimit::hit(&context);
+    let response =
+        statements::with_request_id(id.clone(), execute_query(schema, context, params, rate));
+    with_rate_limit_headers(with_request_id_header(response.await, &id), rate)
+}
+
+async fn execute_post(
+    schema: &Schema,
+    context: Context,
+    body: &[u8],
+    rate: Option<RateLimit>,
+) -> Response {
+    let request: GraphQLBatchRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(err) => {
+            return error_response(StatusCode::BAD_REQUEST, "BAD_REQUEST", &err.to_string())
+        }
+    };
+    let raw: Option<RawRequest> = serde_json::from_slice(body).ok();
+
+    let fields: Vec<(&'static str, String)> = raw
+        .iter()
+        .flat_map(|raw| raw.operations())
+        .flat_map(root_fields)
+        .collect();
+    if let Some(refused) = check_policy(&context, &fields) {
+        return refused;
+    }
+    let operations: Vec<&RawOperation> = raw.iter().flat_map(|raw| raw.operations()).collect();
+    let introspection =
+        operations
+            .iter()
+            .fold(visibility::Introspection::default(), |found, operation| {
+                let more = visibility::inspect(&operation.query);
+                visibility::Introspection {
+                    present: found.present || more.present,
+                    aliased: found.aliased || more.aliased,
+                }
+            });
+    if let Some(refused) = refuse_aliased_introspection(&context, &introspection) {
+        return refused;
+    }
+    let mutations = mutation_fields(&fields);
+
+    if mutations
+        .iter()
+        .any(|field| !MAINTENANCE_EXEMPT.contains(&field.as_str()))
+    {
+        match site::is_maintenance(&context.pool).await {
+            Ok(false) => {}
+            Ok(true) => {
+                return error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "MAINTENANCE",
+                    "The site is read-only for maintenance, try again later",
+                )
+            }
+            Err(err) => {
+                log::error!("Could not check maintenance mode - {:?}", err);
+                return error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "INTERNAL",
+                    "Internal server error",
+                );
+            }
+        }
+    }
+
+    let key = if mutations.is_empty() {
+        cache_key(&context, body)
+    } else {
+        None
+    };
+    if let Some(ref key) = key {
+        if let Some(cached) = cache::get(key, context.config.anonymous_cache_ttl) {
+            return json_response(cached.to_vec(), StatusCode::OK);
+        }
+    }
+
+    let tracing = Tracing::start(&context);
+    let started = Instant::now();
+    let (response, statements) = statements::record(request.execute(schema, &context)).await;
+    log_statements(&context, &operations, started.elapsed(), &statements);
+
+    respond(
+        &context,
+        &response,
+        response.is_ok(),
+        key,
+        tracing,
+        rate,
+        Some(&operations[..]).filter(|_| introspection.present),
+    )
+}
+
+async fn execute_query(
+    schema: &Schema,
+    context: Context,
+    params: HashMap<String, String>,
+    rate: Option<RateLimit>,
+) -> Response {
+    let operation = match params.get("query") {
+        Some(query) => RawOperation {
+            query: query.clone(),
+            operation_name: params.get("operationName").cloned(),
+            variables: params
+                .get("variables")
+                .and_then(|variables| serde_json::from_str(variables).ok()),
+        },
+        None => return error_response(StatusCode::BAD_REQUEST, "BAD_REQUEST", "Missing query"),
+    };
+    let variables = match params
+        .get("variables")
+        .map(|variables| serde_json::from_str::<InputValue>(variables))
+        .transpose()
+    {
+        Ok(variables) => variables,
+        Err(err) => {
+            return error_response(StatusCode::BAD_REQUEST, "BAD_REQUEST", &err.to_string())
+        }
+    };
+
+    let fields = root_fields(&operation);
+    if !mutation_fields(&fields).is_empty() {
+        return error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "BAD_REQUEST",
+            "Mutations must be sent with POST",
+        );
+    }
+    if let Some(refused) = check_policy(&context, &fields) {
+        return refused;
+    }
+    let introspection = visibility::inspect(&operation.query);
+    if let Some(refused) = refuse_aliased_introspection(&context, &introspection) {
+        return refused;
+    }
+
+    // Same key whichever method the query came in with
+    let key = serde_json::to_vec(&json!({
+        "query": operation.query,
+        "operationName": operation.operation_name,
+        "variables": params.get("variables"),
+    }))
+    .ok()
+    .and_then(|request| cache_key(&context, &request));
+    if let Some(ref key) = key {
+        if let Some(cached) = cache::get(key, context.config.anonymous_cache_ttl) {
+            return json_response(cached.to_vec(), StatusCode::OK);
+        }
+    }
+
+    let request = GraphQLRequest::new(
+        operation.query.clone(),
+        operation.operation_name.clone(),
+        variables,
+    );
+    let tracing = Tracing::start(&context);
+    let started = Instant::now();
+    let (response, statements) = statements::record(request.execute(schema, &context)).await;
+    let operations = [&operation];
+    log_statements(&context, &operations, started.elapsed(), &statements);
+
+    respond(
+        &context,
+        &response,
+        response.is_ok(),
+        key,
+        tracing,
+        rate,
+        Some(&operations[..]).filter(|_| introspection.present),
+    )
+}
@@ -0,0 +1,341 @@
+use crate::{
+    config::Config,
+    events::EventBus,
+    push::{self, RecvError},
+    Context,
+};
+use chrono::Utc;
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLEnum};
+use serde::Serialize;
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+
+const FCM_URL: &str = "https://fcm.googleapis.com/fcm/send";
+/// Apple rejects provider tokens older than an hour and throttles ones renewed too often
+const APNS_TOKEN_LIFETIME: i64 = 50 * 60;
+/// More than this and the oldest installation is forgotten
+const MAX_DEVICES: i64 = 20;
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum DevicePlatform {
+    Apns,
+    Fcm,
+}
+
+impl DevicePlatform {
+    fn from_db(value: &str) -> Option<Self> {
+        match value {
+            "apns" => Some(DevicePlatform::Apns),
+            "fcm" => Some(DevicePlatform::Fcm),
+            _ => None,
+        }
+    }
+
+    fn to_db(self) -> &'static str {
+        match self {
+            DevicePlatform::Apns => "apns",
+            DevicePlatform::Fcm => "fcm",
+        }
+    }
+}
+
+/// Token based APNs credentials, see APNS_KEY
+#[derive(Debug, Clone)]
+pub struct ApnsConfig {
+    /// Path of the .p8 key downloaded from the Apple developer portal
+    pub key_path: String,
+    pub key_id: String,
+    pub team_id: String,
+    /// Bundle id of the app
+    pub topic: String,
+    /// Development builds get their tokens from the sandbox environment
+    pub sandbox: bool,
+}
+
+pub async fn register(
+    context: &Context,
+    token: String,
+    platform: DevicePlatform,
+) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    if token.is_empty() || token.len() > 4096 {
+        return Err("Invalid device token".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO device_token (token, uid, platform)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (token) DO UPDATE
+        SET uid = $2, platform = $3, created = now()
+        "#,
+        token,
+        uid,
+        platform.to_db()
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM device_token
+        WHERE uid = $1 AND token NOT IN (
+            SELECT token FROM device_token
+            WHERE uid = $1
+            ORDER BY created DESC
+            LIMIT $2
+        )
+        "#,
+        uid,
+        MAX_DEVICES
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+pub async fn unregister(context: &Context, token: String) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    Ok(sqlx::query!(
+        r#"
+        DELETE FROM device_token
+        WHERE token = $1 AND uid = $2
+        RETURNING token
+        "#,
+        token,
+        uid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .is_some())
+}
+
+#[derive(Serialize)]
+struct ApnsClaims<'a> {
+    iss: &'a str,
+    iat: i64,
+}
+
+struct Apns {
+    config: ApnsConfig,
+    key: jsonwebtoken::EncodingKey,
+    client: reqwest::Client,
+    /// Provider token and when it was made
+    token: Mutex<Option<(String, i64)>>,
+}
+
+impl Apns {
+    fn new(config: ApnsConfig) -> anyhow::Result<Self> {
+        let key = jsonwebtoken::EncodingKey::from_ec_pem(&std::fs::read(&config.key_path)?)?;
+        Ok(Apns {
+            config,
+            key,
+            // APNs only speaks HTTP/2
+            client: reqwest::Client::builder()
+                .http2_prior_knowledge()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            token: Mutex::new(None),
+        })
+    }
+
+    async fn provider_token(&self) -> anyhow::Result<String> {
+        let now = Utc::now().timestamp();
+        let mut cached = self.token.lock().await;
+        if let Some((ref token, issued)) = *cached {
+            if now - issued < APNS_TOKEN_LIFETIME {
+                return Ok(token.clone());
+            }
+        }
+
+        let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::ES256);
+        header.kid = Some(self.config.key_id.clone());
+        let token = jsonwebtoken::encode(
+            &header,
+            &ApnsClaims {
+                iss: &self.config.team_id,
+                iat: now,
+            },
+            &self.key,
+        )?;
+        *cached = Some((token.clone(), now));
+        Ok(token)
+    }
+
+    /// Ok(false) when the device token is no longer valid
+    async fn send(&self, device: &str, payload: &serde_json::Value) -> anyhow::Result<bool> {
+        let host = if self.config.sandbox {
+            "api.sandbox.push.apple.com"
+        } else {
+            "api.push.apple.com"
+        };
+        let response = self
+            .client
+            .post(&format!("https://{}/3/device/{}", host, device))
+            .bearer_auth(self.provider_token().await?)
+            .header("apns-topic", self.config.topic.as_str())
+            .header("apns-push-type", "alert")
+            .json(payload)
+            .send()
+            .await?;
+
+        match response.status().as_u16() {
+            200 => Ok(true),
+            410 => Ok(false),
+            status => {
+                let body = response.text().await.unwrap_or_default();
+                if body.contains("BadDeviceToken") {
+                    Ok(false)
+                } else {
+                    Err(anyhow::anyhow!("APNs answered {} - {}", status, body))
+                }
+            }
+        }
+    }
+}
+
+struct Fcm {
+    server_key: String,
+    client: reqwest::Client,
+}
+
+impl Fcm {
+    /// Ok(false) when the device token is no longer valid
+    async fn send(&self, device: &str, payload: &serde_json::Value) -> anyhow::Result<bool> {
+        let message = json!({
+            "to": device,
+            "notification": payload["aps"]["alert"],
+            "data": payload["data"],
+        });
+        let response: serde_json::Value = self
+            .client
+            .post(FCM_URL)
+            .header("authorization", format!("key={}", self.server_key))
+            .json(&message)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        match response["results"][0]["error"].as_str() {
+            None => Ok(true),
+            Some("NotRegistered") | Some("InvalidRegistration") => Ok(false),
+            Some(err) => Err(anyhow::anyhow!("FCM refused the message - {}", err)),
+        }
+    }
+}
+
+/// Alert text for the notification shade, the app fetches the rest through the API
+fn notification(kind: &str, data: serde_json::Value) -> serde_json::Value {
+    let title = match kind {
+        "reply" => "New reply",
+        "thread" => "New comment in a thread you follow",
+        "warning" => "You received a warning",
+        "membership" => "Your request to join was answered",
+        _ => "New message",
+    };
+    json!({
+        "aps": { "alert": { "title": title }, "sound": "default" },
+        "data": data,
+    })
+}
+
+async fn deliver(
+    pool: &sqlx::PgPool,
+    apns: Option<&Apns>,
+    fcm: Option<&Fcm>,
+    uid: &str,
+    payload: &serde_json::Value,
+) -> anyhow::Result<()> {
+    let devices = sqlx::query!(
+        r#"
+        SELECT token, platform
+        FROM device_token
+        WHERE uid = $1
+        "#,
+        uid
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    for device in devices {
+        let sent = match DevicePlatform::from_db(&device.platform) {
+            Some(DevicePlatform::Apns) => match apns {
+                Some(apns) => apns.send(&device.token, payload).await,
+                None => continue,
+            },
+            Some(DevicePlatform::Fcm) => match fcm {
+                Some(fcm) => fcm.send(&device.token, payload).await,
+                None => continue,
+            },
+            None => continue,
+        };
+        match sent {
+            Ok(true) => {}
+            // Uninstalled, or the token was rotated
+            Ok(false) => {
+                sqlx::query!(
+                    r#"
+                    DELETE FROM device_token
+                    WHERE token = $1
+                    "#,
+                    device.token
+                )
+                .execute(pool)
+                .await?;
+            }
+            Err(err) => log::warn!("Push to {} device failed - {}", device.platform, err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Background worker, pushes the same replies and messages as push::run to the mobile apps.
+/// Only run it in one process, with PG_EVENTS so it hears about what Throat writes.
+pub async fn run(pool: sqlx::PgPool, events: Arc<dyn EventBus>, config: Arc<Config>) {
+    let apns = match config.apns {
+        Some(ref apns) => match Apns::new(apns.clone()) {
+            Ok(apns) => Some(apns),
+            Err(err) => {
+                log::error!("Can't use APNS_KEY {} - {}", apns.key_path, err);
+                None
+            }
+        },
+        None => None,
+    };
+    let fcm = config.fcm_server_key.clone().map(|server_key| Fcm {
+        server_key,
+        client: reqwest::Client::new(),
+    });
+    if apns.is_none() && fcm.is_none() {
+        return;
+    }
+
+    let mut receiver = events.subscribe();
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                log::warn!("Mobile push worker fell behind, skipped {} events", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if let Some((uid, data)) = push::payload(&event) {
+            let kind = data["type"].as_str().unwrap_or_default().to_string();
+            let payload = notification(&kind, data);
+            if let Err(err) = deliver(&pool, apns.as_ref(), fcm.as_ref(), uid, &payload).await {
+                log::error!("Could not push {:?} to devices - {}", event, err);
+            }
+        }
+    }
+}
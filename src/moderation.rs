@@ -0,0 +1,374 @@
+use crate::{legacy, post::DeleteStatus, user::UserRef, Context};
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, ID};
+use sqlx::{Postgres, Transaction};
+
+/// Most targets one bulk action may touch
+const MAX_BATCH: usize = 500;
+
+fn check_batch<T>(targets: &[T]) -> Result<(), FieldError> {
+    if targets.is_empty() {
+        Err("Nothing to act on".into())
+    } else if targets.len() > MAX_BATCH {
+        Err(format!("At most {} at once", MAX_BATCH).into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Post ids and the sid they were all in, if they shared one. Fails unless the viewer
+/// moderates every sub involved.
+async fn posts_to_moderate(
+    context: &Context,
+    ids: &[ID],
+) -> Result<(Vec<i32>, Option<String>), FieldError> {
+    check_batch(ids)?;
+    let pids = ids
+        .iter()
+        .map(|id| context.config.post_ids.decode(id))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let posts = sqlx::query!(
+        r#"
+        SELECT pid, sid
+        FROM sub_post
+        WHERE pid = ANY($1)
+        "#,
+        &pids
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    if posts.len() != pids.len() {
+        return Err("Some of the posts don't exist".into());
+    }
+
+    let mut sids: Vec<String> = posts.into_iter().filter_map(|post| post.sid).collect();
+    sids.sort();
+    sids.dedup();
+    if let Some(sid) = sids.iter().find(|sid| !context.user.is_mod(sid)) {
+        return Err(format!("Not a mod of {}", sid).into());
+    }
+
+    Ok((pids, if sids.len() == 1 { sids.pop() } else { None }))
+}
+
+pub(crate) async fn log_action(
+    tx: &mut Transaction<'_, Postgres>,
+    uid: &str,
+    sid: Option<String>,
+    action: &str,
+    targets: Vec<String>,
+    reason: Option<String>,
+) -> Result<(), FieldError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO mod_log (uid, sid, action, targets, reason)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        uid,
+        sid,
+        action,
+        &targets,
+        reason
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// Removes every post as a mod, or as an admin where the viewer doesn't mod the sub. Returns how
+/// many weren't removed already.
+pub async fn bulk_remove_posts(
+    context: &Context,
+    ids: Vec<ID>,
+    reason: String,
+) -> Result<i32, FieldError> {
+    let uid = context.user.user_id()?;
+    let (pids, sid) = posts_to_moderate(context, &ids).await?;
+    let mod_of: Vec<String> = context.user.modded_subs();
+
+    let mut tx = context.pool.begin().await?;
+    let removed = sqlx::query!(
+        r#"
+        UPDATE sub_post
+        SET deleted = CASE WHEN sid = ANY($2) THEN $3 ELSE $4 END
+        WHERE pid = ANY($1) AND coalesce(deleted, 0) = 0
+        RETURNING pid
+        "#,
+        &pids,
+        &mod_of,
+        DeleteStatus::Mod.to_db(),
+        DeleteStatus::Admin.to_db()
+    )
+    .fetch(&mut tx)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    let targets = removed.iter().map(|post| post.pid.to_string()).collect();
+    log_action(&mut tx, uid, sid, "remove_posts", targets, Some(reason)).await?;
+    tx.commit().await?;
+
+    for pid in &pids {
+        context.post_loader.clear(*pid).await;
+    }
+    Ok(removed.len() as i32)
+}
+
+/// Undoes removals by mods, and by admins when the viewer is one. Posts their authors deleted
+/// stay deleted.
+pub async fn bulk_approve(context: &Context, ids: Vec<ID>) -> Result<i32, FieldError> {
+    let uid = context.user.user_id()?;
+    let (pids, sid) = posts_to_moderate(context, &ids).await?;
+
+    let mut tx = context.pool.begin().await?;
+    let restored = sqlx::query!(
+        r#"
+        UPDATE sub_post
+        SET deleted = 0
+        WHERE pid = ANY($1) AND (deleted = ANY($3) OR (deleted = ANY($4) AND $2))
+        RETURNING pid
+        "#,
+        &pids,
+        context.user.is_admin(),
+        &legacy::post_deleted_codes(DeleteStatus::Mod),
+        &legacy::post_deleted_codes(DeleteStatus::Admin)
+    )
+    .fetch(&mut tx)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    let targets = restored.iter().map(|post| post.pid.to_string()).collect();
+    log_action(&mut tx, uid, sid, "approve_posts", targets, None).await?;
+    tx.commit().await?;
+
+    for pid in &pids {
+        context.post_loader.clear(*pid).await;
+    }
+    Ok(restored.len() as i32)
+}
+
+/// Bans users from the sub by name, skipping ones already banned. Returns how many were banned.
+pub async fn bulk_ban_users(
+    context: &Context,
+    sub: String,
+    users: Vec<String>,
+    reason: String,
+) -> Result<i32, FieldError> {
+    let uid = context.user.user_id()?;
+    check_batch(&users)?;
+    let sub = context
+        .sub_loader
+        .load(sub.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+
+    let mut uids = vec![];
+    for user in context
+        .user_loader
+        .load_many(users.into_iter().map(UserRef::name).collect())
+        .await
+        .into_iter()
+    {
+        match user {
+            (_, Ok(user)) if user.uid == uid => return Err("You can't ban yourself".into()),
+            (_, Ok(user)) => uids.push(user.uid),
+            (name, Err(_)) => return Err(format!("Could not find user {}", name).into()),
+        }
+    }
+
+    let mut tx = context.pool.begin().await?;
+    let banned = sqlx::query!(
+        r#"
+        INSERT INTO sub_ban (uid, sid, created, reason, effective, created_by_id)
+        SELECT u, $2, now(), $3, true, $4
+        FROM unnest($1::text[]) u
+        WHERE NOT EXISTS (
+            SELECT 1 FROM sub_ban b WHERE b.uid = u AND b.sid = $2 AND b.effective
+        )
+        RETURNING uid
+        "#,
+        &uids,
+        sub.sid,
+        reason,
+        uid
+    )
+    .fetch(&mut tx)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    let targets = banned.iter().map(|ban| ban.uid.clone()).collect();
+    log_action(
+        &mut tx,
+        uid,
+        Some(sub.sid.clone()),
+        "ban_users",
+        targets,
+        Some(reason),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(banned.len() as i32)
+}
+
+/// Longest mute, anything beyond that should be a ban
+const MAX_MUTE_HOURS: i32 = 24 * 90;
+
+/// Keeps the user from commenting and sending modmail in the sub until it runs out. Muting
+/// again replaces the old expiry. Returns when the mute ends.
+pub async fn mute_user(
+    context: &Context,
+    sub: String,
+    user: String,
+    hours: i32,
+    reason: Option<String>,
+) -> Result<NaiveDateTime, FieldError> {
+    let uid = context.user.user_id()?;
+    if !(1..=MAX_MUTE_HOURS).contains(&hours) {
+        return Err(format!("Mutes last 1 to {} hours", MAX_MUTE_HOURS).into());
+    }
+    let sub = context
+        .sub_loader
+        .load(sub.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+    let target = context
+        .user_loader
+        .load(UserRef::name(user))
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    if target.uid == uid {
+        return Err("You can't mute yourself".into());
+    }
+
+    let expires = Utc::now().naive_utc() + Duration::hours(hours as i64);
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO sub_mute (uid, sid, muted_by, reason, expires)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (uid, sid) DO UPDATE
+        SET muted_by = $3, reason = $4, expires = $5
+        "#,
+        target.uid,
+        sub.sid,
+        uid,
+        reason,
+        expires
+    )
+    .execute(&mut tx)
+    .await?;
+    log_action(
+        &mut tx,
+        uid,
+        Some(sub.sid.clone()),
+        "mute_user",
+        vec![target.uid.clone()],
+        reason,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(expires)
+}
+
+pub async fn unmute_user(context: &Context, sub: String, user: String) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub = context
+        .sub_loader
+        .load(sub.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+    let target = context
+        .user_loader
+        .load(UserRef::name(user))
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+
+    let mut tx = context.pool.begin().await?;
+    let unmuted = sqlx::query!(
+        r#"
+        DELETE FROM sub_mute
+        WHERE uid = $1 AND sid = $2 AND expires > now()
+        RETURNING uid
+        "#,
+        target.uid,
+        sub.sid
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .is_some();
+    if unmuted {
+        log_action(
+            &mut tx,
+            uid,
+            Some(sub.sid),
+            "unmute_user",
+            vec![target.uid],
+            None,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    Ok(unmuted)
+}
+
+/// Fails while the user is muted in the sub, for the mutations that create comments and
+/// modmail. Expired mutes are simply ignored.
+pub async fn check_not_muted(context: &Context, sid: &str) -> Result<(), FieldError> {
+    let uid = context.user.user_id()?;
+    match sqlx::query!(
+        r#"
+        SELECT expires
+        FROM sub_mute
+        WHERE uid = $1 AND sid = $2 AND expires > now()
+        "#,
+        uid,
+        sid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    {
+        Some(mute) => Err(format!("You are muted in this sub until {}", mute.expires).into()),
+        None => Ok(()),
+    }
+}
+
+/// Fails while the user is banned from the sub, expired bans don't count
+pub async fn check_not_banned(context: &Context, sid: &str) -> Result<(), FieldError> {
+    let uid = context.user.user_id()?;
+    let banned = sqlx::query!(
+        r#"
+        SELECT 1 as "banned!"
+        FROM sub_ban
+        WHERE uid = $1 AND sid = $2 AND effective
+            AND (expires IS NULL OR expires > now())
+        "#,
+        uid,
+        sid
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+    if banned.is_some() {
+        Err("You are banned from this sub".into())
+    } else {
+        Ok(())
+    }
+}
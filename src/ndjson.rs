@@ -0,0 +1,36 @@
+use futures::{channel::mpsc, SinkExt};
+use std::future::Future;
+
+/// Lines produced ahead of a slow client before the producer waits for it
+const BUFFER: usize = 64;
+
+/// Newline delimited JSON as it's produced, hand it to the HTTP layer as a streaming body
+pub type Lines = mpsc::Receiver<Vec<u8>>;
+
+/// Where a producer writes its lines
+pub struct LineSender(mpsc::Sender<Vec<u8>>);
+
+impl LineSender {
+    /// False once the client went away, the producer should stop then
+    pub async fn send(&mut self, value: &serde_json::Value) -> bool {
+        let mut line = value.to_string().into_bytes();
+        line.push(b'\n');
+        self.0.send(line).await.is_ok()
+    }
+
+    /// Last line of a stream that broke off, so clients can tell it apart from a complete one
+    pub async fn fail(&mut self, message: &str) {
+        self.send(&serde_json::json!({ "error": message })).await;
+    }
+}
+
+/// Runs `produce` in the background, only as far ahead of the client as the buffer allows
+pub fn spawn<F, Fut>(produce: F) -> Lines
+where
+    F: FnOnce(LineSender) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (sender, lines) = mpsc::channel(BUFFER);
+    tokio::spawn(produce(LineSender(sender)));
+    lines
+}
@@ -0,0 +1,106 @@
+//! oEmbed (https://oembed.com) for post permalinks, so links unfurl with a title and author in
+//! chat apps and the fediverse
+use crate::{
+    middleware::Response,
+    post::{self, DeleteStatus},
+    user::UserRef,
+    Context,
+};
+use http::{header, StatusCode};
+use serde_json::json;
+
+const EMBED_WIDTH: i32 = 500;
+const EMBED_HEIGHT: i32 = 200;
+
+fn reply(status: StatusCode, body: String) -> Response {
+    let mut response = Response::new(body.into_bytes());
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Sub and post id out of `{site_url}/s/{sub}/{pid}[/{slug}]`, query and fragment ignored
+fn parse_permalink<'a>(site_url: &str, url: &'a str) -> Option<(&'a str, &'a str)> {
+    let path = url.strip_prefix(site_url)?;
+    let path = path.split(|c| c == '?' || c == '#').next()?;
+    let mut parts = path.trim_start_matches('/').split('/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("s"), Some(sub), Some(pid)) if !sub.is_empty() && !pid.is_empty() => Some((sub, pid)),
+        _ => None,
+    }
+}
+
+/// GET /oembed?url=...&format=json, XML isn't offered
+pub async fn oembed(context: Context, url: &str, format: Option<&str>) -> Response {
+    if format.map_or(false, |format| format != "json") {
+        return reply(StatusCode::NOT_IMPLEMENTED, "Only json is supported".into());
+    }
+    let (sub, pid) = match parse_permalink(&context.config.site_url, url) {
+        Some(parts) => parts,
+        None => return reply(StatusCode::NOT_FOUND, "Not a post permalink".into()),
+    };
+
+    let path = match post::get_post_by_path(&context, sub.to_string(), pid.to_string().into(), None)
+        .await
+    {
+        Ok(path) if path.post.deleted == DeleteStatus::Not => path,
+        _ => return reply(StatusCode::NOT_FOUND, "Post not found".into()),
+    };
+    let post = path.post;
+    let permalink = match post::permalink(&context, &post).await {
+        Ok(permalink) => permalink,
+        Err(_) => return reply(StatusCode::NOT_FOUND, "Post not found".into()),
+    };
+    let author = match post.uid {
+        Some(ref uid) => context
+            .user_loader
+            .load(UserRef::Uid(uid.clone()))
+            .await
+            .ok()
+            .and_then(|user| user.name),
+        None => None,
+    };
+    let title = post.title.clone().unwrap_or_default();
+
+    let mut html = format!(
+        r#"<blockquote class="throat-embed"><a href="{}">{}</a>"#,
+        escape(&permalink),
+        escape(&title)
+    );
+    if let Some(ref author) = author {
+        html.push_str(&format!(
+            r#" by <a href="{}/u/{}">{}</a>"#,
+            escape(&context.config.site_url),
+            escape(author),
+            escape(author)
+        ));
+    }
+    html.push_str("</blockquote>");
+
+    let mut body = json!({
+        "version": "1.0",
+        "type": "rich",
+        "title": title,
+        "provider_url": context.config.site_url,
+        "html": html,
+        "width": EMBED_WIDTH,
+        "height": EMBED_HEIGHT,
+    });
+    if let Some(author) = author {
+        body["author_url"] = json!(format!("{}/u/{}", context.config.site_url, author));
+        body["author_name"] = json!(author);
+    }
+
+    reply(StatusCode::OK, body.to_string())
+}
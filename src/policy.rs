@@ -0,0 +1,93 @@
+use crate::auth::UserState;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Roles OPERATION_POLICY can have lists for, a viewer only gets the one for the highest role they
+/// have
+const ROLES: &[&str] = &["anonymous", "user", "mod", "admin"];
+
+/// What a role may run. Entries are root field names, or `query`/`mutation` for every field of
+/// that kind. An empty allow list allows everything that isn't denied.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RolePolicy {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+/// Lists by role, roles without an entry may run anything
+pub type OperationPolicy = HashMap<String, RolePolicy>;
+
+/// Parses OPERATION_POLICY, e.g. `{"anonymous": {"allow": ["query"]}}`
+pub fn parse(json: &str) -> Result<OperationPolicy, String> {
+    let policy: OperationPolicy = serde_json::from_str(json).map_err(|err| err.to_string())?;
+    if let Some(role) = policy.keys().find(|role| !ROLES.contains(&role.as_str())) {
+        return Err(format!("unknown role {:?}, use {}", role, ROLES.join(", ")));
+    }
+    Ok(policy)
+}
+
+pub fn role(user: &UserState) -> &'static str {
+    if user.is_anon() {
+        "anonymous"
+    } else if user.is_admin() {
+        "admin"
+    } else if !user.modded_subs().is_empty() {
+        "mod"
+    } else {
+        "user"
+    }
+}
+
+impl RolePolicy {
+    /// Whether a root field of an operation of `kind` may run. Fragments at the root show up as
+    /// `...`, they could expand to anything so only a kind-wide allow lets them through.
+    pub fn permits(&self, kind: &str, field: &str) -> bool {
+        if field == "__typename" {
+            return true;
+        }
+        if field == "..." && !self.deny.is_empty() {
+            return false;
+        }
+        let listed = |list: &[String]| list.iter().any(|entry| entry == kind || entry == field);
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+/// First root field the viewer's role isn't allowed to run
+pub fn refused<'a>(
+    policy: &OperationPolicy,
+    user: &UserState,
+    fields: &'a [(&'static str, String)],
+) -> Option<&'a str> {
+    let rules = policy.get(role(user))?;
+    fields
+        .iter()
+        .find(|(kind, field)| !rules.permits(kind, field))
+        .map(|(_, field)| field.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds_and_fields() {
+        let policy =
+            parse(r#"{"anonymous": {"allow": ["query"], "deny": ["getRuntimeStats"]}}"#).unwrap();
+        let anonymous = &policy["anonymous"];
+        assert!(anonymous.permits("query", "getPost"));
+        assert!(!anonymous.permits("query", "getRuntimeStats"));
+        assert!(!anonymous.permits("mutation", "editPost"));
+        assert!(anonymous.permits("mutation", "__typename"));
+        assert!(!anonymous.permits("query", "..."));
+    }
+
+    #[test]
+    fn unknown_roles_are_rejected() {
+        assert!(parse(r#"{"moderator": {}}"#).is_err());
+        assert!(parse(r#"{"user": {"allowed": []}}"#).is_err());
+    }
+}
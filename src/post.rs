@@ -1,10 +1,30 @@
-use crate::{auth::UserState, sub::Sub, user::User};
-use crate::{comment::Comment, Context, Cursor, Edge, Page, PageInfo};
+use crate::comment::{self, Comment, CommentParent};
+use crate::{
+    attachment::{self, Attachment},
+    auth::UserState,
+    images, language, legacy,
+    links::{self, LinkMetadata, LinkStatus},
+    markdown, membership, moderation, saved, site, stats,
+    sub::Sub,
+    submitter,
+    user::{User, UserRef},
+    visit,
+    vote::{self, Votable, VotableValue, VoteDirection},
+    word_filter,
+};
+use crate::{
+    events::Event,
+    parse_offset,
+    repo::{Memory, PostRepo},
+    Context, Cursor, Edge, Page, PageInfo,
+};
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
 use dataloader::BatchFn;
 use futures_util::stream::StreamExt;
-use juniper::{graphql_object, FieldError, GraphQLEnum, ID};
+use juniper::{
+    graphql_interface, graphql_object, FieldError, GraphQLEnum, GraphQLObject, Object, Value, ID,
+};
 use std::{collections::HashMap, sync::Arc};
 
 #[derive(Debug, Clone, GraphQLEnum, PartialEq)]
@@ -15,13 +35,64 @@ pub enum DeleteStatus {
     Admin,
 }
 
-#[derive(Debug, Clone, GraphQLEnum)]
+impl DeleteStatus {
+    /// Throat's own code, what gets written. Filters on stored rows go through
+    /// legacy::post_deleted_codes and comment_status_codes, which also know legacy codes.
+    pub(crate) fn to_db(&self) -> i32 {
+        match self {
+            DeleteStatus::Not => 0,
+            DeleteStatus::User => 1,
+            DeleteStatus::Mod => 2,
+            DeleteStatus::Admin => 3,
+        }
+    }
+}
+
+/// How the viewer's deletion of content by `author` in sub `sid` is recorded: as the author's
+/// own, as a mod of the sub or as an admin, in that order
+pub(crate) fn deletion_by(
+    user: &UserState,
+    sid: &str,
+    author: Option<&str>,
+) -> Result<DeleteStatus, FieldError> {
+    let uid = user.user_id()?;
+    if author == Some(uid) {
+        Ok(DeleteStatus::User)
+    } else if user.modded_subs().iter().any(|sub| sub == sid) {
+        Ok(DeleteStatus::Mod)
+    } else if user.is_admin() {
+        Ok(DeleteStatus::Admin)
+    } else {
+        Err("Not Authorized".into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
 pub enum PostType {
     Text,
     Link,
     Poll,
 }
 
+impl PostType {
+    pub fn to_db(self) -> i32 {
+        match self {
+            PostType::Text => 0,
+            PostType::Link => 1,
+            PostType::Poll => 3,
+        }
+    }
+}
+
+/// ptype values to filter on, including legacy codes, an empty list matches every type
+pub(crate) fn type_filter(types: Option<Vec<PostType>>) -> Vec<i32> {
+    types
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(legacy::post_type_codes)
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct Post {
     pub pid: i32,
@@ -34,7 +105,7 @@ pub struct Post {
     pub posted: Option<NaiveDateTime>,
     pub edited: Option<NaiveDateTime>,
     pub ptype: PostType,
-    pub comments: Vec<String>,
+    pub comment_count: i32,
     pub sid: Option<String>,
     pub thumbnail: Option<String>,
     pub title: Option<String>,
@@ -42,13 +113,9 @@ pub struct Post {
     pub flair: Option<String>,
 }
 
-#[graphql_object(context = Context)]
 impl Post {
-    fn id(&self, _context: &Context) -> ID {
-        self.pid.to_string().into()
-    }
-
-    fn content(&self, context: &Context) -> &Option<String> {
+    /// The content unless it's deleted and the viewer may not see deleted content
+    fn visible_content(&self, context: &Context) -> &Option<String> {
         if self.deleted == DeleteStatus::Not
             || context.user.can_view_deleted(
                 &self.sid.to_owned().unwrap_or_else(|| "".to_string()),
@@ -60,6 +127,24 @@ impl Post {
             &None
         }
     }
+}
+
+#[graphql_object(context = Context, impl = VotableValue)]
+impl Post {
+    fn id(&self, context: &Context) -> ID {
+        context.config.post_ids.encode(self.pid)
+    }
+
+    fn content(&self, context: &Context) -> &Option<String> {
+        self.visible_content(context)
+    }
+
+    /// content as sanitized HTML, the same previewMarkdown shows
+    fn content_html(&self, context: &Context) -> Option<String> {
+        self.visible_content(context)
+            .as_ref()
+            .map(|content| markdown::render(&context.config.site_url, content))
+    }
 
     fn up_votes(&self, _context: &Context) -> i32 {
         self.up_votes
@@ -73,6 +158,10 @@ impl Post {
         self.up_votes - self.down_votes
     }
 
+    async fn viewer_vote(&self, context: &Context) -> Result<Option<VoteDirection>, FieldError> {
+        vote::post_vote(context, self.pid).await
+    }
+
     fn deleted(&self, _context: &Context) -> &DeleteStatus {
         &self.deleted
     }
@@ -81,6 +170,42 @@ impl Post {
         &self.link
     }
 
+    /// Whether the link still works, as of the last check. Always UNKNOWN for other post types.
+    async fn link_status(&self, context: &Context) -> Result<LinkStatus, FieldError> {
+        if self.link.is_none() {
+            return Ok(LinkStatus::Unknown);
+        }
+        links::get_status(context, self.pid).await
+    }
+
+    /// What the linked page says about itself, null until it has been fetched
+    async fn link_metadata(&self, context: &Context) -> Result<Option<LinkMetadata>, FieldError> {
+        if self.link.is_none() {
+            return Ok(None);
+        }
+        links::get_metadata(context, self.pid).await
+    }
+
+    async fn is_saved_by_viewer(&self, context: &Context) -> Result<bool, FieldError> {
+        saved::post_saved(context, self.pid).await
+    }
+
+    /// Comments by others since the viewer last visited, see markPostVisited. Null when they
+    /// haven't visited yet.
+    async fn new_comment_count(&self, context: &Context) -> Result<Option<i32>, FieldError> {
+        visit::new_comment_count(context, self.pid).await
+    }
+
+    /// ISO 639-3 code like "eng", null until detected or when detection wasn't confident
+    async fn language(&self, context: &Context) -> Result<Option<String>, FieldError> {
+        language::post_language(&context.pool, self.pid).await
+    }
+
+    /// Images added to a text post, in the order they were uploaded
+    async fn attachments(&self, context: &Context) -> Result<Vec<Attachment>, FieldError> {
+        attachment::post_attachments(context, self.pid).await
+    }
+
     fn nsfw(&self, _context: &Context) -> bool {
         self.nsfw
     }
@@ -101,6 +226,19 @@ impl Post {
         &self.thumbnail
     }
 
+    /// Full url of the thumbnail, resized to fit within width x height when an image proxy is
+    /// configured
+    fn thumbnail_url(
+        &self,
+        context: &Context,
+        width: Option<i32>,
+        height: Option<i32>,
+    ) -> Option<String> {
+        self.thumbnail
+            .as_ref()
+            .map(|thumbnail| images::thumbnail_url(&context.config, thumbnail, width, height))
+    }
+
     fn title(&self, _context: &Context) -> &Option<String> {
         &self.title
     }
@@ -109,6 +247,11 @@ impl Post {
         &self.flair
     }
 
+    /// Canonical link to the post, every client should share this one
+    async fn permalink(&self, context: &Context) -> Result<String, FieldError> {
+        permalink(context, self).await
+    }
+
     async fn sub(&self, context: &Context) -> Result<Sub, FieldError> {
         context
             .sub_loader
@@ -120,7 +263,7 @@ impl Post {
     async fn author(&self, context: &Context) -> Result<User, FieldError> {
         context
             .user_loader
-            .load(self.uid.clone().ok_or("Post has no author")?.into())
+            .load(UserRef::Uid(self.uid.clone().ok_or("Post has no author")?))
             .await
             .map_err(|err| format!("{:?}", err).into())
     }
@@ -130,49 +273,659 @@ impl Post {
         ctx: &Context,
         limit: Option<i32>,
         after: Option<Cursor>,
-    ) -> Page<Result<Comment, FieldError>> {
-        let limit = limit.unwrap_or(25);
-        let after: String = after.unwrap_or_else(|| "".into());
-
-        let page: Vec<_> = self
-            .comments
-            .iter()
-            .skip_while(|cid| (after != "") && (cid != &&after))
-            .take(limit as usize)
-            .cloned()
-            .collect();
-
-        let comments = ctx.comment_loader.load_many(page.clone()).await;
-
-        let page_len = page.len();
-
-        Page {
-            total_count: self.comments.len() as i32,
-            page_info: PageInfo {
-                has_next_page: page_len as i32 == limit,
-                end_cursor: page.last().cloned().unwrap_or_else(|| "".into()),
-            },
-            edges: comments
-                .into_iter()
-                .map(|comment| Edge {
-                    cursor: comment.0,
-                    node: comment.1.map_err(|err| format!("{:?}", err).into()),
-                })
-                .collect(),
-        }
+        collapsed_below_score: Option<i32>,
+    ) -> Result<Page<Result<Comment, FieldError>>, FieldError> {
+        comment::children_page(
+            ctx,
+            CommentParent::Post(self.pid),
+            self.comment_count,
+            limit,
+            after,
+            collapsed_below_score,
+        )
+        .await
     }
 
     fn comment_count(&self, _context: &Context) -> i32 {
-        self.comments.len() as i32
+        self.comment_count
+    }
+
+    /// Every comment in the thread, not counting deleted ones the viewer isn't allowed to see
+    async fn visible_comment_count(&self, context: &Context) -> Result<i32, FieldError> {
+        let sees_all_deleted = context
+            .user
+            .can_view_deleted(&self.sid.to_owned().unwrap_or_else(|| "".to_string()), "");
+        let viewer = context.user.user_id().unwrap_or("");
+
+        Ok(sqlx::query!(
+            r#"
+            SELECT count(*) as "cnt!"
+            FROM sub_post_comment
+            WHERE pid = $1
+                AND (coalesce(status, 0) = 0 OR $2 OR uid = $3)
+            "#,
+            self.pid,
+            sees_all_deleted,
+            viewer
+        )
+        .fetch_one(&context.pool)
+        .await?
+        .cnt as i32)
+    }
+
+    async fn submitter_ip(&self, context: &Context) -> Result<Option<String>, FieldError> {
+        submitter::post_ip(context, self.pid).await
+    }
+}
+
+/// Lowercase ascii words of the title joined by dashes, capped so URLs stay readable
+pub fn slugify(title: &str) -> String {
+    let slug = title
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+
+    match slug.char_indices().nth(80) {
+        Some((end, _)) => slug[..end].trim_end_matches('-').to_string(),
+        None if slug.is_empty() => "_".to_string(),
+        None => slug,
+    }
+}
+
+/// `{site_url}/s/{sub}/{pid}/{slug}`
+pub async fn permalink(context: &Context, post: &Post) -> Result<String, FieldError> {
+    let sub = context
+        .sub_loader
+        .load(post.sid.clone().ok_or("Post not in a sub?")?.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+
+    Ok(format!(
+        "{}/s/{}/{}/{}",
+        context.config.site_url,
+        sub.name.unwrap_or_default(),
+        *context.config.post_ids.encode(post.pid),
+        slugify(post.title.as_deref().unwrap_or(""))
+    ))
+}
+
+pub struct PostPath {
+    pub post: Post,
+    redirect: Option<String>,
+}
+
+#[graphql_object(context = Context)]
+impl PostPath {
+    fn post(&self, _context: &Context) -> &Post {
+        &self.post
+    }
+
+    /// Canonical permalink, only set when the requested path was stale and should be replaced
+    fn redirect(&self, _context: &Context) -> &Option<String> {
+        &self.redirect
     }
 }
 
+pub async fn get_post_by_path(
+    context: &Context,
+    sub: String,
+    pid: ID,
+    slug: Option<String>,
+) -> Result<PostPath, FieldError> {
+    let post = context
+        .post_loader
+        .load(context.config.post_ids.decode(&pid)?)
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    let post_sub = context
+        .sub_loader
+        .load(post.sid.clone().ok_or("Post not in a sub?")?.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+
+    let sub_name = post_sub.name.unwrap_or_default();
+    if !sub_name.eq_ignore_ascii_case(&sub) {
+        return Err(format!("Post not found {}", *pid).into());
+    }
+
+    let stale = sub_name != sub
+        || *pid != *context.config.post_ids.encode(post.pid)
+        || slug.as_deref() != Some(slugify(post.title.as_deref().unwrap_or("")).as_str());
+
+    Ok(PostPath {
+        redirect: if stale {
+            Some(permalink(context, &post).await?)
+        } else {
+            None
+        },
+        post,
+    })
+}
+
+const EXCERPT_LENGTH: usize = 200;
+
+/// Just what link previews and meta tags need, see get_post_preview
+#[derive(GraphQLObject, Debug)]
+pub struct PostPreview {
+    pub id: ID,
+    pub title: String,
+    /// Start of the text with whitespace collapsed, at most 200 characters
+    pub excerpt: Option<String>,
+    /// Full url, at its original size
+    pub thumbnail: Option<String>,
+    pub sub_name: String,
+    pub score: i32,
+    pub nsfw: bool,
+    pub permalink: String,
+}
+
+fn excerpt(content: &str) -> String {
+    let collapsed = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    match collapsed.char_indices().nth(EXCERPT_LENGTH) {
+        Some((end, _)) => format!("{}…", collapsed[..end].trim_end()),
+        None => collapsed,
+    }
+}
+
+/// One query and no loaders, for rendering meta tags server side. Anonymous requests for it are
+/// shared through the response cache like any other query.
+pub async fn get_post_preview(context: &Context, id: ID) -> Result<PostPreview, FieldError> {
+    let pid = context.config.post_ids.decode(&id)?;
+    let post = sqlx::query!(
+        r#"
+        SELECT p.title, p.content, p.thumbnail, p.nsfw, s.name as "sub_name!",
+            coalesce(SUM(CASE WHEN v.positive > 0 THEN 1 WHEN v.positive < 0 THEN -1 ELSE 0 END), 0)
+                as "score!"
+        FROM sub_post p
+        JOIN sub s ON s.sid = p.sid
+        LEFT JOIN sub_post_vote v ON v.pid = p.pid
+        WHERE p.pid = $1 AND coalesce(p.deleted, 0) = 0
+        GROUP BY p.pid, s.name
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Post not found {}", *id))?;
+
+    let title = post.title.unwrap_or_default();
+    Ok(PostPreview {
+        id: context.config.post_ids.encode(pid),
+        permalink: format!(
+            "{}/s/{}/{}/{}",
+            context.config.site_url,
+            post.sub_name,
+            *context.config.post_ids.encode(pid),
+            slugify(&title)
+        ),
+        excerpt: post
+            .content
+            .as_deref()
+            .map(excerpt)
+            .filter(|excerpt| !excerpt.is_empty()),
+        title,
+        thumbnail: post
+            .thumbnail
+            .map(|thumbnail| images::thumbnail_url(&context.config, &thumbnail, None, None)),
+        sub_name: post.sub_name,
+        score: post.score as i32,
+        nsfw: post.nsfw.unwrap_or(false),
+    })
+}
+
+#[graphql_interface]
+impl Votable for Post {
+    fn id(&self, context: &Context) -> ID {
+        context.config.post_ids.encode(self.pid)
+    }
+
+    fn score(&self) -> i32 {
+        self.up_votes - self.down_votes
+    }
+
+    fn up_votes(&self) -> i32 {
+        self.up_votes
+    }
+
+    fn down_votes(&self) -> i32 {
+        self.down_votes
+    }
+
+    async fn viewer_vote(&self, context: &Context) -> Result<Option<VoteDirection>, FieldError> {
+        vote::post_vote(context, self.pid).await
+    }
+}
+
+/// Error for an edit based on an outdated copy, carries what is stored now so the client can
+/// merge instead of clobbering the other edit
+pub fn edit_conflict(content: Option<String>, last_edit: Option<NaiveDateTime>) -> FieldError {
+    let mut extensions = Object::with_capacity(3);
+    extensions.add_field("code", Value::scalar("CONFLICT"));
+    extensions.add_field(
+        "content",
+        content.map(Value::scalar).unwrap_or_else(Value::null),
+    );
+    extensions.add_field(
+        "lastEditedAt",
+        last_edit
+            .map(|time| Value::scalar(time.timestamp() as f64))
+            .unwrap_or_else(Value::null),
+    );
+    FieldError::new(
+        "Edited somewhere else in the meantime",
+        Value::Object(extensions),
+    )
+}
+
+/// `last_edited_at` is the edited (or if never edited, posted) time of the copy the edit was
+/// made on. The API hands out whole seconds so that's the precision it is compared at.
+pub async fn edit_post(
+    context: &Context,
+    id: ID,
+    content: String,
+    last_edited_at: Option<NaiveDateTime>,
+) -> Result<Post, FieldError> {
+    let uid = context.user.user_id()?;
+    let pid = context.config.post_ids.decode(&id)?;
+
+    let sid = sqlx::query!(
+        r#"
+        SELECT sid
+        FROM sub_post
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .and_then(|row| row.sid);
+    let held = match sid {
+        Some(ref sid) => word_filter::screen(context, sid, &content).await?,
+        None => None,
+    };
+
+    let updated = sqlx::query!(
+        r#"
+        UPDATE sub_post
+        SET content = $3, edited = now()
+        WHERE pid = $1 AND uid = $2 AND ptype = ANY($5) AND coalesce(deleted, 0) = 0
+            AND ($4::timestamp IS NULL
+                 OR date_trunc('second', coalesce(edited, posted)) = date_trunc('second', $4))
+        RETURNING pid
+        "#,
+        pid,
+        uid,
+        content,
+        last_edited_at,
+        &legacy::post_type_codes(PostType::Text)
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+
+    let current = sqlx::query!(
+        r#"
+        SELECT uid, content, deleted, ptype, coalesce(edited, posted) as last_edit
+        FROM sub_post
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Post not found {}", *id))?;
+
+    if updated.is_none() {
+        if current.uid.as_deref() != Some(uid) {
+            return Err("Only the author can edit a post".into());
+        }
+        if current.deleted.unwrap_or(0) != 0 {
+            return Err("Deleted posts can't be edited".into());
+        }
+        if legacy::post_type(current.ptype) != Some(PostType::Text) {
+            return Err("Only text posts can be edited".into());
+        }
+        return Err(edit_conflict(current.content, current.last_edit));
+    }
+    if let (Some(sid), Some(pattern)) = (sid, held) {
+        word_filter::hold_post(context, pid, &sid, pattern).await?;
+    }
+
+    context.post_loader.clear(pid).await;
+    context.publish(Event::PostEdited { pid }).await;
+    context
+        .post_loader
+        .load(pid)
+        .await
+        .map_err(|err| format!("{:?}", err).into())
+}
+
+/// Deletes the post as its author, or removes it as a mod or admin. Removals end up in the mod
+/// log with the reason.
+pub async fn delete_post(
+    context: &Context,
+    id: ID,
+    reason: Option<String>,
+) -> Result<Post, FieldError> {
+    let pid = context.config.post_ids.decode(&id)?;
+    let post = sqlx::query!(
+        r#"
+        SELECT uid, sid, deleted
+        FROM sub_post
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Post not found {}", *id))?;
+    let sid = post.sid.unwrap_or_default();
+    let status = deletion_by(&context.user, &sid, post.uid.as_deref())?;
+    if post.deleted.unwrap_or(0) != 0 {
+        return Err("This is already deleted".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    // Checked again here, a delete racing this one must not log a second removal
+    let deleted = sqlx::query!(
+        r#"
+        UPDATE sub_post
+        SET deleted = $2
+        WHERE pid = $1 AND coalesce(deleted, 0) = 0
+        RETURNING pid
+        "#,
+        pid,
+        status.to_db()
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    if deleted.is_none() {
+        return Err("This is already deleted".into());
+    }
+    if status != DeleteStatus::User {
+        moderation::log_action(
+            &mut tx,
+            context.user.user_id()?,
+            Some(sid),
+            "remove_posts",
+            vec![pid.to_string()],
+            reason,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    context.post_loader.clear(pid).await;
+    context
+        .post_loader
+        .load(pid)
+        .await
+        .map_err(|err| format!("{:?}", err).into())
+}
+
+/// Throat's sub_metadata key for each post type and whether subs without the key accept it.
+/// Text and link posts have to be turned off, polls turned on.
+const POST_TYPE_KEYS: &[(PostType, &str, bool)] = &[
+    (PostType::Text, "allow_text_posts", true),
+    (PostType::Link, "allow_link_posts", true),
+    (PostType::Poll, "allow_polls", false),
+];
+
+pub async fn allowed_post_types(
+    pool: &sqlx::PgPool,
+    sid: &str,
+) -> Result<Vec<PostType>, FieldError> {
+    let keys: Vec<String> = POST_TYPE_KEYS
+        .iter()
+        .map(|(_, key, _)| key.to_string())
+        .collect();
+    let values: HashMap<String, Option<String>> = sqlx::query!(
+        r#"
+        SELECT key as "key!", value
+        FROM sub_metadata
+        WHERE sid = $1 AND key = ANY($2)
+        "#,
+        sid,
+        &keys
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .map(|row| row.map(|row| (row.key, row.value)))
+    .collect::<Result<_, _>>()?;
+    Ok(POST_TYPE_KEYS
+        .iter()
+        .filter(|(_, key, default)| match values.get(*key) {
+            Some(value) => value.as_deref() == Some("1"),
+            None => *default,
+        })
+        .map(|(ptype, _, _)| *ptype)
+        .collect())
+}
+
+/// For the sub's mods, the change ends up in the mod log. Posts already there stay.
+pub async fn set_allowed_post_types(
+    context: &Context,
+    sub: String,
+    types: Vec<PostType>,
+) -> Result<Vec<PostType>, FieldError> {
+    let uid = context.user.user_id()?;
+    if types.is_empty() {
+        return Err("A sub has to accept at least one post type".into());
+    }
+    let sub: Sub = context
+        .sub_loader
+        .load(sub.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    for (ptype, &key, _) in POST_TYPE_KEYS {
+        sqlx::query!(
+            r#"
+            DELETE FROM sub_metadata
+            WHERE sid = $1 AND key = $2
+            "#,
+            sub.sid,
+            key
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO sub_metadata (sid, key, value)
+            VALUES ($1, $2, $3)
+            "#,
+            sub.sid,
+            key,
+            if types.contains(ptype) { "1" } else { "0" }
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    let names: Vec<String> = types.iter().map(|ptype| format!("{:?}", ptype)).collect();
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sub.sid.clone()),
+        "set_allowed_post_types",
+        vec![],
+        Some(names.join(", ")),
+    )
+    .await?;
+    tx.commit().await?;
+
+    allowed_post_types(&context.pool, &sub.sid).await
+}
+
+/// Longest title Throat accepts
+const MAX_TITLE_LENGTH: usize = 350;
+/// sub_post.link is a varchar(255)
+const MAX_LINK_LENGTH: usize = 255;
+
+/// Submits a text post, or a link post when `link` is given, where the sub accepts that type.
+/// Held for the mods when it trips a word filter, like edits are.
+pub async fn create_post(
+    context: &Context,
+    sub_name: String,
+    title: String,
+    content: Option<String>,
+    link: Option<String>,
+    nsfw: Option<bool>,
+) -> Result<Post, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub = context
+        .sub_loader
+        .load(sub_name.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+
+    let title = title.trim().to_string();
+    if title.is_empty() {
+        return Err("Posts need a title".into());
+    }
+    if title.chars().count() > MAX_TITLE_LENGTH {
+        return Err(format!("Titles can be at most {} characters", MAX_TITLE_LENGTH).into());
+    }
+    let link = link
+        .map(|link| link.trim().to_string())
+        .filter(|link| !link.is_empty());
+    if let Some(ref link) = link {
+        if !(link.starts_with("http://") || link.starts_with("https://"))
+            || link.len() > MAX_LINK_LENGTH
+        {
+            return Err("Links must start with http:// or https://".into());
+        }
+    }
+    let ptype = if link.is_some() {
+        PostType::Link
+    } else {
+        PostType::Text
+    };
+    if !allowed_post_types(&context.pool, &sub.sid)
+        .await?
+        .contains(&ptype)
+    {
+        return Err(match ptype {
+            PostType::Link => "This sub doesn't accept link posts",
+            _ => "This sub doesn't accept text posts",
+        }
+        .into());
+    }
+
+    moderation::check_not_banned(context, &sub.sid).await?;
+    moderation::check_not_muted(context, &sub.sid).await?;
+    membership::check_member(context, &sub.sid).await?;
+    membership::check_requirements(context, &sub.sid).await?;
+    let held = word_filter::screen(
+        context,
+        &sub.sid,
+        &format!("{}\n{}", title, content.as_deref().unwrap_or_default()),
+    )
+    .await?;
+
+    // Held posts are saved removed, they're never public before a mod approves them
+    let deleted = if held.is_some() {
+        DeleteStatus::Mod
+    } else {
+        DeleteStatus::Not
+    };
+    let mut tx = context.pool.begin().await?;
+    let pid = sqlx::query!(
+        r#"
+        INSERT INTO sub_post (sid, uid, title, content, link, ptype, nsfw, posted,
+                              score, upvotes, downvotes, comments, deleted)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, now(), 0, 0, 0, 0, $8)
+        RETURNING pid
+        "#,
+        sub.sid,
+        uid,
+        title,
+        content,
+        link,
+        ptype.to_db(),
+        nsfw.unwrap_or(false) || sub.nsfw,
+        deleted.to_db()
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .pid;
+
+    if let Some(pattern) = held {
+        word_filter::log_post_hold(&mut tx, uid, pid, &sub.sid, pattern).await?;
+    }
+    submitter::record(context, &mut tx, submitter::Submission::Post(pid)).await?;
+    language::record(&mut tx, pid, &title, content.as_deref()).await?;
+    tx.commit().await?;
+    // With PG_EVENTS the insert trigger from 0012_post_created.sql already announced it
+    if !context.config.pg_events {
+        context
+            .publish(Event::PostCreated {
+                pid,
+                sid: sub.sid.clone(),
+            })
+            .await;
+    }
+
+    context
+        .post_loader
+        .load(pid)
+        .await
+        .map_err(|err| format!("{:?}", err).into())
+}
+
+/// Only this many seen posts are remembered per user, the oldest are forgotten first
+const SEEN_POSTS_KEPT: i64 = 1000;
+
+pub async fn mark_seen(context: &Context, ids: Vec<ID>) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let pids = ids
+        .iter()
+        .map(|id| context.config.post_ids.decode(id))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO post_seen (uid, pid)
+        SELECT $1, pid FROM sub_post WHERE pid = ANY($2)
+        ON CONFLICT (uid, pid) DO UPDATE SET seen = now()
+        "#,
+        uid,
+        &pids
+    )
+    .execute(&context.pool)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM post_seen
+        WHERE uid = $1 AND pid NOT IN (
+            SELECT pid FROM post_seen WHERE uid = $1 ORDER BY seen DESC LIMIT $2
+        )
+        "#,
+        uid,
+        SEEN_POSTS_KEPT
+    )
+    .execute(&context.pool)
+    .await?;
+
+    Ok(true)
+}
+
 pub struct PostLoader {
-    pub pool: sqlx::PgPool,
+    pub repo: Arc<dyn PostRepo>,
 }
 
 pub async fn get_home_posts(
     context: &Context,
+    types: Option<Vec<PostType>>,
+    language: Option<String>,
+    hide_seen: Option<bool>,
     count: Option<i32>,
     after: Option<String>,
 ) -> Result<Page<Post>, FieldError> {
@@ -180,26 +933,11 @@ pub async fn get_home_posts(
         UserState::Anonymous => {
             get_related_posts(
                 context,
-                sqlx::query!(
-                    r#"
-                    SELECT value
-                    FROM site_metadata
-                    WHERE key = 'default'
-                    "#
-                )
-                .fetch(&context.pool)
-                .map(|metadata| -> Option<String> {
-                    if let Ok(metadata) = metadata {
-                        metadata.value
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>()
-                .await
-                .into_iter()
-                .filter_map(|v| v)
-                .collect::<Vec<_>>(),
+                site::default_sub_ids(&context.pool).await?,
+                types,
+                language,
+                hide_seen,
+                false,
                 count,
                 after,
             )
@@ -229,6 +967,10 @@ pub async fn get_home_posts(
                 .into_iter()
                 .filter_map(|v| v)
                 .collect::<Vec<_>>(),
+                types,
+                language,
+                hide_seen,
+                false,
                 count,
                 after,
             )
@@ -240,19 +982,68 @@ pub async fn get_home_posts(
 pub async fn get_related_posts(
     context: &Context,
     id: Vec<String>,
+    types: Option<Vec<PostType>>,
+    language: Option<String>,
+    hide_seen: Option<bool>,
+    newest_first: bool,
+    count: Option<i32>,
+    after: Option<String>,
+) -> Result<Page<Post>, FieldError> {
+    get_posts(
+        context,
+        Some(id),
+        types,
+        language,
+        hide_seen,
+        newest_first,
+        count,
+        after,
+    )
+    .await
+}
+
+pub async fn get_all_posts(
+    context: &Context,
+    types: Option<Vec<PostType>>,
+    language: Option<String>,
+    hide_seen: Option<bool>,
+    count: Option<i32>,
+    after: Option<String>,
+) -> Result<Page<Post>, FieldError> {
+    get_posts(
+        context, None, types, language, hide_seen, false, count, after,
+    )
+    .await
+}
+
+/// Posts by any of the given users or in any of the given subs, or every post for `None`. Oldest
+/// first unless `newest_first`.
+async fn get_posts(
+    context: &Context,
+    id: Option<Vec<String>>,
+    types: Option<Vec<PostType>>,
+    language: Option<String>,
+    hide_seen: Option<bool>,
+    newest_first: bool,
     count: Option<i32>,
     after: Option<String>,
 ) -> Result<Page<Post>, FieldError> {
+    let types = type_filter(types);
+    let seen_by = if hide_seen.unwrap_or(false) {
+        context.user.user_id().ok()
+    } else {
+        None
+    };
     let count = count.unwrap_or(25);
-    let after: i64 = after.map(|v| v.parse().unwrap_or(0)).unwrap_or(0);
+    let after = parse_offset(after)?;
 
     let edges = sqlx::query!(
         r#"
             SELECT pid, content, deleted, link, nsfw, posted, edited, ptype, sid, thumbnail, 
-            title, uid, flair, c.child_arr as comments, v.up as up_votes, v.down as down_votes
+            title, uid, flair, c.comment_count, v.up as up_votes, v.down as down_votes
             FROM sub_post
             LEFT JOIN ( 
-                SELECT c.pid AS pid, array_agg(c.cid) as child_arr
+                SELECT c.pid AS pid, count(*) as comment_count
                 FROM sub_post_comment AS c
                 where c.parentcid IS NULL
                 GROUP BY c.pid
@@ -264,14 +1055,25 @@ pub async fn get_related_posts(
                 FROM sub_post_vote as v
                 GROUP BY v.pid
             ) v USING (pid)
-            WHERE uid = ANY($3) OR sid = ANY($3)
-            ORDER BY posted
+            WHERE ($3::text[] IS NULL OR uid = ANY($3) OR sid = ANY($3))
+                AND (cardinality($4::int[]) = 0 OR ptype = ANY($4))
+                AND ($5::text IS NULL OR pid NOT IN (
+                    SELECT s.pid FROM post_seen s WHERE s.uid = $5
+                ))
+                AND ($6::text IS NULL OR pid IN (
+                    SELECT l.pid FROM post_language l WHERE l.language = $6
+                ))
+            ORDER BY CASE WHEN $7::bool THEN posted END DESC, posted
             LIMIT $1
             OFFSET $2
             "#,
         count as i64,
-        after as i64,
-        id.as_slice()
+        after,
+        id.as_deref(),
+        &types,
+        seen_by,
+        language,
+        newest_first
     )
     .fetch(&context.pool)
     .enumerate()
@@ -291,29 +1093,15 @@ pub async fn get_related_posts(
                 content: post.content,
                 thumbnail: post.thumbnail,
                 sid: post.sid,
-                comments: post.comments.unwrap_or_default(),
-                ptype: match post.ptype {
-                    Some(0) => Ok(PostType::Text),
-                    Some(1) => Ok(PostType::Link),
-                    Some(3) => Ok(PostType::Poll),
-                    _ => Err(format!(
-                        "Unknown Post Type! {:?} - {:?}",
-                        post.pid, post.ptype
-                    )),
-                }?,
+                comment_count: post.comment_count.unwrap_or(0) as i32,
+                ptype: legacy::post_type(post.ptype).ok_or_else(|| {
+                    format!("Unknown Post Type! {:?} - {:?}", post.pid, post.ptype)
+                })?,
                 edited: post.edited,
                 link: post.link,
-                deleted: match post.deleted {
-                    Some(1) => Ok(DeleteStatus::User),
-                    Some(2) => Ok(DeleteStatus::Mod),
-                    Some(3) => Ok(DeleteStatus::Admin),
-                    Some(0) => Ok(DeleteStatus::Not),
-                    None => Ok(DeleteStatus::Not),
-                    _ => Err(format!(
-                        "Unknown Delete Type! {:?} - {:?}",
-                        post.pid, post.deleted
-                    )),
-                }?,
+                deleted: legacy::post_deleted(post.deleted).ok_or_else(|| {
+                    format!("Unknown Delete Type! {:?} - {:?}", post.pid, post.deleted)
+                })?,
             },
             cursor: format!("{}", i),
         })
@@ -334,9 +1122,19 @@ pub async fn get_related_posts(
             r#"
                 SELECT count(*) as "cnt!"
                 FROM sub_post
-                WHERE uid = ANY($1) OR sid = ANY($1)
+                WHERE ($1::text[] IS NULL OR uid = ANY($1) OR sid = ANY($1))
+                    AND (cardinality($2::int[]) = 0 OR ptype = ANY($2))
+                    AND ($3::text IS NULL OR pid NOT IN (
+                        SELECT s.pid FROM post_seen s WHERE s.uid = $3
+                    ))
+                    AND ($4::text IS NULL OR pid IN (
+                        SELECT l.pid FROM post_language l WHERE l.language = $4
+                    ))
                 "#,
-            id.as_slice()
+            id.as_deref(),
+            &types,
+            seen_by,
+            language
         )
         .fetch_one(&context.pool)
         .await?
@@ -350,13 +1148,21 @@ pub async fn get_related_posts(
 #[async_trait]
 impl BatchFn<i32, Result<Post, Arc<FieldError>>> for PostLoader {
     async fn load(&self, ids: &[i32]) -> HashMap<i32, Result<Post, Arc<FieldError>>> {
-        let posts: Vec<Result<Post, FieldError>> = sqlx::query!(
+        stats::record_batch("post", ids.len());
+        self.repo.load_posts(ids).await
+    }
+}
+
+#[async_trait]
+impl PostRepo for sqlx::PgPool {
+    async fn load_posts(&self, ids: &[i32]) -> HashMap<i32, Result<Post, Arc<FieldError>>> {
+        let posts: Vec<Result<(i32, Result<Post, FieldError>), FieldError>> = sqlx::query!(
             r#"
             SELECT pid, content, deleted, link, nsfw, posted, edited, ptype, sid, thumbnail, 
-            title, uid, flair, c.child_arr as comments, v.up as up_votes, v.down as down_votes
+            title, uid, flair, c.comment_count, v.up as up_votes, v.down as down_votes
             FROM sub_post
             LEFT JOIN ( 
-                SELECT c.pid AS pid, array_agg(c.cid) as child_arr
+                SELECT c.pid AS pid, count(*) as comment_count
                 FROM sub_post_comment AS c
                 where c.parentcid IS NULL
                 GROUP BY c.pid
@@ -372,65 +1178,83 @@ impl BatchFn<i32, Result<Post, Arc<FieldError>>> for PostLoader {
             "#,
             ids
         )
-        .fetch(&self.pool)
-        .map(|post| -> Result<Post, FieldError> {
-            let post = post?;
-            Ok(Post {
-                up_votes: post.up_votes.unwrap_or(0) as i32,
-                down_votes: post.down_votes.unwrap_or(0) as i32,
-                posted: post.posted,
-                pid: post.pid,
-                flair: post.flair,
-                comments: post.comments.unwrap_or_default(),
-                uid: post.uid,
-                title: post.title,
-                nsfw: post.nsfw.unwrap_or(false),
-                content: post.content,
-                thumbnail: post.thumbnail,
-                sid: post.sid,
-                ptype: match post.ptype {
-                    Some(0) => Ok(PostType::Text),
-                    Some(1) => Ok(PostType::Link),
-                    Some(3) => Ok(PostType::Poll),
-                    _ => Err(format!(
-                        "Unknown Post Type! {:?} - {:?}",
-                        post.pid, post.ptype
-                    )),
-                }?,
-                edited: post.edited,
-                link: post.link,
-                deleted: match post.deleted {
-                    Some(1) => Ok(DeleteStatus::User),
-                    Some(2) => Ok(DeleteStatus::Mod),
-                    Some(3) => Ok(DeleteStatus::Admin),
-                    Some(0) => Ok(DeleteStatus::Not),
-                    None => Ok(DeleteStatus::Not),
-                    _ => Err(format!(
-                        "Unknown Delete Type! {:?} - {:?}",
-                        post.pid, post.deleted
-                    )),
-                }?,
-            })
-        })
+        .fetch(self)
+        .map(
+            |post| -> Result<(i32, Result<Post, FieldError>), FieldError> {
+                let post = post?;
+                let pid = post.pid;
+                // A row that fails to decode only fails its own key
+                let decode = move || -> Result<Post, FieldError> {
+                    Ok(Post {
+                        up_votes: post.up_votes.unwrap_or(0) as i32,
+                        down_votes: post.down_votes.unwrap_or(0) as i32,
+                        posted: post.posted,
+                        pid: post.pid,
+                        flair: post.flair,
+                        comment_count: post.comment_count.unwrap_or(0) as i32,
+                        uid: post.uid,
+                        title: post.title,
+                        nsfw: post.nsfw.unwrap_or(false),
+                        content: post.content,
+                        thumbnail: post.thumbnail,
+                        sid: post.sid,
+                        ptype: legacy::post_type(post.ptype).ok_or_else(|| {
+                            format!("Unknown Post Type! {:?} - {:?}", post.pid, post.ptype)
+                        })?,
+                        edited: post.edited,
+                        link: post.link,
+                        deleted: legacy::post_deleted(post.deleted).ok_or_else(|| {
+                            format!("Unknown Delete Type! {:?} - {:?}", post.pid, post.deleted)
+                        })?,
+                    })
+                };
+                Ok((pid, decode()))
+            },
+        )
         .collect()
         .await;
 
-        let mut map: HashMap<i32, Result<Post, Arc<FieldError>>> = posts
-            .into_iter()
-            .filter_map(|post| {
-                if let Ok(post) = post {
-                    Some((post.pid, Ok(post)))
-                } else {
-                    None
-                }
-            })
-            .collect();
+        let mut map: HashMap<i32, Result<Post, Arc<FieldError>>> = HashMap::new();
+        let mut batch_error: Option<Arc<FieldError>> = None;
+
+        posts.into_iter().for_each(|post| match post {
+            Ok((pid, post)) => {
+                map.insert(
+                    pid,
+                    post.map_err(|err| {
+                        Arc::new(format!("Could not load post {} - {}", pid, err.message()).into())
+                    }),
+                );
+            }
+            Err(err) => {
+                log::error!("Batch Load Post - {:?}", err);
+                batch_error = Some(Arc::new(err));
+            }
+        });
 
         ids.iter().for_each(|id| {
             map.entry(id.to_owned())
-                .or_insert_with(|| Err(Arc::new(format!("Post not found {}", id).into())));
+                .or_insert_with(|| match batch_error {
+                    Some(ref err) => Err(err.clone()),
+                    None => Err(Arc::new(format!("Post not found {}", id).into())),
+                });
         });
 
         map
     }
 }
+
+#[async_trait]
+impl PostRepo for Memory {
+    async fn load_posts(&self, ids: &[i32]) -> HashMap<i32, Result<Post, Arc<FieldError>>> {
+        ids.iter()
+            .map(|&id| {
+                let post = self.posts.iter().find(|post| post.pid == id).cloned();
+                (
+                    id,
+                    post.ok_or_else(|| Arc::new(format!("Post not found {}", id).into())),
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{
+    config::Config,
+    events::{Event, EventBus},
+    Context,
+};
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLInputObject};
+use serde_json::json;
+use std::sync::Arc;
+pub(crate) use tokio::sync::broadcast::RecvError;
+use web_push::{
+    ContentEncoding, SubscriptionInfo, VapidSignatureBuilder, WebPushClient, WebPushError,
+    WebPushMessageBuilder,
+};
+
+/// More than this and the oldest browser is forgotten
+const MAX_SUBSCRIPTIONS: i64 = 20;
+
+/// The `keys` of a browser PushSubscription
+#[derive(Debug, GraphQLInputObject)]
+pub struct PushKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+pub async fn register(
+    context: &Context,
+    endpoint: String,
+    keys: PushKeys,
+) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    if !endpoint.starts_with("https://") {
+        return Err("Push endpoints have to be https urls".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO push_subscription (endpoint, uid, p256dh, auth)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (endpoint) DO UPDATE
+        SET uid = $2, p256dh = $3, auth = $4, created = now()
+        "#,
+        endpoint,
+        uid,
+        keys.p256dh,
+        keys.auth
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM push_subscription
+        WHERE uid = $1 AND endpoint NOT IN (
+            SELECT endpoint FROM push_subscription
+            WHERE uid = $1
+            ORDER BY created DESC
+            LIMIT $2
+        )
+        "#,
+        uid,
+        MAX_SUBSCRIPTIONS
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+pub async fn unregister(context: &Context, endpoint: String) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    Ok(sqlx::query!(
+        r#"
+        DELETE FROM push_subscription
+        WHERE endpoint = $1 AND uid = $2
+        RETURNING endpoint
+        "#,
+        endpoint,
+        uid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .is_some())
+}
+
+/// Who to notify and what the service worker receives, it fetches the rest through the API
+pub(crate) fn payload(event: &Event) -> Option<(&str, serde_json::Value)> {
+    match event {
+        Event::CommentReplied { cid, uid } => Some((uid, json!({ "type": "reply", "cid": cid }))),
+        Event::ThreadCommented { cid, pid, uid } => {
+            Some((uid, json!({ "type": "thread", "cid": cid, "pid": pid })))
+        }
+        Event::MessageReceived { mid, uid } => {
+            Some((uid, json!({ "type": "message", "mid": mid })))
+        }
+        Event::UserWarned { id, uid, .. } => Some((uid, json!({ "type": "warning", "id": id }))),
+        Event::MembershipDecided { uid, sid, approved } => Some((
+            uid,
+            json!({ "type": "membership", "sid": sid, "approved": approved }),
+        )),
+        _ => None,
+    }
+}
+
+async fn deliver(
+    pool: &sqlx::PgPool,
+    client: &WebPushClient,
+    vapid_key: &[u8],
+    uid: &str,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let subscriptions = sqlx::query!(
+        r#"
+        SELECT endpoint, p256dh, auth
+        FROM push_subscription
+        WHERE uid = $1
+        "#,
+        uid
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    for subscription in subscriptions {
+        let info = SubscriptionInfo::new(
+            &subscription.endpoint,
+            &subscription.p256dh,
+            &subscription.auth,
+        );
+        let mut builder = WebPushMessageBuilder::new(&info)?;
+        builder.set_payload(ContentEncoding::AesGcm, payload);
+        builder.set_vapid_signature(VapidSignatureBuilder::from_pem(vapid_key, &info)?.build()?);
+
+        match client.send(builder.build()?).await {
+            Ok(()) => {}
+            // The browser dropped the subscription, it will never work again
+            Err(WebPushError::EndpointNotValid) | Err(WebPushError::EndpointNotFound) => {
+                sqlx::query!(
+                    r#"
+                    DELETE FROM push_subscription
+                    WHERE endpoint = $1
+                    "#,
+                    subscription.endpoint
+                )
+                .execute(pool)
+                .await?;
+            }
+            Err(err) => log::warn!("Push to {} failed - {}", subscription.endpoint, err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Background worker, pushes replies and messages to every browser their recipient registered.
+/// Only run it in one process, with PG_EVENTS so it hears about what Throat writes.
+pub async fn run(pool: sqlx::PgPool, events: Arc<dyn EventBus>, config: Arc<Config>) {
+    let vapid_key = match config.vapid_private_key {
+        Some(ref path) => match std::fs::read(path) {
+            Ok(key) => key,
+            Err(err) => {
+                log::error!("Can't read VAPID_PRIVATE_KEY {} - {}", path, err);
+                return;
+            }
+        },
+        // Only the mobile apps are set up
+        None => return,
+    };
+    let client = match WebPushClient::new() {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("Could not set up the push client - {}", err);
+            return;
+        }
+    };
+
+    let mut receiver = events.subscribe();
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                log::warn!("Push worker fell behind, skipped {} events", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if let Some((uid, payload)) = payload(&event) {
+            let payload = payload.to_string();
+            if let Err(err) = deliver(&pool, &client, &vapid_key, uid, payload.as_bytes()).await {
+                log::error!("Could not push {:?} - {}", event, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{auth::UserState, Context};
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Most viewers tracked at once, finished windows are dropped once it's reached
+const MAX_VIEWERS: usize = 100_000;
+
+lazy_static! {
+    // Start of the current window and requests made in it, by viewer
+    static ref WINDOWS: Mutex<HashMap<String, (Instant, usize)>> = Mutex::new(HashMap::new());
+}
+
+/// Where a viewer stands in the current window. Nothing is refused, the numbers are only there so
+/// well-behaved clients can slow down before a proxy in front starts refusing them.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub limit: usize,
+    pub remaining: usize,
+    /// Until the window starts over
+    pub reset: Duration,
+}
+
+impl RateLimit {
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            ("x-ratelimit-limit", self.limit.to_string()),
+            ("x-ratelimit-remaining", self.remaining.to_string()),
+            ("x-ratelimit-reset", self.reset.as_secs().to_string()),
+        ]
+    }
+
+    pub fn extension(&self) -> serde_json::Value {
+        serde_json::json!({
+            "limit": self.limit,
+            "remaining": self.remaining,
+            "reset": self.reset.as_secs(),
+        })
+    }
+}
+
+/// Logged in viewers are counted by account, everyone else by address
+fn viewer(context: &Context) -> String {
+    match context.user {
+        UserState::LoggedIn { ref id, .. } => format!("u:{}", id),
+        UserState::Anonymous => format!("ip:{}", context.request.ip.as_deref().unwrap_or("")),
+    }
+}
+
+/// Counts a request, None when RATE_LIMIT is off
+pub fn hit(context: &Context) -> Option<RateLimit> {
+    let limit = context.config.rate_limit;
+    let window = context.config.rate_limit_window;
+    if limit == 0 {
+        return None;
+    }
+
+    let mut windows = WINDOWS.lock().unwrap();
+    if windows.len() >= MAX_VIEWERS {
+        windows.retain(|_, (started, _)| started.elapsed() < window);
+    }
+    let entry = windows
+        .entry(viewer(context))
+        .or_insert_with(|| (Instant::now(), 0));
+    if entry.0.elapsed() >= window {
+        *entry = (Instant::now(), 0);
+    }
+    entry.1 += 1;
+
+    Some(RateLimit {
+        limit,
+        remaining: limit.saturating_sub(entry.1),
+        reset: window.checked_sub(entry.0.elapsed()).unwrap_or_default(),
+    })
+}
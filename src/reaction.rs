@@ -0,0 +1,422 @@
+use crate::{moderation, sub::Sub, Context};
+use chrono::NaiveDateTime;
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLObject, ID};
+
+/// Most custom emoji one sub can have
+const MAX_EMOJI: i64 = 100;
+/// Different reactions one user can leave on one comment
+const MAX_PER_USER: i64 = 10;
+const MAX_URL_LENGTH: usize = 255;
+
+/// An image mods added for reactions in their sub, used by its name
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct SubEmoji {
+    pub name: String,
+    pub url: String,
+    pub created: NaiveDateTime,
+}
+
+/// Everyone who reacted to a comment with the same emoji
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct Reaction {
+    /// A standard emoji, or the name of one of the sub's custom emoji
+    pub emoji: String,
+    /// Image of a custom emoji, null for standard ones
+    pub url: Option<String>,
+    pub count: i32,
+    pub viewer_reacted: bool,
+}
+
+/// Custom emoji names are short lowercase words, like `party_parrot`
+fn valid_name(name: &str) -> bool {
+    (2..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Standard emoji are taken as they come, a sequence of a few non-ASCII characters
+fn standard_emoji(emoji: &str) -> bool {
+    !emoji.is_empty()
+        && emoji.len() <= 32
+        && emoji.chars().all(|c| !c.is_ascii() && !c.is_whitespace())
+}
+
+async fn load_sub(context: &Context, name: String) -> Result<Sub, FieldError> {
+    context
+        .sub_loader
+        .load(name.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })
+}
+
+/// Subs opt in to reactions on comments, they're off by default
+pub async fn reactions_allowed(pool: &sqlx::PgPool, sid: &str) -> Result<bool, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT value
+        FROM sub_metadata
+        WHERE sid = $1 AND key = 'comment_reactions'
+        "#,
+        sid
+    )
+    .fetch_optional(pool)
+    .await?
+    .and_then(|row| row.value)
+    .as_deref()
+        == Some("1"))
+}
+
+/// For the sub's mods, the change ends up in the mod log. Turning reactions off hides the ones
+/// already left but doesn't delete them.
+pub async fn set_comment_reactions(
+    context: &Context,
+    sub: String,
+    enabled: bool,
+) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub = load_sub(context, sub).await?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM sub_metadata
+        WHERE sid = $1 AND key = 'comment_reactions'
+        "#,
+        sub.sid
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO sub_metadata (sid, key, value)
+        VALUES ($1, 'comment_reactions', $2)
+        "#,
+        sub.sid,
+        if enabled { "1" } else { "0" }
+    )
+    .execute(&mut tx)
+    .await?;
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sub.sid.clone()),
+        if enabled {
+            "enable_comment_reactions"
+        } else {
+            "disable_comment_reactions"
+        },
+        vec![],
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(enabled)
+}
+
+pub async fn sub_emoji(pool: &sqlx::PgPool, sid: &str) -> Result<Vec<SubEmoji>, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT name, url, created
+        FROM sub_emoji
+        WHERE sid = $1
+        ORDER BY name
+        "#,
+        sid
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| SubEmoji {
+        name: row.name,
+        url: row.url,
+        created: row.created,
+    })
+    .collect())
+}
+
+/// For the sub's mods. Adding a name that's already there replaces its image.
+pub async fn add_emoji(
+    context: &Context,
+    sub: String,
+    name: String,
+    url: String,
+) -> Result<SubEmoji, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub = load_sub(context, sub).await?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+    if !valid_name(&name) {
+        return Err(format!("Invalid emoji name {}", name).into());
+    }
+    if url.len() > MAX_URL_LENGTH || !url.starts_with("https://") {
+        return Err("Emoji images need an https:// URL".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    let count = sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM sub_emoji
+        WHERE sid = $1 AND name <> $2
+        "#,
+        sub.sid,
+        name
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .cnt;
+    if count >= MAX_EMOJI {
+        return Err(format!("Subs can have at most {} custom emoji", MAX_EMOJI).into());
+    }
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO sub_emoji (sid, name, url, created_by)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (sid, name) DO UPDATE SET url = EXCLUDED.url
+        RETURNING created
+        "#,
+        sub.sid,
+        name,
+        url,
+        uid
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sub.sid.clone()),
+        "add_emoji",
+        vec![name.clone()],
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(SubEmoji {
+        name,
+        url,
+        created: row.created,
+    })
+}
+
+/// For the sub's mods, reactions with the emoji go with it
+pub async fn remove_emoji(
+    context: &Context,
+    sub: String,
+    name: String,
+) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub = load_sub(context, sub).await?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    let removed = sqlx::query!(
+        r#"
+        DELETE FROM sub_emoji
+        WHERE sid = $1 AND name = $2
+        RETURNING name
+        "#,
+        sub.sid,
+        name
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    if removed.is_none() {
+        return Err(format!("Unknown emoji {}", name).into());
+    }
+    sqlx::query!(
+        r#"
+        DELETE FROM sub_post_comment_reaction r
+        USING sub_post_comment c, sub_post p
+        WHERE r.cid = c.cid AND c.pid = p.pid AND p.sid = $1 AND r.emoji = $2
+        "#,
+        sub.sid,
+        name
+    )
+    .execute(&mut tx)
+    .await?;
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sub.sid.clone()),
+        "remove_emoji",
+        vec![name],
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Reactions on the comment, most used first. Empty where the sub has reactions turned off.
+pub async fn comment_reactions(
+    context: &Context,
+    cid: &str,
+    sid: &str,
+) -> Result<Vec<Reaction>, FieldError> {
+    if !reactions_allowed(&context.pool, sid).await? {
+        return Ok(vec![]);
+    }
+    let uid = context.user.user_id().ok();
+    Ok(sqlx::query!(
+        r#"
+        SELECT r.emoji, e.url as "url?", count(*) as "count!",
+            coalesce(bool_or(r.uid = $2), false) as "viewer_reacted!"
+        FROM sub_post_comment_reaction r
+        LEFT JOIN sub_emoji e ON e.sid = $3 AND e.name = r.emoji
+        WHERE r.cid = $1
+        GROUP BY r.emoji, e.url
+        ORDER BY count(*) DESC, min(r.time)
+        "#,
+        cid,
+        uid,
+        sid
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| Reaction {
+        emoji: row.emoji,
+        url: row.url,
+        count: row.count as i32,
+        viewer_reacted: row.viewer_reacted,
+    })
+    .collect())
+}
+
+/// Sub of a comment that can be reacted to
+async fn reactable_sid(context: &Context, id: &ID) -> Result<String, FieldError> {
+    let comment = sqlx::query!(
+        r#"
+        SELECT c.status, p.sid
+        FROM sub_post_comment c
+        JOIN sub_post p ON p.pid = c.pid
+        WHERE c.cid = $1
+        "#,
+        id.as_str()
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Comment not found {}", id.as_str()))?;
+    if comment.status.unwrap_or(0) != 0 {
+        return Err("Deleted comments can't get reactions".into());
+    }
+    let sid = comment.sid.unwrap_or_default();
+    if !reactions_allowed(&context.pool, &sid).await? {
+        return Err("This sub doesn't allow reactions".into());
+    }
+    Ok(sid)
+}
+
+/// Adds the viewer's reaction, reacting twice with the same emoji is the same as once.
+/// Reactions are separate from votes and don't change the score.
+pub async fn react(context: &Context, id: ID, emoji: String) -> Result<Vec<Reaction>, FieldError> {
+    let uid = context.user.user_id()?;
+    let sid = reactable_sid(context, &id).await?;
+    moderation::check_not_banned(context, &sid).await?;
+    if !standard_emoji(&emoji) {
+        let custom = valid_name(&emoji)
+            && sqlx::query!(
+                r#"
+                SELECT 1 as "one!"
+                FROM sub_emoji
+                WHERE sid = $1 AND name = $2
+                "#,
+                sid,
+                emoji
+            )
+            .fetch_optional(&context.pool)
+            .await?
+            .is_some();
+        if !custom {
+            return Err(format!("Unknown emoji {}", emoji).into());
+        }
+    }
+
+    let mut tx = context.pool.begin().await?;
+    let count = sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM sub_post_comment_reaction
+        WHERE cid = $1 AND uid = $2 AND emoji <> $3
+        "#,
+        id.as_str(),
+        uid,
+        emoji
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .cnt;
+    if count >= MAX_PER_USER {
+        return Err(format!("At most {} reactions per comment", MAX_PER_USER).into());
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO sub_post_comment_reaction (cid, uid, emoji)
+        VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        "#,
+        id.as_str(),
+        uid,
+        emoji
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    comment_reactions(context, id.as_str(), &sid).await
+}
+
+/// Takes back the viewer's reaction, fine if there was none
+pub async fn unreact(
+    context: &Context,
+    id: ID,
+    emoji: String,
+) -> Result<Vec<Reaction>, FieldError> {
+    let uid = context.user.user_id()?;
+    let sid = reactable_sid(context, &id).await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM sub_post_comment_reaction
+        WHERE cid = $1 AND uid = $2 AND emoji = $3
+        "#,
+        id.as_str(),
+        uid,
+        emoji
+    )
+    .execute(&context.pool)
+    .await?;
+
+    comment_reactions(context, id.as_str(), &sid).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emoji_names() {
+        assert!(valid_name("party_parrot"));
+        assert!(!valid_name("Party"));
+        assert!(!valid_name("x"));
+        assert!(standard_emoji("👍"));
+        assert!(standard_emoji("👍🏽"));
+        assert!(!standard_emoji(":+1:"));
+        assert!(!standard_emoji("👍 👍"));
+    }
+}
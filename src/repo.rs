@@ -0,0 +1,88 @@
+//! Where the loaders get their rows from. Postgres implements all of these through sqlx::PgPool,
+//! Memory keeps the rows in memory so what loaders and resolvers make of them can be unit tested
+//! without a database. Only loader reads go through here, resolvers that write or count still
+//! use the pool.
+
+use crate::{
+    comment::{ChildrenKey, ChildrenPage, Comment},
+    post::Post,
+    sub::Sub,
+    user::{User, UserRef},
+};
+use async_trait::async_trait;
+use juniper::FieldError;
+use std::{collections::HashMap, sync::Arc};
+use unicase::UniCase;
+
+/// Every key has to be answered, missing rows with a not found error
+#[async_trait]
+pub trait PostRepo: Send + Sync {
+    async fn load_posts(&self, ids: &[i32]) -> HashMap<i32, Result<Post, Arc<FieldError>>>;
+}
+
+#[async_trait]
+pub trait SubRepo: Send + Sync {
+    /// Keys are names, current or previous, or sids
+    async fn load_subs(
+        &self,
+        keys: &[UniCase<String>],
+    ) -> HashMap<UniCase<String>, Result<Sub, Arc<FieldError>>>;
+}
+
+#[async_trait]
+pub trait UserRepo: Send + Sync {
+    async fn load_users(&self, keys: &[UserRef])
+        -> HashMap<UserRef, Result<User, Arc<FieldError>>>;
+}
+
+#[async_trait]
+pub trait CommentRepo: Send + Sync {
+    async fn load_comments(
+        &self,
+        keys: &[String],
+    ) -> HashMap<String, Result<Comment, Arc<FieldError>>>;
+
+    async fn load_children(
+        &self,
+        keys: &[ChildrenKey],
+    ) -> HashMap<ChildrenKey, Result<ChildrenPage, Arc<FieldError>>>;
+}
+
+#[derive(Clone)]
+pub struct Repos {
+    pub posts: Arc<dyn PostRepo>,
+    pub subs: Arc<dyn SubRepo>,
+    pub users: Arc<dyn UserRepo>,
+    pub comments: Arc<dyn CommentRepo>,
+}
+
+impl Repos {
+    pub fn postgres(pool: &sqlx::PgPool) -> Self {
+        Repos {
+            posts: Arc::new(pool.clone()),
+            subs: Arc::new(pool.clone()),
+            users: Arc::new(pool.clone()),
+            comments: Arc::new(pool.clone()),
+        }
+    }
+
+    pub fn memory(memory: Memory) -> Self {
+        let memory = Arc::new(memory);
+        Repos {
+            posts: memory.clone(),
+            subs: memory.clone(),
+            users: memory.clone(),
+            comments: memory,
+        }
+    }
+}
+
+/// Rows held in memory, answered the way Postgres would. Each module implements its trait for
+/// this next to the sqlx::PgPool one.
+#[derive(Default, Clone)]
+pub struct Memory {
+    pub posts: Vec<Post>,
+    pub subs: Vec<Sub>,
+    pub users: Vec<User>,
+    pub comments: Vec<Comment>,
+}
@@ -0,0 +1,317 @@
+use crate::{
+    content::Content,
+    parse_offset,
+    sub::Sub,
+    user::{User, UserRef},
+    Context, Cursor, Edge, Page, PageInfo,
+};
+use chrono::NaiveDateTime;
+use futures_util::stream::StreamExt;
+use juniper::{graphql_object, FieldError, GraphQLEnum, ID};
+
+/// sub_post_report.reason and sub_post_comment_report.reason are varchar(128)
+const MAX_REASON_LENGTH: usize = 128;
+const MAX_SHOWN: i32 = 100;
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum ReportKind {
+    Post,
+    Comment,
+}
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum ReportStatus {
+    Open,
+    Closed,
+}
+
+/// A report from Throat's sub_post_report or sub_post_comment_report. Ids are only unique
+/// together with the kind.
+#[derive(Debug, Clone)]
+pub struct Report {
+    kind: ReportKind,
+    id: i32,
+    pid: Option<i32>,
+    cid: Option<String>,
+    uid: Option<String>,
+    reason: Option<String>,
+    created: Option<NaiveDateTime>,
+    open: bool,
+}
+
+#[graphql_object(context = Context)]
+impl Report {
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn kind(&self) -> ReportKind {
+        self.kind
+    }
+
+    /// The reported post or comment, null once it's gone
+    async fn content(&self, context: &Context) -> Option<Content> {
+        match (self.kind, self.pid, &self.cid) {
+            (ReportKind::Post, Some(pid), _) => {
+                context.post_loader.load(pid).await.ok().map(Content::Post)
+            }
+            (ReportKind::Comment, _, Some(cid)) => context
+                .comment_loader
+                .load(cid.clone())
+                .await
+                .ok()
+                .map(Content::Comment),
+            _ => None,
+        }
+    }
+
+    /// Only admins see who reported, null for mods
+    async fn reporter(&self, context: &Context) -> Option<User> {
+        if !context.user.is_admin() {
+            return None;
+        }
+        let uid = self.uid.clone()?;
+        context.user_loader.load(UserRef::Uid(uid)).await.ok()
+    }
+
+    fn reason(&self) -> &Option<String> {
+        &self.reason
+    }
+
+    fn created_at(&self) -> Option<NaiveDateTime> {
+        self.created
+    }
+
+    fn status(&self) -> ReportStatus {
+        if self.open {
+            ReportStatus::Open
+        } else {
+            ReportStatus::Closed
+        }
+    }
+}
+
+#[graphql_object(name = "ReportNode", context = Context)]
+impl Edge<Report> {
+    fn node(&self) -> &Report {
+        &self.node
+    }
+
+    fn cursor(&self) -> &Cursor {
+        &self.cursor
+    }
+}
+
+#[graphql_object(name = "ReportPage", context = Context)]
+impl Page<Report> {
+    fn edges(&self) -> &Vec<Edge<Report>> {
+        &self.edges
+    }
+
+    fn page_info(&self) -> &PageInfo {
+        &self.page_info
+    }
+
+    fn total_count(&self) -> i32 {
+        self.total_count
+    }
+}
+
+fn check_reason(reason: String) -> Result<String, FieldError> {
+    let reason = reason.trim().to_string();
+    if reason.is_empty() {
+        return Err("Reports need a reason".into());
+    }
+    if reason.chars().count() > MAX_REASON_LENGTH {
+        return Err(format!("Reasons can be at most {} characters", MAX_REASON_LENGTH).into());
+    }
+    Ok(reason)
+}
+
+/// Reports the post to the sub's mods. Each user can have one open report per post.
+pub async fn report_post(context: &Context, id: ID, reason: String) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let reason = check_reason(reason)?;
+    let pid = context.config.post_ids.decode(&id)?;
+    let post = sqlx::query!(
+        r#"
+        SELECT deleted
+        FROM sub_post
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Post not found {}", *id))?;
+    if post.deleted.unwrap_or(0) != 0 {
+        return Err("Deleted content can't be reported".into());
+    }
+
+    let reported = sqlx::query!(
+        r#"
+        INSERT INTO sub_post_report (pid, uid, datetime, reason, open, send_to_admin)
+        SELECT $1, $2, now(), $3, true, false
+        WHERE NOT EXISTS (
+            SELECT 1 FROM sub_post_report WHERE pid = $1 AND uid = $2 AND open
+        )
+        RETURNING id
+        "#,
+        pid,
+        uid,
+        reason
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+    if reported.is_none() {
+        return Err("You already reported this".into());
+    }
+    Ok(true)
+}
+
+/// Like reportPost, for comments
+pub async fn report_comment(context: &Context, id: ID, reason: String) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let reason = check_reason(reason)?;
+    let comment = sqlx::query!(
+        r#"
+        SELECT status
+        FROM sub_post_comment
+        WHERE cid = $1
+        "#,
+        id.as_str()
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Comment not found {}", *id))?;
+    if comment.status.unwrap_or(0) != 0 {
+        return Err("Deleted content can't be reported".into());
+    }
+
+    let reported = sqlx::query!(
+        r#"
+        INSERT INTO sub_post_comment_report (cid, uid, datetime, reason, open, send_to_admin)
+        SELECT $1, $2, now(), $3, true, false
+        WHERE NOT EXISTS (
+            SELECT 1 FROM sub_post_comment_report WHERE cid = $1 AND uid = $2 AND open
+        )
+        RETURNING id
+        "#,
+        id.as_str(),
+        uid,
+        reason
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+    if reported.is_none() {
+        return Err("You already reported this".into());
+    }
+    Ok(true)
+}
+
+/// Reports on the sub's posts and comments, newest first. For the sub's mods and admins.
+pub async fn get_reports(
+    context: &Context,
+    sub_name: String,
+    status: Option<ReportStatus>,
+    count: Option<i32>,
+    after: Option<String>,
+) -> Result<Page<Report>, FieldError> {
+    let sub: Sub = context
+        .sub_loader
+        .load(sub_name.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+    let open = status.map(|status| status == ReportStatus::Open);
+    let count = count.unwrap_or(25).max(0).min(MAX_SHOWN) as i64;
+    let offset = parse_offset(after)?;
+
+    let edges = sqlx::query!(
+        r#"
+        SELECT kind as "kind!", id as "id!", pid, cid, uid, reason, datetime, open as "open!"
+        FROM (
+            SELECT 'post' as kind, r.id, r.pid, NULL::text as cid, r.uid, r.reason,
+                r.datetime, r.open
+            FROM sub_post_report r
+            JOIN sub_post p ON p.pid = r.pid
+            WHERE p.sid = $1
+            UNION ALL
+            SELECT 'comment' as kind, r.id, c.pid, r.cid, r.uid, r.reason, r.datetime, r.open
+            FROM sub_post_comment_report r
+            JOIN sub_post_comment c ON c.cid = r.cid
+            JOIN sub_post p ON p.pid = c.pid
+            WHERE p.sid = $1
+        ) reports
+        WHERE $2::bool IS NULL OR open = $2
+        ORDER BY datetime DESC NULLS LAST, kind, id DESC
+        LIMIT $3
+        OFFSET $4
+        "#,
+        sub.sid,
+        open,
+        count,
+        offset
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .enumerate()
+    .map(|(i, row)| Edge {
+        node: Report {
+            kind: if row.kind == "post" {
+                ReportKind::Post
+            } else {
+                ReportKind::Comment
+            },
+            id: row.id,
+            pid: row.pid,
+            cid: row.cid,
+            uid: row.uid,
+            reason: row.reason,
+            created: row.datetime,
+            open: row.open,
+        },
+        cursor: (offset + i as i64 + 1).to_string(),
+    })
+    .collect::<Vec<_>>();
+
+    let total_count = sqlx::query!(
+        r#"
+        SELECT (
+            SELECT count(*)
+            FROM sub_post_report r
+            JOIN sub_post p ON p.pid = r.pid
+            WHERE p.sid = $1 AND ($2::bool IS NULL OR r.open = $2)
+        ) + (
+            SELECT count(*)
+            FROM sub_post_comment_report r
+            JOIN sub_post_comment c ON c.cid = r.cid
+            JOIN sub_post p ON p.pid = c.pid
+            WHERE p.sid = $1 AND ($2::bool IS NULL OR r.open = $2)
+        ) as "cnt!"
+        "#,
+        sub.sid,
+        open
+    )
+    .fetch_one(&context.pool)
+    .await?
+    .cnt as i32;
+    let end_cursor = edges
+        .last()
+        .map_or_else(|| "".into(), |edge| edge.cursor.clone());
+
+    Ok(Page {
+        page_info: PageInfo {
+            has_next_page: offset + (edges.len() as i64) < total_count as i64,
+            end_cursor,
+        },
+        edges,
+        total_count,
+    })
+}
@@ -0,0 +1,191 @@
+//! Throat's JSON API (/api/v3) for bots and tools that haven't moved to GraphQL yet. Every
+//! endpoint runs a fixed query through middleware::execute, so auth, caching and logging behave
+//! exactly as they do for /graphql, and reshapes the result into what Throat used to return.
+use crate::{
+    config::Config,
+    middleware::{self, Response},
+    Context, Schema,
+};
+use http::{header, StatusCode};
+use serde_json::{json, Value};
+
+const POSTS_PER_PAGE: i32 = 25;
+
+const POST_FIELDS: &str = r#"
+    id title link content posted edited postType score upVotes downVotes thumbnail
+    commentCount nsfw flair
+    sub { name }
+    author { uid name }
+"#;
+
+fn reply(status: StatusCode, body: Value) -> Response {
+    let mut response = Response::new(body.to_string().into_bytes());
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    reply(status, json!({ "status": "error", "error": message }))
+}
+
+/// Runs the query and hands back its data, or the response to send when it failed
+async fn run(
+    schema: &Schema,
+    context: Context,
+    query: &str,
+    variables: Value,
+) -> Result<Value, Response> {
+    let body = json!({ "query": query, "variables": variables }).to_string();
+    let response = middleware::execute(schema, context, body.as_bytes()).await;
+    let result: Value = serde_json::from_slice(response.body())
+        .map_err(|_| error(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error"))?;
+
+    match result["errors"][0]["message"].as_str() {
+        // Field errors come back with a 200, for a lookup those mean nothing was found
+        Some(message) => Err(error(
+            match response.status() {
+                StatusCode::OK => StatusCode::NOT_FOUND,
+                status => status,
+            },
+            message,
+        )),
+        None => Ok(result["data"].clone()),
+    }
+}
+
+/// Throat's numbering of post types
+fn ptype(post_type: &Value) -> i32 {
+    match post_type.as_str() {
+        Some("LINK") => 1,
+        Some("POLL") => 3,
+        _ => 0,
+    }
+}
+
+/// A GraphQL Post in Throat's field names
+fn legacy_post(config: &Config, post: &Value) -> Value {
+    let pid = post["id"]
+        .as_str()
+        .and_then(|id| config.post_ids.decode(id).ok());
+    json!({
+        "pid": pid,
+        "sub": post["sub"]["name"],
+        "title": post["title"],
+        "link": post["link"],
+        "content": post["content"],
+        "posted": post["posted"],
+        "edited": post["edited"],
+        "ptype": ptype(&post["postType"]),
+        "score": post["score"],
+        "upvotes": post["upVotes"],
+        "downvotes": post["downVotes"],
+        "thumbnail": post["thumbnail"],
+        "comments": post["commentCount"],
+        "nsfw": post["nsfw"],
+        "flair": post["flair"],
+        "uid": post["author"]["uid"],
+        "user": post["author"]["name"],
+    })
+}
+
+/// GET /api/v3/post/{pid}
+pub async fn post(schema: &Schema, context: Context, pid: i32) -> Response {
+    let config = context.config.clone();
+    let query = format!(
+        "query($id: ID!) {{ getPost(id: $id) {{ {} }} }}",
+        POST_FIELDS
+    );
+    let variables = json!({ "id": *context.config.post_ids.encode(pid) });
+    match run(schema, context, &query, variables).await {
+        Ok(data) => reply(
+            StatusCode::OK,
+            json!({ "status": "ok", "post": legacy_post(&config, &data["getPost"]) }),
+        ),
+        Err(response) => response,
+    }
+}
+
+/// GET /api/v3/sub/{name}
+pub async fn sub(schema: &Schema, context: Context, name: String) -> Response {
+    let query = r#"
+        query($name: String!) {
+            getSub(name: $name) { name title sidebar nsfw creation subscribers }
+        }
+    "#;
+    match run(schema, context, query, json!({ "name": name })).await {
+        Ok(data) => {
+            let sub = &data["getSub"];
+            reply(
+                StatusCode::OK,
+                json!({
+                    "status": "ok",
+                    "sub": {
+                        "name": sub["name"],
+                        "title": sub["title"],
+                        "sidebar": sub["sidebar"],
+                        "nsfw": sub["nsfw"],
+                        "creation": sub["creation"],
+                        "subscribers": sub["subscribers"],
+                    },
+                }),
+            )
+        }
+        Err(response) => response,
+    }
+}
+
+/// GET /api/v3/sub/{name}/{sort}?page=N, newest first like Throat's `new`. The schema has no
+/// hot or top order, so those come back newest first too rather than breaking the bots asking
+/// for them.
+pub async fn sub_posts(
+    schema: &Schema,
+    context: Context,
+    name: String,
+    sort: String,
+    page: Option<i32>,
+) -> Response {
+    if !["hot", "new", "top"].contains(&sort.as_str()) {
+        return error(StatusCode::NOT_FOUND, "Unknown sort");
+    }
+    let page = page.unwrap_or(1).max(1);
+    // page comes from the query string, i32 math would overflow for large ones
+    let offset = match (page as i64 - 1).checked_mul(POSTS_PER_PAGE as i64) {
+        Some(offset) => offset,
+        None => return error(StatusCode::BAD_REQUEST, "Invalid page"),
+    };
+    let config = context.config.clone();
+    let query = format!(
+        r#"query($name: String!, $count: Int!, $after: String!) {{
+            getSub(name: $name) {{
+                posts(newestFirst: true, count: $count, after: $after) {{
+                    edges {{ node {{ {} }} }}
+                }}
+            }}
+        }}"#,
+        POST_FIELDS
+    );
+    let variables = json!({
+        "name": name,
+        "count": POSTS_PER_PAGE,
+        "after": offset.to_string(),
+    });
+    match run(schema, context, &query, variables).await {
+        Ok(data) => {
+            let posts: Vec<Value> = data["getSub"]["posts"]["edges"]
+                .as_array()
+                .map(|edges| {
+                    edges
+                        .iter()
+                        .map(|edge| legacy_post(&config, &edge["node"]))
+                        .collect()
+                })
+                .unwrap_or_default();
+            reply(StatusCode::OK, json!({ "status": "ok", "posts": posts }))
+        }
+        Err(response) => response,
+    }
+}
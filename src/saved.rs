@@ -0,0 +1,249 @@
+use crate::{content::Content, parse_offset, Context, Edge, Page, PageInfo};
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLEnum, ID};
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum SavedKind {
+    Posts,
+    Comments,
+    All,
+}
+
+/// Posts are saved in Throat's own user_saved, so both sites show the same list
+pub async fn save_post(context: &Context, id: ID) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let pid = context.config.post_ids.decode(&id)?;
+    let post = sqlx::query!(
+        r#"
+        SELECT pid
+        FROM sub_post
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+    if post.is_none() {
+        return Err(format!("Post not found {}", *id).into());
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO user_saved (uid, pid)
+        SELECT $1, $2
+        WHERE NOT EXISTS (SELECT 1 FROM user_saved WHERE uid = $1 AND pid = $2)
+        "#,
+        uid,
+        pid
+    )
+    .execute(&context.pool)
+    .await?;
+    Ok(true)
+}
+
+pub async fn unsave_post(context: &Context, id: ID) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let pid = context.config.post_ids.decode(&id)?;
+    Ok(sqlx::query!(
+        r#"
+        DELETE FROM user_saved
+        WHERE uid = $1 AND pid = $2
+        RETURNING pid
+        "#,
+        uid,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .is_some())
+}
+
+pub async fn save_comment(context: &Context, id: ID) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let saved = sqlx::query!(
+        r#"
+        INSERT INTO user_saved_comment (uid, cid)
+        SELECT $1, cid FROM sub_post_comment WHERE cid = $2
+        ON CONFLICT (uid, cid) DO UPDATE SET saved = user_saved_comment.saved
+        RETURNING cid
+        "#,
+        uid,
+        id.as_str()
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+    if saved.is_none() {
+        return Err(format!("Comment not found {}", *id).into());
+    }
+    Ok(true)
+}
+
+pub async fn unsave_comment(context: &Context, id: ID) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    Ok(sqlx::query!(
+        r#"
+        DELETE FROM user_saved_comment
+        WHERE uid = $1 AND cid = $2
+        RETURNING cid
+        "#,
+        uid,
+        id.as_str()
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .is_some())
+}
+
+pub async fn post_saved(context: &Context, pid: i32) -> Result<bool, FieldError> {
+    let uid = match context.user.user_id() {
+        Ok(uid) => uid,
+        Err(_) => return Ok(false),
+    };
+    Ok(sqlx::query!(
+        r#"
+        SELECT 1 as "one!"
+        FROM user_saved
+        WHERE uid = $1 AND pid = $2
+        LIMIT 1
+        "#,
+        uid,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .is_some())
+}
+
+pub async fn comment_saved(context: &Context, cid: &str) -> Result<bool, FieldError> {
+    let uid = match context.user.user_id() {
+        Ok(uid) => uid,
+        Err(_) => return Ok(false),
+    };
+    Ok(sqlx::query!(
+        r#"
+        SELECT 1 as "one!"
+        FROM user_saved_comment
+        WHERE uid = $1 AND cid = $2
+        "#,
+        uid,
+        cid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .is_some())
+}
+
+/// The viewer's saved posts and comments, newest content first. Throat doesn't record when posts
+/// were saved, so both go by when they were written.
+pub async fn get_saved(
+    context: &Context,
+    kind: Option<SavedKind>,
+    count: Option<i32>,
+    after: Option<String>,
+) -> Result<Page<Content>, FieldError> {
+    let uid = context.user.user_id()?;
+    let kind = kind.unwrap_or(SavedKind::All);
+    let posts = kind != SavedKind::Comments;
+    let comments = kind != SavedKind::Posts;
+    let count = count.unwrap_or(25);
+    let offset = parse_offset(after)?;
+
+    let rows = sqlx::query!(
+        r#"
+        SELECT kind as "kind!", id as "id!"
+        FROM (
+            SELECT DISTINCT 'post' as kind, p.pid::text as id, p.posted as time
+            FROM user_saved s
+            JOIN sub_post p ON p.pid = s.pid
+            WHERE s.uid = $1 AND $2
+            UNION ALL
+            SELECT 'comment' as kind, c.cid as id, c.time
+            FROM user_saved_comment s
+            JOIN sub_post_comment c ON c.cid = s.cid
+            WHERE s.uid = $1 AND $3
+        ) saved
+        ORDER BY time DESC NULLS LAST, id DESC
+        LIMIT $4
+        OFFSET $5
+        "#,
+        uid,
+        posts,
+        comments,
+        count as i64,
+        offset
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let pids = rows
+        .iter()
+        .filter(|row| row.kind == "post")
+        .filter_map(|row| row.id.parse::<i32>().ok())
+        .collect::<Vec<_>>();
+    let cids = rows
+        .iter()
+        .filter(|row| row.kind == "comment")
+        .map(|row| row.id.clone())
+        .collect::<Vec<_>>();
+
+    let mut loaded_posts = context.post_loader.load_many(pids).await;
+    let mut loaded_comments = context.comment_loader.load_many(cids).await;
+
+    let edges = rows
+        .into_iter()
+        .enumerate()
+        .map(|(i, row)| -> Result<Edge<Content>, FieldError> {
+            let node = if row.kind == "post" {
+                let pid = row.id.parse::<i32>()?;
+                Content::Post(
+                    loaded_posts
+                        .remove(&pid)
+                        .ok_or_else(|| format!("Post not found {}", pid))?
+                        .map_err(|err| format!("{:?}", err))?,
+                )
+            } else {
+                Content::Comment(
+                    loaded_comments
+                        .remove(&row.id)
+                        .ok_or_else(|| format!("Could not find {}", row.id))?
+                        .map_err(|err| format!("{:?}", err))?,
+                )
+            };
+
+            Ok(Edge {
+                node,
+                cursor: (offset + i as i64 + 1).to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let total_count = sqlx::query!(
+        r#"
+        SELECT (SELECT count(DISTINCT pid) FROM user_saved WHERE uid = $1 AND $2)
+            + (SELECT count(*) FROM user_saved_comment WHERE uid = $1 AND $3) as "cnt!"
+        "#,
+        uid,
+        posts,
+        comments
+    )
+    .fetch_one(&context.pool)
+    .await?
+    .cnt as i32;
+
+    let end_cursor = edges
+        .iter()
+        .last()
+        .map_or("".into(), |val| val.cursor.clone());
+
+    Ok(Page {
+        edges,
+        total_count,
+        page_info: PageInfo {
+            has_next_page: offset + (count as i64) < total_count as i64,
+            end_cursor,
+        },
+    })
+}
@@ -0,0 +1,334 @@
+use crate::{
+    comment::Comment,
+    parse_offset,
+    post::Post,
+    sub::Sub,
+    user::{User, UserRef},
+    Context, Cursor, Edge, Page, PageInfo,
+};
+use futures_util::stream::StreamExt;
+use juniper::{graphql_object, FieldError, GraphQLEnum, GraphQLUnion};
+use unicase::UniCase;
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum SearchType {
+    Post,
+    Comment,
+    Sub,
+    User,
+}
+
+impl SearchType {
+    fn kind(self) -> &'static str {
+        match self {
+            SearchType::Post => "post",
+            SearchType::Comment => "comment",
+            SearchType::Sub => "sub",
+            SearchType::User => "user",
+        }
+    }
+}
+
+#[derive(Debug, Clone, GraphQLUnion)]
+#[graphql(context = Context)]
+pub enum SearchResult {
+    Post(Post),
+    Comment(Comment),
+    Sub(Sub),
+    User(User),
+}
+
+#[graphql_object(name = "SearchResultNode", context = Context)]
+impl Edge<SearchResult> {
+    fn node(&self) -> &SearchResult {
+        &self.node
+    }
+
+    fn cursor(&self) -> &Cursor {
+        &self.cursor
+    }
+}
+
+#[graphql_object(name = "SearchResultPage", context = Context)]
+impl Page<SearchResult> {
+    fn edges(&self) -> &Vec<Edge<SearchResult>> {
+        &self.edges
+    }
+
+    fn page_info(&self) -> &PageInfo {
+        &self.page_info
+    }
+
+    fn total_count(&self) -> i32 {
+        self.total_count
+    }
+}
+
+/// Wraps the query for ILIKE, treating any wildcards the user typed literally
+fn like_pattern(query: &str) -> String {
+    format!(
+        "%{}%",
+        query
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    )
+}
+
+/// One search over everything, newest first. `types` defaults to all of them.
+pub async fn search(
+    context: &Context,
+    query: String,
+    types: Option<Vec<SearchType>>,
+    count: Option<i32>,
+    after: Option<String>,
+) -> Result<Page<SearchResult>, FieldError> {
+    let count = count.unwrap_or(25);
+    let offset = parse_offset(after)?;
+    let pattern = like_pattern(query.trim());
+    let kinds = types
+        .unwrap_or_else(|| {
+            vec![
+                SearchType::Post,
+                SearchType::Comment,
+                SearchType::Sub,
+                SearchType::User,
+            ]
+        })
+        .into_iter()
+        .map(|kind| kind.kind().to_string())
+        .collect::<Vec<_>>();
+
+    let rows = sqlx::query!(
+        r#"
+        SELECT kind as "kind!", id as "id!"
+        FROM (
+            SELECT 'post' as kind, pid::text as id, posted as time
+            FROM sub_post
+            WHERE 'post' = ANY($2) AND coalesce(deleted, 0) = 0
+                AND (title ILIKE $1 OR content ILIKE $1)
+            UNION ALL
+            SELECT 'comment' as kind, cid as id, time
+            FROM sub_post_comment
+            WHERE 'comment' = ANY($2) AND coalesce(status, 0) = 0 AND content ILIKE $1
+            UNION ALL
+            SELECT 'sub' as kind, sid as id, creation as time
+            FROM sub
+            WHERE 'sub' = ANY($2) AND (name ILIKE $1 OR title ILIKE $1)
+            UNION ALL
+            SELECT 'user' as kind, uid as id, joindate as time
+            FROM public.user
+            WHERE 'user' = ANY($2) AND status = 0 AND name ILIKE $1
+        ) results
+        ORDER BY time DESC NULLS LAST, id
+        LIMIT $3
+        OFFSET $4
+        "#,
+        pattern,
+        &kinds,
+        count as i64,
+        offset
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let ids = |kind: &str| {
+        rows.iter()
+            .filter(|row| row.kind == kind)
+            .map(|row| row.id.clone())
+            .collect::<Vec<_>>()
+    };
+    let mut posts = context
+        .post_loader
+        .load_many(
+            ids("post")
+                .iter()
+                .filter_map(|id| id.parse().ok())
+                .collect(),
+        )
+        .await;
+    let mut comments = context.comment_loader.load_many(ids("comment")).await;
+    let mut subs = context
+        .sub_loader
+        .load_many(ids("sub").into_iter().map(UniCase::new).collect())
+        .await;
+    let mut users = context
+        .user_loader
+        .load_many(ids("user").into_iter().map(UserRef::Uid).collect())
+        .await;
+
+    let edges = rows
+        .into_iter()
+        .enumerate()
+        .map(|(i, row)| -> Result<Edge<SearchResult>, FieldError> {
+            let not_found = || format!("Could not find {} {}", row.kind, row.id);
+            let node = match row.kind.as_str() {
+                "post" => SearchResult::Post(
+                    posts
+                        .remove(&row.id.parse::<i32>()?)
+                        .ok_or_else(not_found)?
+                        .map_err(|err| format!("{:?}", err))?,
+                ),
+                "comment" => SearchResult::Comment(
+                    comments
+                        .remove(&row.id)
+                        .ok_or_else(not_found)?
+                        .map_err(|err| format!("{:?}", err))?,
+                ),
+                "sub" => SearchResult::Sub(
+                    subs.remove(&UniCase::new(row.id.clone()))
+                        .ok_or_else(not_found)?
+                        .map_err(|err| format!("{:?}", err))?,
+                ),
+                _ => SearchResult::User(
+                    users
+                        .remove(&UserRef::Uid(row.id.clone()))
+                        .ok_or_else(not_found)?
+                        .map_err(|err| format!("{:?}", err))?,
+                ),
+            };
+
+            Ok(Edge {
+                node,
+                cursor: (offset + i as i64 + 1).to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let total_count = sqlx::query!(
+        r#"
+        SELECT (SELECT count(*) FROM sub_post
+                WHERE 'post' = ANY($2) AND coalesce(deleted, 0) = 0
+                    AND (title ILIKE $1 OR content ILIKE $1))
+            + (SELECT count(*) FROM sub_post_comment
+                WHERE 'comment' = ANY($2) AND coalesce(status, 0) = 0 AND content ILIKE $1)
+            + (SELECT count(*) FROM sub
+                WHERE 'sub' = ANY($2) AND (name ILIKE $1 OR title ILIKE $1))
+            + (SELECT count(*) FROM public.user
+                WHERE 'user' = ANY($2) AND status = 0 AND name ILIKE $1) as "cnt!"
+        "#,
+        pattern,
+        &kinds
+    )
+    .fetch_one(&context.pool)
+    .await?
+    .cnt as i32;
+
+    let end_cursor = edges
+        .iter()
+        .last()
+        .map_or("".into(), |val| val.cursor.clone());
+
+    Ok(Page {
+        edges,
+        total_count,
+        page_info: PageInfo {
+            has_next_page: offset + (count as i64) < total_count as i64,
+            end_cursor,
+        },
+    })
+}
+
+const DEFAULT_SIMILARITY: f64 = 0.3;
+
+fn similarity_threshold(threshold: Option<f64>) -> Result<String, FieldError> {
+    let threshold = threshold.unwrap_or(DEFAULT_SIMILARITY);
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err("Similarity threshold must be between 0 and 1".into());
+    }
+    Ok(threshold.to_string())
+}
+
+/// Subs with names close to `query`, best match first. Uses pg_trgm so typos still match.
+pub async fn search_subs(
+    context: &Context,
+    query: String,
+    threshold: Option<f64>,
+    count: Option<i32>,
+) -> Result<Vec<Sub>, FieldError> {
+    let threshold = similarity_threshold(threshold)?;
+    let count = count.unwrap_or(25);
+
+    // The % operator is what the trigram index can answer, its cutoff is a setting
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        "SELECT set_config('pg_trgm.similarity_threshold', $1, true)",
+        threshold
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    let ids = sqlx::query!(
+        r#"
+        SELECT sid
+        FROM sub
+        WHERE lower(name) % lower($1)
+        ORDER BY similarity(lower(name), lower($1)) DESC, name
+        LIMIT $2
+        "#,
+        query,
+        count as i64
+    )
+    .fetch(&mut tx)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| UniCase::new(row.sid))
+    .collect::<Vec<_>>();
+    tx.commit().await?;
+
+    let mut subs = context.sub_loader.load_many(ids.clone()).await;
+    Ok(ids
+        .iter()
+        .filter_map(|id| subs.remove(id).and_then(|sub| sub.ok()))
+        .collect())
+}
+
+/// Users with names close to `query`, best match first
+pub async fn search_users(
+    context: &Context,
+    query: String,
+    threshold: Option<f64>,
+    count: Option<i32>,
+) -> Result<Vec<User>, FieldError> {
+    let threshold = similarity_threshold(threshold)?;
+    let count = count.unwrap_or(25);
+
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        "SELECT set_config('pg_trgm.similarity_threshold', $1, true)",
+        threshold
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    let ids = sqlx::query!(
+        r#"
+        SELECT uid
+        FROM public.user
+        WHERE status = 0 AND lower(name) % lower($1)
+        ORDER BY similarity(lower(name), lower($1)) DESC, name
+        LIMIT $2
+        "#,
+        query,
+        count as i64
+    )
+    .fetch(&mut tx)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| UserRef::Uid(row.uid))
+    .collect::<Vec<_>>();
+    tx.commit().await?;
+
+    let mut users = context.user_loader.load_many(ids.clone()).await;
+    Ok(ids
+        .iter()
+        .filter_map(|id| users.remove(id).and_then(|user| user.ok()))
+        .collect())
+}
@@ -0,0 +1,508 @@
+use crate::{
+    activitypub, attachment, auth, changes, config::Config, digest, errors, events::EventBus,
+    export, ide, mailer, middleware, ndjson, oembed, rest, site, stats, Context, Mutation, Query,
+    RequestInfo, Schema,
+};
+use bytes::Buf;
+use chrono::NaiveDateTime;
+use futures_util::{StreamExt, TryStreamExt};
+use juniper::FieldError;
+use sqlx::postgres::PgPoolOptions;
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+use warp::{
+    http::StatusCode,
+    hyper::Body,
+    multipart::{FormData, Part},
+    Filter, Rejection, Reply,
+};
+
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+pub fn schema() -> Schema {
+    Schema::new(
+        Query,
+        Mutation,
+        juniper::EmptySubscription::<Context>::new(),
+    )
+}
+
+/// Connects to Postgres, retrying with exponential backoff so the server doesn't crash loop
+/// when it comes up before the database. In lazy mode connections are only made once needed.
+pub async fn connect(url: &str, config: &Config) -> Result<sqlx::PgPool, sqlx::Error> {
+    if config.db_connect_lazy {
+        return PgPoolOptions::new().connect_lazy(url);
+    }
+
+    let mut backoff = Duration::from_millis(500);
+    let mut attempt = 1;
+    loop {
+        match sqlx::Pool::connect(url).await {
+            Ok(pool) => return Ok(pool),
+            Err(err) if attempt < config.db_connect_attempts => {
+                log::warn!(
+                    "Could not connect to the database (attempt {} of {}), retrying in {:?} - {}",
+                    attempt,
+                    config.db_connect_attempts,
+                    backoff,
+                    err
+                );
+                tokio::time::delay_for(backoff).await;
+                backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Link target in digest emails, so unsubscribing works straight from the mail client
+async fn unsubscribe_digest(
+    pool: sqlx::PgPool,
+    params: HashMap<String, String>,
+) -> Result<impl Reply, Infallible> {
+    let token = params.get("token").map(String::as_str).unwrap_or_default();
+    Ok(match digest::unsubscribe(&pool, token).await {
+        Ok(_) => warp::reply::with_status("You won't get any more digest emails.", StatusCode::OK),
+        Err(_) => warp::reply::with_status(
+            "This unsubscribe link is invalid or has expired.",
+            StatusCode::BAD_REQUEST,
+        ),
+    })
+}
+
+/// Signed links handed out by SubExport.downloadUrl
+async fn download_export(
+    pool: sqlx::PgPool,
+    config: Arc<Config>,
+    id: String,
+    params: HashMap<String, String>,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let expires = params
+        .get("expires")
+        .and_then(|expires| expires.parse().ok())
+        .unwrap_or_default();
+    let signature = params
+        .get("signature")
+        .map(String::as_str)
+        .unwrap_or_default();
+    Ok(
+        match export::download(&pool, &config, &id, expires, signature).await {
+            Ok(Some((body, content_type))) => Box::new(
+                warp::http::Response::builder()
+                    .header("content-type", content_type)
+                    .header(
+                        "content-disposition",
+                        format!("attachment; filename=\"export-{}\"", id),
+                    )
+                    .body(body),
+            ),
+            Ok(None) => Box::new(warp::reply::with_status(
+                "This download link is invalid or has expired.",
+                StatusCode::NOT_FOUND,
+            )),
+            Err(err) => {
+                log::error!("Could not load export {} - {}", id, err);
+                Box::new(warp::reply::with_status(
+                    "Internal server error",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        },
+    )
+}
+
+/// NDJSON is sent as it's produced, errors from before the first line are JSON like on /graphql
+fn ndjson_response(lines: Result<ndjson::Lines, FieldError>) -> warp::http::Response<Body> {
+    match lines {
+        Ok(lines) => warp::http::Response::builder()
+            .header("content-type", "application/x-ndjson")
+            .body(Body::wrap_stream(lines.map(Ok::<_, Infallible>)))
+            .unwrap_or_default(),
+        Err(err) if err.message() == "Not Authorized" => {
+            middleware::error_response(StatusCode::FORBIDDEN, "NOT_AUTHORIZED", err.message())
+                .map(Body::from)
+        }
+        Err(err) => {
+            middleware::error_response(StatusCode::BAD_REQUEST, "BAD_REQUEST", err.message())
+                .map(Body::from)
+        }
+    }
+}
+
+/// Optional timestamp query parameter like 2020-01-31T12:00:00, in UTC
+fn time_param(
+    params: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<NaiveDateTime>, FieldError> {
+    params
+        .get(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| format!("{} must be a time like 2020-01-31T12:00:00", name).into())
+        })
+        .transpose()
+}
+
+/// GET /stream/exports/{sub}/{kind}?from=&to=, a sub export of any size as NDJSON
+async fn stream_export(
+    context: Context,
+    sub: String,
+    kind: String,
+    params: HashMap<String, String>,
+) -> Result<impl Reply, Infallible> {
+    let kind = match export::ExportKind::parse(&kind) {
+        Some(kind) => kind,
+        None => {
+            return Ok(ndjson_response(Err(
+                format!("Unknown export {}", kind).into()
+            )))
+        }
+    };
+    let lines = match (time_param(&params, "from"), time_param(&params, "to")) {
+        (Ok(from), Ok(to)) => export::stream_sub(&context, sub, kind, from, to).await,
+        (Err(err), _) | (_, Err(err)) => Err(err),
+    };
+    Ok(ndjson_response(lines))
+}
+
+fn change_types(types: &str) -> Result<Vec<changes::ChangeType>, FieldError> {
+    types
+        .split(',')
+        .map(|kind| match kind {
+            "post" => Ok(changes::ChangeType::Post),
+            "comment" => Ok(changes::ChangeType::Comment),
+            kind => Err(format!("Unknown change type {}", kind).into()),
+        })
+        .collect()
+}
+
+/// GET /stream/changes?since=&after=&types=post,comment, getChanges without pages
+async fn stream_changes(
+    context: Context,
+    params: HashMap<String, String>,
+) -> Result<impl Reply, Infallible> {
+    let types = params
+        .get("types")
+        .map(|types| change_types(types))
+        .transpose();
+    let lines = match (time_param(&params, "since"), types) {
+        (Ok(since), Ok(types)) => {
+            changes::stream_changes(&context, since, types, params.get("after").cloned())
+        }
+        (Err(err), _) | (_, Err(err)) => Err(err),
+    };
+    Ok(ndjson_response(lines))
+}
+
+/// Multipart form with the image in a `file` field, answers with the attachment as JSON
+async fn receive_upload(context: Context, form: FormData) -> Result<impl Reply, Infallible> {
+    let fail = |status, message: String| {
+        warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": message })),
+            status,
+        )
+    };
+
+    // Read-only like mutations are, see middleware
+    match site::is_maintenance(&context.pool).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "error": "The site is read-only for maintenance, try again later",
+                    "code": "MAINTENANCE",
+                })),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ))
+        }
+        Err(err) => {
+            log::error!("Could not check maintenance mode - {:?}", err);
+            return Ok(fail(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error".into(),
+            ));
+        }
+    }
+
+    let parts: Vec<Part> = match form.try_collect().await {
+        Ok(parts) => parts,
+        Err(err) => return Ok(fail(StatusCode::BAD_REQUEST, err.to_string())),
+    };
+    let part = match parts.into_iter().find(|part| part.name() == "file") {
+        Some(part) => part,
+        None => return Ok(fail(StatusCode::BAD_REQUEST, "No file field".into())),
+    };
+    let data = match part
+        .stream()
+        .try_fold(Vec::new(), |mut data, chunk| async move {
+            data.extend_from_slice(chunk.bytes());
+            Ok(data)
+        })
+        .await
+    {
+        Ok(data) => data,
+        Err(err) => return Ok(fail(StatusCode::BAD_REQUEST, err.to_string())),
+    };
+
+    Ok(match attachment::upload(&context, &data).await {
+        Ok(attachment) => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "id": attachment.id.to_string(),
+                "url": attachment.url,
+                "contentType": attachment.content_type,
+                "size": attachment.size,
+            })),
+            StatusCode::OK,
+        ),
+        Err(err) => fail(StatusCode::BAD_REQUEST, err.message().to_string()),
+    })
+}
+
+/// Ids from clients end up in logs, so only take ones that can't mess them up
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// 200 once the database answers, 503 while it doesn't. The pool reconnects by itself, so this
+/// recovers without a restart once Postgres is back.
+async fn ready(pool: sqlx::PgPool) -> Result<impl Reply, Infallible> {
+    Ok(match sqlx::query("SELECT 1").execute(&pool).await {
+        Ok(_) => warp::reply::with_status("ready", StatusCode::OK),
+        Err(err) => {
+            log::warn!("Readiness check failed - {}", err);
+            warp::reply::with_status("database unavailable", StatusCode::SERVICE_UNAVAILABLE)
+        }
+    })
+}
+
+/// The warp side of the HTTP layer, other frameworks can call middleware::execute and
+/// middleware::execute_get the same way.
+///
+/// Everything the API serves: /graphql (POST and GET), the IDE, /ready, /upload, /digest/unsubscribe, /exports,
+/// the NDJSON /stream routes, /oembed, the ActivityPub actors and Throat's /api/v3, with CORS
+/// applied.
+/// Mount it next to your own routes to embed the API in another warp application.
+/// `events` is shared with whatever workers consume them, see events::from_config.
+pub fn make_routes(
+    config: Arc<Config>,
+    pool: sqlx::PgPool,
+    events: Arc<dyn EventBus>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let mailer = mailer::from_config(&config);
+    stats::mark_started();
+    tokio::spawn(activitypub::forget_outboxes(events.clone()));
+
+    let trust_proxy = config.trust_proxy;
+    let request = warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::header::optional::<String>("x-request-id"))
+        .and(warp::header::optional::<String>("accept-language"))
+        .map(
+            move |addr: Option<SocketAddr>,
+                  forwarded: Option<String>,
+                  user_agent,
+                  request_id: Option<String>,
+                  accept_language: Option<String>| RequestInfo {
+                id: request_id
+                    .filter(|id| valid_request_id(id))
+                    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+                ip: forwarded
+                    .filter(|_| trust_proxy)
+                    .and_then(|forwarded| {
+                        forwarded.split(',').next().map(|ip| ip.trim().to_string())
+                    })
+                    .or_else(|| addr.map(|addr| addr.ip().to_string())),
+                user_agent,
+                languages: accept_language
+                    .map(|header| errors::parse_accept_language(&header))
+                    .unwrap_or_default(),
+            },
+        );
+
+    let ide = ide::routes(&config, "/graphql");
+
+    let unsubscribe_pool = pool.clone();
+    let unsubscribe = warp::get()
+        .and(warp::path!("digest" / "unsubscribe"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |params: HashMap<String, String>| {
+            unsubscribe_digest(unsubscribe_pool.clone(), params)
+        });
+
+    let export_pool = pool.clone();
+    let export_config = config.clone();
+    let exports = warp::get()
+        .and(warp::path!("exports" / String))
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |id: String, params: HashMap<String, String>| {
+            download_export(export_pool.clone(), export_config.clone(), id, params)
+        });
+
+    let ready_pool = pool.clone();
+    let readiness = warp::get()
+        .and(warp::path("ready"))
+        .and(warp::path::end())
+        .and_then(move || ready(ready_pool.clone()));
+
+    let auth_pool = pool.clone();
+    let impersonate_pool = pool.clone();
+    let user = warp::any()
+        .and(
+            warp::header::<String>("authorization")
+                .and(warp::any().map(move || auth_pool.clone()))
+                .map(auth::UserState::login)
+                .or(warp::any().map(auth::UserState::anonymous))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("x-impersonate-user"))
+        .and(warp::any().map(move || impersonate_pool.clone()))
+        .map(auth::UserState::impersonate);
+    let state = warp::any().and(user).and(request).map(
+        move |user: auth::UserState, request: RequestInfo| -> Context {
+            Context::new(
+                user,
+                request,
+                pool.clone(),
+                mailer.clone(),
+                events.clone(),
+                config.clone(),
+            )
+        },
+    );
+    let schema = Arc::new(schema());
+
+    let rest_schema = schema.clone();
+    let legacy_post = warp::get()
+        .and(warp::path!("api" / "v3" / "post" / i32))
+        .and(state.clone())
+        .and_then(move |pid, context| {
+            let schema = rest_schema.clone();
+            async move { Ok::<_, Infallible>(rest::post(&schema, context, pid).await) }
+        });
+    let rest_schema = schema.clone();
+    let legacy_sub = warp::get()
+        .and(warp::path!("api" / "v3" / "sub" / String))
+        .and(state.clone())
+        .and_then(move |name, context| {
+            let schema = rest_schema.clone();
+            async move { Ok::<_, Infallible>(rest::sub(&schema, context, name).await) }
+        });
+    let rest_schema = schema.clone();
+    let legacy_sub_posts = warp::get()
+        .and(warp::path!("api" / "v3" / "sub" / String / String))
+        .and(state.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(
+            move |name, sort, context, params: HashMap<String, String>| {
+                let schema = rest_schema.clone();
+                let page = params.get("page").and_then(|page| page.parse().ok());
+                async move {
+                    Ok::<_, Infallible>(rest::sub_posts(&schema, context, name, sort, page).await)
+                }
+            },
+        );
+
+    let embed = warp::get()
+        .and(warp::path!("oembed"))
+        .and(state.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|context, params: HashMap<String, String>| async move {
+            let url = params.get("url").map(String::as_str).unwrap_or_default();
+            let format = params.get("format").map(String::as_str);
+            Ok::<_, Infallible>(oembed::oembed(context, url, format).await)
+        });
+
+    let federation = warp::get()
+        .and(warp::path!(".well-known" / "webfinger"))
+        .and(state.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|context, params: HashMap<String, String>| async move {
+            let resource = params
+                .get("resource")
+                .map(String::as_str)
+                .unwrap_or_default();
+            Ok::<_, Infallible>(activitypub::webfinger(context, resource).await)
+        })
+        .or(warp::get()
+            .and(warp::path!("ap" / "s" / String))
+            .and(state.clone())
+            .and_then(|name, context| async move {
+                Ok::<_, Infallible>(activitypub::actor(context, name).await)
+            }))
+        .or(warp::get()
+            .and(warp::path!("ap" / "s" / String / "outbox"))
+            .and(state.clone())
+            .and_then(|name, context| async move {
+                Ok::<_, Infallible>(activitypub::outbox(context, name).await)
+            }))
+        .or(warp::post()
+            .and(warp::path!("ap" / "s" / String / "inbox"))
+            .map(|_name: String| activitypub::inbox()));
+
+    let max_upload = config
+        .uploads
+        .as_ref()
+        .map_or(0, |uploads| uploads.max_size) as u64;
+    let upload = warp::post()
+        .and(warp::path!("upload"))
+        .and(state.clone())
+        .and(warp::multipart::form().max_length(max_upload + 64 * 1024))
+        .and_then(receive_upload);
+
+    let streams = warp::get()
+        .and(warp::path!("stream" / "exports" / String / String))
+        .and(state.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|sub, kind, context, params| stream_export(context, sub, kind, params))
+        .or(warp::get()
+            .and(warp::path!("stream" / "changes"))
+            .and(state.clone())
+            .and(warp::query::<HashMap<String, String>>())
+            .and_then(stream_changes));
+
+    let post_schema = schema.clone();
+    let graphql_filter = warp::post()
+        .and(state.clone())
+        .and(warp::body::bytes())
+        .and_then(move |context, body| {
+            let schema = post_schema.clone();
+            async move { Ok::<_, Infallible>(middleware::execute(&schema, context, &body).await) }
+        })
+        .or(warp::get()
+            .and(state)
+            .and(warp::query::<HashMap<String, String>>())
+            .and_then(move |context, params| {
+                let schema = schema.clone();
+                async move {
+                    Ok::<_, Infallible>(middleware::execute_get(&schema, context, params).await)
+                }
+            }));
+
+    readiness
+        .or(unsubscribe)
+        .or(exports)
+        .or(ide)
+        .or(warp::path("graphql").and(graphql_filter))
+        .or(legacy_post)
+        .or(legacy_sub)
+        .or(legacy_sub_posts)
+        .or(embed)
+        .or(federation)
+        .or(upload)
+        .or(streams)
+        .with(
+            warp::cors()
+                .allow_method("POST")
+                .allow_header("authorization")
+                .allow_header("x-impersonate-user")
+                .allow_header("x-request-id")
+                .allow_headers(vec!["content-type"])
+                .allow_any_origin(),
+        )
+}
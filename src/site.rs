@@ -0,0 +1,427 @@
+use crate::{sub::Sub, Context};
+use futures_util::stream::StreamExt;
+use juniper::{graphql_object, FieldError, GraphQLEnum, GraphQLInputObject};
+use lazy_static::lazy_static;
+use std::sync::RwLock;
+use unicase::UniCase;
+
+const DEFAULT_MAX_TITLE_LENGTH: i32 = 350;
+const DEFAULT_MAX_CONTENT_LENGTH: i32 = 65535;
+/// Longest an account counts as new for vote weighting, ten years
+const MAX_NEW_ACCOUNT_VOTE_DAYS: i32 = 3650;
+
+lazy_static! {
+    // Every anonymous home feed request needs these, so keep them around until an admin changes them
+    static ref DEFAULT_SUBS: RwLock<Option<Vec<String>>> = RwLock::new(None);
+}
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum RegistrationMode {
+    Open,
+    InviteOnly,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum NsfwPolicy {
+    Allow,
+    Blur,
+    Hide,
+}
+
+impl RegistrationMode {
+    fn from_db(value: &str) -> Option<Self> {
+        match value {
+            "open" => Some(RegistrationMode::Open),
+            "invite" => Some(RegistrationMode::InviteOnly),
+            "closed" => Some(RegistrationMode::Closed),
+            _ => None,
+        }
+    }
+
+    fn to_db(self) -> &'static str {
+        match self {
+            RegistrationMode::Open => "open",
+            RegistrationMode::InviteOnly => "invite",
+            RegistrationMode::Closed => "closed",
+        }
+    }
+}
+
+impl NsfwPolicy {
+    fn from_db(value: &str) -> Option<Self> {
+        match value {
+            "allow" => Some(NsfwPolicy::Allow),
+            "blur" => Some(NsfwPolicy::Blur),
+            "hide" => Some(NsfwPolicy::Hide),
+            _ => None,
+        }
+    }
+
+    fn to_db(self) -> &'static str {
+        match self {
+            NsfwPolicy::Allow => "allow",
+            NsfwPolicy::Blur => "blur",
+            NsfwPolicy::Hide => "hide",
+        }
+    }
+}
+
+/// Site wide settings, stored as key/value rows in site_metadata
+#[derive(Debug, Clone)]
+pub struct SiteConfig {
+    default_subs: Vec<String>,
+    registration_mode: RegistrationMode,
+    nsfw_policy: NsfwPolicy,
+    max_title_length: i32,
+    max_content_length: i32,
+    maintenance: bool,
+    pub(crate) new_account_vote_days: i32,
+    pub(crate) new_account_vote_weight: f64,
+}
+
+#[graphql_object(context = Context)]
+impl SiteConfig {
+    /// Subs shown on the home feed to anonymous users
+    async fn default_subs(&self, context: &Context) -> Vec<Sub> {
+        context
+            .sub_loader
+            .load_many(
+                self.default_subs
+                    .iter()
+                    .cloned()
+                    .map(UniCase::new)
+                    .collect(),
+            )
+            .await
+            .values()
+            .filter_map(|sub| sub.clone().ok())
+            .collect()
+    }
+
+    fn registration_mode(&self, _context: &Context) -> RegistrationMode {
+        self.registration_mode
+    }
+
+    fn nsfw_policy(&self, _context: &Context) -> NsfwPolicy {
+        self.nsfw_policy
+    }
+
+    fn max_title_length(&self, _context: &Context) -> i32 {
+        self.max_title_length
+    }
+
+    fn max_content_length(&self, _context: &Context) -> i32 {
+        self.max_content_length
+    }
+
+    /// While set every mutation is rejected with a MAINTENANCE error
+    fn maintenance(&self, _context: &Context) -> bool {
+        self.maintenance
+    }
+
+    /// Accounts younger than this many days vote with newAccountVoteWeight, 0 turns it off. At
+    /// most 3650.
+    fn new_account_vote_days(&self, _context: &Context) -> i32 {
+        self.new_account_vote_days
+    }
+
+    /// How much a new account's vote counts towards rankings, between 0 and 1. Scores shown on
+    /// posts still count every vote in full.
+    fn new_account_vote_weight(&self, _context: &Context) -> f64 {
+        self.new_account_vote_weight
+    }
+}
+
+#[derive(Debug, GraphQLInputObject)]
+pub struct SiteConfigInput {
+    /// Sub names, replaces the whole list
+    pub default_subs: Option<Vec<String>>,
+    pub registration_mode: Option<RegistrationMode>,
+    pub nsfw_policy: Option<NsfwPolicy>,
+    pub max_title_length: Option<i32>,
+    pub max_content_length: Option<i32>,
+    /// 0 turns vote weighting off
+    pub new_account_vote_days: Option<i32>,
+    /// Between 0 and 1
+    pub new_account_vote_weight: Option<f64>,
+}
+
+pub async fn get_site_config(context: &Context) -> Result<SiteConfig, FieldError> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT key, value
+        FROM site_metadata
+        WHERE key IN ('default', 'registration_mode', 'nsfw_policy',
+                      'max_title_length', 'max_content_length', 'maintenance',
+                      'new_account_vote_days', 'new_account_vote_weight')
+        "#
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let mut config = SiteConfig {
+        default_subs: vec![],
+        registration_mode: RegistrationMode::Open,
+        nsfw_policy: NsfwPolicy::Allow,
+        max_title_length: DEFAULT_MAX_TITLE_LENGTH,
+        max_content_length: DEFAULT_MAX_CONTENT_LENGTH,
+        maintenance: false,
+        new_account_vote_days: 0,
+        new_account_vote_weight: 1.0,
+    };
+
+    for row in rows {
+        let value = match row.value {
+            Some(value) => value,
+            None => continue,
+        };
+        match row.key.as_deref() {
+            Some("default") => config.default_subs.push(value),
+            Some("registration_mode") => {
+                config.registration_mode =
+                    RegistrationMode::from_db(&value).unwrap_or(config.registration_mode)
+            }
+            Some("nsfw_policy") => {
+                config.nsfw_policy = NsfwPolicy::from_db(&value).unwrap_or(config.nsfw_policy)
+            }
+            Some("max_title_length") => {
+                config.max_title_length = value.parse().unwrap_or(config.max_title_length)
+            }
+            Some("max_content_length") => {
+                config.max_content_length = value.parse().unwrap_or(config.max_content_length)
+            }
+            Some("maintenance") => config.maintenance = value == "1",
+            Some("new_account_vote_days") => {
+                config.new_account_vote_days = value.parse().unwrap_or(0)
+            }
+            Some("new_account_vote_weight") => {
+                config.new_account_vote_weight = value.parse().unwrap_or(1.0)
+            }
+            _ => {}
+        }
+    }
+
+    Ok(config)
+}
+
+pub async fn update_site_config(
+    context: &Context,
+    input: SiteConfigInput,
+) -> Result<SiteConfig, FieldError> {
+    context.user.require_admin()?;
+
+    let mut values: Vec<(&str, Vec<String>)> = vec![];
+
+    if let Some(names) = input.default_subs {
+        let mut sids = vec![];
+        for name in names {
+            let sub = context
+                .sub_loader
+                .load(name.into())
+                .await
+                .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+            sids.push(sub.sid.clone());
+        }
+        values.push(("default", sids));
+    }
+    if let Some(mode) = input.registration_mode {
+        values.push(("registration_mode", vec![mode.to_db().into()]));
+    }
+    if let Some(policy) = input.nsfw_policy {
+        values.push(("nsfw_policy", vec![policy.to_db().into()]));
+    }
+    for (key, length) in &[
+        ("max_title_length", input.max_title_length),
+        ("max_content_length", input.max_content_length),
+    ] {
+        if let Some(length) = length {
+            if *length <= 0 {
+                return Err(format!("{} must be positive", key).into());
+            }
+            values.push((*key, vec![length.to_string()]));
+        }
+    }
+    if let Some(days) = input.new_account_vote_days {
+        if days < 0 {
+            return Err("new_account_vote_days can't be negative".into());
+        }
+        if days > MAX_NEW_ACCOUNT_VOTE_DAYS {
+            return Err(format!(
+                "new_account_vote_days can be at most {}",
+                MAX_NEW_ACCOUNT_VOTE_DAYS
+            )
+            .into());
+        }
+        values.push(("new_account_vote_days", vec![days.to_string()]));
+    }
+    if let Some(weight) = input.new_account_vote_weight {
+        if !(0.0..=1.0).contains(&weight) {
+            return Err("new_account_vote_weight must be between 0 and 1".into());
+        }
+        values.push(("new_account_vote_weight", vec![weight.to_string()]));
+    }
+
+    let mut tx = context.pool.begin().await?;
+    for (key, rows) in values {
+        sqlx::query!(
+            r#"
+            DELETE FROM site_metadata
+            WHERE key = $1
+            "#,
+            key
+        )
+        .execute(&mut tx)
+        .await?;
+
+        for value in rows {
+            sqlx::query!(
+                r#"
+                INSERT INTO site_metadata (key, value)
+                VALUES ($1, $2)
+                "#,
+                key,
+                value
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+    }
+    tx.commit().await?;
+    invalidate_default_subs();
+
+    get_site_config(context).await
+}
+
+fn invalidate_default_subs() {
+    *DEFAULT_SUBS.write().unwrap() = None;
+}
+
+/// sids of the subs making up the anonymous home feed
+pub async fn default_sub_ids(pool: &sqlx::PgPool) -> Result<Vec<String>, FieldError> {
+    let cached = DEFAULT_SUBS.read().unwrap().clone();
+    if let Some(ids) = cached {
+        return Ok(ids);
+    }
+
+    let ids = sqlx::query!(
+        r#"
+        SELECT value
+        FROM site_metadata
+        WHERE key = 'default'
+        "#
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .filter_map(|metadata| metadata.value)
+    .collect::<Vec<_>>();
+
+    *DEFAULT_SUBS.write().unwrap() = Some(ids.clone());
+    Ok(ids)
+}
+
+pub async fn get_default_subs(context: &Context) -> Result<Vec<Sub>, FieldError> {
+    let ids = default_sub_ids(&context.pool).await?;
+    Ok(context
+        .sub_loader
+        .load_many(ids.into_iter().map(UniCase::new).collect())
+        .await
+        .values()
+        .filter_map(|sub| sub.clone().ok())
+        .collect())
+}
+
+pub async fn add_default_sub(context: &Context, name: String) -> Result<Vec<Sub>, FieldError> {
+    context.user.require_admin()?;
+    let sub = context
+        .sub_loader
+        .load(name.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO site_metadata (key, value)
+        SELECT 'default', $1
+        WHERE NOT EXISTS (
+            SELECT 1 FROM site_metadata WHERE key = 'default' AND value = $1
+        )
+        "#,
+        sub.sid
+    )
+    .execute(&context.pool)
+    .await?;
+    invalidate_default_subs();
+
+    get_default_subs(context).await
+}
+
+pub async fn remove_default_sub(context: &Context, name: String) -> Result<Vec<Sub>, FieldError> {
+    context.user.require_admin()?;
+    let sub = context
+        .sub_loader
+        .load(name.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM site_metadata
+        WHERE key = 'default' AND value = $1
+        "#,
+        sub.sid
+    )
+    .execute(&context.pool)
+    .await?;
+    invalidate_default_subs();
+
+    get_default_subs(context).await
+}
+
+pub async fn is_maintenance(pool: &sqlx::PgPool) -> Result<bool, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM site_metadata
+        WHERE key = 'maintenance' AND value = '1'
+        "#
+    )
+    .fetch_one(pool)
+    .await?
+    .cnt > 0)
+}
+
+pub async fn set_maintenance_mode(context: &Context, enabled: bool) -> Result<bool, FieldError> {
+    context.user.require_admin()?;
+
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM site_metadata
+        WHERE key = 'maintenance'
+        "#
+    )
+    .execute(&mut tx)
+    .await?;
+    if enabled {
+        sqlx::query!(
+            r#"
+            INSERT INTO site_metadata (key, value)
+            VALUES ('maintenance', '1')
+            "#
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    log::warn!("Maintenance mode set to {} by {:?}", enabled, context.user);
+    Ok(enabled)
+}
@@ -0,0 +1,127 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    cell::RefCell,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+tokio::task_local! {
+    static STATEMENTS: RefCell<Vec<Statement>>;
+    static REQUEST_ID: String;
+}
+
+// Totals since startup, divide one by the other for the average statements per request
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+static STATEMENTS_RUN: AtomicU64 = AtomicU64::new(0);
+
+/// Requests recorded and statements they ran since the process started
+pub fn totals() -> (u64, u64) {
+    (
+        REQUESTS.load(Ordering::Relaxed),
+        STATEMENTS_RUN.load(Ordering::Relaxed),
+    )
+}
+
+/// A SQL statement sqlx ran on behalf of the current request
+#[derive(Debug, Clone)]
+pub struct Statement {
+    /// sqlx's one line summary of the statement
+    pub summary: String,
+    pub elapsed: Duration,
+}
+
+/// Wraps the real logger to pick sqlx's statement log lines out and attribute them to the request
+/// being recorded on the task that ran them. Everything is still passed on to the inner logger,
+/// prefixed with the request id when logged while handling a request.
+pub struct StatementLogger {
+    inner: env_logger::Logger,
+}
+
+impl StatementLogger {
+    pub fn init(inner: env_logger::Logger) -> Result<(), log::SetLoggerError> {
+        // sqlx only logs statements when its target is enabled at info
+        log::set_max_level(inner.filter().max(LevelFilter::Info));
+        log::set_boxed_logger(Box::new(StatementLogger { inner }))
+    }
+}
+
+fn is_statement(target: &str) -> bool {
+    target.starts_with("sqlx::query")
+}
+
+impl Log for StatementLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        (is_statement(metadata.target()) && metadata.level() <= Level::Info)
+            || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if is_statement(record.target()) {
+            let message = record.args().to_string();
+            let _ = STATEMENTS.try_with(|statements| {
+                statements.borrow_mut().push(Statement {
+                    summary: message.lines().next().unwrap_or_default().to_string(),
+                    elapsed: parse_elapsed(&message).unwrap_or_default(),
+                })
+            });
+        }
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        match REQUEST_ID.try_with(|id| id.clone()) {
+            Ok(id) => self.inner.log(
+                &Record::builder()
+                    .args(format_args!("[{}] {}", id, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            Err(_) => self.inner.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// sqlx writes the time taken as `elapsed: ` followed by a Debug formatted Duration
+fn parse_elapsed(message: &str) -> Option<Duration> {
+    let value = message
+        .split("elapsed: ")
+        .nth(1)?
+        .split_whitespace()
+        .next()?;
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let nanos = match unit.trim_end_matches(',') {
+        "ns" => number,
+        "µs" | "us" => number * 1e3,
+        "ms" => number * 1e6,
+        "s" => number * 1e9,
+        _ => return None,
+    };
+    Some(Duration::from_nanos(nanos as u64))
+}
+
+/// Runs `future`, collecting every statement it makes sqlx run on this task
+pub async fn record<F: Future>(future: F) -> (F::Output, Vec<Statement>) {
+    STATEMENTS
+        .scope(RefCell::new(vec![]), async move {
+            let output = future.await;
+            let statements = STATEMENTS.with(|statements| statements.replace(vec![]));
+            REQUESTS.fetch_add(1, Ordering::Relaxed);
+            STATEMENTS_RUN.fetch_add(statements.len() as u64, Ordering::Relaxed);
+            (output, statements)
+        })
+        .await
+}
+
+/// Runs `future` with every line it logs tagged with `id`
+pub async fn with_request_id<F: Future>(id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(id, future).await
+}
@@ -0,0 +1,124 @@
+use crate::Context;
+use juniper::{FieldError, GraphQLObject};
+use lazy_static::lazy_static;
+use std::{collections::HashMap, convert::TryFrom, sync::Mutex, time::Instant};
+
+lazy_static! {
+    static ref STARTED: Instant = Instant::now();
+    // Batches dispatched and keys loaded by each loader, plus the largest batch seen
+    static ref LOADERS: Mutex<HashMap<&'static str, (u64, u64, u64)>> =
+        Mutex::new(HashMap::new());
+    // Hits and misses of each in-process cache
+    static ref CACHES: Mutex<HashMap<&'static str, (u64, u64)>> = Mutex::new(HashMap::new());
+}
+
+/// Uptime counts from here, call it once the server starts
+pub fn mark_started() {
+    lazy_static::initialize(&STARTED);
+}
+
+/// Called by every loader's BatchFn with the number of keys it was handed
+pub fn record_batch(loader: &'static str, keys: usize) {
+    let mut loaders = LOADERS.lock().unwrap();
+    let entry = loaders.entry(loader).or_insert((0, 0, 0));
+    entry.0 += 1;
+    entry.1 += keys as u64;
+    entry.2 = entry.2.max(keys as u64);
+}
+
+pub fn record_cache(cache: &'static str, hit: bool) {
+    let mut caches = CACHES.lock().unwrap();
+    let entry = caches.entry(cache).or_insert((0, 0));
+    if hit {
+        entry.0 += 1;
+    } else {
+        entry.1 += 1;
+    }
+}
+
+#[derive(GraphQLObject, Debug)]
+pub struct LoaderStats {
+    pub name: String,
+    pub batches: i32,
+    pub keys: i32,
+    pub average_batch_size: f64,
+    pub largest_batch: i32,
+}
+
+#[derive(GraphQLObject, Debug)]
+pub struct CacheStats {
+    pub name: String,
+    pub hits: i32,
+    pub misses: i32,
+    /// Null until the cache was asked at least once
+    pub hit_ratio: Option<f64>,
+}
+
+#[derive(GraphQLObject, Debug)]
+pub struct PoolStats {
+    /// Open connections, idle or not
+    pub size: i32,
+    pub idle: i32,
+}
+
+/// Counters since this process started. Every instance keeps its own.
+#[derive(GraphQLObject, Debug)]
+pub struct RuntimeStats {
+    pub uptime_seconds: f64,
+    pub loaders: Vec<LoaderStats>,
+    pub caches: Vec<CacheStats>,
+    pub pool: PoolStats,
+}
+
+fn int(value: u64) -> i32 {
+    i32::try_from(value).unwrap_or(i32::MAX)
+}
+
+pub fn runtime_stats(context: &Context) -> Result<RuntimeStats, FieldError> {
+    context.user.require_admin()?;
+
+    let mut loaders: Vec<LoaderStats> = LOADERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, &(batches, keys, largest))| LoaderStats {
+            name: name.to_string(),
+            batches: int(batches),
+            keys: int(keys),
+            average_batch_size: if batches > 0 {
+                keys as f64 / batches as f64
+            } else {
+                0.0
+            },
+            largest_batch: int(largest),
+        })
+        .collect();
+    loaders.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut caches: Vec<CacheStats> = CACHES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, &(hits, misses))| CacheStats {
+            name: name.to_string(),
+            hits: int(hits),
+            misses: int(misses),
+            hit_ratio: if hits + misses > 0 {
+                Some(hits as f64 / (hits + misses) as f64)
+            } else {
+                None
+            },
+        })
+        .collect();
+    caches.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(RuntimeStats {
+        uptime_seconds: STARTED.elapsed().as_secs_f64(),
+        loaders,
+        caches,
+        pool: PoolStats {
+            size: context.pool.size() as i32,
+            idle: context.pool.num_idle() as i32,
+        },
+    })
+}
@@ -1,21 +1,35 @@
-use crate::post::{self, Post};
-use crate::{user::User, Context, Cursor, Edge, Page, PageInfo};
+use crate::post::{self, Post, PostType};
+use crate::{
+    attachment, comment,
+    events::Event,
+    growth::{self, HistoryInterval, SubscriberCount},
+    membership::{self, JoinRequest, PostingRequirements},
+    parse_offset,
+    reaction::{self, SubEmoji},
+    repo::{Memory, SubRepo},
+    stats,
+    top::{self, TopRange},
+    user::{User, UserRef},
+    word_filter::{self, WordFilter},
+    Context, Cursor, Edge, Page, PageInfo,
+};
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
 use dataloader::BatchFn;
 use futures_util::stream::StreamExt;
-use juniper::{graphql_object, FieldError, FieldResult};
+use juniper::{graphql_object, FieldError, FieldResult, GraphQLObject};
 use std::{collections::HashMap, sync::Arc};
 use unicase::UniCase;
 
 #[derive(Debug, Clone)]
 pub struct Sub {
-    sid: String,
+    pub sid: String,
     pub name: Option<String>,
     pub nsfw: bool,
     pub sidebar: String,
     pub title: Option<String>,
     pub creation: NaiveDateTime,
+    pub previous_names: Vec<String>,
 }
 
 #[graphql_object(context = Context)]
@@ -35,13 +49,28 @@ impl Sub {
         .cnt as i32)
     }
 
+    /// Oldest first, newestFirst turns that around
     async fn posts(
         &self,
         context: &Context,
+        types: Option<Vec<PostType>>,
+        language: Option<String>,
+        hide_seen: Option<bool>,
+        newest_first: Option<bool>,
         count: Option<i32>,
         after: Option<String>,
     ) -> Result<Page<Post>, FieldError> {
-        post::get_related_posts(context, vec![self.sid.clone()], count, after).await
+        post::get_related_posts(
+            context,
+            vec![self.sid.clone()],
+            types,
+            language,
+            hide_seen,
+            newest_first.unwrap_or(false),
+            count,
+            after,
+        )
+        .await
     }
 
     fn name(&self, _context: &Context) -> &Option<String> {
@@ -74,7 +103,7 @@ impl Sub {
         .await
         .into_iter()
         .filter_map(|m| match m {
-            Ok(m) => Some(UniCase::new(m.uid)),
+            Ok(m) => Some(UserRef::Uid(m.uid)),
             _ => None,
         })
         .collect::<Vec<_>>();
@@ -91,6 +120,92 @@ impl Sub {
     fn creation(&self, _context: &Context) -> &NaiveDateTime {
         &self.creation
     }
+
+    /// Who is subscribed, oldest subscription first. Only for the owner and admins.
+    async fn subscriber_list(
+        &self,
+        context: &Context,
+        count: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Page<Subscriber>, FieldError> {
+        get_subscribers(context, &self.sid, count, after).await
+    }
+
+    /// Highest scoring posts over the range, at most 100. Rankings are cached for a while, so
+    /// this stays cheap for best-of pages on big subs.
+    async fn top_posts(
+        &self,
+        context: &Context,
+        range: TopRange,
+        types: Option<Vec<PostType>>,
+        limit: Option<i32>,
+    ) -> Result<Vec<Post>, FieldError> {
+        top::top_posts(context, &self.sid, range, types, limit).await
+    }
+
+    /// Subscribers over the range in buckets of `interval`, for charting growth. Only for the
+    /// sub's mods.
+    async fn subscriber_history(
+        &self,
+        context: &Context,
+        range: TopRange,
+        interval: HistoryInterval,
+    ) -> Result<Vec<SubscriberCount>, FieldError> {
+        growth::subscriber_history(context, &self.sid, self.creation, range, interval).await
+    }
+
+    /// Only approved members can join, see requestToJoin
+    async fn restricted(&self, context: &Context) -> Result<bool, FieldError> {
+        membership::is_restricted(&context.pool, &self.sid).await
+    }
+
+    /// What createPost accepts here, see setAllowedPostTypes
+    async fn allowed_post_types(&self, context: &Context) -> Result<Vec<PostType>, FieldError> {
+        post::allowed_post_types(&context.pool, &self.sid).await
+    }
+
+    /// Account age and score needed to post or comment here, see setPostingRequirements
+    async fn posting_requirements(
+        &self,
+        context: &Context,
+    ) -> Result<PostingRequirements, FieldError> {
+        membership::posting_requirements(&context.pool, &self.sid).await
+    }
+
+    /// Deepest replies may nest here, null for no limit
+    async fn max_reply_depth(&self, context: &Context) -> Result<Option<i32>, FieldError> {
+        comment::max_reply_depth(context, &self.sid).await
+    }
+
+    /// Whether comments may carry images, see attachToComment
+    async fn comment_images(&self, context: &Context) -> Result<bool, FieldError> {
+        attachment::comment_images_allowed(&context.pool, &self.sid).await
+    }
+
+    /// Whether comments can get reactions, see reactToComment
+    async fn comment_reactions(&self, context: &Context) -> Result<bool, FieldError> {
+        reaction::reactions_allowed(&context.pool, &self.sid).await
+    }
+
+    /// Custom emoji for reactions besides the standard ones, see addSubEmoji
+    async fn emoji(&self, context: &Context) -> Result<Vec<SubEmoji>, FieldError> {
+        reaction::sub_emoji(&context.pool, &self.sid).await
+    }
+
+    /// Checked on every edit, see addWordFilter. Only for the sub's mods.
+    async fn word_filters(&self, context: &Context) -> Result<Vec<WordFilter>, FieldError> {
+        word_filter::sub_filters(context, &self.sid).await
+    }
+
+    /// Requests to join waiting for a decision, oldest first. Only for the sub's mods.
+    async fn pending_members(&self, context: &Context) -> Result<Vec<JoinRequest>, FieldError> {
+        membership::pending_members(context, &self.sid).await
+    }
+
+    /// Older names of the sub, oldest first. Lookups by any of them still find this sub.
+    fn previous_names(&self, _context: &Context) -> &Vec<String> {
+        &self.previous_names
+    }
 }
 
 #[graphql_object(name = "PostNode", context = Context)]
@@ -119,6 +234,39 @@ impl Page<Post> {
     }
 }
 
+#[derive(GraphQLObject, Debug)]
+pub struct Subscriber {
+    pub name: String,
+    /// Unknown for subscriptions older than Throat started recording it
+    pub subscribed: Option<NaiveDateTime>,
+}
+
+#[graphql_object(name = "SubscriberNode", context = Context)]
+impl Edge<Subscriber> {
+    fn node(&self) -> &Subscriber {
+        &self.node
+    }
+
+    fn cursor(&self) -> &Cursor {
+        &self.cursor
+    }
+}
+
+#[graphql_object(name = "SubscriberPage", context = Context)]
+impl Page<Subscriber> {
+    fn edges(&self) -> &Vec<Edge<Subscriber>> {
+        &self.edges
+    }
+
+    fn page_info(&self) -> &PageInfo {
+        &self.page_info
+    }
+
+    fn total_count(&self) -> i32 {
+        self.total_count
+    }
+}
+
 #[graphql_object(name = "SubsNode", context = Context)]
 impl Edge<Sub> {
     fn node(&self) -> &Sub {
@@ -156,7 +304,10 @@ pub async fn get_subs(
     let edges = sqlx::query_as!(
         Sub,
         r#"
-        SELECT name, nsfw, sidebar, title, creation, sid 
+        SELECT name, nsfw, sidebar, title, creation, sid,
+            array(
+                SELECT a.name FROM sub_alias a WHERE a.sid = sub.sid ORDER BY a.renamed
+            ) as "previous_names!"
         FROM sub
         WHERE name > $1
         ORDER BY name
@@ -198,16 +349,93 @@ pub async fn get_subs(
     })
 }
 
+async fn get_subscribers(
+    context: &Context,
+    sid: &str,
+    count: Option<i32>,
+    after: Option<String>,
+) -> FieldResult<Page<Subscriber>> {
+    if !context.user.is_owner(sid) {
+        return Err("Only the owner can see who is subscribed".into());
+    }
+    let count = count.unwrap_or(50).max(0).min(500) as i64;
+    let offset = parse_offset(after)?;
+
+    let edges = sqlx::query!(
+        r#"
+        SELECT u.name as "name!", s.time
+        FROM sub_subscriber s
+        JOIN public.user u ON u.uid = s.uid
+        WHERE s.sid = $1 AND s.status = 1
+        ORDER BY s.time NULLS FIRST, u.name
+        LIMIT $2 OFFSET $3
+        "#,
+        sid,
+        count,
+        offset
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .enumerate()
+    .map(|(i, row)| Edge {
+        node: Subscriber {
+            name: row.name,
+            subscribed: row.time,
+        },
+        cursor: (offset + i as i64 + 1).to_string(),
+    })
+    .collect::<Vec<_>>();
+
+    let total_count = sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM sub_subscriber
+        WHERE sid = $1 AND status = 1
+        "#,
+        sid
+    )
+    .fetch_one(&context.pool)
+    .await?
+    .cnt as i32;
+    let end_cursor = edges
+        .last()
+        .map_or_else(|| "".into(), |edge| edge.cursor.clone());
+
+    Ok(Page {
+        page_info: PageInfo {
+            has_next_page: offset + (edges.len() as i64) < total_count as i64,
+            end_cursor,
+        },
+        edges,
+        total_count,
+    })
+}
+
 pub struct SubLoader {
-    pub pool: sqlx::PgPool,
+    pub repo: Arc<dyn SubRepo>,
 }
 
 #[async_trait]
-impl BatchFn<unicase::UniCase<String>, Result<Sub, Arc<FieldError>>> for SubLoader {
+impl BatchFn<UniCase<String>, Result<Sub, Arc<FieldError>>> for SubLoader {
     async fn load(
         &self,
-        keys: &[unicase::UniCase<String>],
-    ) -> HashMap<unicase::UniCase<String>, Result<Sub, Arc<FieldError>>> {
+        keys: &[UniCase<String>],
+    ) -> HashMap<UniCase<String>, Result<Sub, Arc<FieldError>>> {
+        stats::record_batch("sub", keys.len());
+        self.repo.load_subs(keys).await
+    }
+}
+
+#[async_trait]
+impl SubRepo for sqlx::PgPool {
+    async fn load_subs(
+        &self,
+        keys: &[UniCase<String>],
+    ) -> HashMap<UniCase<String>, Result<Sub, Arc<FieldError>>> {
         let sql_keys = keys
             .iter()
             .map(|case| case.clone().into())
@@ -215,19 +443,26 @@ impl BatchFn<unicase::UniCase<String>, Result<Sub, Arc<FieldError>>> for SubLoad
 
         let results: Vec<_> = sqlx::query_as!(
             Sub,
-            r#"SELECT sid, name, creation, title, sidebar, nsfw
+            r#"SELECT sid, name, creation, title, sidebar, nsfw,
+                array(
+                    SELECT a.name FROM sub_alias a WHERE a.sid = sub.sid ORDER BY a.renamed
+                ) as "previous_names!"
             FROM sub
             WHERE lower(name) in (select lower(x) FROM unnest($1::text[]) x)
             OR sid = ANY($1::text[])
+            OR sid IN (
+                SELECT a.sid
+                FROM sub_alias a
+                WHERE lower(a.name) in (select lower(x) FROM unnest($1::text[]) x)
+            )
             "#,
             &sql_keys
         )
-        .fetch(&self.pool)
+        .fetch(self)
         .collect::<Vec<_>>()
         .await;
 
-        let mut map: HashMap<unicase::UniCase<String>, Result<Sub, Arc<FieldError>>> =
-            HashMap::new();
+        let mut map: HashMap<UniCase<String>, Result<Sub, Arc<FieldError>>> = HashMap::new();
 
         results.iter().for_each(|value| {
             if let Ok(value) = value {
@@ -235,6 +470,10 @@ impl BatchFn<unicase::UniCase<String>, Result<Sub, Arc<FieldError>>> for SubLoad
                 if let Some(ref name) = value.name {
                     map.insert(name.clone().into(), Ok(value.clone()));
                 }
+                value.previous_names.iter().for_each(|name| {
+                    map.entry(name.clone().into())
+                        .or_insert_with(|| Ok(value.clone()));
+                });
             }
         });
 
@@ -246,3 +485,128 @@ impl BatchFn<unicase::UniCase<String>, Result<Sub, Arc<FieldError>>> for SubLoad
         map
     }
 }
+
+#[async_trait]
+impl SubRepo for Memory {
+    async fn load_subs(
+        &self,
+        keys: &[UniCase<String>],
+    ) -> HashMap<UniCase<String>, Result<Sub, Arc<FieldError>>> {
+        keys.iter()
+            .map(|key| {
+                let sub = self.subs.iter().find(|sub| {
+                    std::iter::once(&sub.sid)
+                        .chain(sub.name.iter())
+                        .chain(sub.previous_names.iter())
+                        .any(|name| UniCase::new(name.as_str()) == UniCase::new(key.as_str()))
+                });
+                (
+                    key.clone(),
+                    sub.cloned()
+                        .ok_or_else(|| Arc::new(format!("Could not find {}", key).into())),
+                )
+            })
+            .collect()
+    }
+}
+
+fn valid_sub_name(name: &str) -> bool {
+    (2..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+pub async fn rename_sub(context: &Context, old: String, new: String) -> FieldResult<Sub> {
+    context.user.require_admin()?;
+    if !valid_sub_name(&new) {
+        return Err(format!("Invalid sub name {}", new).into());
+    }
+
+    let sub = context
+        .sub_loader
+        .load(old.clone().into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    let current = sub.name.clone().unwrap_or_default();
+
+    let taken = sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM (
+            SELECT sid FROM sub WHERE lower(name) = lower($1)
+            UNION ALL
+            SELECT sid FROM sub_alias WHERE lower(name) = lower($1)
+        ) n
+        WHERE sid <> $2
+        "#,
+        new,
+        sub.sid
+    )
+    .fetch_one(&context.pool)
+    .await?
+    .cnt;
+    if taken > 0 {
+        return Err(format!("{} is already taken", new).into());
+    }
+
+    let mut tx = context.pool.begin().await?;
+    // Renaming back to an old name, or just changing its case, shouldn't leave an alias behind
+    sqlx::query!(
+        r#"
+        DELETE FROM sub_alias
+        WHERE sid = $1 AND lower(name) IN (lower($2), lower($3))
+        "#,
+        sub.sid,
+        new,
+        current
+    )
+    .execute(&mut tx)
+    .await?;
+    if !current.eq_ignore_ascii_case(&new) {
+        sqlx::query!(
+            r#"
+            INSERT INTO sub_alias (name, sid)
+            VALUES ($1, $2)
+            "#,
+            current,
+            sub.sid
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    sqlx::query!(
+        r#"
+        UPDATE sub
+        SET name = $1
+        WHERE sid = $2
+        "#,
+        new,
+        sub.sid
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    log::info!("Sub {} renamed to {} by {:?}", current, new, context.user);
+    context
+        .publish(Event::SubRenamed {
+            sid: sub.sid.clone(),
+            name: new.clone(),
+        })
+        .await;
+
+    Ok(sqlx::query_as!(
+        Sub,
+        r#"SELECT sid, name, creation, title, sidebar, nsfw,
+            array(
+                SELECT a.name FROM sub_alias a WHERE a.sid = sub.sid ORDER BY a.renamed
+            ) as "previous_names!"
+        FROM sub
+        WHERE sid = $1
+        "#,
+        sub.sid
+    )
+    .fetch_one(&context.pool)
+    .await?)
+}
@@ -0,0 +1,94 @@
+use crate::{
+    user::{User, UserRef},
+    Context,
+};
+use futures_util::stream::StreamExt;
+use juniper::FieldError;
+use sqlx::{Postgres, Transaction};
+
+pub enum Submission<'a> {
+    Post(i32),
+    Comment(&'a str),
+}
+
+/// Called by the post and comment creation paths, in the transaction that saves the content.
+/// Does nothing unless the deployment opted in with RECORD_SUBMITTER_IP.
+pub async fn record(
+    context: &Context,
+    tx: &mut Transaction<'_, Postgres>,
+    submission: Submission<'_>,
+) -> Result<(), FieldError> {
+    if !context.config.record_submitter_ip {
+        return Ok(());
+    }
+    let ip = match context.request.ip {
+        Some(ref ip) => ip,
+        None => return Ok(()),
+    };
+    let uid = context.user.user_id()?;
+    let (pid, cid) = match submission {
+        Submission::Post(pid) => (Some(pid), None),
+        Submission::Comment(cid) => (None, Some(cid)),
+    };
+
+    sqlx::query!(
+        r#"
+        INSERT INTO submitter_info (pid, cid, uid, ip, user_agent)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        pid,
+        cid,
+        uid,
+        ip,
+        context.request.user_agent
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn post_ip(context: &Context, pid: i32) -> Result<Option<String>, FieldError> {
+    context.user.require_admin()?;
+    Ok(sqlx::query!(
+        r#"
+        SELECT ip
+        FROM submitter_info
+        WHERE pid = $1
+        ORDER BY time DESC
+        LIMIT 1
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .map(|row| row.ip))
+}
+
+pub async fn find_alt_accounts(context: &Context, ip: String) -> Result<Vec<User>, FieldError> {
+    context.user.require_admin()?;
+    let ids = sqlx::query!(
+        r#"
+        SELECT DISTINCT uid
+        FROM submitter_info
+        WHERE ip = $1
+        "#,
+        ip
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| UserRef::Uid(row.uid))
+    .collect::<Vec<_>>();
+
+    Ok(context
+        .user_loader
+        .load_many(ids)
+        .await
+        .values()
+        .filter_map(|user| user.clone().ok())
+        .collect())
+}
@@ -0,0 +1,90 @@
+use crate::{post::Post, Context};
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, ID};
+
+/// Most threads one user can follow
+const MAX_SUBSCRIPTIONS: i64 = 500;
+
+/// Notifications for every new comment in the thread, see Event::ThreadCommented
+pub async fn subscribe(context: &Context, id: ID) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let pid = context.config.post_ids.decode(&id)?;
+
+    let count = sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM post_subscription
+        WHERE uid = $1
+        "#,
+        uid
+    )
+    .fetch_one(&context.pool)
+    .await?
+    .cnt;
+    if count >= MAX_SUBSCRIPTIONS {
+        return Err(format!("You can follow at most {} threads", MAX_SUBSCRIPTIONS).into());
+    }
+
+    let subscribed = sqlx::query!(
+        r#"
+        INSERT INTO post_subscription (uid, pid)
+        SELECT $1, pid FROM sub_post WHERE pid = $2 AND coalesce(deleted, 0) = 0
+        ON CONFLICT (uid, pid) DO UPDATE SET created = post_subscription.created
+        RETURNING pid
+        "#,
+        uid,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+    if subscribed.is_none() {
+        return Err(format!("Post not found {}", *id).into());
+    }
+    Ok(true)
+}
+
+pub async fn unsubscribe(context: &Context, id: ID) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let pid = context.config.post_ids.decode(&id)?;
+
+    Ok(sqlx::query!(
+        r#"
+        DELETE FROM post_subscription
+        WHERE uid = $1 AND pid = $2
+        RETURNING pid
+        "#,
+        uid,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .is_some())
+}
+
+/// Most recently followed first
+pub async fn subscriptions(context: &Context, uid: &str) -> Result<Vec<Post>, FieldError> {
+    context.user.private_user_data(uid)?;
+    let pids: Vec<i32> = sqlx::query!(
+        r#"
+        SELECT pid
+        FROM post_subscription
+        WHERE uid = $1
+        ORDER BY created DESC
+        "#,
+        uid
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| row.pid)
+    .collect();
+
+    let mut posts = context.post_loader.load_many(pids.clone()).await;
+    Ok(pids
+        .iter()
+        .filter_map(|pid| posts.remove(pid).and_then(|post| post.ok()))
+        .collect())
+}
@@ -0,0 +1,146 @@
+use crate::post::{type_filter, Post, PostType};
+use crate::{stats, Context};
+use chrono::{Duration, Utc};
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLEnum};
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+const MAX_LIMIT: i32 = 100;
+/// Most rankings kept at once, the oldest are dropped first
+const MAX_RANKINGS: usize = 1000;
+
+lazy_static! {
+    // Ranked pids by sid, range and post types. Ranking a big sub's year is one of the more
+    // expensive queries there is and the answer barely changes, so it is only redone once stale.
+    static ref RANKINGS: Mutex<HashMap<(String, TopRange, Vec<i32>), (Instant, Arc<Vec<i32>>)>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq, Eq, Hash)]
+pub enum TopRange {
+    Day,
+    Week,
+    Month,
+    Year,
+    All,
+}
+
+impl TopRange {
+    pub(crate) fn since(self) -> Option<Duration> {
+        match self {
+            TopRange::Day => Some(Duration::days(1)),
+            TopRange::Week => Some(Duration::weeks(1)),
+            TopRange::Month => Some(Duration::days(30)),
+            TopRange::Year => Some(Duration::days(365)),
+            TopRange::All => None,
+        }
+    }
+
+    /// Longer ranges change slower, so they are kept longer
+    fn max_age(self) -> std::time::Duration {
+        std::time::Duration::from_secs(match self {
+            TopRange::Day => 5 * 60,
+            TopRange::Week => 30 * 60,
+            TopRange::Month => 60 * 60,
+            TopRange::Year | TopRange::All => 6 * 60 * 60,
+        })
+    }
+}
+
+/// Ranks by the sum of the votes' weights, so votes from new accounts count for less
+async fn rank(
+    pool: &sqlx::PgPool,
+    sid: &str,
+    range: TopRange,
+    types: &[i32],
+    ignore_bot_votes: bool,
+) -> Result<Vec<i32>, FieldError> {
+    let since = range.since().map(|since| Utc::now().naive_utc() - since);
+    Ok(sqlx::query!(
+        r#"
+        SELECT p.pid
+        FROM sub_post p
+        LEFT JOIN sub_post_vote v ON v.pid = p.pid
+            AND NOT ($5 AND v.uid IN (SELECT b.uid FROM user_bot b WHERE b.approved))
+        WHERE p.sid = $1 AND coalesce(p.deleted, 0) = 0
+            AND ($2::timestamp IS NULL OR p.posted > $2)
+            AND (cardinality($3::int[]) = 0 OR p.ptype = ANY($3))
+        GROUP BY p.pid
+        ORDER BY SUM(CASE WHEN v.positive > 0 THEN v.weight WHEN v.positive < 0 THEN -v.weight
+                          ELSE 0 END)
+            DESC NULLS LAST, p.posted DESC
+        LIMIT $4
+        "#,
+        sid,
+        since,
+        types,
+        MAX_LIMIT as i64,
+        ignore_bot_votes
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| row.pid)
+    .collect())
+}
+
+/// Best posts of the sub over the range, best first. The ranking is shared between viewers and
+/// redone every few minutes to hours depending on the range.
+pub async fn top_posts(
+    context: &Context,
+    sid: &str,
+    range: TopRange,
+    types: Option<Vec<PostType>>,
+    limit: Option<i32>,
+) -> Result<Vec<Post>, FieldError> {
+    let limit = limit.unwrap_or(25).max(0).min(MAX_LIMIT) as usize;
+    let types = type_filter(types);
+    let key = (sid.to_string(), range, types.clone());
+
+    let cached = RANKINGS
+        .lock()
+        .unwrap()
+        .get(&key)
+        .filter(|(stored, _)| stored.elapsed() < range.max_age())
+        .map(|(_, pids)| pids.clone());
+    stats::record_cache("top_rankings", cached.is_some());
+    let pids = match cached {
+        Some(pids) => pids,
+        None => {
+            let pids = Arc::new(
+                rank(
+                    &context.pool,
+                    sid,
+                    range,
+                    &types,
+                    context.config.ignore_bot_votes,
+                )
+                .await?,
+            );
+            let mut rankings = RANKINGS.lock().unwrap();
+            if rankings.len() >= MAX_RANKINGS {
+                rankings.retain(|(_, range, _), (stored, _)| stored.elapsed() < range.max_age());
+            }
+            if rankings.len() < MAX_RANKINGS {
+                rankings.insert(key, (Instant::now(), pids.clone()));
+            }
+            pids
+        }
+    };
+
+    let pids: Vec<i32> = pids.iter().take(limit).copied().collect();
+    let mut posts = context.post_loader.load_many(pids.clone()).await;
+    // Removed since the ranking was made, or otherwise gone
+    Ok(pids
+        .iter()
+        .filter_map(|pid| posts.remove(pid).and_then(|post| post.ok()))
+        .collect())
+}
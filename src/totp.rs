@@ -0,0 +1,170 @@
+use crate::{auth::UserState, Context};
+use juniper::{FieldError, GraphQLObject};
+use rand::Rng;
+use std::time::{SystemTime, UNIX_EPOCH};
+use totp_lite::{totp_custom, Sha1, DEFAULT_STEP};
+
+const ISSUER: &str = "Throat";
+const DIGITS: u32 = 6;
+
+#[derive(GraphQLObject, Debug)]
+pub struct TotpEnrollment {
+    /// Base32 encoded secret for manual entry
+    pub secret: String,
+    /// otpauth:// uri, suitable for rendering as a QR code
+    pub uri: String,
+}
+
+fn decode_secret(secret: &str) -> Result<Vec<u8>, FieldError> {
+    base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)
+        .ok_or_else(|| "Corrupt TOTP secret".into())
+}
+
+/// Accepts the current code as well as the ones directly before and after it to allow for clock
+/// drift between the server and the user's device
+fn check_code(secret: &[u8], code: &str) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    [now - DEFAULT_STEP, now, now + DEFAULT_STEP]
+        .iter()
+        .any(|time| totp_custom::<Sha1>(DEFAULT_STEP, DIGITS, secret, *time) == code)
+}
+
+async fn get_secret(
+    pool: &sqlx::PgPool,
+    uid: &str,
+    key: &str,
+) -> Result<Option<String>, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT value
+        FROM user_metadata
+        WHERE uid = $1 AND key = $2
+        "#,
+        uid,
+        key
+    )
+    .fetch_optional(pool)
+    .await?
+    .and_then(|row| row.value))
+}
+
+async fn set_secret(
+    pool: &sqlx::PgPool,
+    uid: &str,
+    key: &str,
+    value: Option<&str>,
+) -> Result<(), FieldError> {
+    sqlx::query!(
+        r#"
+        DELETE FROM user_metadata
+        WHERE uid = $1 AND key = $2
+        "#,
+        uid,
+        key
+    )
+    .execute(pool)
+    .await?;
+
+    if let Some(value) = value {
+        sqlx::query!(
+            r#"
+            INSERT INTO user_metadata (uid, key, value)
+            VALUES ($1, $2, $3)
+            "#,
+            uid,
+            key,
+            value
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn is_enabled(pool: &sqlx::PgPool, uid: &str) -> Result<bool, FieldError> {
+    Ok(get_secret(pool, uid, "totp_secret").await?.is_some())
+}
+
+/// Sensitive mutations call this before doing anything. Users without TOTP pass straight through,
+/// everyone else has to supply a valid code.
+pub async fn require_code(
+    pool: &sqlx::PgPool,
+    uid: &str,
+    code: Option<&str>,
+) -> Result<(), FieldError> {
+    match get_secret(pool, uid, "totp_secret").await? {
+        None => Ok(()),
+        Some(secret) => {
+            let code = code.ok_or("TOTP code required")?;
+            if check_code(&decode_secret(&secret)?, code) {
+                Ok(())
+            } else {
+                Err("Invalid TOTP code".into())
+            }
+        }
+    }
+}
+
+pub async fn enable(context: &Context) -> Result<TotpEnrollment, FieldError> {
+    let uid = context.user.user_id()?;
+    if is_enabled(&context.pool, uid).await? {
+        return Err("TOTP is already enabled".into());
+    }
+
+    let secret = base32::encode(
+        base32::Alphabet::RFC4648 { padding: false },
+        &rand::thread_rng().gen::<[u8; 20]>(),
+    );
+
+    // Not active until the user proves their device is set up with confirmTotp
+    set_secret(&context.pool, uid, "totp_pending", Some(&secret)).await?;
+
+    let name = match context.user {
+        UserState::LoggedIn { ref name, .. } => name.clone(),
+        _ => uid.to_string(),
+    };
+
+    Ok(TotpEnrollment {
+        uri: format!(
+            "otpauth://totp/{issuer}:{name}?secret={secret}&issuer={issuer}&digits={digits}",
+            issuer = ISSUER,
+            name = name,
+            secret = secret,
+            digits = DIGITS
+        ),
+        secret,
+    })
+}
+
+pub async fn confirm(context: &Context, code: String) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let secret = get_secret(&context.pool, uid, "totp_pending")
+        .await?
+        .ok_or("No pending TOTP enrollment")?;
+
+    if !check_code(&decode_secret(&secret)?, &code) {
+        return Err("Invalid TOTP code".into());
+    }
+
+    set_secret(&context.pool, uid, "totp_secret", Some(&secret)).await?;
+    set_secret(&context.pool, uid, "totp_pending", None).await?;
+
+    Ok(true)
+}
+
+pub async fn disable(context: &Context, code: String) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    if !is_enabled(&context.pool, uid).await? {
+        return Err("TOTP is not enabled".into());
+    }
+    require_code(&context.pool, uid, Some(&code)).await?;
+
+    set_secret(&context.pool, uid, "totp_secret", None).await?;
+
+    Ok(true)
+}
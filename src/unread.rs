@@ -0,0 +1,63 @@
+use crate::{legacy, post::DeleteStatus, Context};
+use juniper::{FieldError, GraphQLObject};
+
+/// Throat's message.mtype values. Private messages between users, the mod team writing to a
+/// user, and the kinds Throat sends on its own when someone answers or mentions them.
+const PRIVATE_MESSAGE: i32 = 1;
+const MOD_MESSAGE: i32 = 2;
+const NOTIFICATION_TYPES: &[i32] = &[4, 5, 8];
+
+/// Badge counts for the viewer, all from one statement so polling them stays cheap
+#[derive(GraphQLObject, Debug)]
+pub struct UnreadCounts {
+    /// Unread replies to the viewer's posts and comments, and mentions
+    pub notifications: i32,
+    /// Unread private messages
+    pub messages: i32,
+    /// Unread messages from the mods of a sub
+    pub modmail: i32,
+    /// Join requests and filter-held posts waiting in the subs the viewer mods
+    pub mod_queue: i32,
+}
+
+pub async fn get_counts(context: &Context, uid: &str) -> Result<UnreadCounts, FieldError> {
+    if context.user.user_id()? != uid {
+        return Err("Not Authorized".into());
+    }
+    let modded = context.user.modded_subs();
+
+    let row = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT count(*) FROM message
+             WHERE receivedby = $1 AND read IS NULL AND mtype = ANY($2)) as "notifications!",
+            (SELECT count(*) FROM message
+             WHERE receivedby = $1 AND read IS NULL AND mtype = $3) as "messages!",
+            (SELECT count(*) FROM message
+             WHERE receivedby = $1 AND read IS NULL AND mtype = $4) as "modmail!",
+            (SELECT count(*) FROM sub_member_request
+             WHERE sid = ANY($5) AND status = 'pending')
+            + (SELECT count(DISTINCT p.pid)
+               FROM mod_log l
+               CROSS JOIN unnest(l.targets) as target
+               JOIN sub_post p ON p.pid::text = target
+               WHERE l.action = 'filter_hold_post' AND l.sid = ANY($5) AND p.deleted = ANY($6))
+            as "mod_queue!"
+        "#,
+        uid,
+        NOTIFICATION_TYPES,
+        PRIVATE_MESSAGE,
+        MOD_MESSAGE,
+        &modded,
+        &legacy::post_deleted_codes(DeleteStatus::Mod)
+    )
+    .fetch_one(&context.pool)
+    .await?;
+
+    Ok(UnreadCounts {
+        notifications: row.notifications as i32,
+        messages: row.messages as i32,
+        modmail: row.modmail as i32,
+        mod_queue: row.mod_queue as i32,
+    })
+}
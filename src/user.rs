@@ -1,12 +1,28 @@
+use crate::content::{self, ActivityDay, Content};
 use crate::post::{self, Post};
-use crate::{Context, Page};
+use crate::{
+    bot::{self, BotStatus},
+    digest::{self, DigestFrequency},
+    events::Event,
+    legacy,
+    repo::{Memory, UserRepo},
+    stats, thread, totp,
+    unread::{self, UnreadCounts},
+    warning::{self, Warning},
+    Context, Page,
+};
 use async_trait::async_trait;
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime, Utc};
 use dataloader::BatchFn;
 use futures_util::stream::StreamExt;
 use juniper::{graphql_object, FieldError, GraphQLEnum};
 use std::{collections::HashMap, sync::Arc};
 use unicase::UniCase;
+use uuid::Uuid;
+
+const RESET_TOKEN: &str = "reset";
+const VERIFY_TOKEN: &str = "verify";
+const MIN_PASSWORD_LENGTH: usize = 7;
 
 #[derive(Debug, Clone, Copy, GraphQLEnum)]
 pub enum Crypto {
@@ -23,10 +39,10 @@ pub enum UserStatus {
 
 #[derive(Debug, Clone)]
 pub struct User {
-    uid: String,
+    pub(crate) uid: String,
     crypto: Crypto,
     joindate: Option<NaiveDateTime>,
-    name: Option<String>,
+    pub(crate) name: Option<String>,
     email: Option<String>,
     password: Option<String>,
 
@@ -78,60 +94,510 @@ impl User {
         Ok(self.resets)
     }
 
+    async fn totp_enabled(&self, ctx: &Context) -> Result<bool, FieldError> {
+        ctx.user.private_user_data(&self.uid)?;
+        totp::is_enabled(&ctx.pool, &self.uid).await
+    }
+
+    /// Everything clients show a badge for, only for the viewer themselves
+    async fn unread_counts(&self, ctx: &Context) -> Result<UnreadCounts, FieldError> {
+        unread::get_counts(ctx, &self.uid).await
+    }
+
+    /// Posts followed with subscribeToPost, most recently followed first
+    async fn thread_subscriptions(&self, ctx: &Context) -> Result<Vec<Post>, FieldError> {
+        thread::subscriptions(ctx, &self.uid).await
+    }
+
+    /// How often top posts from subscribed subs are mailed
+    async fn digest_frequency(&self, ctx: &Context) -> Result<DigestFrequency, FieldError> {
+        ctx.user.private_user_data(&self.uid)?;
+        digest::get_frequency(&ctx.pool, &self.uid).await
+    }
+
+    async fn email_verified(&self, ctx: &Context) -> Result<bool, FieldError> {
+        ctx.user.private_user_data(&self.uid)?;
+        Ok(sqlx::query!(
+            r#"
+            SELECT count(*) as "cnt!"
+            FROM user_metadata
+            WHERE uid = $1 AND key = 'email_verified' AND value = '1'
+            "#,
+            self.uid
+        )
+        .fetch_one(&ctx.pool)
+        .await?
+        .cnt > 0)
+    }
+
+    /// Approved bot accounts, clients should badge their posts and comments
+    async fn is_bot(&self, ctx: &Context) -> Result<bool, FieldError> {
+        bot::is_bot(&ctx.pool, &self.uid).await
+    }
+
+    /// Includes pending requests, only for the user themselves and admins
+    async fn bot_status(&self, ctx: &Context) -> Result<BotStatus, FieldError> {
+        ctx.user.private_user_data(&self.uid)?;
+        bot::get_status(&ctx.pool, &self.uid).await
+    }
+
+    /// Only warnings from subs the viewer mods, all of them for admins and the user themselves
+    async fn warnings(&self, ctx: &Context) -> Result<Vec<Warning>, FieldError> {
+        warning::get_warnings(ctx, &self.uid).await
+    }
+
+    /// Warnings in the sub over the last `days` days (30 by default, at most 3650)
+    async fn warning_count(
+        &self,
+        ctx: &Context,
+        sub: String,
+        days: Option<i32>,
+    ) -> Result<i32, FieldError> {
+        warning::get_warning_count(ctx, &self.uid, sub, days).await
+    }
+
     async fn posts(
         &self,
         context: &Context,
         count: Option<i32>,
         after: Option<String>,
     ) -> Result<Page<Post>, FieldError> {
-        post::get_related_posts(context, vec![self.uid.clone()], count, after).await
+        post::get_related_posts(
+            context,
+            vec![self.uid.clone()],
+            None,
+            None,
+            None,
+            false,
+            count,
+            after,
+        )
+        .await
+    }
+
+    /// Posts and comments interleaved, newest first
+    async fn overview(
+        &self,
+        context: &Context,
+        count: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Page<Content>, FieldError> {
+        content::get_user_overview(context, &self.uid, count, after).await
+    }
+
+    /// Posts and comments per day of `year`, for a contribution graph on the profile
+    async fn activity_heatmap(
+        &self,
+        context: &Context,
+        year: Option<i32>,
+    ) -> Result<Vec<ActivityDay>, FieldError> {
+        content::get_activity_heatmap(context, &self.uid, year).await
+    }
+}
+
+pub async fn issue_token(
+    pool: &sqlx::PgPool,
+    uid: &str,
+    kind: &str,
+    valid: Duration,
+) -> Result<String, FieldError> {
+    let token = Uuid::new_v4().to_simple().to_string();
+    sqlx::query!(
+        r#"
+        INSERT INTO user_token (token, uid, kind, expires)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        token,
+        uid,
+        kind,
+        Utc::now().naive_utc() + valid
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(token)
+}
+
+/// Tokens are single use, redeeming one deletes it and returns the uid it was issued to
+pub async fn redeem_token(
+    pool: &sqlx::PgPool,
+    token: &str,
+    kind: &str,
+) -> Result<String, FieldError> {
+    sqlx::query!(
+        r#"
+        DELETE FROM user_token
+        WHERE token = $1 AND kind = $2 AND expires > now()
+        RETURNING uid
+        "#,
+        token,
+        kind
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|row| row.uid)
+    .ok_or_else(|| "Invalid or expired token".into())
+}
+
+async fn send_reset_token(context: &Context, uid: &str, email: &str) -> Result<(), FieldError> {
+    let token = issue_token(&context.pool, uid, RESET_TOKEN, Duration::hours(2)).await?;
+    context
+        .mailer
+        .send(
+            email,
+            "Password reset",
+            &format!(
+                "Someone requested a password reset for your account. \
+                Use this token to choose a new password, it is valid for two hours:\n\n{}\n\n\
+                If this wasn't you, you can ignore this email.",
+                token
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn request_password_reset(context: &Context, email: String) -> Result<bool, FieldError> {
+    let user = sqlx::query!(
+        r#"
+        SELECT uid, email as "email!"
+        FROM public.user
+        WHERE lower(email) = lower($1) AND status = 0
+        "#,
+        email
+    )
+    .fetch_optional(&context.pool)
+    .await?;
+
+    // Always report success, otherwise this can be used to find out who is registered. Failing
+    // to send is only logged for the same reason.
+    if let Some(user) = user {
+        if let Err(err) = send_reset_token(context, &user.uid, &user.email).await {
+            log::error!("Password reset for {} failed - {}", user.uid, err.message());
+        }
+    }
+
+    Ok(true)
+}
+
+pub async fn reset_password(
+    context: &Context,
+    token: String,
+    new_password: String,
+) -> Result<bool, FieldError> {
+    check_password_length(&new_password)?;
+
+    let uid = redeem_token(&context.pool, &token, RESET_TOKEN).await?;
+    let password = bcrypt::hash(&new_password, bcrypt::DEFAULT_COST)?;
+
+    sqlx::query!(
+        r#"
+        UPDATE public.user
+        SET password = $1, crypto = 1, resets = resets + 1
+        WHERE uid = $2
+        "#,
+        password,
+        uid
+    )
+    .execute(&context.pool)
+    .await?;
+
+    // Any other outstanding reset tokens are now stale
+    sqlx::query!(
+        r#"
+        DELETE FROM user_token
+        WHERE uid = $1 AND kind = $2
+        "#,
+        uid,
+        RESET_TOKEN
+    )
+    .execute(&context.pool)
+    .await?;
+
+    Ok(true)
+}
+
+fn check_password_length(password: &str) -> Result<(), FieldError> {
+    if password.len() < MIN_PASSWORD_LENGTH {
+        Err(format!(
+            "Password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )
+        .into())
+    } else {
+        Ok(())
+    }
+}
+
+async fn check_password(context: &Context, uid: &str, password: &str) -> Result<(), FieldError> {
+    let user = sqlx::query!(
+        r#"
+        SELECT crypto, password
+        FROM public.user
+        WHERE uid = $1
+        "#,
+        uid
+    )
+    .fetch_one(&context.pool)
+    .await?;
+
+    match (user.crypto, user.password) {
+        (1, Some(hash)) if bcrypt::verify(password, &hash)? => Ok(()),
+        (1, _) => Err("Incorrect password".into()),
+        _ => Err("Password is managed by the identity provider".into()),
+    }
+}
+
+pub async fn me(context: &Context) -> Result<User, FieldError> {
+    context
+        .user_loader
+        .load(UserRef::Uid(context.user.user_id()?.to_string()))
+        .await
+        .map_err(|err| format!("{:?}", err).into())
+}
+
+pub async fn change_password(
+    context: &Context,
+    old_password: String,
+    new_password: String,
+    totp_code: Option<String>,
+) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    check_password_length(&new_password)?;
+    check_password(context, uid, &old_password).await?;
+    totp::require_code(&context.pool, uid, totp_code.as_deref()).await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE public.user
+        SET password = $1
+        WHERE uid = $2
+        "#,
+        bcrypt::hash(&new_password, bcrypt::DEFAULT_COST)?,
+        uid
+    )
+    .execute(&context.pool)
+    .await?;
+
+    Ok(true)
+}
+
+pub async fn delete_account(
+    context: &Context,
+    password: String,
+    totp_code: Option<String>,
+) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    check_password(context, uid, &password).await?;
+    totp::require_code(&context.pool, uid, totp_code.as_deref()).await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE public.user
+        SET status = 10
+        WHERE uid = $1
+        "#,
+        uid
+    )
+    .execute(&context.pool)
+    .await?;
+    context
+        .publish(Event::AccountDeleted {
+            uid: uid.to_string(),
+        })
+        .await;
+
+    Ok(true)
+}
+
+/// Comments scoring below this are collapsed unless a query asks for a different threshold
+pub async fn set_collapse_below_score(
+    context: &Context,
+    score: Option<i32>,
+) -> Result<Option<i32>, FieldError> {
+    let uid = context.user.user_id()?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM user_metadata
+        WHERE uid = $1 AND key = 'collapse_below_score'
+        "#,
+        uid
+    )
+    .execute(&context.pool)
+    .await?;
+
+    if let Some(score) = score {
+        sqlx::query!(
+            r#"
+            INSERT INTO user_metadata (uid, key, value)
+            VALUES ($1, 'collapse_below_score', $2)
+            "#,
+            uid,
+            score.to_string()
+        )
+        .execute(&context.pool)
+        .await?;
+    }
+
+    Ok(score)
+}
+
+pub async fn request_email_verification(context: &Context) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let user = context
+        .user_loader
+        .load(UserRef::Uid(uid.to_string()))
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    let email = user.email.ok_or("No email address set")?;
+
+    let token = issue_token(&context.pool, uid, VERIFY_TOKEN, Duration::days(2)).await?;
+    context
+        .mailer
+        .send(
+            &email,
+            "Verify your email address",
+            &format!(
+                "Use this token to verify your email address, it is valid for two days:\n\n{}",
+                token
+            ),
+        )
+        .await?;
+
+    Ok(true)
+}
+
+pub async fn verify_email(context: &Context, token: String) -> Result<bool, FieldError> {
+    let uid = redeem_token(&context.pool, &token, VERIFY_TOKEN).await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM user_metadata
+        WHERE uid = $1 AND key = 'email_verified'
+        "#,
+        uid
+    )
+    .execute(&context.pool)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO user_metadata (uid, key, value)
+        VALUES ($1, 'email_verified', '1')
+        "#,
+        uid
+    )
+    .execute(&context.pool)
+    .await?;
+
+    Ok(true)
+}
+
+/// Key for the user loader. uids are matched exactly and names case insensitively, keeping them
+/// apart means a name that happens to equal someone else's uid can never load the wrong user.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum UserRef {
+    Uid(String),
+    Name(UniCase<String>),
+}
+
+impl UserRef {
+    pub fn name(name: String) -> Self {
+        UserRef::Name(UniCase::new(name))
     }
 }
 
+impl std::fmt::Display for UserRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UserRef::Uid(uid) => write!(f, "uid {}", uid),
+            UserRef::Name(name) => write!(f, "name {}", name),
+        }
+    }
+}
+
+/// Splits keys into the uids and lowercased names the SQL query matches on
+fn partition_keys(keys: &[UserRef]) -> (Vec<String>, Vec<String>) {
+    let mut uids = vec![];
+    let mut names = vec![];
+    keys.iter().for_each(|key| match key {
+        UserRef::Uid(uid) => uids.push(uid.clone()),
+        UserRef::Name(name) => names.push(name.to_lowercase()),
+    });
+    (uids, names)
+}
+
+/// Answers every requested key, either with the user it refers to or a not found error
+fn index_users(
+    keys: &[UserRef],
+    users: Vec<User>,
+) -> HashMap<UserRef, Result<User, Arc<FieldError>>> {
+    let mut user_map: HashMap<UserRef, Result<User, Arc<FieldError>>> = HashMap::new();
+
+    users.into_iter().for_each(|user| {
+        if let Some(ref name) = user.name {
+            user_map.insert(UserRef::name(name.clone()), Ok(user.clone()));
+        }
+        user_map.insert(UserRef::Uid(user.uid.clone()), Ok(user));
+    });
+    user_map.retain(|key, _| keys.contains(key));
+
+    keys.iter().for_each(|key| {
+        user_map
+            .entry(key.to_owned())
+            .or_insert_with(|| Err(Arc::new(format!("user not found - {}", key).into())));
+    });
+
+    user_map
+}
+
 pub struct UserLoader {
-    pub pool: sqlx::PgPool,
+    pub repo: Arc<dyn UserRepo>,
 }
 
 #[async_trait]
-impl BatchFn<UniCase<String>, Result<User, Arc<FieldError>>> for UserLoader {
-    async fn load(
+impl BatchFn<UserRef, Result<User, Arc<FieldError>>> for UserLoader {
+    async fn load(&self, keys: &[UserRef]) -> HashMap<UserRef, Result<User, Arc<FieldError>>> {
+        stats::record_batch("user", keys.len());
+        self.repo.load_users(keys).await
+    }
+}
+
+#[async_trait]
+impl UserRepo for sqlx::PgPool {
+    async fn load_users(
         &self,
-        keys: &[UniCase<String>],
-    ) -> HashMap<UniCase<String>, Result<User, Arc<FieldError>>> {
+        keys: &[UserRef],
+    ) -> HashMap<UserRef, Result<User, Arc<FieldError>>> {
+        let (uids, names) = partition_keys(keys);
         let users: Vec<Result<User, FieldError>> = sqlx::query!(
             r#"
                 SELECT uid, crypto, joindate, name, email, password, score, given, status, resets
                 FROM public.user
                 WHERE uid = ANY($1::text[])
-                OR lower(name) = ANY($1::text[])
+                OR lower(name) = ANY($2::text[])
                 "#,
-            &keys
-                .iter()
-                .map(|key| key.to_lowercase())
-                .collect::<Vec<String>>()
+            &uids,
+            &names
         )
-        .fetch(&self.pool)
+        .fetch(self)
         .map(|user| -> Result<User, FieldError> {
             let user = user?;
             Ok(User {
                 uid: user.uid.clone(),
-                crypto: match user.crypto {
-                    1 => Ok(Crypto::BCrypt),
-                    2 => Ok(Crypto::KeyCloak),
-                    _ => Err(format!(
+                crypto: legacy::crypto(user.crypto).ok_or_else(|| {
+                    format!(
                         "Unable to deal with crypto - {} for user {}",
                         user.crypto, user.uid
-                    )),
-                }?,
-                status: match user.status {
-                    0 => Ok(UserStatus::Ok),
-                    10 => Ok(UserStatus::Deleted),
-                    5 => Ok(UserStatus::SiteBan),
-                    _ => Err(format!(
+                    )
+                })?,
+                status: legacy::user_status(user.status).ok_or_else(|| {
+                    format!(
                         "Unable to deal with status - {} for user {}",
                         user.status, user.uid
-                    )),
-                }?,
+                    )
+                })?,
                 joindate: user.joindate,
                 resets: user.resets,
                 given: user.given,
@@ -146,23 +612,78 @@ impl BatchFn<UniCase<String>, Result<User, Arc<FieldError>>> for UserLoader {
 
         log::debug!("Batch Load User - {:?}", users);
 
-        let mut user_map: HashMap<UniCase<String>, Result<User, Arc<FieldError>>> = HashMap::new();
+        index_users(keys, users.into_iter().filter_map(Result::ok).collect())
+    }
+}
 
-        users.into_iter().for_each(|user| {
-            if let Ok(user) = user {
-                user_map.insert(user.uid.clone().into(), Ok(user.clone()));
-                if let Some(ref name) = user.name {
-                    user_map.insert(name.clone().into(), Ok(user));
-                }
-            }
-        });
+#[async_trait]
+impl UserRepo for Memory {
+    async fn load_users(
+        &self,
+        keys: &[UserRef],
+    ) -> HashMap<UserRef, Result<User, Arc<FieldError>>> {
+        index_users(keys, self.users.clone())
+    }
+}
 
-        keys.iter().for_each(|id| {
-            user_map
-                .entry(id.to_owned())
-                .or_insert_with(|| Err(Arc::new(format!("user not found - {}", id).into())));
-        });
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        user_map
+    fn user(uid: &str, name: &str) -> User {
+        User {
+            uid: uid.into(),
+            crypto: Crypto::BCrypt,
+            joindate: None,
+            name: Some(name.into()),
+            email: None,
+            password: None,
+            score: 0,
+            given: 0,
+            status: UserStatus::Ok,
+            resets: 0,
+        }
+    }
+
+    fn found(map: &HashMap<UserRef, Result<User, Arc<FieldError>>>, key: &UserRef) -> String {
+        map[key]
+            .as_ref()
+            .map(|user| user.uid.clone())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn names_are_lowercased_uids_are_not() {
+        let (uids, names) =
+            partition_keys(&[UserRef::Uid("AbC".into()), UserRef::name("MiXeD".into())]);
+        assert_eq!(uids, vec!["AbC".to_string()]);
+        assert_eq!(names, vec!["mixed".to_string()]);
+    }
+
+    #[test]
+    fn mixed_case_names_share_a_user() {
+        let keys = vec![UserRef::name("Alice".into()), UserRef::name("ALICE".into())];
+        let map = index_users(&keys, vec![user("u1", "alice")]);
+
+        assert_eq!(found(&map, &keys[0]), "u1");
+        assert_eq!(found(&map, &keys[1]), "u1");
+    }
+
+    #[test]
+    fn uid_and_name_lookups_do_not_collide() {
+        // u2 is named after the other user's uid
+        let keys = vec![UserRef::Uid("bob".into()), UserRef::name("Bob".into())];
+        let map = index_users(&keys, vec![user("bob", "alice"), user("u2", "bob")]);
+
+        assert_eq!(found(&map, &keys[0]), "bob");
+        assert_eq!(found(&map, &keys[1]), "u2");
+    }
+
+    #[test]
+    fn uids_are_case_sensitive() {
+        let keys = vec![UserRef::Uid("U1".into())];
+        let map = index_users(&keys, vec![user("u1", "alice")]);
+
+        assert!(map[&keys[0]].is_err());
     }
 }
@@ -0,0 +1,120 @@
+use crate::Context;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use juniper::GraphQLObject;
+use serde_json::json;
+
+/// A field on its way out, marked `#[graphql(deprecated = ...)]` with the same reason where it's
+/// resolved
+#[derive(GraphQLObject, Clone)]
+pub struct DeprecatedField {
+    /// `Type.field`
+    pub field: String,
+    pub reason: String,
+    /// Day after which the field may be removed, YYYY-MM-DD
+    pub sunset: String,
+}
+
+/// Every deprecated field with the day it may go away. Resolvers of these call `used` so the
+/// response tells clients about it too.
+const DEPRECATIONS: &[(&str, &str, &str)] = &[(
+    "Query.apiVersion",
+    "Use capabilities and schemaVersion",
+    "2027-04-01",
+)];
+
+/// What build of the API answers, for clients that track compatibility
+#[derive(GraphQLObject)]
+pub struct SchemaVersion {
+    /// Version of the throatql crate
+    pub version: String,
+    /// Commit it was built from, `unknown` when built outside a git checkout without GIT_SHA set
+    pub git_sha: String,
+    pub build_date: Option<DateTime<Utc>>,
+    pub deprecations: Vec<DeprecatedField>,
+}
+
+/// What this server has turned on, so clients can feature-detect instead of assuming
+#[derive(GraphQLObject)]
+pub struct Capabilities {
+    /// GraphQL subscriptions, the schema has none yet and events only go out as push
+    pub subscriptions: bool,
+    /// POST /upload and attaching images to posts and comments
+    pub uploads: bool,
+    pub max_upload_bytes: Option<i32>,
+    /// search, searchSubs and searchUsers
+    pub search: bool,
+    /// ActivityPub actors and outboxes for subs, only served by the built-in server
+    pub federation: bool,
+    /// Web Push, see pushPublicKey
+    pub web_push: bool,
+    /// Languages error messages can be had in besides English
+    pub error_languages: Vec<String>,
+}
+
+pub fn capabilities(context: &Context) -> Capabilities {
+    let config = &context.config;
+    let mut error_languages: Vec<String> = config.error_translations.keys().cloned().collect();
+    error_languages.sort();
+    Capabilities {
+        subscriptions: false,
+        uploads: config.uploads.is_some(),
+        max_upload_bytes: config
+            .uploads
+            .as_ref()
+            .map(|uploads| uploads.max_size.min(i32::MAX as usize) as i32),
+        search: true,
+        federation: cfg!(feature = "server") && !config.site_url.is_empty(),
+        web_push: config.vapid_public_key.is_some(),
+        error_languages,
+    }
+}
+
+fn deprecated_field(&(field, reason, sunset): &(&str, &str, &str)) -> DeprecatedField {
+    DeprecatedField {
+        field: field.to_string(),
+        reason: reason.to_string(),
+        sunset: sunset.to_string(),
+    }
+}
+
+pub fn schema_version() -> SchemaVersion {
+    SchemaVersion {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_sha: env!("THROATQL_GIT_SHA").to_string(),
+        build_date: env!("THROATQL_BUILD_TIME")
+            .parse()
+            .ok()
+            .map(|secs| DateTime::from_utc(NaiveDateTime::from_timestamp(secs, 0), Utc)),
+        deprecations: DEPRECATIONS.iter().map(deprecated_field).collect(),
+    }
+}
+
+/// Notes that the request used a deprecated field, it's listed in the response's extensions
+pub fn used(context: &Context, field: &'static str) {
+    if let Ok(mut used) = context.deprecations.lock() {
+        if !used.contains(&field) {
+            used.push(field);
+        }
+    }
+}
+
+/// The `deprecations` extension for the fields the request used, if any
+pub fn extension(context: &Context) -> Option<serde_json::Value> {
+    let used = context.deprecations.lock().ok()?;
+    if used.is_empty() {
+        return None;
+    }
+    let fields: Vec<_> = DEPRECATIONS
+        .iter()
+        .filter(|(field, _, _)| used.contains(field))
+        .map(deprecated_field)
+        .map(|deprecated| {
+            json!({
+                "field": deprecated.field,
+                "reason": deprecated.reason,
+                "sunset": deprecated.sunset,
+            })
+        })
+        .collect();
+    Some(json!(fields))
+}
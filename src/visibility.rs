@@ -0,0 +1,463 @@
+use graphql_parser::query::{
+    Definition, Document, Field, OperationDefinition, Selection, SelectionSet, Value as Argument,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Fields only admins can use, by the type they're on. Everyone else would just get Not
+/// Authorized from them, so they're left out of what introspection shows non-admins.
+const ADMIN_FIELDS: &[(&str, &str)] = &[
+    ("Query", "getRuntimeStats"),
+    ("Query", "pendingBotAccounts"),
+    ("Query", "adminFindAltAccounts"),
+    ("Query", "voteAnomalies"),
+    ("Mutation", "updateSiteConfig"),
+    ("Mutation", "addDefaultSub"),
+    ("Mutation", "removeDefaultSub"),
+    ("Mutation", "setMaintenanceMode"),
+    ("Mutation", "approveBotAccount"),
+    ("Mutation", "denyBotAccount"),
+    ("Mutation", "renameSub"),
+    ("Post", "submitterIp"),
+];
+
+/// Types nothing but the fields above return
+const ADMIN_TYPES: &[&str] = &[
+    "RuntimeStats",
+    "LoaderStats",
+    "CacheStats",
+    "PoolStats",
+    "BotRequest",
+    "VoteAnomaly",
+    "AnomalyReason",
+];
+
+/// Whether a field is introspection, whose answer describes the schema
+fn is_introspection(field: &str) -> bool {
+    field == "__schema" || field == "__type"
+}
+
+/// How a document uses introspection, fragments included
+#[derive(Debug, Default, PartialEq)]
+pub struct Introspection {
+    /// Asks for __schema or __type somewhere. Also set for documents that don't parse, they
+    /// might still do.
+    pub present: bool,
+    /// Renames a field inside it. trim reads a type's name from its `name` key, so these can't be
+    /// trimmed.
+    pub aliased: bool,
+}
+
+/// Looks through every operation of the document, following fragment spreads
+pub fn inspect(query: &str) -> Introspection {
+    let document = match graphql_parser::parse_query::<&str>(query) {
+        Ok(document) => document,
+        Err(_) => {
+            return Introspection {
+                present: true,
+                aliased: false,
+            }
+        }
+    };
+    let fragments = fragments(&document);
+
+    let mut found = Introspection::default();
+    let mut visited = vec![];
+    for (_, selection_set) in operations(&document) {
+        walk(selection_set, false, &fragments, &mut visited, &mut found);
+    }
+    found
+}
+
+fn fragments<'a, 'd>(
+    document: &'d Document<'a, &'a str>,
+) -> HashMap<&'a str, &'d SelectionSet<'a, &'a str>> {
+    document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Fragment(fragment) => Some((fragment.name, &fragment.selection_set)),
+            Definition::Operation(_) => None,
+        })
+        .collect()
+}
+
+/// Every operation in the document with its name
+fn operations<'a, 'd>(
+    document: &'d Document<'a, &'a str>,
+) -> Vec<(Option<&'a str>, &'d SelectionSet<'a, &'a str>)> {
+    document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Operation(OperationDefinition::Query(query)) => {
+                Some((query.name, &query.selection_set))
+            }
+            Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+                Some((None, selection_set))
+            }
+            Definition::Operation(OperationDefinition::Mutation(mutation)) => {
+                Some((mutation.name, &mutation.selection_set))
+            }
+            Definition::Operation(OperationDefinition::Subscription(subscription)) => {
+                Some((subscription.name, &subscription.selection_set))
+            }
+            Definition::Fragment(_) => None,
+        })
+        .collect()
+}
+
+fn walk<'a, 'd>(
+    selection_set: &'d SelectionSet<'a, &'a str>,
+    inside: bool,
+    fragments: &HashMap<&'a str, &'d SelectionSet<'a, &'a str>>,
+    visited: &mut Vec<(&'a str, bool)>,
+    found: &mut Introspection,
+) {
+    for selection in &selection_set.items {
+        match selection {
+            Selection::Field(field) => {
+                let inside = inside || is_introspection(field.name);
+                found.present |= inside;
+                found.aliased |= inside && field.alias.is_some();
+                walk(&field.selection_set, inside, fragments, visited, found);
+            }
+            Selection::InlineFragment(fragment) => {
+                walk(&fragment.selection_set, inside, fragments, visited, found)
+            }
+            Selection::FragmentSpread(spread) => {
+                // Once per fragment and side, cycles are left for juniper to report
+                if !visited.contains(&(spread.fragment_name, inside)) {
+                    visited.push((spread.fragment_name, inside));
+                    if let Some(selection_set) = fragments.get(spread.fragment_name) {
+                        walk(selection_set, inside, fragments, visited, found);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Where in the introspection schema a part of the response is. Types carry their name when
+/// the position already tells it.
+enum Position {
+    Root,
+    Schema,
+    Type(Option<String>),
+    Field,
+    InputValue,
+    Directive,
+}
+
+impl Position {
+    fn child(&self, field: &Field<&str>, variables: Option<&Value>) -> Option<Position> {
+        match (self, field.name) {
+            (Position::Root, "__schema") => Some(Position::Schema),
+            (Position::Root, "__type") => Some(Position::Type(type_argument(field, variables))),
+            (Position::Schema, "types") => Some(Position::Type(None)),
+            (Position::Schema, "queryType") => Some(Position::Type(Some("Query".into()))),
+            (Position::Schema, "mutationType") => Some(Position::Type(Some("Mutation".into()))),
+            (Position::Schema, "subscriptionType") => Some(Position::Type(None)),
+            (Position::Schema, "directives") => Some(Position::Directive),
+            (Position::Type(_), "fields") => Some(Position::Field),
+            (Position::Type(_), "interfaces")
+            | (Position::Type(_), "possibleTypes")
+            | (Position::Type(_), "ofType") => Some(Position::Type(None)),
+            (Position::Type(_), "inputFields") => Some(Position::InputValue),
+            (Position::Field, "args") | (Position::Directive, "args") => Some(Position::InputValue),
+            (Position::Field, "type") | (Position::InputValue, "type") => {
+                Some(Position::Type(None))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The name `__type` was asked for with
+fn type_argument(field: &Field<&str>, variables: Option<&Value>) -> Option<String> {
+    match field.arguments.iter().find(|(name, _)| *name == "name")? {
+        (_, Argument::String(name)) => Some(name.clone()),
+        (_, Argument::Variable(variable)) => variables?
+            .get(*variable)
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        _ => None,
+    }
+}
+
+/// Takes the admin surface out of the introspection in one operation's result. Parts of the
+/// result are told apart by where the query put them, so a type's fields are filtered whatever
+/// else was asked of it. Types whose name can't be told only keep their kind.
+pub fn trim(
+    query: &str,
+    operation_name: Option<&str>,
+    variables: Option<&Value>,
+    result: &mut Value,
+) {
+    let data = match result.get_mut("data") {
+        Some(data) => data,
+        None => return,
+    };
+    let document = match graphql_parser::parse_query::<&str>(query) {
+        Ok(document) => document,
+        Err(_) => {
+            *data = Value::Null;
+            return;
+        }
+    };
+    let fragments = fragments(&document);
+    for (name, selection_set) in operations(&document) {
+        if operation_name.is_none() || name == operation_name {
+            trim_selection(
+                selection_set,
+                &Position::Root,
+                data,
+                &fragments,
+                variables,
+                &mut vec![],
+            );
+        }
+    }
+}
+
+fn trim_selection<'a, 'd>(
+    selection_set: &'d SelectionSet<'a, &'a str>,
+    position: &Position,
+    value: &mut Value,
+    fragments: &HashMap<&'a str, &'d SelectionSet<'a, &'a str>>,
+    variables: Option<&Value>,
+    spreads: &mut Vec<&'a str>,
+) {
+    match value {
+        Value::Array(values) => {
+            for value in values {
+                trim_selection(
+                    selection_set,
+                    position,
+                    value,
+                    fragments,
+                    variables,
+                    spreads,
+                );
+            }
+            return;
+        }
+        Value::Object(_) => {}
+        _ => return,
+    }
+    let type_name = match position {
+        Position::Type(known) => known.clone().or_else(|| {
+            value
+                .get("name")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        }),
+        _ => None,
+    };
+
+    for selection in &selection_set.items {
+        match selection {
+            Selection::Field(field) => {
+                let child = match position.child(field, variables) {
+                    Some(child) => child,
+                    None => continue,
+                };
+                let child_value = match value.get_mut(field.alias.unwrap_or(field.name)) {
+                    Some(child_value) => child_value,
+                    None => continue,
+                };
+                if let Position::Type(known) = &child {
+                    match &mut *child_value {
+                        Value::Array(types) => types.retain(|value| !is_hidden(value, known)),
+                        single => {
+                            if is_hidden(single, known) {
+                                *single = Value::Null;
+                            }
+                        }
+                    }
+                }
+                if let (Position::Field, Value::Array(fields)) = (&child, &mut *child_value) {
+                    let admin: Vec<&str> = ADMIN_FIELDS
+                        .iter()
+                        .filter(|&&(on, _)| Some(on) == type_name.as_deref())
+                        .map(|&(_, name)| name)
+                        .collect();
+                    // Without their names, fields of types with admin fields can't be told apart
+                    fields.retain(|field| match field.get("name").and_then(Value::as_str) {
+                        Some(name) => !admin.contains(&name),
+                        None => admin.is_empty(),
+                    });
+                }
+                trim_selection(
+                    &field.selection_set,
+                    &child,
+                    child_value,
+                    fragments,
+                    variables,
+                    spreads,
+                );
+            }
+            Selection::InlineFragment(fragment) => trim_selection(
+                &fragment.selection_set,
+                position,
+                value,
+                fragments,
+                variables,
+                spreads,
+            ),
+            Selection::FragmentSpread(spread) => {
+                if let (false, Some(selection_set)) = (
+                    spreads.contains(&spread.fragment_name),
+                    fragments.get(spread.fragment_name),
+                ) {
+                    spreads.push(spread.fragment_name);
+                    trim_selection(
+                        selection_set,
+                        position,
+                        value,
+                        fragments,
+                        variables,
+                        spreads,
+                    );
+                    spreads.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Admin types, and types without a name that show more than their kind. Wrapping types like
+/// NON_NULL have no name and only show their kind and what they wrap.
+fn is_hidden(value: &Value, known: &Option<String>) -> bool {
+    let object = match value.as_object() {
+        Some(object) => object,
+        None => return false,
+    };
+    match known
+        .as_deref()
+        .or_else(|| object.get("name").and_then(Value::as_str))
+    {
+        Some(name) => ADMIN_TYPES.contains(&name),
+        None => object.iter().any(|(key, value)| {
+            !value.is_null() && key != "kind" && key != "ofType" && key != "__typename"
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn admin_fields_and_types_are_removed() {
+        let mut response = json!({
+            "data": {
+                "__schema": {
+                    "types": [
+                        {
+                            "kind": "OBJECT",
+                            "name": "Query",
+                            "fields": [{ "name": "getPost" }, { "name": "getRuntimeStats" }],
+                        },
+                        { "kind": "OBJECT", "name": "RuntimeStats", "fields": [] },
+                        { "kind": "OBJECT", "name": null, "fields": [] },
+                    ],
+                },
+            },
+        });
+        trim(
+            "{ __schema { types { kind name fields { name } } } }",
+            None,
+            None,
+            &mut response,
+        );
+
+        let types = &response["data"]["__schema"]["types"];
+        assert_eq!(types.as_array().unwrap().len(), 1);
+        assert_eq!(types[0]["fields"], json!([{ "name": "getPost" }]));
+    }
+
+    #[test]
+    fn root_types_are_trimmed_without_their_names() {
+        let mut response = json!({
+            "data": {
+                "__schema": {
+                    "queryType": {
+                        "fields": [{ "name": "getPost" }, { "name": "getRuntimeStats" }],
+                    },
+                    "mutationType": {
+                        "fields": [{ "name": "createPost" }, { "name": "renameSub" }],
+                    },
+                },
+            },
+        });
+        trim(
+            "{ __schema { queryType { fields { name } } mutationType { fields { name } } } }",
+            None,
+            None,
+            &mut response,
+        );
+
+        let schema = &response["data"]["__schema"];
+        assert_eq!(
+            schema["queryType"]["fields"],
+            json!([{ "name": "getPost" }])
+        );
+        assert_eq!(
+            schema["mutationType"]["fields"],
+            json!([{ "name": "createPost" }])
+        );
+    }
+
+    #[test]
+    fn admin_types_asked_for_by_name_are_hidden() {
+        let mut response = json!({
+            "data": { "__type": { "fields": [{ "name": "loaders" }] } },
+        });
+        trim(
+            "{ __type(name: \"RuntimeStats\") { fields { name } } }",
+            None,
+            None,
+            &mut response,
+        );
+        assert!(response["data"]["__type"].is_null());
+
+        let mut response = json!({
+            "data": { "__type": { "fields": [{ "name": "loaders" }] } },
+        });
+        trim(
+            "query Stats($name: String!) { __type(name: $name) { fields { name } } }",
+            Some("Stats"),
+            Some(&json!({ "name": "RuntimeStats" })),
+            &mut response,
+        );
+        assert!(response["data"]["__type"].is_null());
+    }
+
+    #[test]
+    fn introspection_is_found_in_fragments() {
+        let found = inspect("{ ... on Query { __schema { types { name } } } }");
+        assert!(found.present);
+        let found = inspect(
+            "query { ...Schema } fragment Schema on Query { __type(name: \"Query\") { name } }",
+        );
+        assert!(found.present);
+        assert_eq!(
+            inspect("{ getSub(name: \"test\") { name } }"),
+            Introspection::default()
+        );
+    }
+
+    #[test]
+    fn aliases_inside_introspection_are_noticed() {
+        let found = inspect("{ __schema { types { n: name k: kind f: fields { n: name } } } }");
+        assert!(found.aliased);
+        let found =
+            inspect("{ __schema { types { ...Type } } } fragment Type on __Type { n: name }");
+        assert!(found.aliased);
+        // Aliases elsewhere don't matter
+        let found = inspect("{ sub: getSub(name: \"test\") { name } __schema { types { name } } }");
+        assert!(found.present && !found.aliased);
+    }
+}
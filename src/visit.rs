@@ -0,0 +1,94 @@
+use crate::Context;
+use chrono::NaiveDateTime;
+use juniper::{FieldError, ID};
+
+/// Only this many visited posts are remembered per user, the oldest are forgotten first
+const VISITS_KEPT: i64 = 5000;
+
+/// Call once the post's comments were shown, comments from before now stop counting as new
+pub async fn mark_visited(context: &Context, id: ID) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let pid = context.config.post_ids.decode(&id)?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO post_visit (uid, pid)
+        SELECT $1, pid FROM sub_post WHERE pid = $2
+        ON CONFLICT (uid, pid) DO UPDATE SET visited = now()
+        "#,
+        uid,
+        pid
+    )
+    .execute(&context.pool)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM post_visit
+        WHERE uid = $1 AND pid NOT IN (
+            SELECT pid FROM post_visit WHERE uid = $1 ORDER BY visited DESC LIMIT $2
+        )
+        "#,
+        uid,
+        VISITS_KEPT
+    )
+    .execute(&context.pool)
+    .await?;
+
+    Ok(true)
+}
+
+/// Comments by others since the viewer's last visit, None when they never visited or aren't
+/// logged in
+pub async fn new_comment_count(context: &Context, pid: i32) -> Result<Option<i32>, FieldError> {
+    let uid = match context.user.user_id() {
+        Ok(uid) => uid,
+        Err(_) => return Ok(None),
+    };
+    let visited = match context.last_visit(pid).await? {
+        Some(visited) => visited,
+        None => return Ok(None),
+    };
+
+    Ok(Some(
+        sqlx::query!(
+            r#"
+            SELECT count(*) as "cnt!"
+            FROM sub_post_comment
+            WHERE pid = $1 AND time > $2 AND uid IS DISTINCT FROM $3
+                AND coalesce(status, 0) = 0
+            "#,
+            pid,
+            visited,
+            uid
+        )
+        .fetch_one(&context.pool)
+        .await?
+        .cnt as i32,
+    ))
+}
+
+/// Whether a comment came in after the viewer last visited its post. Their own comments never
+/// count as new, neither does anything on posts they haven't visited before.
+pub async fn is_new(
+    context: &Context,
+    pid: Option<i32>,
+    author: Option<&str>,
+    time: Option<NaiveDateTime>,
+) -> Result<bool, FieldError> {
+    let viewer = match context.user.user_id() {
+        Ok(uid) => uid,
+        Err(_) => return Ok(false),
+    };
+    if author == Some(viewer) {
+        return Ok(false);
+    }
+    let (pid, time) = match (pid, time) {
+        (Some(pid), Some(time)) => (pid, time),
+        _ => return Ok(false),
+    };
+    Ok(context
+        .last_visit(pid)
+        .await?
+        .map_or(false, |visited| time > visited))
+}
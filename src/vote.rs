@@ -0,0 +1,486 @@
+use crate::{comment::Comment, moderation, post::Post, site, Context};
+use chrono::{Duration, Utc};
+use juniper::{graphql_interface, FieldError, GraphQLEnum, GraphQLObject, ID};
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum VoteDirection {
+    Up,
+    Down,
+}
+
+impl VoteDirection {
+    fn from_db(positive: i32) -> Option<Self> {
+        match positive {
+            p if p > 0 => Some(VoteDirection::Up),
+            p if p < 0 => Some(VoteDirection::Down),
+            _ => None,
+        }
+    }
+
+    fn to_db(self) -> i32 {
+        match self {
+            VoteDirection::Up => 1,
+            VoteDirection::Down => -1,
+        }
+    }
+}
+
+/// The counts after votePost or voteComment, enough to update a vote widget without refetching
+#[derive(GraphQLObject, Debug)]
+pub struct VoteResult {
+    pub id: ID,
+    pub score: i32,
+    pub up_votes: i32,
+    pub down_votes: i32,
+    pub viewer_vote: Option<VoteDirection>,
+    pub change: VoteChange,
+}
+
+/// What a vote mutation did to the viewer's vote
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum VoteChange {
+    Created,
+    /// Went from up to down or the other way around
+    Changed,
+    Removed,
+    /// Voted the same way again, or took back a vote there wasn't
+    Unchanged,
+}
+
+impl VoteChange {
+    fn between(previous: Option<i32>, new: Option<i32>) -> Self {
+        match (previous, new) {
+            (None, Some(_)) => VoteChange::Created,
+            (Some(_), None) => VoteChange::Removed,
+            (Some(previous), Some(new)) if previous.signum() != new.signum() => VoteChange::Changed,
+            _ => VoteChange::Unchanged,
+        }
+    }
+}
+
+/// What the voter's post votes count for in rankings, less while their account is new
+async fn vote_weight(context: &Context, uid: &str) -> Result<f64, FieldError> {
+    let config = site::get_site_config(context).await?;
+    if config.new_account_vote_days <= 0 {
+        return Ok(1.0);
+    }
+    let joined = sqlx::query!(
+        r#"
+        SELECT joindate
+        FROM public.user
+        WHERE uid = $1
+        "#,
+        uid
+    )
+    .fetch_one(&context.pool)
+    .await?
+    .joindate;
+    // site_metadata can also be written by hand, so the cap in updateSiteConfig isn't enough
+    let new_since = Utc::now()
+        .naive_utc()
+        .checked_sub_signed(Duration::days(config.new_account_vote_days as i64))
+        .ok_or_else(|| {
+            format!(
+                "Invalid new_account_vote_days {}",
+                config.new_account_vote_days
+            )
+        })?;
+    Ok(match joined {
+        Some(joined) if joined > new_since => config.new_account_vote_weight,
+        _ => 1.0,
+    })
+}
+
+/// How a vote moves the score and the up and down counts when it goes from `previous` to `new`
+fn deltas(previous: Option<i32>, new: Option<i32>) -> (i32, i32, i32) {
+    let up = |vote: Option<i32>| (vote.unwrap_or(0) > 0) as i32;
+    let down = |vote: Option<i32>| (vote.unwrap_or(0) < 0) as i32;
+    (
+        new.unwrap_or(0).signum() - previous.unwrap_or(0).signum(),
+        up(new) - up(previous),
+        down(new) - down(previous),
+    )
+}
+
+/// Anything with a vote widget, so clients can share one fragment between posts and comments
+#[graphql_interface(for = [Post, Comment], context = Context)]
+pub trait Votable {
+    fn id(&self, context: &Context) -> ID;
+    fn score(&self) -> i32;
+    fn up_votes(&self) -> i32;
+    fn down_votes(&self) -> i32;
+    /// How the current user voted, null when they haven't or aren't logged in
+    async fn viewer_vote(&self, context: &Context) -> Result<Option<VoteDirection>, FieldError>;
+}
+
+pub async fn post_vote(context: &Context, pid: i32) -> Result<Option<VoteDirection>, FieldError> {
+    let uid = match context.user.user_id() {
+        Ok(uid) => uid,
+        Err(_) => return Ok(None),
+    };
+
+    Ok(sqlx::query!(
+        r#"
+        SELECT positive
+        FROM sub_post_vote
+        WHERE pid = $1 AND uid = $2
+        "#,
+        pid,
+        uid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .map(|vote| vote.positive)
+    .and_then(VoteDirection::from_db))
+}
+
+pub async fn comment_vote(
+    context: &Context,
+    cid: &str,
+) -> Result<Option<VoteDirection>, FieldError> {
+    let uid = match context.user.user_id() {
+        Ok(uid) => uid,
+        Err(_) => return Ok(None),
+    };
+
+    Ok(sqlx::query!(
+        r#"
+        SELECT positive
+        FROM sub_post_comment_vote
+        WHERE cid = $1 AND uid = $2
+        "#,
+        cid,
+        uid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .map(|vote| vote.positive)
+    .and_then(VoteDirection::from_db))
+}
+
+/// Votes on someone else's post, voting again replaces the earlier vote and a null direction
+/// takes it back. The author's score and the voter's given votes change along with the post's.
+pub async fn vote_post(
+    context: &Context,
+    id: ID,
+    direction: Option<VoteDirection>,
+) -> Result<VoteResult, FieldError> {
+    let uid = context.user.user_id()?;
+    let pid = context.config.post_ids.decode(&id)?;
+    let post = sqlx::query!(
+        r#"
+        SELECT uid, sid, deleted
+        FROM sub_post
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Post not found {}", *id))?;
+    if post.deleted.unwrap_or(0) != 0 {
+        return Err("Deleted posts can't be voted on".into());
+    }
+    if post.uid.as_deref() == Some(uid) {
+        return Err("You can't vote on your own post".into());
+    }
+    moderation::check_not_banned(context, &post.sid.unwrap_or_default()).await?;
+    let weight = vote_weight(context, uid).await?;
+
+    let mut tx = context.pool.begin().await?;
+    let new = direction.map(VoteDirection::to_db);
+    let previous = record_post_vote(&mut tx, pid, uid, new, weight).await?;
+    let (score, up, down) = deltas(previous, new);
+    if score != 0 || up != 0 || down != 0 {
+        sqlx::query!(
+            r#"
+            UPDATE sub_post
+            SET score = score + $2, upvotes = upvotes + $3, downvotes = downvotes + $4
+            WHERE pid = $1
+            "#,
+            pid,
+            score,
+            up,
+            down
+        )
+        .execute(&mut tx)
+        .await?;
+        update_user_scores(&mut tx, post.uid.as_deref(), uid, score).await?;
+    }
+    tx.commit().await?;
+    context.post_loader.clear(pid).await;
+
+    let counts = sqlx::query!(
+        r#"
+        SELECT coalesce(SUM(CASE WHEN positive > 0 THEN 1 ELSE 0 END), 0) as "up!",
+            coalesce(SUM(CASE WHEN positive < 0 THEN 1 ELSE 0 END), 0) as "down!"
+        FROM sub_post_vote
+        WHERE pid = $1
+        "#,
+        pid
+    )
+    .fetch_one(&context.pool)
+    .await?;
+    Ok(VoteResult {
+        id,
+        score: (counts.up - counts.down) as i32,
+        up_votes: counts.up as i32,
+        down_votes: counts.down as i32,
+        viewer_vote: direction,
+        change: VoteChange::between(previous, new),
+    })
+}
+
+/// Same rules as vote_post
+pub async fn vote_comment(
+    context: &Context,
+    id: ID,
+    direction: Option<VoteDirection>,
+) -> Result<VoteResult, FieldError> {
+    let uid = context.user.user_id()?;
+    let comment = sqlx::query!(
+        r#"
+        SELECT c.uid, c.status, p.sid
+        FROM sub_post_comment c
+        JOIN sub_post p ON p.pid = c.pid
+        WHERE c.cid = $1
+        "#,
+        id.as_str()
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .ok_or_else(|| format!("Comment not found {}", *id))?;
+    if comment.status.unwrap_or(0) != 0 {
+        return Err("Deleted comments can't be voted on".into());
+    }
+    if comment.uid.as_deref() == Some(uid) {
+        return Err("You can't vote on your own comment".into());
+    }
+    moderation::check_not_banned(context, &comment.sid.unwrap_or_default()).await?;
+
+    let mut tx = context.pool.begin().await?;
+    let new = direction.map(VoteDirection::to_db);
+    let previous = record_comment_vote(&mut tx, id.as_str(), uid, new).await?;
+    let (score, up, down) = deltas(previous, new);
+    let counts = sqlx::query!(
+        r#"
+        UPDATE sub_post_comment
+        SET score = coalesce(score, 0) + $2, upvotes = upvotes + $3, downvotes = downvotes + $4
+        WHERE cid = $1
+        RETURNING score as "score!", upvotes, downvotes
+        "#,
+        id.as_str(),
+        score,
+        up,
+        down
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    if score != 0 {
+        update_user_scores(&mut tx, comment.uid.as_deref(), uid, score).await?;
+    }
+    tx.commit().await?;
+    context.comment_loader.clear(id.to_string()).await;
+
+    Ok(VoteResult {
+        id,
+        score: counts.score,
+        up_votes: counts.upvotes,
+        down_votes: counts.downvotes,
+        viewer_vote: direction,
+        change: VoteChange::between(previous, new),
+    })
+}
+
+/// Sets the voter's vote on the post and returns what it was. The insert either creates the
+/// vote or, when (uid, pid) is taken, leaves it to the locked read and update after it, so
+/// concurrent votes by the same user wait for each other instead of adding a second row.
+async fn record_post_vote(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    pid: i32,
+    uid: &str,
+    new: Option<i32>,
+    weight: f64,
+) -> Result<Option<i32>, FieldError> {
+    if let Some(positive) = new {
+        let created = sqlx::query!(
+            r#"
+            INSERT INTO sub_post_vote (pid, uid, positive, datetime, weight)
+            VALUES ($1, $2, $3, now(), $4)
+            ON CONFLICT (uid, pid) DO NOTHING
+            RETURNING pid
+            "#,
+            pid,
+            uid,
+            positive,
+            weight
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if created.is_some() {
+            return Ok(None);
+        }
+    }
+    let previous = sqlx::query!(
+        r#"
+        SELECT positive
+        FROM sub_post_vote
+        WHERE pid = $1 AND uid = $2
+        FOR UPDATE
+        "#,
+        pid,
+        uid
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(|vote| vote.positive);
+    match new {
+        Some(positive) => {
+            sqlx::query!(
+                r#"
+                UPDATE sub_post_vote
+                SET positive = $3, datetime = now(), weight = $4
+                WHERE pid = $1 AND uid = $2 AND positive <> $3
+                "#,
+                pid,
+                uid,
+                positive,
+                weight
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        None => {
+            sqlx::query!(
+                r#"
+                DELETE FROM sub_post_vote
+                WHERE pid = $1 AND uid = $2
+                "#,
+                pid,
+                uid
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    Ok(previous)
+}
+
+/// Same as record_post_vote, comment votes have no weight
+async fn record_comment_vote(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    cid: &str,
+    uid: &str,
+    new: Option<i32>,
+) -> Result<Option<i32>, FieldError> {
+    if let Some(positive) = new {
+        let created = sqlx::query!(
+            r#"
+            INSERT INTO sub_post_comment_vote (cid, uid, positive, datetime)
+            VALUES ($1, $2, $3, now())
+            ON CONFLICT (uid, cid) DO NOTHING
+            RETURNING cid
+            "#,
+            cid,
+            uid,
+            positive
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if created.is_some() {
+            return Ok(None);
+        }
+    }
+    let previous = sqlx::query!(
+        r#"
+        SELECT positive
+        FROM sub_post_comment_vote
+        WHERE cid = $1 AND uid = $2
+        FOR UPDATE
+        "#,
+        cid,
+        uid
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(|vote| vote.positive);
+    match new {
+        Some(positive) => {
+            sqlx::query!(
+                r#"
+                UPDATE sub_post_comment_vote
+                SET positive = $3, datetime = now()
+                WHERE cid = $1 AND uid = $2 AND positive <> $3
+                "#,
+                cid,
+                uid,
+                positive
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        None => {
+            sqlx::query!(
+                r#"
+                DELETE FROM sub_post_comment_vote
+                WHERE cid = $1 AND uid = $2
+                "#,
+                cid,
+                uid
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    Ok(previous)
+}
+
+/// Authors' score is the sum of the votes on their content, `given` the sum of the votes they
+/// cast
+async fn update_user_scores(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    author: Option<&str>,
+    voter: &str,
+    delta: i32,
+) -> Result<(), FieldError> {
+    if let Some(author) = author {
+        sqlx::query!(
+            r#"
+            UPDATE public.user
+            SET score = score + $2
+            WHERE uid = $1
+            "#,
+            author,
+            delta
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query!(
+        r#"
+        UPDATE public.user
+        SET given = given + $2
+        WHERE uid = $1
+        "#,
+        voter,
+        delta
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changing_a_vote() {
+        assert_eq!(deltas(None, Some(1)), (1, 1, 0));
+        assert_eq!(deltas(Some(1), Some(-1)), (-2, -1, 1));
+        assert_eq!(deltas(Some(-1), None), (1, 0, -1));
+        assert_eq!(deltas(Some(1), Some(1)), (0, 0, 0));
+        assert_eq!(VoteChange::between(Some(1), Some(1)), VoteChange::Unchanged);
+        assert_eq!(VoteChange::between(Some(-1), Some(1)), VoteChange::Changed);
+    }
+}
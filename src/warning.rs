@@ -0,0 +1,165 @@
+use crate::{auth::UserState, events::Event, user::UserRef, Context};
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLObject, ID};
+
+const MAX_REASON_LENGTH: usize = 1000;
+/// Longest window warnings are counted over, ten years. Far longer ones would overflow the date.
+const MAX_WINDOW_DAYS: i64 = 3650;
+
+#[derive(GraphQLObject, Debug)]
+pub struct Warning {
+    pub id: ID,
+    pub sub_name: String,
+    /// Null once the mod's account is gone
+    pub issued_by: Option<String>,
+    pub reason: String,
+    pub time: NaiveDateTime,
+}
+
+/// Warnings of `uid` the viewer may see: all of them for admins and the user themselves, only
+/// those from their own subs for mods
+pub async fn get_warnings(context: &Context, uid: &str) -> Result<Vec<Warning>, FieldError> {
+    let viewer = context.user.user_id()?;
+    let everything = viewer == uid || context.user.is_admin();
+    let modded = context.user.modded_subs();
+
+    Ok(sqlx::query!(
+        r#"
+        SELECT w.id, s.name as "sub_name!", m.name as issued_by, w.reason, w.time
+        FROM user_warning w
+        JOIN sub s ON s.sid = w.sid
+        LEFT JOIN public.user m ON m.uid = w.issued_by
+        WHERE w.uid = $1 AND ($2 OR w.sid = ANY($3))
+        ORDER BY w.time DESC
+        "#,
+        uid,
+        everything,
+        &modded
+    )
+    .fetch(&context.pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| Warning {
+        id: row.id.to_string().into(),
+        sub_name: row.sub_name,
+        issued_by: row.issued_by,
+        reason: row.reason,
+        time: row.time,
+    })
+    .collect())
+}
+
+/// Warnings `uid` got in the sub over the last `days` days, the escalation counter automod rules
+/// and mods go by. `days` is capped at MAX_WINDOW_DAYS.
+pub async fn count_recent(
+    pool: &sqlx::PgPool,
+    uid: &str,
+    sid: &str,
+    days: i64,
+) -> Result<i32, FieldError> {
+    let since = Utc::now().naive_utc() - Duration::days(days.max(1).min(MAX_WINDOW_DAYS));
+    Ok(sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM user_warning
+        WHERE uid = $1 AND sid = $2 AND time > $3
+        "#,
+        uid,
+        sid,
+        since
+    )
+    .fetch_one(pool)
+    .await?
+    .cnt as i32)
+}
+
+/// Same visibility rules as get_warnings, for a single sub
+pub async fn get_warning_count(
+    context: &Context,
+    uid: &str,
+    sub: String,
+    days: Option<i32>,
+) -> Result<i32, FieldError> {
+    let viewer = context.user.user_id()?;
+    let sub = context
+        .sub_loader
+        .load(sub.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    if viewer != uid && !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+    count_recent(&context.pool, uid, &sub.sid, days.unwrap_or(30) as i64).await
+}
+
+pub async fn warn_user(
+    context: &Context,
+    sub: String,
+    user: String,
+    reason: String,
+) -> Result<Warning, FieldError> {
+    let viewer = context.user.user_id()?;
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.len() > MAX_REASON_LENGTH {
+        return Err(format!("The reason has to be 1 to {} bytes", MAX_REASON_LENGTH).into());
+    }
+    let sub = context
+        .sub_loader
+        .load(sub.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+    let target = context
+        .user_loader
+        .load(UserRef::name(user))
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })?;
+    if target.uid == viewer {
+        return Err("You can't warn yourself".into());
+    }
+
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO user_warning (uid, sid, issued_by, reason)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, time
+        "#,
+        target.uid,
+        sub.sid,
+        viewer,
+        reason
+    )
+    .fetch_one(&context.pool)
+    .await?;
+
+    log::info!(
+        "{} warned {} in {}",
+        viewer,
+        target.uid,
+        sub.name.clone().unwrap_or_default()
+    );
+    context
+        .publish(Event::UserWarned {
+            id: row.id,
+            uid: target.uid,
+            sid: sub.sid,
+        })
+        .await;
+
+    Ok(Warning {
+        id: row.id.to_string().into(),
+        sub_name: sub.name.unwrap_or_default(),
+        issued_by: match context.user {
+            UserState::LoggedIn { ref name, .. } => Some(name.clone()),
+            UserState::Anonymous => None,
+        },
+        reason,
+        time: row.time,
+    })
+}
@@ -0,0 +1,416 @@
+use crate::{moderation, post::DeleteStatus, sub::Sub, Context};
+use chrono::NaiveDateTime;
+use futures_util::stream::StreamExt;
+use juniper::{FieldError, GraphQLEnum, GraphQLObject, ID};
+use regex::{Regex, RegexBuilder};
+use sqlx::{Postgres, Transaction};
+
+/// Most filters one sub can have, every one of them runs on every edit
+const MAX_FILTERS: i64 = 200;
+const MAX_PATTERN_LENGTH: usize = 500;
+/// Compiled size limit for regexes, keeps a mod from making every edit slow
+const MAX_REGEX_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, GraphQLEnum, PartialEq)]
+pub enum FilterAction {
+    /// The content is refused
+    Remove,
+    /// The content goes through but stays removed until a mod approves it
+    Hold,
+}
+
+impl FilterAction {
+    fn to_db(self) -> &'static str {
+        match self {
+            FilterAction::Remove => "remove",
+            FilterAction::Hold => "hold",
+        }
+    }
+
+    fn from_db(action: &str) -> Self {
+        match action {
+            "hold" => FilterAction::Hold,
+            _ => FilterAction::Remove,
+        }
+    }
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct WordFilter {
+    pub id: ID,
+    /// A word or phrase, matched whole and ignoring case, unless isRegex
+    pub pattern: String,
+    pub is_regex: bool,
+    pub action: FilterAction,
+    pub created: NaiveDateTime,
+}
+
+#[derive(GraphQLObject, Debug)]
+pub struct WordFilterMatch {
+    pub filter: WordFilter,
+    /// The part of the text that matched
+    pub matched: String,
+}
+
+/// Words match whole and case-insensitively, regexes only case-insensitively
+fn compile(pattern: &str, is_regex: bool) -> Result<Regex, FieldError> {
+    if pattern.trim().is_empty() || pattern.len() > MAX_PATTERN_LENGTH {
+        return Err(format!(
+            "Filters need a pattern of at most {} characters",
+            MAX_PATTERN_LENGTH
+        )
+        .into());
+    }
+    let source = if is_regex {
+        pattern.to_string()
+    } else {
+        format!(r"\b{}\b", regex::escape(pattern.trim()))
+    };
+    RegexBuilder::new(&source)
+        .case_insensitive(true)
+        .size_limit(MAX_REGEX_SIZE)
+        .build()
+        .map_err(|err| format!("Invalid pattern: {}", err).into())
+}
+
+async fn load_sub(context: &Context, name: String) -> Result<Sub, FieldError> {
+    context
+        .sub_loader
+        .load(name.into())
+        .await
+        .map_err(|err| -> FieldError { format!("{:?}", err).into() })
+}
+
+async fn load_filters(pool: &sqlx::PgPool, sid: &str) -> Result<Vec<WordFilter>, FieldError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT id, pattern, is_regex, action, created
+        FROM sub_word_filter
+        WHERE sid = $1
+        ORDER BY id
+        "#,
+        sid
+    )
+    .fetch(pool)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?
+    .into_iter()
+    .map(|row| WordFilter {
+        id: row.id.to_string().into(),
+        pattern: row.pattern,
+        is_regex: row.is_regex,
+        action: FilterAction::from_db(&row.action),
+        created: row.created,
+    })
+    .collect())
+}
+
+/// Filters whose pattern no longer compiles are skipped, they were checked when saved
+fn matches(filters: Vec<WordFilter>, text: &str) -> Vec<WordFilterMatch> {
+    filters
+        .into_iter()
+        .filter_map(|filter| {
+            let regex = compile(&filter.pattern, filter.is_regex).ok()?;
+            let matched = regex.find(text)?.as_str().to_string();
+            Some(WordFilterMatch { filter, matched })
+        })
+        .collect()
+}
+
+/// Runs before content is saved. A matching remove filter refuses it, otherwise the pattern of a
+/// matching hold filter is returned and the caller should hold the content with hold_post or
+/// hold_comment once saved.
+pub(crate) async fn screen(
+    context: &Context,
+    sid: &str,
+    text: &str,
+) -> Result<Option<String>, FieldError> {
+    let found = matches(load_filters(&context.pool, sid).await?, text);
+    if found
+        .iter()
+        .any(|found| found.filter.action == FilterAction::Remove)
+    {
+        return Err("This contains words the sub doesn't allow".into());
+    }
+    Ok(found.into_iter().next().map(|found| found.filter.pattern))
+}
+
+/// Removes a post on behalf of a hold filter, bulkApprove puts it back
+pub(crate) async fn hold_post(
+    context: &Context,
+    pid: i32,
+    sid: &str,
+    pattern: String,
+) -> Result<(), FieldError> {
+    let uid = context.user.user_id()?;
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        r#"
+        UPDATE sub_post
+        SET deleted = $2
+        WHERE pid = $1
+        "#,
+        pid,
+        DeleteStatus::Mod.to_db()
+    )
+    .execute(&mut tx)
+    .await?;
+    log_post_hold(&mut tx, uid, pid, sid, pattern).await?;
+    tx.commit().await?;
+    context.post_loader.clear(pid).await;
+    Ok(())
+}
+
+/// The mod log side of hold_post, for posts that are saved removed in the first place
+pub(crate) async fn log_post_hold(
+    tx: &mut Transaction<'_, Postgres>,
+    uid: &str,
+    pid: i32,
+    sid: &str,
+    pattern: String,
+) -> Result<(), FieldError> {
+    // Logged under the author, there is no mod to name
+    moderation::log_action(
+        tx,
+        uid,
+        Some(sid.to_string()),
+        "filter_hold_post",
+        vec![pid.to_string()],
+        Some(format!("Matched word filter {}", pattern)),
+    )
+    .await
+}
+
+pub(crate) async fn hold_comment(
+    context: &Context,
+    cid: &str,
+    sid: &str,
+    pattern: String,
+) -> Result<(), FieldError> {
+    let uid = context.user.user_id()?;
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        r#"
+        UPDATE sub_post_comment
+        SET status = $2
+        WHERE cid = $1
+        "#,
+        cid,
+        DeleteStatus::Mod.to_db()
+    )
+    .execute(&mut tx)
+    .await?;
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sid.to_string()),
+        "filter_hold_comment",
+        vec![cid.to_string()],
+        Some(format!("Matched word filter {}", pattern)),
+    )
+    .await?;
+    tx.commit().await?;
+    context.comment_loader.clear(cid.to_string()).await;
+    Ok(())
+}
+
+pub async fn sub_filters(context: &Context, sid: &str) -> Result<Vec<WordFilter>, FieldError> {
+    if !context.user.is_mod(sid) {
+        return Err("Not Authorized".into());
+    }
+    load_filters(&context.pool, sid).await
+}
+
+pub async fn add_filter(
+    context: &Context,
+    sub: String,
+    pattern: String,
+    is_regex: bool,
+    action: FilterAction,
+) -> Result<WordFilter, FieldError> {
+    let uid = context.user.user_id()?;
+    let sub = load_sub(context, sub).await?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+    compile(&pattern, is_regex)?;
+
+    let mut tx = context.pool.begin().await?;
+    let count = sqlx::query!(
+        r#"
+        SELECT count(*) as "cnt!"
+        FROM sub_word_filter
+        WHERE sid = $1
+        "#,
+        sub.sid
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .cnt;
+    if count >= MAX_FILTERS {
+        return Err(format!("Subs can have at most {} word filters", MAX_FILTERS).into());
+    }
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO sub_word_filter (sid, pattern, is_regex, action, created_by)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, created
+        "#,
+        sub.sid,
+        pattern,
+        is_regex,
+        action.to_db(),
+        uid
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sub.sid.clone()),
+        "add_word_filter",
+        vec![row.id.to_string()],
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(WordFilter {
+        id: row.id.to_string().into(),
+        pattern,
+        is_regex,
+        action,
+        created: row.created,
+    })
+}
+
+fn filter_id(id: &ID) -> Result<i32, FieldError> {
+    id.parse()
+        .map_err(|_| format!("Word filter not found {}", *id).into())
+}
+
+async fn filter_sid(context: &Context, id: i32) -> Result<String, FieldError> {
+    let sid = sqlx::query!(
+        r#"
+        SELECT sid
+        FROM sub_word_filter
+        WHERE id = $1
+        "#,
+        id
+    )
+    .fetch_optional(&context.pool)
+    .await?
+    .map(|row| row.sid)
+    .ok_or_else(|| format!("Word filter not found {}", id))?;
+    if !context.user.is_mod(&sid) {
+        return Err("Not Authorized".into());
+    }
+    Ok(sid)
+}
+
+pub async fn update_filter(
+    context: &Context,
+    id: ID,
+    pattern: String,
+    is_regex: bool,
+    action: FilterAction,
+) -> Result<WordFilter, FieldError> {
+    let uid = context.user.user_id()?;
+    let id = filter_id(&id)?;
+    let sid = filter_sid(context, id).await?;
+    compile(&pattern, is_regex)?;
+
+    let mut tx = context.pool.begin().await?;
+    let row = sqlx::query!(
+        r#"
+        UPDATE sub_word_filter
+        SET pattern = $2, is_regex = $3, action = $4
+        WHERE id = $1
+        RETURNING created
+        "#,
+        id,
+        pattern,
+        is_regex,
+        action.to_db()
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sid),
+        "update_word_filter",
+        vec![id.to_string()],
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(WordFilter {
+        id: id.to_string().into(),
+        pattern,
+        is_regex,
+        action,
+        created: row.created,
+    })
+}
+
+pub async fn delete_filter(context: &Context, id: ID) -> Result<bool, FieldError> {
+    let uid = context.user.user_id()?;
+    let id = filter_id(&id)?;
+    let sid = filter_sid(context, id).await?;
+
+    let mut tx = context.pool.begin().await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM sub_word_filter
+        WHERE id = $1
+        "#,
+        id
+    )
+    .execute(&mut tx)
+    .await?;
+    moderation::log_action(
+        &mut tx,
+        uid,
+        Some(sid),
+        "delete_word_filter",
+        vec![id.to_string()],
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Which of the sub's filters would catch `text`, for mods trying out their filters. With a
+/// `pattern` only that unsaved filter is tried instead.
+pub async fn test_filters(
+    context: &Context,
+    sub: String,
+    text: String,
+    pattern: Option<String>,
+    is_regex: Option<bool>,
+) -> Result<Vec<WordFilterMatch>, FieldError> {
+    let sub = load_sub(context, sub).await?;
+    if !context.user.is_mod(&sub.sid) {
+        return Err("Not Authorized".into());
+    }
+    let filters = match pattern {
+        Some(pattern) => {
+            let is_regex = is_regex.unwrap_or(false);
+            compile(&pattern, is_regex)?;
+            vec![WordFilter {
+                id: ID::new(""),
+                pattern,
+                is_regex,
+                action: FilterAction::Remove,
+                created: chrono::Utc::now().naive_utc(),
+            }]
+        }
+        None => load_filters(&context.pool, &sub.sid).await?,
+    };
+    Ok(matches(filters, &text))
+}
@@ -0,0 +1,146 @@
+//! Runs GraphQL documents against the schema on a throwaway database.
+//!
+//! The base Throat schema isn't part of this repository, so TEST_DATABASE_URL has to point at a
+//! database that has it (an empty copy of production is fine). Every test clones it into a
+//! database of its own with CREATE DATABASE ... TEMPLATE, applies migrations/ and
+//! tests/fixtures/ on top and drops it again when done. Tests are skipped when it isn't set.
+
+use model::{
+    auth::{Role, UserState},
+    config::Config,
+    events::LocalBus,
+    mailer::LogMailer,
+    Context, Mutation, Query, RequestInfo, Schema,
+};
+use sqlx::{Executor, PgPool};
+use std::{env, fs, path::Path, sync::Arc};
+
+pub struct TestDb {
+    pub pool: PgPool,
+    admin: PgPool,
+    name: String,
+    schema: Schema,
+}
+
+/// Server part and database name of a postgres:// url
+fn split_database(url: &str) -> (&str, &str) {
+    let at = url.rfind('/').unwrap();
+    (&url[..at], &url[at + 1..])
+}
+
+fn apply_dir(dir: &Path) -> Vec<String> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "sql"))
+        .collect();
+    files.sort();
+    files
+        .into_iter()
+        .map(|path| fs::read_to_string(path).unwrap())
+        .collect()
+}
+
+impl TestDb {
+    pub async fn new() -> Option<Self> {
+        dotenv::dotenv().ok();
+        let url = env::var("TEST_DATABASE_URL").ok()?;
+        let (server, template) = split_database(&url);
+
+        let name = format!("throatql_test_{}", uuid::Uuid::new_v4().to_simple());
+        let admin = sqlx::Pool::connect(&url).await.unwrap();
+        admin
+            .execute(format!("CREATE DATABASE {} TEMPLATE {}", name, template).as_str())
+            .await
+            .unwrap();
+
+        let pool: PgPool = sqlx::Pool::connect(&format!("{}/{}", server, name))
+            .await
+            .unwrap();
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        for sql in apply_dir(&root.join("migrations"))
+            .into_iter()
+            .chain(apply_dir(&root.join("tests/fixtures")))
+        {
+            pool.execute(sql.as_str()).await.unwrap();
+        }
+
+        Some(TestDb {
+            pool,
+            admin,
+            name,
+            schema: Schema::new(
+                Query,
+                Mutation,
+                juniper::EmptySubscription::<Context>::new(),
+            ),
+        })
+    }
+
+    pub fn context(&self, user: UserState) -> Context {
+        Context::new(
+            user,
+            RequestInfo::default(),
+            self.pool.clone(),
+            Arc::new(LogMailer),
+            Arc::new(LocalBus::new()),
+            Arc::new(Config::from_env()),
+        )
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// Result of the document as JSON, errors included
+    pub async fn run(
+        &self,
+        user: UserState,
+        query: &str,
+        variables: serde_json::Value,
+    ) -> serde_json::Value {
+        let request: juniper::http::GraphQLRequest =
+            serde_json::from_value(serde_json::json!({ "query": query, "variables": variables }))
+                .unwrap();
+        let context = self.context(user);
+        let response = request.execute(&self.schema, &context).await;
+        serde_json::to_value(&response).unwrap()
+    }
+
+    pub async fn close(self) {
+        self.pool.close().await;
+        self.admin
+            .execute(format!("DROP DATABASE {}", self.name).as_str())
+            .await
+            .unwrap();
+    }
+}
+
+/// Matches the users in tests/fixtures
+pub fn user(name: &str) -> UserState {
+    UserState::LoggedIn {
+        name: name.into(),
+        id: format!("{}-uid", name),
+        roles: vec![],
+    }
+}
+
+pub fn admin() -> UserState {
+    UserState::LoggedIn {
+        name: "admin".into(),
+        id: "admin-uid".into(),
+        roles: vec![Role::Admin],
+    }
+}
+
+pub fn errors(response: &serde_json::Value) -> Vec<String> {
+    response["errors"]
+        .as_array()
+        .map(|errors| {
+            errors
+                .iter()
+                .map(|error| error["message"].as_str().unwrap_or_default().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,15 @@
+-- Two users and an admin, one sub with a text post and a comment thread
+INSERT INTO public.user (uid, name, email, crypto, status, joindate)
+VALUES ('alice-uid', 'alice', 'alice@example.com', 1, 0, now()),
+       ('bob-uid', 'bob', 'bob@example.com', 1, 0, now()),
+       ('admin-uid', 'admin', 'admin@example.com', 1, 0, now());
+
+INSERT INTO sub (sid, name, title, nsfw, sidebar, creation)
+VALUES ('test-sid', 'test', 'Test', false, '', now());
+
+INSERT INTO sub_post (pid, sid, uid, title, content, ptype, posted, deleted, nsfw)
+VALUES (1, 'test-sid', 'alice-uid', 'First post', 'Hello', 0, now() - interval '1 hour', 0, false);
+
+INSERT INTO sub_post_comment (cid, pid, uid, content, time, status, parentcid, score, upvotes, downvotes)
+VALUES ('c1', 1, 'bob-uid', 'Top level', now() - interval '30 minutes', 0, NULL, 0, 0, 0),
+       ('c2', 1, 'alice-uid', 'Reply', now() - interval '20 minutes', 0, 'c1', 0, 0, 0);
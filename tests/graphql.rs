@@ -0,0 +1,307 @@
+mod common;
+
+use common::{admin, errors, user, TestDb};
+use model::auth::UserState;
+use serde_json::json;
+
+#[tokio::test]
+async fn me_requires_login() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+
+    let response = db
+        .run(UserState::Anonymous, "{ me { name } }", json!({}))
+        .await;
+    assert!(!errors(&response).is_empty());
+
+    let response = db.run(user("alice"), "{ me { name } }", json!({})).await;
+    assert_eq!(response["data"]["me"]["name"], "alice");
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn comment_tree_loads() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+
+    let response = db
+        .run(
+            UserState::Anonymous,
+            r#"{ getComment(id: "c1") { content children { edges { node { id } } } } }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(response["data"]["getComment"]["content"], "Top level");
+    assert_eq!(
+        response["data"]["getComment"]["children"]["edges"][0]["node"]["id"],
+        "c2"
+    );
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn only_admins_change_site_config() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+    let mutation = "mutation { setMaintenanceMode(enabled: false) }";
+
+    let response = db.run(user("alice"), mutation, json!({})).await;
+    assert!(!errors(&response).is_empty());
+
+    let response = db.run(admin(), mutation, json!({})).await;
+    assert_eq!(errors(&response), Vec::<String>::new());
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn only_authors_edit_comments() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+    let mutation = r#"mutation { editComment(id: "c1", content: "Edited") { content } }"#;
+
+    let response = db.run(user("alice"), mutation, json!({})).await;
+    assert!(!errors(&response).is_empty());
+
+    let response = db.run(user("bob"), mutation, json!({})).await;
+    assert_eq!(response["data"]["editComment"]["content"], "Edited");
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn created_posts_come_back() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+    let mutation = r#"mutation {
+        createPost(subName: "test", title: "Second post", content: "Hi") { title content }
+    }"#;
+
+    let response = db.run(UserState::anonymous(), mutation, json!({})).await;
+    assert!(!errors(&response).is_empty());
+
+    let response = db.run(user("alice"), mutation, json!({})).await;
+    assert_eq!(response["data"]["createPost"]["title"], "Second post");
+    assert_eq!(response["data"]["createPost"]["content"], "Hi");
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn comment_votes_count_once() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+    let mutation =
+        r#"mutation { voteComment(cid: "c1", direction: UP) { score upVotes viewerVote change } }"#;
+
+    let response = db.run(user("bob"), mutation, json!({})).await;
+    assert!(!errors(&response).is_empty());
+
+    db.run(user("alice"), mutation, json!({})).await;
+    let response = db.run(user("alice"), mutation, json!({})).await;
+    assert_eq!(response["data"]["voteComment"]["score"], 1);
+    assert_eq!(response["data"]["voteComment"]["upVotes"], 1);
+    assert_eq!(response["data"]["voteComment"]["viewerVote"], "UP");
+    assert_eq!(response["data"]["voteComment"]["change"], "UNCHANGED");
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn comment_deletion_records_who_deleted() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+
+    let mutation = r#"mutation { deleteComment(id: "c1") { deleted } }"#;
+    let response = db.run(user("alice"), mutation, json!({})).await;
+    assert!(!errors(&response).is_empty());
+
+    let response = db.run(admin(), mutation, json!({})).await;
+    assert_eq!(response["data"]["deleteComment"]["deleted"], "ADMIN");
+
+    let mutation = r#"mutation { deleteComment(id: "c2") { deleted } }"#;
+    let response = db.run(user("alice"), mutation, json!({})).await;
+    assert_eq!(response["data"]["deleteComment"]["deleted"], "USER");
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn subscribing_updates_the_count() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+
+    let response = db
+        .run(
+            user("alice"),
+            r#"mutation { subscribeSub(sub: "test") { subscribers } }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(response["data"]["subscribeSub"]["subscribers"], 1);
+
+    let response = db
+        .run(
+            user("alice"),
+            r#"mutation { unsubscribeSub(sub: "test") { subscribers } }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(response["data"]["unsubscribeSub"]["subscribers"], 0);
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn subs_refuse_post_types_they_turned_off() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+
+    let response = db
+        .run(
+            admin(),
+            r#"mutation { setAllowedPostTypes(sub: "test", types: [TEXT]) }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(response["data"]["setAllowedPostTypes"], json!(["TEXT"]));
+
+    let response = db
+        .run(
+            user("alice"),
+            r#"mutation {
+                createPost(subName: "test", title: "A link", link: "https://example.com") { title }
+            }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(
+        errors(&response),
+        vec!["This sub doesn't accept link posts"]
+    );
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn new_accounts_wait_where_subs_ask_for_it() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+
+    let response = db
+        .run(
+            admin(),
+            r#"mutation {
+                setPostingRequirements(sub: "test", minAccountAgeDays: 7) { minAccountAgeDays minScore }
+            }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(
+        response["data"]["setPostingRequirements"],
+        json!({"minAccountAgeDays": 7, "minScore": null})
+    );
+
+    let response = db
+        .run(
+            user("alice"),
+            r#"mutation { createPost(subName: "test", title: "Hi", content: "Hi") { title } }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(
+        errors(&response),
+        vec!["Your account has to be 7 days old to post or comment here"]
+    );
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn mods_see_reports_on_their_sub() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+
+    let report = r#"mutation { reportComment(id: "c2", reason: "Spam") }"#;
+    let response = db.run(user("bob"), report, json!({})).await;
+    assert_eq!(response["data"]["reportComment"], true);
+    let response = db.run(user("bob"), report, json!({})).await;
+    assert_eq!(errors(&response), vec!["You already reported this"]);
+
+    let reports = r#"{
+        getReports(subName: "test", status: OPEN) {
+            totalCount
+            edges { node { kind reason status } }
+        }
+    }"#;
+    let response = db.run(user("alice"), reports, json!({})).await;
+    assert!(!errors(&response).is_empty());
+
+    let response = db.run(admin(), reports, json!({})).await;
+    assert_eq!(response["data"]["getReports"]["totalCount"], 1);
+    assert_eq!(
+        response["data"]["getReports"]["edges"][0]["node"],
+        json!({"kind": "COMMENT", "reason": "Spam", "status": "OPEN"})
+    );
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn legacy_sub_listing_is_newest_first() {
+    let db = match TestDb::new().await {
+        Some(db) => db,
+        None => return,
+    };
+    let response = db
+        .run(
+            user("alice"),
+            r#"mutation { createPost(subName: "test", title: "Second post", content: "Hi") { title } }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(response["data"]["createPost"]["title"], "Second post");
+
+    let response = model::rest::sub_posts(
+        db.schema(),
+        db.context(user("bob")),
+        "test".into(),
+        "new".into(),
+        None,
+    )
+    .await;
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    let titles: Vec<&str> = body["posts"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|post| post["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(titles, vec!["Second post", "First post"]);
+
+    db.close().await;
+}